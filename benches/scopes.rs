@@ -0,0 +1,57 @@
+// Times scripts that lean on variable lookups with each kind of store scopes
+// can keep their variables in.  Rerunning it after changing a store, or after
+// adding a new kind, shows whether the change made scripts faster.
+//
+// Run it with `cargo bench`.
+
+extern crate gate;
+
+use gate::{Program, StoreKind};
+use std::time::{Duration, Instant};
+
+const ITERATIONS: usize = 20;
+
+const CALLS: &'static str = r#"
+    fn add(a, b) { c = a + b  c }
+    total = 0
+    for i in 0..20000 { total = add(total, i) }
+"#;
+
+const NESTED_BLOCKS: &'static str = r#"
+    total = 0
+    for i in 0..5000 {
+        a = i
+        for j in 0..4 { b = a + j  if b > 2 { c = b - 2  total += c } }
+    }
+"#;
+
+const GLOBALS: &'static str = r#"
+    a = 1  b = 2  c = 3  d = 4  e = 5  f = 6  g = 7  h = 8
+    total = 0
+    for i in 0..20000 { total += a + b + c + d + e + f + g + h }
+"#;
+
+fn time(source: &str, kind: StoreKind) -> Duration {
+    let mut total = Duration::new(0, 0);
+    for _ in 0..ITERATIONS {
+        let mut program = Program::with_store(kind);
+        let start = Instant::now();
+        program.run_source(source).expect("benchmark script failed");
+        total += start.elapsed();
+    }
+    total / ITERATIONS as u32
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1e6
+}
+
+fn main() {
+    let scripts = [("calls", CALLS), ("nested blocks", NESTED_BLOCKS), ("globals", GLOBALS)];
+    for &(name, source) in &scripts {
+        for &kind in &[StoreKind::Hash, StoreKind::Slots] {
+            let label = format!("{} ({:?}):", name, kind);
+            println!("{:<24} {:>8.3} ms", label, millis(time(source, kind)));
+        }
+    }
+}
@@ -0,0 +1,60 @@
+// Times a script that dispatches on string symbols, once with symbols that
+// come from string literals and once with the same symbols built at run time.
+// Equal literals share their storage, so the first comparisons are decided by
+// the pointer check that comparing two Rc<str>s starts with, while the second
+// have to compare characters.  Both run on the same build, so this shows what
+// sharing literals saves, not how strings compare to how they were stored
+// before they were Rc<str>s.
+//
+// Run it with `cargo bench`.
+
+extern crate gate;
+
+use gate::Program;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: usize = 20;
+
+const DISPATCH: &'static str = r#"
+    n = 0
+    for i in 0..20000 {
+        switch ops[i % 4] {
+            case "counter_increment": n += 1
+            case "counter_decrement": n -= 1
+            case "counter_double": n *= 2
+            default: n = 0
+        }
+    }
+"#;
+
+const LITERAL: &'static str = r#"
+    ops = ["counter_increment", "counter_decrement", "counter_double", "counter_reset"]
+"#;
+
+const BUILT: &'static str = r#"
+    ops = [lower("COUNTER_INCREMENT"), lower("COUNTER_DECREMENT"),
+           lower("COUNTER_DOUBLE"), lower("COUNTER_RESET")]
+"#;
+
+fn time(setup: &str) -> Duration {
+    let source = format!("{}\n{}", setup, DISPATCH);
+    let mut total = Duration::new(0, 0);
+    for _ in 0..ITERATIONS {
+        let mut program = Program::new();
+        let start = Instant::now();
+        program.run_source(&source).expect("benchmark script failed");
+        total += start.elapsed();
+    }
+    total / ITERATIONS as u32
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1e6
+}
+
+fn main() {
+    let literal = time(LITERAL);
+    let built = time(BUILT);
+    println!("interned symbols: {:>8.3} ms", millis(literal));
+    println!("built symbols:    {:>8.3} ms", millis(built));
+}
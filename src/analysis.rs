@@ -0,0 +1,339 @@
+// The analysis module inspects parsed programs without running them, so hosts
+// can vet scripts before evaluating them.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use indexmap::IndexMap;
+
+use binary_op::BinaryOp;
+use data::Data;
+use expr::Expression;
+use expr::Expression::*;
+
+// A Lint is a likely mistake found by analysing a program.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Lint {
+    // A function that isn't called from the top level of the program, either
+    // directly or through other functions.
+    UnusedFunction(String),
+    // A branch whose condition always has the same value, so it never runs.
+    // The function holding it is given, if any.
+    DeadBranch {
+        func: Option<String>,
+        branch: Branch,
+    },
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Branch {
+    IfBody,
+    IfElse,
+    WhileBody,
+}
+
+impl Lint {
+    // code returns the lint's code, which `gate explain` describes.
+    pub fn code(&self) -> &'static str {
+        match self {
+            &Lint::UnusedFunction(_) => "E0036",
+            &Lint::DeadBranch { .. } => "E0037",
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Lint::UnusedFunction(ref name) => write!(f, "function \"{}\" is never called", name),
+            &Lint::DeadBranch { ref func, branch } => {
+                match branch {
+                    Branch::IfBody => write!(f, "the body of an if")?,
+                    Branch::IfElse => write!(f, "the else branch of an if")?,
+                    Branch::WhileBody => write!(f, "the body of a while loop")?,
+                }
+                if let &Some(ref name) = func {
+                    write!(f, " in function \"{}\"", name)?;
+                }
+                let always = if branch == Branch::IfElse { "true" } else { "false" };
+                write!(f, " never runs, since its condition is always {}", always)
+            }
+        }
+    }
+}
+
+// A Call is an edge in a program's call graph.  The caller is the function
+// the call is made from, or None for calls at the top level of the program.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Call {
+    pub caller: Option<String>,
+    pub callee: String,
+}
+
+// call_graph returns every distinct call made by a program, in the order they
+// first appear.  Namespaced builtins are named as `namespace.name`.  Calls to
+// computed values, such as `handlers[0]()`, can't be known without running the
+// program and aren't included.
+pub fn call_graph(exprs: &[Expression]) -> Vec<Call> {
+    let mut calls = vec![];
+    for e in exprs {
+        collect_calls(e, None, &mut calls);
+    }
+    calls
+}
+
+fn collect_calls(e: &Expression, caller: Option<&String>, calls: &mut Vec<Call>) {
+    if let &FunctionCall { ref func, .. } = e {
+        let callee = match **func {
+            Variable(ref name) => Some(name.clone()),
+            FieldAccess { ref object, ref field } => {
+                match **object {
+                    Variable(ref ns) => Some(format!("{}.{}", ns, field)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        if let Some(callee) = callee {
+            let call = Call {
+                caller: caller.cloned(),
+                callee: callee,
+            };
+            if !calls.contains(&call) {
+                calls.push(call);
+            }
+        }
+    }
+
+    let caller = match e {
+        &FunctionDef { ref name, .. } => Some(name),
+        _ => caller,
+    };
+    for c in e.children() {
+        collect_calls(c, caller, calls);
+    }
+}
+
+// defined_functions returns the names of the functions a program defines.
+pub fn defined_functions(exprs: &[Expression]) -> HashSet<String> {
+    fn collect(e: &Expression, names: &mut HashSet<String>) {
+        if let &FunctionDef { ref name, .. } = e {
+            names.insert(name.clone());
+        }
+        for c in e.children() {
+            collect(c, names);
+        }
+    }
+
+    let mut names = HashSet::new();
+    for e in exprs {
+        collect(e, &mut names);
+    }
+    names
+}
+
+// dead_code finds functions that are never called and branches that can never
+// run.  Functions are matched by name, so a function counts as called if
+// anything with its name is used.
+pub fn dead_code(exprs: &[Expression]) -> Vec<Lint> {
+    let mut lints = vec![];
+
+    // Maps each function to the names used in its body.  The top level of the
+    // program is stored under None.
+    let mut uses = IndexMap::new();
+    for e in exprs {
+        collect_uses(e, None, &mut uses);
+    }
+
+    let mut called = HashSet::new();
+    let mut pending: Vec<&String> = uses.get(&None).map_or(vec![], |u| u.iter().collect());
+    while let Some(name) = pending.pop() {
+        if called.insert(name.clone()) {
+            if let Some(u) = uses.get(&Some(name.clone())) {
+                pending.extend(u.iter());
+            }
+        }
+    }
+    for name in uses.keys().filter_map(|k| k.as_ref()) {
+        if !called.contains(name) {
+            lints.push(Lint::UnusedFunction(name.clone()));
+        }
+    }
+
+    for e in exprs {
+        dead_branches(e, None, &mut lints);
+    }
+    lints
+}
+
+fn collect_uses(e: &Expression,
+                func: Option<&String>,
+                uses: &mut IndexMap<Option<String>, Vec<String>>) {
+    match e {
+        &FunctionDef { ref name, ref body, .. } => {
+            uses.entry(Some(name.clone())).or_insert(vec![]);
+            collect_uses(body, Some(name), uses);
+        }
+        &Variable(ref name) => {
+            uses.entry(func.cloned()).or_insert(vec![]).push(name.clone());
+        }
+        e => {
+            for c in e.children() {
+                collect_uses(c, func, uses);
+            }
+        }
+    }
+}
+
+fn dead_branches(e: &Expression, func: Option<&String>, lints: &mut Vec<Lint>) {
+    let mut dead = |branch| {
+        lints.push(Lint::DeadBranch {
+            func: func.cloned(),
+            branch: branch,
+        })
+    };
+
+    match e {
+        &IfExpr { ref cond, ref else_branch, .. } => {
+            match constant(cond).map(|d| d.to_bool()) {
+                Some(true) if else_branch.is_some() => dead(Branch::IfElse),
+                Some(false) => dead(Branch::IfBody),
+                _ => {}
+            }
+        }
+        &WhileLoop { ref cond, .. } => {
+            if constant(cond).map(|d| d.to_bool()) == Some(false) {
+                dead(Branch::WhileBody);
+            }
+        }
+        _ => {}
+    }
+
+    let func = match e {
+        &FunctionDef { ref name, .. } => Some(name),
+        _ => func,
+    };
+    for c in e.children() {
+        dead_branches(c, func, lints);
+    }
+}
+
+// constant returns the value of an expression that's the same every time it's
+// evaluated, such as `1 + 2`, by folding literals together.
+pub fn constant(e: &Expression) -> Option<Data> {
+    match e {
+        &NilLiteral => Some(Data::Nil),
+        &BooleanLiteral(b) => Some(Data::Boolean(b)),
+        &NumberLiteral(n) => Some(Data::Number(n)),
+        &IntLiteral(i) => Some(Data::Int(i)),
+        &StrLiteral(ref s) => Some(Data::Str(s.clone())),
+        &ParenExpr(ref e) => constant(e),
+        &UnaryExpr { ref op, ref operand } => op.eval(&constant(operand)?).ok(),
+        // A logical operator is constant if its left side decides the result,
+        // even when the right side isn't.
+        &BinaryExpr { ref left, op: BinaryOp::And, ref right, .. } => {
+            if !constant(left)?.to_bool() {
+                return Some(Data::Boolean(false));
+            }
+            constant(right).map(|r| Data::Boolean(r.to_bool()))
+        }
+        &BinaryExpr { ref left, op: BinaryOp::Or, ref right, .. } => {
+            if constant(left)?.to_bool() {
+                return Some(Data::Boolean(true));
+            }
+            constant(right).map(|r| Data::Boolean(r.to_bool()))
+        }
+        &BinaryExpr { ref left, op: BinaryOp::Coalesce, ref right, .. } => {
+            match constant(left)? {
+                Data::Nil => constant(right),
+                d => Some(d),
+            }
+        }
+        &BinaryExpr { ref left, ref op, ref right, .. } => {
+            op.eval(&constant(left)?, &constant(right)?).ok()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data::Data::*;
+    use parser::Parser;
+    use super::*;
+
+    fn parse(source: &str) -> Vec<Expression> {
+        Parser::new(source).map(|e| e.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_constant() {
+        let cases = vec![
+            ("1 + 2 * 3", Some(Int(7))),
+            ("-(1 - 3)", Some(Int(2))),
+            ("1 / 2", Some(Number(0.5))),
+            ("\"a\" == \"a\"", Some(Boolean(true))),
+            ("false && x", Some(Boolean(false))),
+            ("x && false", None),
+            ("nil ?? 2", Some(Int(2))),
+            ("1 ?? x", Some(Int(1))),
+            ("1 + x", None),
+            ("1 + nil", None),
+        ];
+
+        for (source, exp) in cases {
+            assert_eq!(constant(&parse(source)[0]), exp);
+        }
+    }
+
+    #[test]
+    fn test_call_graph() {
+        let call = |caller: Option<&str>, callee: &str| {
+            Call {
+                caller: caller.map(|c| c.to_owned()),
+                callee: callee.to_owned(),
+            }
+        };
+
+        let exprs = parse("fn f(x) { println(x)  math.sqrt(g(x)) }  fn g(x) { x[0]() }  \
+                           f(1)  println(f)  f(2)");
+        assert_eq!(call_graph(&exprs),
+                   vec![call(Some("f"), "println"),
+                        call(Some("f"), "math.sqrt"),
+                        call(Some("f"), "g"),
+                        call(None, "f"),
+                        call(None, "println")]);
+    }
+
+    #[test]
+    fn test_unused_functions() {
+        let exprs = parse("fn a() { b() }  fn b() { 1 }  fn c() { d() }  fn d() { c() }  \
+                           fn e() { e() }  a()");
+        assert_eq!(dead_code(&exprs),
+                   vec![Lint::UnusedFunction("c".to_owned()),
+                        Lint::UnusedFunction("d".to_owned()),
+                        Lint::UnusedFunction("e".to_owned())]);
+
+        // Passing a function around counts as using it.
+        let exprs = parse("fn a() { 1 }  list(a)");
+        assert_eq!(dead_code(&exprs), vec![]);
+    }
+
+    #[test]
+    fn test_dead_branches() {
+        let exprs = parse("if 1 < 2 { a } else { b }  if x { a } else { b }  \
+                           fn f() { while x { } }  fn g() { if nil || false { 1 } }  \
+                           f()  g()");
+        assert_eq!(dead_code(&exprs),
+                   vec![Lint::DeadBranch {
+                            func: None,
+                            branch: Branch::IfElse,
+                        },
+                        Lint::DeadBranch {
+                            func: Some("g".to_owned()),
+                            branch: Branch::IfBody,
+                        }]);
+        assert_eq!(dead_code(&parse("while 0 > 1 { }"))[0].to_string(),
+                   "the body of a while loop never runs, since its condition is always false");
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use data::Data;
+
+// An AuditEvent records something a script did that a host may need to keep
+// track of.  The interpreter records builtin calls itself, while builtins that
+// touch files, the network or the environment record their own events with Context::audit.
+#[derive(Clone,Debug,PartialEq)]
+pub enum AuditEvent {
+    // The arguments are None if the builtin's arguments are redacted.
+    BuiltinCall {
+        name: String,
+        args: Option<Vec<Data>>,
+    },
+    FileAccess {
+        path: String,
+        write: bool,
+    },
+    NetAccess {
+        addr: String,
+        listen: bool,
+    },
+    EnvAccess(String),
+}
+
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &AuditEvent::BuiltinCall { ref name, args: Some(ref args) } => {
+                write!(f, "call {}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}", arg)?;
+                }
+                write!(f, ")")
+            }
+            &AuditEvent::BuiltinCall { ref name, args: None } => {
+                write!(f, "call {}(<redacted>)", name)
+            }
+            &AuditEvent::FileAccess { ref path, write: true } => write!(f, "write file {}", path),
+            &AuditEvent::FileAccess { ref path, write: false } => write!(f, "read file {}", path),
+            &AuditEvent::NetAccess { ref addr, listen: true } => write!(f, "listen {}", addr),
+            &AuditEvent::NetAccess { ref addr, listen: false } => write!(f, "connect {}", addr),
+            &AuditEvent::EnvAccess(ref name) => write!(f, "read env {}", name),
+        }
+    }
+}
+
+// An AuditLog collects the events of a running script.
+#[derive(Debug)]
+pub struct AuditLog {
+    pub events: Vec<AuditEvent>,
+    // The builtins whose arguments are left out of the log.
+    redacted: HashSet<String>,
+}
+
+impl AuditLog {
+    pub fn new(redacted: &[&str]) -> Self {
+        AuditLog {
+            events: vec![],
+            redacted: redacted.iter().map(|&name| name.to_owned()).collect(),
+        }
+    }
+
+    pub fn builtin_call(&mut self, name: &str, args: &[Data]) {
+        let args = if self.redacted.contains(name) {
+            None
+        } else {
+            Some(args.to_vec())
+        };
+        self.events.push(AuditEvent::BuiltinCall {
+            name: name.to_owned(),
+            args: args,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data::Data::*;
+    use super::*;
+
+    #[test]
+    fn test_redaction() {
+        let mut log = AuditLog::new(&["login"]);
+        log.builtin_call("list", &[Number(1.0), Str("a".into())]);
+        log.builtin_call("login", &[Str("hunter2".into())]);
+
+        let lines: Vec<_> = log.events.iter().map(|e| e.to_string()).collect();
+        assert_eq!(lines, vec![r#"call list(Number(1.0), Str("a"))"#, "call login(<redacted>)"]);
+    }
+}
@@ -1,30 +1,174 @@
 extern crate clap;
 extern crate gate;
 extern crate rustyline;
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
 
-use std::{fs, io};
-use std::io::Read;
+use std::{env, fs, io, path, process, thread};
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+
+// STACK_SIZE is the size of the stack scripts run on, which is far bigger than
+// a main thread's so that they can recurse deeply.  Gate's own limit is a
+// little under it, so recursing too deeply is an error rather than a crash.
+const STACK_SIZE: usize = 64 << 20;
 
 fn main() {
+    let script = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(|| {
+            gate::stack::set_limit(STACK_SIZE - (4 << 20));
+            run_cli();
+        })
+        .unwrap();
+    // The panic has already been reported.
+    if script.join().is_err() {
+        process::exit(101);
+    }
+}
+
+fn run_cli() {
     let matches = clap::App::new("gate")
         .version("0.1.0")
         .about("A simple programming language")
         .arg(clap::Arg::with_name("interactive")
             .short("i")
             .long("interactive"))
+        .arg(clap::Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Read settings from FILE instead of ~/.gaterc"))
+        .arg(clap::Arg::with_name("plugin")
+            .long("plugin")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("LIB")
+            .help("Load builtins from a plugin library (needs the plugins feature)"))
+        .arg(clap::Arg::with_name("log-file")
+            .long("log-file")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Append a JSON record of each runtime error in INPUT to FILE"))
         .arg(clap::Arg::with_name("INPUT").help("An optional file to run"))
+        .subcommand(clap::SubCommand::with_name("rename")
+            .about("Renames a variable, leaving others with the same name alone")
+            .arg(clap::Arg::with_name("write")
+                .short("w")
+                .long("write")
+                .help("Overwrite the file instead of printing the result"))
+            .arg(clap::Arg::with_name("FILE").required(true))
+            .arg(clap::Arg::with_name("OLD")
+                .required(true)
+                .help("The variable to rename, found by its first use"))
+            .arg(clap::Arg::with_name("NEW").required(true)))
+        .subcommand(clap::SubCommand::with_name("compile")
+            .about("Compiles a script into a binary file that runs without parsing")
+            .arg(clap::Arg::with_name("output")
+                .short("o")
+                .takes_value(true)
+                .help("Where to write the compiled script (defaults to FILE with .gatec)"))
+            .arg(clap::Arg::with_name("FILE").required(true)))
+        .subcommand(clap::SubCommand::with_name("doctest")
+            .about("Runs the examples in a script's comments and reports any that fail")
+            .arg(clap::Arg::with_name("write")
+                .short("w")
+                .long("write")
+                .help("Record the actual output of failing examples in the file"))
+            .arg(clap::Arg::with_name("FILE").required(true)))
+        .subcommand(clap::SubCommand::with_name("notebook")
+            .about("Runs cells of code sent as JSON requests on stdin, one per line"))
+        .subcommand(clap::SubCommand::with_name("lint")
+            .about("Reports functions that are never called and branches that never run")
+            .arg(clap::Arg::with_name("FILE").required(true)))
+        .subcommand(clap::SubCommand::with_name("calls")
+            .about("Lists the calls each function makes")
+            .arg(clap::Arg::with_name("dot")
+                .long("dot")
+                .help("Print the call graph in Graphviz's dot format"))
+            .arg(clap::Arg::with_name("FILE").required(true)))
+        .subcommand(clap::SubCommand::with_name("test")
+            .about("Runs the test_ functions in scripts and reports any that fail")
+            .arg(clap::Arg::with_name("PATH")
+                .help("A script, or a directory of scripts (defaults to tests/lang)")))
+        .subcommand(clap::SubCommand::with_name("explain")
+            .about("Describes an error code, such as E0007, with examples")
+            .arg(clap::Arg::with_name("CODE").required(true)))
+        .subcommand(clap::SubCommand::with_name("parse")
+            .about("Prints what a script parses to, or checks a corpus of parser tests")
+            .arg(clap::Arg::with_name("verify")
+                .long("verify")
+                .conflicts_with("update")
+                .help("Check every script in the corpus at PATH against its snapshot"))
+            .arg(clap::Arg::with_name("update")
+                .long("update")
+                .help("Write the snapshots in the corpus at PATH that are missing or differ"))
+            .arg(clap::Arg::with_name("PATH")
+                .required_unless_one(&["verify", "update"])
+                .help("The script to parse, or the corpus (defaults to tests/corpus)")))
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("rename") {
+        run_rename(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("compile") {
+        run_compile(matches.value_of("FILE").unwrap(), matches.value_of("output"));
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("doctest") {
+        run_doctest(matches.value_of("FILE").unwrap(), matches.is_present("write"));
+        return;
+    }
+    if matches.subcommand_matches("notebook").is_some() {
+        run_notebook();
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("lint") {
+        run_lint(matches.value_of("FILE").unwrap());
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("parse") {
+        run_parse(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("test") {
+        run_tests(matches.value_of("PATH").unwrap_or("tests/lang"));
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("explain") {
+        run_explain(matches.value_of("CODE").unwrap());
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("calls") {
+        run_calls(matches.value_of("FILE").unwrap(), matches.is_present("dot"));
+        return;
+    }
+
+    // Scripts run from the command line are the user's own, so unlike embedded
+    // ones they may use files and the network, unless the configuration file
+    // says otherwise.
+    let config = load_config(matches.value_of("config"));
     let mut program = gate::Program::new();
+    program.allow_fs(config.sandbox != "closed");
+    program.allow_net(config.sandbox == "open");
+    for path in matches.values_of("plugin").into_iter().flat_map(|paths| paths) {
+        load_plugin(&mut program, path);
+    }
     let mut has_run = false;
 
     if let Some(input) = matches.value_of("INPUT") {
-        run_file(&mut program, input);
+        run_file(&mut program, input, matches.value_of("log-file"));
         has_run = true;
     }
 
     if matches.is_present("interactive") {
-        run_interactive(&mut program);
+        run_interactive(&mut program, &config);
         has_run = true;
     }
 
@@ -33,88 +177,808 @@ fn main() {
     }
 }
 
-fn run_interactive(program: &mut gate::Program) {
+// load_plugin loads the plugin at path, ending the process if it can't.
+// Plugins are named by the user running the script, who trusts them as much
+// as the command itself.
+#[cfg(feature = "plugins")]
+fn load_plugin(program: &mut gate::Program, path: &str) {
+    // Safety: the plugins are trusted, and nothing outlives the program, since
+    // the process ends with it.
+    if let Err(e) = unsafe { program.load_plugin(path) } {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    }
+}
+
+#[cfg(not(feature = "plugins"))]
+fn load_plugin(_: &mut gate::Program, path: &str) {
+    eprintln!("{}: can't load plugin: gate was built without the plugins feature", path);
+    process::exit(1);
+}
+
+// Config holds the settings from the configuration file, which is a gate
+// script setting any of the variables named after its fields.
+struct Config {
+    prompt: String,
+    continuation_prompt: String,
+    theme: Theme,
+    history_size: usize,
+    // "open" lets scripts use files and the network, "local" only files, and
+    // "closed" neither.
+    sandbox: String,
+    // Scripts to run before the REPL starts.
+    preload: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            prompt: "> ".to_owned(),
+            continuation_prompt: ">> ".to_owned(),
+            theme: Theme::default(),
+            history_size: 100,
+            sandbox: "open".to_owned(),
+            preload: vec![],
+        }
+    }
+}
+
+// A Theme colors the REPL's results and errors with ANSI escape codes.  The
+// default theme, "plain", leaves them alone.
+#[derive(Clone,Copy,Default)]
+struct Theme {
+    result: &'static str,
+    error: &'static str,
+}
+
+impl Theme {
+    fn named(name: &str) -> Option<Theme> {
+        let (result, error) = match name {
+            "plain" => ("", ""),
+            "dark" => ("\x1b[36m", "\x1b[91m"),
+            "light" => ("\x1b[34m", "\x1b[31m"),
+            _ => return None,
+        };
+        Some(Theme {
+            result: result,
+            error: error,
+        })
+    }
+
+    fn print_result<T: std::fmt::Display>(&self, text: T) {
+        print_colored(self.result, text)
+    }
+
+    fn print_error<T: std::fmt::Display>(&self, text: T) {
+        print_colored(self.error, text)
+    }
+}
+
+fn print_colored<T: std::fmt::Display>(color: &str, text: T) {
+    if color.is_empty() {
+        println!("{}", text);
+    } else {
+        println!("{}{}\x1b[0m", color, text);
+    }
+}
+
+// load_config runs the configuration file at path, or at ~/.gaterc if no path
+// is given, and returns its settings.  It's fine for ~/.gaterc not to exist,
+// but any other problem with the file ends the process.
+fn load_config(path: Option<&str>) -> Config {
+    let mut config = Config::default();
+    let path = match (path, env::var_os("HOME")) {
+        (Some(path), _) => path::PathBuf::from(path),
+        (None, Some(home)) => {
+            let path = path::Path::new(&home).join(".gaterc");
+            if !path.exists() {
+                return config;
+            }
+            path
+        }
+        (None, None) => return config,
+    };
+    if let Err(message) = read_config(&path, &mut config) {
+        eprintln!("{}: {}", path.display(), message);
+        process::exit(1);
+    }
+    config
+}
+
+fn read_config(path: &path::Path, config: &mut Config) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut program = gate::Program::new();
+    program.run_source(&source).map_err(|e| e.to_string())?;
+
+    if let Some(prompt) = setting(&program, "prompt")? {
+        config.prompt = prompt;
+    }
+    if let Some(prompt) = setting(&program, "continuation_prompt")? {
+        config.continuation_prompt = prompt;
+    }
+    if let Some(name) = setting::<String>(&program, "theme")? {
+        config.theme = Theme::named(&name)
+            .ok_or_else(|| format!("unknown theme \"{}\" (try plain, dark or light)", name))?;
+    }
+    if let Some(size) = setting(&program, "history_size")? {
+        config.history_size = size;
+    }
+    if let Some(sandbox) = setting::<String>(&program, "sandbox")? {
+        if !["open", "local", "closed"].contains(&sandbox.as_str()) {
+            return Err(format!("unknown sandbox \"{}\" (try open, local or closed)", sandbox));
+        }
+        config.sandbox = sandbox;
+    }
+    if let Some(preload) = setting::<Vec<String>>(&program, "preload")? {
+        config.preload = preload.iter().map(|p| expand_home(p)).collect();
+    }
+    Ok(())
+}
+
+// expand_home replaces a leading ~ in path with the user's home directory.
+fn expand_home(path: &str) -> String {
+    match (path.starts_with("~/"), env::var("HOME")) {
+        (true, Ok(home)) => format!("{}{}", home, &path[1..]),
+        _ => path.to_owned(),
+    }
+}
+
+// setting returns the value of a variable set by the configuration file, or
+// None if it isn't set.
+fn setting<T: DeserializeOwned>(program: &gate::Program, name: &str) -> Result<Option<T>, String> {
+    if program.var(name).is_none() {
+        return Ok(None);
+    }
+    program.var_as(name).map(Some).map_err(|e| format!("{}: {}", name, e))
+}
+
+// Session holds the REPL's theme and its memory of what the user has entered.
+struct Session {
+    theme: Theme,
+    // The last complete input that was evaluated.
+    last_input: String,
+    // The input that most recently defined each function.
+    func_sources: HashMap<String, String>,
+}
+
+fn run_interactive(program: &mut gate::Program, config: &Config) {
+    for path in &config.preload {
+        run_file(program, path, None);
+    }
+
     let mut rl = rustyline::Editor::new();
+    rl.set_history_max_len(config.history_size);
+    let mut session = Session {
+        theme: config.theme,
+        last_input: String::new(),
+        func_sources: HashMap::new(),
+    };
 
     'outer: loop {
-        let mut line = match rl.readline("> ") {
+        let mut line = match rl.readline(&config.prompt) {
             Ok(l) => l,
             Err(_) => break 'outer,
         };
 
+        if line.trim().starts_with(':') {
+            rl.add_history_entry(&line);
+            run_command(program, &mut session, line.trim());
+            continue 'outer;
+        }
+
+        // The number of expressions at the start of the buffer that have
+        // already been evaluated.
+        let mut done = 0;
+
         loop {
-            let mut needs_more_input = false;
-            let mut exprs = vec![];
-
-            {
-                let parser = gate::Parser::new(&line);
-                for expr_res in parser {
-                    match expr_res {
-                        Ok(e) => exprs.push(e),
-                        Err(gate::ParseError::UnexpectedEOF) => {
-                            needs_more_input = true;
-                            break;
-                        }
-                        Err(gate::ParseError::ScanError(gate::TokenError::IncompleteString)) => {
-                            needs_more_input = true;
-                            break;
-                        }
-                        Err(e) => {
-                            println!("{:?}", e);
-                            continue 'outer;
-                        }
+            let (exprs, err) = parse_input(&line);
+            let ok = eval_and_print(program, session.theme, &exprs[done..]);
+
+            match err {
+                Some(ref e) if e.is_incomplete() && ok => {
+                    // The input ended in the middle of an expression, like
+                    // `1 +`, so keep it and wait for the rest.  Everything
+                    // before it has been evaluated, and adding to the end of
+                    // the buffer can't change how it parsed.
+                    done = exprs.len();
+                    line.push('\n');
+                    match rl.readline(&config.continuation_prompt) {
+                        Ok(l) => line.push_str(&l),
+                        Err(_) => break 'outer,
                     }
                 }
+                Some(ref e) if e.is_incomplete() => continue 'outer,
+                Some(e) => {
+                    session.theme.print_error(gate::Error::from(e));
+                    continue 'outer;
+                }
+                None => {
+                    rl.add_history_entry(&line);
+                    session.remember(&line, &exprs);
+                    continue 'outer;
+                }
+            }
+        }
+    }
+}
+
+// parse_input parses as many expressions as it can from the start of input,
+// returning them along with the error that stopped it, if any.
+fn parse_input(input: &str) -> (Vec<gate::Expression>, Option<gate::ParseError>) {
+    let mut exprs = vec![];
+    for expr_res in gate::Parser::new(input) {
+        match expr_res {
+            Ok(e) => exprs.push(e),
+            Err(e) => return (exprs, Some(e)),
+        }
+    }
+    (exprs, None)
+}
+
+impl Session {
+    fn remember(&mut self, input: &str, exprs: &[gate::Expression]) {
+        self.last_input = input.to_owned();
+        for expr in exprs {
+            if let &gate::Expression::FunctionDef { ref name, .. } = expr {
+                self.func_sources.insert(name.clone(), input.to_owned());
+            }
+        }
+    }
+}
+
+// eval_and_print evaluates each expression and prints the value of the last
+// one, stopping at the first error.  It returns whether every expression was
+// evaluated successfully.
+fn eval_and_print(program: &mut gate::Program,
+                  theme: Theme,
+                  exprs: &[gate::Expression])
+                  -> bool {
+    if exprs.is_empty() {
+        return true;
+    }
+
+    let mut last_result = gate::Data::Nil;
+    for expr in exprs {
+        let res = program.eval(expr);
+        print_warnings(program);
+        last_result = match res {
+            Ok(d) => d,
+            Err(gate::ExecuteError::Exit(status)) => process::exit(status),
+            Err(e) => {
+                theme.print_error(gate::Error::from(e));
+                return false;
             }
+        };
+    }
+    theme.print_result(format!("{:?} : {}", last_result, last_result.type_name()));
+    true
+}
 
-            if !needs_more_input {
-                rl.add_history_entry(&line);
+// run_command runs a REPL command, which is a line starting with a colon.
+fn run_command(program: &mut gate::Program, session: &mut Session, command: &str) {
+    if command == ":type" || command.starts_with(":type ") {
+        print_type(program, session.theme, &command[":type".len()..]);
+        return;
+    }
 
-                let mut last_result = gate::Data::Nil;
-                for expr in exprs {
-                    last_result = match expr.eval(program) {
-                        Ok(d) => d,
-                        Err(e) => {
-                            println!("error: {}", e);
-                            continue 'outer;
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some(":edit"), name, None) => {
+            let source = match name {
+                Some(name) => {
+                    match session.func_sources.get(name) {
+                        Some(source) => source.clone(),
+                        None => {
+                            let message = format!("error: no function named \"{}\" was defined \
+                                                   here",
+                                                  name);
+                            session.theme.print_error(message);
+                            return;
                         }
-                    };
+                    }
                 }
-                println!("{:?}", last_result);
-                continue 'outer;
-            } else {
-                line.push('\n');
-                match rl.readline(">> ") {
-                    Ok(l) => line.push_str(&l),
-                    Err(_) => break 'outer,
+                None => session.last_input.clone(),
+            };
+
+            let edited = match edit_in_editor(&source) {
+                Ok(edited) => edited,
+                Err(e) => {
+                    session.theme.print_error(format!("error: can't run editor: {}", e));
+                    return;
                 }
+            };
+
+            let exprs = match parse_input(&edited) {
+                (_, Some(e)) => {
+                    session.theme.print_error(gate::Error::from(e));
+                    return;
+                }
+                (exprs, None) => exprs,
+            };
+
+            session.remember(edited.trim(), &exprs);
+            eval_and_print(program, session.theme, &exprs);
+        }
+        _ => {
+            let message = format!("error: unknown command \"{}\" (try :edit, :edit <function> \
+                                   or :type <expr>)",
+                                  command);
+            session.theme.print_error(message)
+        }
+    }
+}
+
+// print_type prints the type of the expression in source.  Constant
+// expressions aren't evaluated at all, and anything else is evaluated in
+// isolation, so it changes nothing, with its output thrown away.
+fn print_type(program: &mut gate::Program, theme: Theme, source: &str) {
+    let expr = match parse_input(source) {
+        (_, Some(e)) => {
+            theme.print_error(gate::Error::from(e));
+            return;
+        }
+        (ref exprs, None) if exprs.len() != 1 => {
+            theme.print_error("error: :type takes a single expression");
+            return;
+        }
+        (mut exprs, None) => exprs.remove(0),
+    };
+
+    let res = match gate::analysis::constant(&expr) {
+        Some(d) => Ok(d),
+        None => {
+            program.capture_output();
+            let res = program.eval_isolated(&expr);
+            program.context.end_capture();
+            print_warnings(program);
+            res
+        }
+    };
+    match res {
+        Ok(d) => theme.print_result(d.type_name()),
+        Err(e) => theme.print_error(gate::Error::from(e)),
+    }
+}
+
+// edit_in_editor opens source in the user's editor and returns the text once
+// the editor exits.
+fn edit_in_editor(source: &str) -> io::Result<String> {
+    let editor = env::var("VISUAL").or(env::var("EDITOR")).unwrap_or("vi".to_owned());
+    let mut editor_args = editor.split_whitespace();
+    let editor_cmd = editor_args.next().unwrap_or("vi");
+
+    let path = env::temp_dir().join(format!("gate-edit-{}.gate", process::id()));
+    fs::File::create(&path)?.write_all(source.as_bytes())?;
+
+    let status = process::Command::new(editor_cmd).args(editor_args).arg(&path).status();
+    let mut edited = String::new();
+    let read = fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut edited));
+    fs::remove_file(&path).ok();
+
+    if !status?.success() {
+        return Err(io::Error::new(io::ErrorKind::Other, "editor exited with an error"));
+    }
+    read?;
+    Ok(edited)
+}
+
+// run runs a script from source, printing any error, and returns its result.
+fn run(program: &mut gate::Program, input: &str) -> Result<gate::Data, gate::Error> {
+    let res = program.run_source(input);
+    print_warnings(program);
+    exit_if_asked(&res);
+    if let Err(ref e) = res {
+        println!("{}", e);
+        if let Some(span) = e.span() {
+            let (line, column) = span.position(input);
+            println!("  at line {}, column {}: {}", line, column, &input[span.start..span.end]);
+        }
+    }
+    res
+}
+
+// exit_if_asked ends the process with the status a script passed to exit.
+fn exit_if_asked(res: &Result<gate::Data, gate::Error>) {
+    if let &Err(gate::Error::Execute(gate::ExecuteError::Exit(status))) = res {
+        process::exit(status);
+    }
+}
+
+fn print_warnings(program: &mut gate::Program) {
+    for w in program.take_warnings() {
+        eprintln!("warning[{}]: {}", w.code(), w);
+    }
+}
+
+// run_file runs a script, which may be source or compiled with `gate compile`.
+// If log_file is given, a runtime error is also logged there.
+fn run_file(program: &mut gate::Program, filename: &str, log_file: Option<&str>) {
+    program.trace_errors(log_file.is_some());
+    let input = fs::read(filename).expect("can't open file");
+    let (source, res) = if gate::compiled::is_compiled(&input) {
+        let res = program.load_compiled(&input);
+        print_warnings(program);
+        exit_if_asked(&res);
+        if let Err(ref e) = res {
+            println!("{}", e);
+        }
+        (None, res)
+    } else {
+        match String::from_utf8(input) {
+            Ok(source) => {
+                let res = run(program, &source);
+                (Some(source), res)
+            }
+            Err(_) => {
+                println!("error: {} isn't valid UTF-8", filename);
+                return;
             }
         }
+    };
+
+    if let (Some(log_file), Err(e)) = (log_file, res) {
+        let trace = program.take_error_trace();
+        log_error(log_file, filename, source.as_ref().map(|s| s.as_str()), &e, trace);
     }
 }
 
-fn run(program: &mut gate::Program, input: String) {
-    let parser = gate::Parser::new(&input);
-    for expr in parser {
-        match expr.unwrap().eval(program) {
-            Ok(_) => {}
+// log_error appends a JSON record of a runtime error to the file at path, on
+// one line, for diagnosing scripts that run unattended, such as from cron.
+// The record holds when and where the error happened, the functions that were
+// running and the variables in scope.  Errors that stop a script from
+// starting, like parse errors, aren't logged.
+fn log_error(path: &str,
+             filename: &str,
+             source: Option<&str>,
+             e: &gate::Error,
+             trace: Option<gate::ErrorTrace>) {
+    match e {
+        &gate::Error::Execute(_) | &gate::Error::Internal(_) => {}
+        _ => return,
+    }
+
+    let span = match (e.span(), source) {
+        (Some(span), Some(source)) => {
+            let (line, column) = span.position(source);
+            json!({
+                "start": span.start,
+                "end": span.end,
+                "line": line,
+                "column": column,
+                "text": &source[span.start..span.end],
+            })
+        }
+        _ => serde_json::Value::Null,
+    };
+    let trace = trace.unwrap_or_default();
+    let vars: serde_json::Map<_, _> = trace.vars
+        .iter()
+        .map(|&(ref name, ref value)| (name.clone(), json!(format!("{:?}", value))))
+        .collect();
+    let record = json!({
+        "time": timestamp(),
+        "file": filename,
+        "code": e.code(),
+        "message": e.message(),
+        "span": span,
+        "stack": trace.stack,
+        "vars": vars,
+    });
+
+    let written = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut f| writeln!(f, "{}", record));
+    if let Err(err) = written {
+        eprintln!("error: can't write to {}: {}", path, err);
+    }
+}
+
+// timestamp returns the current time in UTC in RFC 3339 format, such as
+// "2024-05-01T12:30:00Z".
+fn timestamp() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+
+    // This converts days since 1970-01-01 to a date in the proleptic
+    // Gregorian calendar, counting years from March so that leap days come
+    // last, as in Howard Hinnant's civil_from_days.
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60)
+}
+
+fn run_compile(filename: &str, output: Option<&str>) {
+    let source = fs::read_to_string(filename).expect("can't open file");
+    let compiled = match gate::compiled::compile(&source) {
+        Ok(compiled) => compiled,
+        Err(e) => {
+            eprintln!("{}", gate::Error::from(e));
+            process::exit(1);
+        }
+    };
+
+    let output = match output {
+        Some(output) => output.into(),
+        None => path::Path::new(filename).with_extension("gatec"),
+    };
+    fs::write(&output, compiled).expect("can't write file");
+}
+
+fn run_rename(matches: &clap::ArgMatches) {
+    let filename = matches.value_of("FILE").unwrap();
+    let (old, new) = (matches.value_of("OLD").unwrap(), matches.value_of("NEW").unwrap());
+    let source = fs::read_to_string(filename).expect("can't open file");
+
+    let renamed = match gate::refactor::find(&source, old) {
+        Ok(Some(span)) => gate::refactor::rename(&source, span, new),
+        Ok(None) => {
+            eprintln!("error: no variable named \"{}\"", old);
+            process::exit(1);
+        }
+        Err(e) => Err(e),
+    };
+
+    match renamed {
+        Ok(renamed) if matches.is_present("write") => {
+            fs::write(filename, renamed).expect("can't write file")
+        }
+        Ok(renamed) => print!("{}", renamed),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// parse_file parses a whole file, exiting if it isn't valid.
+fn parse_file(filename: &str) -> Vec<gate::Expression> {
+    let source = fs::read_to_string(filename).expect("can't open file");
+    match parse_input(&source) {
+        (_, Some(e)) => {
+            eprintln!("{}", gate::Error::from(e));
+            process::exit(1);
+        }
+        (exprs, None) => exprs,
+    }
+}
+
+fn run_lint(filename: &str) {
+    let exprs = parse_file(filename);
+    let lints = gate::analysis::dead_code(&exprs);
+    for lint in &lints {
+        println!("{}: warning[{}]: {}", filename, lint.code(), lint);
+    }
+    if !lints.is_empty() {
+        process::exit(1);
+    }
+}
+
+fn run_tests(path: &str) {
+    let report = gate::testing::run_path(path::Path::new(path)).expect("can't read tests");
+    for failure in &report.failures {
+        print!("{}", failure);
+    }
+    println!("{} tests, {} failed", report.total, report.failures.len());
+    if !report.failures.is_empty() {
+        process::exit(1);
+    }
+}
+
+fn run_explain(code: &str) {
+    match gate::explain::explain(code) {
+        Some(text) => println!("{}", text),
+        None => {
+            eprintln!("error: no error has the code \"{}\"", code);
+            process::exit(1);
+        }
+    }
+}
+
+fn run_doctest(filename: &str, write: bool) {
+    let source = fs::read_to_string(filename).expect("can't open file");
+    if write {
+        match gate::doctest::update(&source) {
+            Ok(updated) => fs::write(filename, updated).expect("can't write file"),
             Err(e) => {
-                println!("error: {}", e);
-                break;
+                eprintln!("{}", e);
+                process::exit(1);
             }
         }
+        return;
+    }
+
+    let failures = match gate::doctest::run(&source) {
+        Ok(failures) => failures,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    for failure in &failures {
+        print!("{}:{}", filename, failure);
+    }
+    let total = gate::doctest::examples(&source).len();
+    println!("{} examples, {} failed", total, failures.len());
+    if !failures.is_empty() {
+        process::exit(1);
     }
 }
 
-fn run_file(program: &mut gate::Program, filename: &str) {
-    let mut input_file = fs::File::open(filename).expect("can't open file");
-    let mut input = String::new();
-    input_file.read_to_string(&mut input).unwrap();
-    run(program, input);
+fn run_parse(matches: &clap::ArgMatches) {
+    let verify = matches.is_present("verify");
+    if !verify && !matches.is_present("update") {
+        let source = fs::read_to_string(matches.value_of("PATH").unwrap())
+            .expect("can't open file");
+        print!("{}", gate::corpus::snapshot(&source));
+        return;
+    }
+
+    let dir = path::Path::new(matches.value_of("PATH").unwrap_or("tests/corpus"));
+    if !verify {
+        let written = gate::corpus::update(dir).expect("can't update corpus");
+        for path in &written {
+            println!("wrote snapshot for {}", path.display());
+        }
+        return;
+    }
+
+    let mismatches = gate::corpus::verify(dir).expect("can't read corpus");
+    for mismatch in &mismatches {
+        print!("{}", mismatch);
+    }
+    let total = gate::corpus::cases(dir).expect("can't read corpus").len();
+    println!("{} scripts, {} failed", total, mismatches.len());
+    if !mismatches.is_empty() {
+        process::exit(1);
+    }
+}
+
+// run_notebook serves a notebook front end over stdin and stdout.  Each line of
+// input is a JSON request with an id and a method, and each gets a reply on
+// one line of output with the same id.  The methods are:
+//
+//     {"id": 1, "method": "execute", "code": "x = 1  println(x)"}
+//     {"id": 2, "method": "reset"}
+//     {"id": 3, "method": "shutdown"}
+fn run_notebook() {
+    let mut kernel = gate::notebook::Kernel::new();
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    for line in stdin.lock().lines() {
+        let line = line.expect("can't read stdin");
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let request: serde_json::Value = match serde_json::from_str(&line) {
+            Ok(r) => r,
+            Err(e) => {
+                let reply = notebook_error(json!(null), format!("invalid request: {}", e));
+                writeln!(out, "{}", reply).expect("can't write stdout");
+                continue;
+            }
+        };
+
+        let id = request["id"].clone();
+        let mut shutdown = false;
+        let reply = match (request["method"].as_str(), request["code"].as_str()) {
+            (Some("execute"), Some(code)) => notebook_reply(id, kernel.execute(code)),
+            (Some("execute"), None) => notebook_error(id, "execute needs code".to_owned()),
+            (Some("reset"), _) => {
+                kernel.reset();
+                json!({"id": id, "status": "ok"})
+            }
+            (Some("shutdown"), _) => {
+                shutdown = true;
+                json!({"id": id, "status": "ok"})
+            }
+            (Some(method), _) => notebook_error(id, format!("unknown method {:?}", method)),
+            (None, _) => notebook_error(id, "missing method".to_owned()),
+        };
+
+        writeln!(out, "{}", reply).expect("can't write stdout");
+        out.flush().expect("can't write stdout");
+        if shutdown {
+            return;
+        }
+    }
+}
+
+fn notebook_reply(id: serde_json::Value, reply: gate::notebook::Reply) -> serde_json::Value {
+    // Values that can't be represented in JSON, like functions, are sent
+    // without data, leaving only the way the REPL would print them.
+    let value = reply.value.map(|d| {
+        json!({
+            "repr": format!("{:?}", d),
+            "type": d.type_name(),
+            "data": serde_json::to_value(&d).ok(),
+        })
+    });
+    let changes: Vec<_> = reply.changes
+        .iter()
+        .map(|c| {
+            let (kind, value) = match c {
+                &gate::Change::Created { ref value, .. } => ("created", Some(value)),
+                &gate::Change::Changed { ref new, .. } => ("changed", Some(new)),
+                &gate::Change::Removed { .. } => ("removed", None),
+            };
+            json!({
+                "name": c.name(),
+                "change": kind,
+                "repr": value.map(|d| format!("{:?}", d)),
+            })
+        })
+        .collect();
+    let warnings: Vec<_> = reply.warnings
+        .iter()
+        .map(|w| json!({"code": w.code(), "message": w.to_string()}))
+        .collect();
+    let error = reply.error.map(|e| {
+        json!({
+            "code": e.code,
+            "message": e.message,
+            "line": e.position.map(|p| p.0),
+            "column": e.position.map(|p| p.1),
+            "text": e.text,
+        })
+    });
+
+    json!({
+        "id": id,
+        "status": if error.is_some() { "error" } else { "ok" },
+        "count": reply.count,
+        "output": reply.output,
+        "warnings": warnings,
+        "changes": changes,
+        "value": value,
+        "error": error,
+    })
+}
+
+fn notebook_error(id: serde_json::Value, message: String) -> serde_json::Value {
+    json!({"id": id, "status": "error", "error": {"message": message}})
+}
+
+fn run_calls(filename: &str, dot: bool) {
+    let calls = gate::analysis::call_graph(&parse_file(filename));
+    let caller_name = |call: &gate::analysis::Call| {
+        call.caller.clone().unwrap_or("(top level)".to_owned())
+    };
+
+    if dot {
+        println!("digraph calls {{");
+        for call in &calls {
+            println!("    {:?} -> {:?};", caller_name(call), call.callee);
+        }
+        println!("}}");
+    } else {
+        for call in &calls {
+            println!("{} -> {}", caller_name(call), call.callee);
+        }
+    }
 }
 
 fn run_stdin(program: &mut gate::Program) {
     let mut input = String::new();
     io::stdin().read_to_string(&mut input).unwrap();
-    run(program, input);
+    let _ = run(program, &input);
 }
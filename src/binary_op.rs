@@ -15,47 +15,164 @@ pub enum BinaryOp {
     Div,
     Mod,
     Eq,
+    NotEq,
+    // ApproxEq compares numbers within DEFAULT_EPSILON, as in `0.1 + 0.2 ~= 0.3`.
+    ApproxEq,
     Lt,
     LtEq,
     Gt,
     GtEq,
+    In,
+    Range,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    And,
+    Or,
+    // Coalesce gives the left side unless it's nil, as in `x ?? 0`.
+    Coalesce,
 }
 
 impl BinaryOp {
     pub fn eval(&self, left: &Data, right: &Data) -> Result {
         match (self, left, right) {
+            // Integer arithmetic that overflows gives the float result
+            // instead.  Dividing two ints always gives a float.
+            (&Add, &Int(l), &Int(r)) => {
+                Ok(l.checked_add(r).map_or(Number(l as f64 + r as f64), Int))
+            }
+            (&Sub, &Int(l), &Int(r)) => {
+                Ok(l.checked_sub(r).map_or(Number(l as f64 - r as f64), Int))
+            }
+            (&Mul, &Int(l), &Int(r)) => {
+                Ok(l.checked_mul(r).map_or(Number(l as f64 * r as f64), Int))
+            }
+            (&Mod, &Int(l), &Int(r)) => {
+                Ok(l.checked_rem(r).map_or(Number(l as f64 % r as f64), Int))
+            }
+            (&Div, &Int(l), &Int(r)) => Ok(Number(l as f64 / r as f64)),
+            (&Lt, &Int(l), &Int(r)) => Ok(Boolean(l < r)),
+            (&LtEq, &Int(l), &Int(r)) => Ok(Boolean(l <= r)),
+            (&Gt, &Int(l), &Int(r)) => Ok(Boolean(l > r)),
+            (&GtEq, &Int(l), &Int(r)) => Ok(Boolean(l >= r)),
+            (&ApproxEq, &Int(l), &Int(r)) => Ok(Boolean(l == r)),
+            // Anything else mixing ints and floats is done with floats.
+            (_, &Int(l), &Number(r)) if self.is_numeric() => {
+                self.eval(&Number(l as f64), &Number(r))
+            }
+            (_, &Number(l), &Int(r)) if self.is_numeric() => {
+                self.eval(&Number(l), &Number(r as f64))
+            }
             (&Add, &Number(l), &Number(r)) => Ok(Number(l + r)),
             (&Sub, &Number(l), &Number(r)) => Ok(Number(l - r)),
             (&Mul, &Number(l), &Number(r)) => Ok(Number(l * r)),
             (&Div, &Number(l), &Number(r)) => Ok(Number(l / r)),
             (&Mod, &Number(l), &Number(r)) => Ok(Number(l % r)),
+            // Strings that share storage, like two values of the same literal,
+            // are equal without comparing their characters, since comparing
+            // two Rc<str>s checks their pointers first.
             (&Eq, _, _) => Ok(Boolean(left == right)),
+            (&NotEq, _, _) => Ok(Boolean(left != right)),
+            (&ApproxEq, &Number(l), &Number(r)) => Ok(Boolean(approx_eq(l, r, DEFAULT_EPSILON))),
             (&Lt, &Number(l), &Number(r)) => Ok(Boolean(l < r)),
             (&LtEq, &Number(l), &Number(r)) => Ok(Boolean(l <= r)),
             (&Gt, &Number(l), &Number(r)) => Ok(Boolean(l > r)),
             (&GtEq, &Number(l), &Number(r)) => Ok(Boolean(l >= r)),
+            (&In, _, &List(ref l)) => Ok(Boolean(l.borrow().contains(left))),
+            (&In, _, &Set(ref s)) => Ok(Boolean(s.borrow().contains(left))),
+            (&In, _, &NumArray(ref a)) => {
+                Ok(Boolean(left.to_f64().map_or(false, |n| a.borrow().contains(&n))))
+            }
+            (&In, &Str(ref k), &Map(ref m)) => Ok(Boolean(m.borrow().contains_key(&**k))),
+            (&In, &Str(ref sub), &Str(ref s)) => Ok(Boolean(s.contains(&**sub))),
+            (&In, &Number(n), &Data::Range(start, end)) => {
+                Ok(Boolean(start <= n && n < end && (n - start).fract() == 0.0))
+            }
+            (&In, &Int(n), &Data::Range(..)) => self.eval(&Number(n as f64), right),
+            (&BinaryOp::Range, &Number(l), &Number(r)) => Ok(Data::Range(l, r)),
+            (&BinaryOp::Range, &Int(l), &Int(r)) => Ok(Data::Range(l as f64, r as f64)),
+            (&BitAnd, &Int(l), &Int(r)) => Ok(Int(l & r)),
+            (&BitOr, &Int(l), &Int(r)) => Ok(Int(l | r)),
+            (&BitXor, &Int(l), &Int(r)) => Ok(Int(l ^ r)),
+            // Shifting by a negative amount or by 64 or more bits is an error.
+            // Shifting right keeps the sign.
+            (&Shl, &Int(l), &Int(r)) if 0 <= r && r < 64 => Ok(Int(l << r)),
+            (&Shr, &Int(l), &Int(r)) if 0 <= r && r < 64 => Ok(Int(l >> r)),
+            // These are short-circuited when evaluating an expression, so only
+            // get here when both operands are already known.
+            (&And, _, _) => Ok(Boolean(left.to_bool() && right.to_bool())),
+            (&Or, _, _) => Ok(Boolean(left.to_bool() || right.to_bool())),
+            (&Coalesce, &Nil, _) => Ok(right.clone()),
+            (&Coalesce, _, _) => Ok(left.clone()),
             (o, l, r) => {
                 Err(ExecuteError::InvalidOperation {
                     left: l.type_name(),
                     op: o.clone(),
                     right: r.type_name(),
+                    values: Some((render_operand(l), render_operand(r))),
+                    span: None,
                 })
             }
         }
     }
 
+    // is_numeric returns whether the operator works on two numbers, treating
+    // ints and floats alike.
+    fn is_numeric(&self) -> bool {
+        match self {
+            &Add | &Sub | &Mul | &Div | &Mod | &Eq | &NotEq | &ApproxEq | &Lt | &LtEq | &Gt |
+            &GtEq | &BinaryOp::Range => true,
+            _ => false,
+        }
+    }
+
+    // is_comparison returns whether the operator orders its operands.  These
+    // operators can be chained, as in `a < b <= c`.
+    pub fn is_comparison(&self) -> bool {
+        match self {
+            &Lt | &LtEq | &Gt | &GtEq => true,
+            _ => false,
+        }
+    }
+
+    // binds_right returns whether, in `a op b right c`, the right operator is
+    // applied first, so that `b right c` is the right operand of this one.
+    // That's the case when it binds more tightly.  Operators that bind equally
+    // tightly group from the left, so `a - b - c` is `(a - b) - c`, and a
+    // comparison followed by another forms a chain.
+    pub fn binds_right(&self, right: &BinaryOp) -> bool {
+        if self.is_comparison() && right.is_comparison() {
+            return false;
+        }
+        right.precendence() > self.precendence()
+    }
+
     pub fn precendence(&self) -> u8 {
         match self {
-            &Add => 3,
-            &Sub => 3,
-            &Mul => 4,
-            &Div => 4,
-            &Mod => 2,
-            &Eq => 0,
-            &Lt => 1,
-            &LtEq => 1,
-            &Gt => 1,
-            &GtEq => 1,
+            &Add => 10,
+            &Sub => 10,
+            &Mul => 11,
+            &Div => 11,
+            &Mod => 9,
+            &Shl => 8,
+            &Shr => 8,
+            &BitAnd => 7,
+            &BitXor => 6,
+            &BitOr => 5,
+            &Eq => 2,
+            &NotEq => 2,
+            &ApproxEq => 2,
+            &Lt => 3,
+            &LtEq => 3,
+            &Gt => 3,
+            &GtEq => 3,
+            &In => 3,
+            &BinaryOp::Range => 4,
+            &And => 1,
+            &Or => 0,
+            &Coalesce => 0,
         }
     }
 }
@@ -69,16 +186,58 @@ impl fmt::Display for BinaryOp {
             &Div => write!(f, "/"),
             &Mod => write!(f, "%"),
             &Eq => write!(f, "=="),
+            &NotEq => write!(f, "!="),
+            &ApproxEq => write!(f, "~="),
             &Lt => write!(f, "<"),
             &LtEq => write!(f, "<="),
             &Gt => write!(f, ">"),
             &GtEq => write!(f, ">="),
+            &In => write!(f, "in"),
+            &BinaryOp::Range => write!(f, ".."),
+            &BitAnd => write!(f, "&"),
+            &BitOr => write!(f, "|"),
+            &BitXor => write!(f, "^"),
+            &Shl => write!(f, "<<"),
+            &Shr => write!(f, ">>"),
+            &And => write!(f, "&&"),
+            &Or => write!(f, "||"),
+            &Coalesce => write!(f, "??"),
         }
     }
 }
 
+// DEFAULT_EPSILON is the tolerance used by the ~= operator.
+pub const DEFAULT_EPSILON: f64 = 1e-9;
+
+// approx_eq returns whether a and b differ by at most eps.  For values larger
+// than 1 the tolerance grows with them, so it's relative rather than absolute,
+// since that's how far apart floats of that size can be after rounding.
+pub fn approx_eq(a: f64, b: f64, eps: f64) -> bool {
+    a == b || (a - b).abs() <= eps * a.abs().max(b.abs()).max(1.0)
+}
+
+// MAX_OPERAND_LEN is the number of characters of an operand kept in an
+// InvalidOperation error.  Longer operands are cut short and end with "...".
+const MAX_OPERAND_LEN: usize = 32;
+
+// render_operand formats an operand for an InvalidOperation error.  Strings
+// are quoted so they can't be mistaken for other values.
+fn render_operand(d: &Data) -> String {
+    let s = match d {
+        &Str(ref s) => format!("{:?}", s),
+        d => d.to_string(),
+    };
+    if s.chars().count() <= MAX_OPERAND_LEN {
+        return s;
+    }
+    let mut short: String = s.chars().take(MAX_OPERAND_LEN).collect();
+    short.push_str("...");
+    short
+}
+
 #[cfg(test)]
 mod tests {
+    use data::Data;
     use data::Data::*;
     use error::ExecuteError::*;
     use super::BinaryOp::*;
@@ -95,13 +254,18 @@ mod tests {
             // Eq
             (Eq, Number(2.0), Number(2.0), Boolean(true)),
             (Eq, Number(-2.0), Number(2.0), Boolean(false)),
-            (Eq, Str("foo".to_owned()), Str("foo".to_owned()), Boolean(true)),
-            (Eq, Str("foo".to_owned()), Str("bar".to_owned()), Boolean(false)),
+            (Eq, Str("foo".into()), Str("foo".into()), Boolean(true)),
+            (Eq, Str("foo".into()), Str("bar".into()), Boolean(false)),
             (Eq, Boolean(false), Boolean(false), Boolean(true)),
             (Eq, Boolean(true), Boolean(true), Boolean(true)),
             (Eq, Boolean(true), Boolean(false), Boolean(false)),
             (Eq, Nil, Boolean(false), Boolean(false)),
             (Eq, Nil, Nil, Boolean(true)),
+            // NotEq
+            (NotEq, Number(2.0), Number(2.0), Boolean(false)),
+            (NotEq, Str("foo".into()), Str("bar".into()), Boolean(true)),
+            (NotEq, Nil, Boolean(false), Boolean(true)),
+            (NotEq, Data::list(vec![Nil]), Data::list(vec![Nil]), Boolean(false)),
             // Lt
             (Lt, Number(-1.0), Number(0.5), Boolean(true)),
             (Lt, Number(1.0), Number(1.0), Boolean(false)),
@@ -118,6 +282,61 @@ mod tests {
             (GtEq, Number(-1.0), Number(0.5), Boolean(false)),
             (GtEq, Number(1.0), Number(1.0), Boolean(true)),
             (GtEq, Number(1.0), Number(0.5), Boolean(true)),
+            // In
+            (In, Number(1.0), Data::list(vec![Nil, Number(1.0)]), Boolean(true)),
+            (In, Number(2.0), Data::list(vec![Nil, Number(1.0)]), Boolean(false)),
+            (In, Nil, Data::set(vec![Nil, Nil]), Boolean(true)),
+            (In, Boolean(true), Data::set(vec![]), Boolean(false)),
+            (In, Str("oo".into()), Str("foo".into()), Boolean(true)),
+            (In, Str("of".into()), Str("foo".into()), Boolean(false)),
+            (In, Number(3.0), Data::Range(1.0, 4.0), Boolean(true)),
+            (In, Number(4.0), Data::Range(1.0, 4.0), Boolean(false)),
+            (In, Number(1.5), Data::Range(1.0, 4.0), Boolean(false)),
+            (In, Int(3), Data::Range(1.0, 4.0), Boolean(true)),
+            // Range
+            (super::BinaryOp::Range, Number(1.0), Number(4.0), Data::Range(1.0, 4.0)),
+            (super::BinaryOp::Range, Int(1), Int(4), Data::Range(1.0, 4.0)),
+            // Int
+            (Add, Int(1), Int(2), Int(3)),
+            (Sub, Int(1), Int(2), Int(-1)),
+            (Mul, Int(-3), Int(4), Int(-12)),
+            (Mod, Int(17), Int(4), Int(1)),
+            (Div, Int(6), Int(4), Number(1.5)),
+            (Div, Int(6), Int(3), Number(2.0)),
+            (Lt, Int(1), Int(2), Boolean(true)),
+            (Eq, Int(2), Int(2), Boolean(true)),
+            // Overflow falls back to floats
+            (Add, Int(i64::MAX), Int(1), Number(i64::MAX as f64 + 1.0)),
+            (Mul, Int(i64::MIN), Int(2), Number(i64::MIN as f64 * 2.0)),
+            // Mixed ints and floats
+            (Add, Int(1), Number(0.5), Number(1.5)),
+            (Mul, Number(2.0), Int(3), Number(6.0)),
+            (Eq, Int(2), Number(2.0), Boolean(true)),
+            (NotEq, Int(2), Number(2.5), Boolean(true)),
+            (Lt, Number(1.5), Int(2), Boolean(true)),
+            (Eq, Int(2), Str("2".into()), Boolean(false)),
+            // Bitwise
+            (BitAnd, Int(0b1100), Int(0b1010), Int(0b1000)),
+            (BitOr, Int(0b1100), Int(0b1010), Int(0b1110)),
+            (BitXor, Int(0b1100), Int(0b1010), Int(0b0110)),
+            (BitAnd, Int(-1), Int(7), Int(7)),
+            (Shl, Int(1), Int(4), Int(16)),
+            (Shl, Int(1), Int(63), Int(i64::MIN)),
+            (Shr, Int(-16), Int(2), Int(-4)),
+            // ApproxEq
+            (ApproxEq, Number(0.1 + 0.2), Number(0.3), Boolean(true)),
+            (ApproxEq, Number(1e12 + 1e-4), Int(1000000000000), Boolean(true)),
+            (ApproxEq, Number(0.3), Number(0.30001), Boolean(false)),
+            (ApproxEq, Int(1), Int(2), Boolean(false)),
+            // And
+            (And, Boolean(true), Number(1.0), Boolean(true)),
+            (And, Boolean(true), Nil, Boolean(false)),
+            // Or
+            (Or, Nil, Boolean(true), Boolean(true)),
+            (Or, Boolean(false), Nil, Boolean(false)),
+            // Coalesce
+            (Coalesce, Nil, Int(1), Int(1)),
+            (Coalesce, Boolean(false), Int(1), Boolean(false)),
         ];
 
         for (op, left, right, exp) in cases {
@@ -130,6 +349,21 @@ mod tests {
                        left: "number".to_owned(),
                        op: Add,
                        right: "boolean".to_owned(),
+                       values: Some(("1".to_owned(), "false".to_owned())),
+                       span: None,
+                   }));
+        assert_eq!(BitOr.eval(&Int(1), &Number(2.0)),
+                   Err(InvalidOperation {
+                       left: "int".to_owned(),
+                       op: BitOr,
+                       right: "number".to_owned(),
+                       values: Some(("1".to_owned(), "2".to_owned())),
+                       span: None,
                    }));
+        let long = Str("x".repeat(40).into());
+        assert_eq!(Sub.eval(&long, &Nil).unwrap_err().to_string(),
+                   format!("invalid operation (string - nil): \"{}... - nil", "x".repeat(31)));
+        assert!(Shl.eval(&Int(1), &Int(64)).is_err());
+        assert!(Shr.eval(&Int(1), &Int(-1)).is_err());
     }
 }
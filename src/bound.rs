@@ -0,0 +1,44 @@
+use context::Context;
+use engine::Engine;
+use expr::{Expression, Result};
+use scope::Env;
+
+// A BoundExpr is an expression paired with the scopes it was bound in, so it
+// can be evaluated later against the same variables, like a deferred callback
+// or a stored trigger.  The scopes are shared rather than copied: the
+// expression sees changes made to them after binding, and its assignments
+// change them.
+#[derive(Clone,Debug)]
+pub struct BoundExpr {
+    expr: Expression,
+    env: Vec<Env>,
+}
+
+impl BoundExpr {
+    pub fn new(expr: Expression, env: Vec<Env>) -> Self {
+        BoundExpr {
+            expr: expr,
+            env: env,
+        }
+    }
+
+    pub fn expr(&self) -> &Expression {
+        &self.expr
+    }
+
+    // eval evaluates the expression in its own scopes, which stand in for
+    // every scope of ctx, including the global one, until it's done.  The
+    // context can belong to a different program than the one that bound it.
+    // It's evaluated like a top-level expression, so panics are caught the
+    // same way as by Engine::eval.
+    pub fn eval(&self, engine: &Engine, ctx: &mut Context) -> Result {
+        // The expression wasn't parsed from the source being run, so errors
+        // in it aren't given spans.
+        let frames = ctx.enter_env(&self.env);
+        let source = ctx.set_source(None);
+        let res = engine.eval(ctx, &self.expr);
+        ctx.set_source(source);
+        ctx.exit_env(frames);
+        res
+    }
+}
@@ -0,0 +1,1981 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fs;
+use std::rc::Rc;
+use std::result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use indexmap::IndexMap;
+use seq::{self, Sequence};
+use set::ValueSet;
+
+use audit::AuditEvent;
+use binary_op;
+use context::Context;
+use data::Data;
+use data::Data::*;
+use engine::Engine;
+use error::ExecuteError;
+use error::ExecuteError::*;
+use expr::{Expression, Result};
+use rng;
+
+// NativeFn is implemented by functions written in Rust that can be called
+// from scripts.  Builtins receive the engine and context they were called from,
+// so they can keep state between calls and interact with the interpreter.
+pub trait NativeFn {
+    fn call(&mut self, engine: &Engine, ctx: &mut Context, args: &[Data]) -> Result;
+
+    // fresh returns a new copy of the function, which the engine calls when
+    // the function is called again before an earlier call has returned.  By
+    // default there's none, and such calls fail with ReentrantCall, since
+    // they'd share the function's state.
+    fn fresh(&self) -> Option<Box<dyn NativeFn>> {
+        None
+    }
+}
+
+// Functions that only need their arguments are NativeFns too.
+impl<F> NativeFn for F
+    where F: FnMut(&[Data]) -> Result
+{
+    fn call(&mut self, _: &Engine, _: &mut Context, args: &[Data]) -> Result {
+        self(args)
+    }
+}
+
+pub type Builtin = fn(&[Data]) -> Result;
+
+// CORE lists the builtins available to every engine.
+pub const CORE: &'static [(&'static str, Builtin)] = &[
+    ("list", list),
+    ("to_list", to_list),
+    ("array", array),
+    ("range", range),
+    ("take", take),
+    ("drop", drop),
+    ("zip", zip),
+    ("enumerate", enumerate),
+    ("push", push),
+    ("pop", pop),
+    ("insert", insert),
+    ("remove", remove),
+    ("slice", slice),
+    ("concat", concat),
+    ("join", join),
+    ("map", map),
+    ("get", get),
+    ("put", put),
+    ("keys", keys),
+    ("values", values),
+    ("has", has),
+    ("delete", delete),
+    ("merge", merge),
+    ("entries", entries),
+    ("set", set),
+    ("union", union),
+    ("intersection", intersection),
+    ("difference", difference),
+    ("copy", copy),
+    ("version", version),
+    ("exit", exit),
+    ("assert", assert),
+    ("assert_eq", assert_eq),
+    ("type", type_of),
+    ("now", now),
+    ("parse_number", parse_number),
+    ("parse_int", parse_int),
+    ("num", num),
+    ("str", str),
+    ("bool", bool),
+    ("approx_eq", approx_eq),
+    ("min", min),
+    ("max", max),
+    ("clamp", clamp),
+    ("sum", sum),
+    ("mean", mean),
+    ("min_of", min_of),
+    ("max_of", max_of),
+    ("dot", dot),
+    ("add_lists", add_lists),
+    ("len", len),
+    ("split", split),
+    ("substr", substr),
+    ("upper", upper),
+    ("lower", lower),
+    ("trim", trim),
+];
+
+// CONTAINED lists the builtins besides those in CORE that don't reach outside
+// the program, for Program::eval_isolated.
+pub const CONTAINED: &'static [&'static str] =
+    &["print", "println", "render", "has_feature", "clock"];
+
+// println(a, b, ...) writes its arguments followed by a newline to the
+// context's output.
+pub struct Println;
+
+impl NativeFn for Println {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        let mut line = String::new();
+        for item in v {
+            line.push_str(&item.to_string());
+        }
+        line.push('\n');
+        ctx.write(&line)?;
+        Ok(Data::Nil)
+    }
+}
+
+// print(a, b, ...) writes its arguments to the context's output, like println
+// but without the newline.
+pub struct Print;
+
+impl NativeFn for Print {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        let mut text = String::new();
+        for item in v {
+            text.push_str(&item.to_string());
+        }
+        ctx.write(&text)?;
+        Ok(Data::Nil)
+    }
+}
+
+// readline() reads a line from the context's input and returns it without the
+// newline, or returns nil at the end of the input.
+pub struct Readline;
+
+impl NativeFn for Readline {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_count(v, 0)?;
+        Ok(ctx.read_line()?.map_or(Nil, |line| Str(line.into())))
+    }
+}
+
+// read_file(path) returns the contents of the file at path, which must be
+// UTF-8.  Like write_file, it fails with NotAllowed unless the host lets the
+// program use files.
+pub struct ReadFile;
+
+impl NativeFn for ReadFile {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_count(v, 1)?;
+        let path = expect_file_access(ctx, "read_file", &v[0], false)?;
+        match fs::read_to_string(&path) {
+            Ok(contents) => Ok(Str(contents.into())),
+            Err(e) => Err(Io(format!("{}: {}", path, e))),
+        }
+    }
+}
+
+// write_file(path, contents) writes the string contents to the file at path,
+// replacing the file if it exists.
+pub struct WriteFile;
+
+impl NativeFn for WriteFile {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_count(v, 2)?;
+        let contents = expect_str(&v[1])?;
+        let path = expect_file_access(ctx, "write_file", &v[0], true)?;
+        match fs::write(&path, contents) {
+            Ok(()) => Ok(Nil),
+            Err(e) => Err(Io(format!("{}: {}", path, e))),
+        }
+    }
+}
+
+// expect_file_access returns the path a file builtin was given, after checking
+// that the program may use files and recording the access.
+pub fn expect_file_access(ctx: &mut Context,
+                          name: &str,
+                          path: &Data,
+                          write: bool)
+                          -> result::Result<String, ExecuteError> {
+    if !ctx.fs_allowed() {
+        return Err(NotAllowed(name.to_owned()));
+    }
+    let path = expect_str(path)?;
+    ctx.audit(AuditEvent::FileAccess {
+        path: path.clone(),
+        write: write,
+    });
+    Ok(path)
+}
+
+// random() returns a random number from 0 up to, but not including, 1.
+pub struct Random;
+
+impl NativeFn for Random {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_count(v, 0)?;
+        Ok(Number(rng::float(ctx.rng())))
+    }
+}
+
+// random_int(lo, hi) returns a random int from lo to hi, including both.
+pub struct RandomInt;
+
+impl NativeFn for RandomInt {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_count(v, 2)?;
+        let (lo, hi) = (expect_int(&v[0])?, expect_int(&v[1])?);
+        if hi < lo {
+            return Err(InvalidArgument {
+                expected: format!("upper bound of at least {}", lo),
+                found: hi.to_string(),
+            });
+        }
+        Ok(Int(rng::int_between(ctx.rng(), lo, hi)))
+    }
+}
+
+// clock() returns the number of seconds since the program started, measured
+// by a clock that never goes backwards, for timing parts of a script.
+pub struct Clock;
+
+impl NativeFn for Clock {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_count(v, 0)?;
+        Ok(Number(seconds(ctx.elapsed())))
+    }
+}
+
+// sleep(ms) waits for the given number of milliseconds.
+pub struct Sleep;
+
+impl NativeFn for Sleep {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_count(v, 1)?;
+        let ms = match v[0].to_f64() {
+            Some(ms) if ms >= 0.0 && ms.is_finite() => ms,
+            _ => {
+                return Err(InvalidArgument {
+                    expected: "milliseconds of at least 0".to_owned(),
+                    found: v[0].to_string(),
+                })
+            }
+        };
+        ctx.sleep(Duration::from_secs_f64(ms / 1000.0));
+        ctx.check_cancelled()?;
+        Ok(Nil)
+    }
+}
+
+// render(template, map[, eval]) returns template with each `{{key}}` replaced
+// by the value stored under key in map.  If eval is true, what's inside the
+// braces can be any expression, such as `{{n * 2}}`.  It's evaluated in a
+// scope of its own holding only the map's entries, so it can't see or change
+// the program's variables, and it may only use names from the map or ones it
+// defines itself, which keeps builtins out of reach unless the map has them.
+pub struct Render;
+
+impl NativeFn for Render {
+    fn call(&mut self, engine: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_range(v, 2, 3)?;
+        let template = expect_str(&v[0])?;
+        let eval = v.get(2).map_or(false, |d| d.to_bool());
+        let vars: Vec<_> = expect_map(&v[1])?
+            .borrow()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
+        let mut out = String::new();
+        let mut rest = &template[..];
+        while let Some(start) = rest.find("{{") {
+            out.push_str(&rest[..start]);
+            let end = match rest[start..].find("}}") {
+                Some(i) => start + i,
+                None => return Err(InvalidTemplate("unclosed \"{{\"".to_owned())),
+            };
+            let source = &rest[start + 2..end];
+            let value = if eval {
+                render_expr(engine, ctx, source, &vars)?
+            } else {
+                let key = source.trim();
+                match vars.iter().find(|&&(ref k, _)| k == key) {
+                    Some(&(_, ref val)) => val.clone(),
+                    None => return Err(InvalidTemplate(format!("no value for \"{}\"", key))),
+                }
+            };
+            out.push_str(&value.to_string());
+            rest = &rest[end + 2..];
+        }
+        out.push_str(rest);
+        Ok(Str(out.into()))
+    }
+
+    // Render keeps no state, so a template can render another one.
+    fn fresh(&self) -> Option<Box<dyn NativeFn>> {
+        Some(Box::new(Render))
+    }
+}
+
+fn render_expr(engine: &Engine,
+               ctx: &mut Context,
+               source: &str,
+               vars: &[(String, Data)])
+               -> Result {
+    let mut exprs: Vec<_> = engine.parser(source).collect();
+    let expr = match exprs.pop() {
+        Some(Ok(e)) if exprs.is_empty() => e,
+        _ => return Err(InvalidTemplate(format!("can't parse \"{}\"", source.trim()))),
+    };
+    let mut names: HashSet<&str> = vars.iter().map(|&(ref name, _)| name.as_str()).collect();
+    bound_names(&expr, &mut names);
+    if let Some(name) = unknown_name(&expr, &names) {
+        return Err(InvalidTemplate(format!("\"{}\" isn't in the map", name)));
+    }
+
+    // Spans in the template aren't in the source being run, so errors in it
+    // aren't given any.
+    let outer = ctx.enter_env(&[]);
+    let source = ctx.set_source(None);
+    ctx.new_scope();
+    for &(ref name, ref val) in vars {
+        ctx.declare_var(name, val.clone());
+    }
+    let res = engine.eval(ctx, &expr);
+    ctx.set_source(source);
+    ctx.exit_env(outer);
+    res
+}
+
+// bound_names adds the names that e assigns, declares or takes as parameters.
+fn bound_names<'a>(e: &'a Expression, names: &mut HashSet<&'a str>) {
+    match e {
+        &Expression::Assignment { ref left, .. } |
+        &Expression::Declaration { ref left, .. } => {
+            names.insert(left);
+        }
+        &Expression::FunctionDef { ref name, ref params, .. } => {
+            names.insert(name);
+            names.extend(params.iter().map(|p| p.as_str()));
+        }
+        &Expression::StructDef { ref name, .. } => {
+            names.insert(name);
+        }
+        &Expression::Lambda { ref params, .. } => {
+            names.extend(params.iter().map(|p| p.as_str()));
+        }
+        &Expression::ForLoop { ref var, .. } |
+        &Expression::TryCatch { ref var, .. } => {
+            names.insert(var);
+        }
+        _ => {}
+    }
+    for c in e.children() {
+        bound_names(c, names);
+    }
+}
+
+// unknown_name returns the first variable e uses that isn't one of names.
+fn unknown_name<'a>(e: &'a Expression, names: &HashSet<&str>) -> Option<&'a str> {
+    if let &Expression::Variable(ref name) = e {
+        if !names.contains(name.as_str()) {
+            return Some(name);
+        }
+    }
+    e.children().into_iter().filter_map(|c| unknown_name(c, names)).next()
+}
+
+// has_feature(name) returns whether an optional feature is available.  The
+// feature "sandbox" is available when the engine limits which builtins can be
+// called, "audit" when the context records an audit log, "fs" when it may use
+// files, "net" when it may use sockets, and "signals" and "sqlite" when gate
+// was built with them.  Hosts declare any other features with
+// Engine::add_feature.
+pub struct HasFeature;
+
+impl NativeFn for HasFeature {
+    fn call(&mut self, engine: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_count(v, 1)?;
+        let name = expect_str(&v[0])?;
+        let available = match name.as_str() {
+            "sandbox" => engine.is_sandboxed(),
+            "audit" => ctx.is_audited(),
+            "fs" => ctx.fs_allowed(),
+            "net" => cfg!(feature = "net") && ctx.net_allowed(),
+            "signals" => cfg!(feature = "signals"),
+            "sqlite" => cfg!(feature = "sqlite"),
+            _ => engine.has_feature(&name),
+        };
+        Ok(Boolean(available))
+    }
+}
+
+// close(handle) closes a host object a builtin returned, such as a socket or
+// database connection.
+pub struct Close;
+
+impl NativeFn for Close {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_count(v, 1)?;
+        if !ctx.handles().close(&v[0]) {
+            return Err(InvalidArgument {
+                expected: "open handle".to_owned(),
+                found: v[0].to_string(),
+            });
+        }
+        Ok(Nil)
+    }
+}
+
+// exit(status) ends the program with the given status, or 0 if it's left out.
+// It fails with Exit, which only the host handles.
+pub fn exit(v: &[Data]) -> Result {
+    expect_arg_range(v, 0, 1)?;
+    let status = match v.get(0) {
+        Some(d) => expect_int(d)?,
+        None => 0,
+    };
+    if status < i32::min_value() as i64 || status > i32::max_value() as i64 {
+        return Err(InvalidArgument {
+            expected: "status that fits in 32 bits".to_owned(),
+            found: status.to_string(),
+        });
+    }
+    Err(Exit(status as i32))
+}
+
+// assert(cond, message) fails with the message, or with a generic one if it's
+// left out, unless cond is truthy.
+pub fn assert(v: &[Data]) -> Result {
+    expect_arg_range(v, 1, 2)?;
+    if v[0].to_bool() {
+        return Ok(Nil);
+    }
+    let message = v.get(1).map_or("condition is false".to_owned(), |d| d.to_string());
+    Err(AssertionFailed(message))
+}
+
+// assert_eq(actual, expected) fails unless actual == expected.
+pub fn assert_eq(v: &[Data]) -> Result {
+    expect_arg_count(v, 2)?;
+    if binary_op::BinaryOp::Eq.eval(&v[0], &v[1])?.to_bool() {
+        return Ok(Nil);
+    }
+    Err(AssertionFailed(format!("expected {:?}, found {:?}", v[1], v[0])))
+}
+
+// version() returns the version of the interpreter, such as "0.1.0".
+pub fn version(v: &[Data]) -> Result {
+    expect_arg_count(v, 0)?;
+    Ok(Str(env!("CARGO_PKG_VERSION").into()))
+}
+
+// type(x) returns the name of x's type, such as "number" or "string".  The
+// type of a struct instance is the name of its struct.
+pub fn type_of(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    Ok(Str(v[0].type_name().into()))
+}
+
+// now() returns the current time as the number of seconds since the start of
+// 1970 in UTC.  The clock can be changed while a program runs, so clock is
+// better for measuring how long something takes.
+pub fn now(v: &[Data]) -> Result {
+    expect_arg_count(v, 0)?;
+    let since = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    Ok(Number(seconds(since)))
+}
+
+fn seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+// list(a, b, ...) creates a new list holding its arguments.
+pub fn list(v: &[Data]) -> Result {
+    Ok(Data::list(v.to_vec()))
+}
+
+// to_list(x) creates a new list holding the values of a list, set or range,
+// or the keys of a map.
+pub fn to_list(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    match v[0].iter() {
+        Some(items) => Ok(Data::list(items.collect())),
+        None => Err(NotIterable(v[0].type_name())),
+    }
+}
+
+// array(values) creates a new array holding the numbers in a list, set, range
+// or array.
+pub fn array(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    let items = match v[0].iter() {
+        Some(items) => items,
+        None => return Err(NotIterable(v[0].type_name())),
+    };
+    let mut numbers = vec![];
+    for d in items {
+        numbers.push(d.to_f64().ok_or_else(|| {
+            InvalidArgument {
+                expected: "number".to_owned(),
+                found: d.type_name(),
+            }
+        })?);
+    }
+    Ok(Data::num_array(numbers))
+}
+
+// range(end), range(start, end) or range(start, end, step) returns the numbers
+// from start, or 0, up to but not including end, counting by step.  With a
+// step of 1 it's the same as start..end, and otherwise it's a sequence, which
+// counts down if step is negative.  Either way the numbers are only produced
+// as they're looped over.
+pub fn range(v: &[Data]) -> Result {
+    expect_arg_range(v, 1, 3)?;
+    let (start, end) = match v.len() {
+        1 => (0.0, expect_number(&v[0])?),
+        _ => (expect_number(&v[0])?, expect_number(&v[1])?),
+    };
+    let step = match v.get(2) {
+        Some(d) => expect_number(d)?,
+        None => 1.0,
+    };
+    if step == 0.0 {
+        return Err(InvalidArgument {
+            expected: "step other than 0".to_owned(),
+            found: v[2].to_string(),
+        });
+    }
+    if step == 1.0 {
+        return Ok(Range(start, end));
+    }
+
+    let numbers = (0u64..)
+        .map(move |i| start + i as f64 * step)
+        .take_while(move |&n| if step > 0.0 { n < end } else { n > end });
+    let seq = if start.fract() == 0.0 && step.fract() == 0.0 {
+        Sequence::new(numbers.map(|n| Int(n as i64)))
+    } else {
+        Sequence::new(numbers.map(Number))
+    };
+    Ok(Data::Seq(Rc::new(seq)))
+}
+
+// take(values, n) returns a sequence of the first n values of a list, set,
+// map, range, array or sequence.  Like the other sequence builtins, it doesn't
+// produce any values until they're looped over, and then only as many as are
+// needed.
+pub fn take(v: &[Data]) -> Result {
+    expect_arg_count(v, 2)?;
+    let (items, n) = (expect_values(&v[0])?, expect_count(&v[1])?);
+    Ok(Data::Seq(Rc::new(Sequence::new(items.take(n)))))
+}
+
+// drop(values, n) returns a sequence of the values after the first n.
+pub fn drop(v: &[Data]) -> Result {
+    expect_arg_count(v, 2)?;
+    let (items, n) = (expect_values(&v[0])?, expect_count(&v[1])?);
+    Ok(Data::Seq(Rc::new(Sequence::new(items.skip(n)))))
+}
+
+// zip(a, b) returns a sequence of [x, y] lists pairing the values of a and b in
+// order.  It ends when either runs out.
+pub fn zip(v: &[Data]) -> Result {
+    expect_arg_count(v, 2)?;
+    let (a, b) = (expect_values(&v[0])?, expect_values(&v[1])?);
+    let pairs = a.zip(b).map(|(x, y)| Data::list(vec![x, y]));
+    Ok(Data::Seq(Rc::new(Sequence::new(pairs))))
+}
+
+// enumerate(values) returns a sequence of [i, x] lists pairing each value with
+// its index, counting from 0.
+pub fn enumerate(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    let pairs = expect_values(&v[0])?
+        .enumerate()
+        .map(|(i, x)| Data::list(vec![Int(i as i64), x]));
+    Ok(Data::Seq(Rc::new(Sequence::new(pairs))))
+}
+
+// push(list, a, b, ...) appends the values to the end of the list and returns
+// the list.
+pub fn push(v: &[Data]) -> Result {
+    if v.is_empty() {
+        return Err(WrongArgCount {
+            expected: 1,
+            found: 0,
+        });
+    }
+
+    let l = expect_list(&v[0])?;
+    v[0].will_change()?;
+    l.borrow_mut().extend(v[1..].iter().cloned());
+    Ok(v[0].clone())
+}
+
+// pop(list) removes and returns the last value of the list, or nil if the list
+// is empty.
+pub fn pop(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    let l = expect_list(&v[0])?;
+    v[0].will_change()?;
+    let last = l.borrow_mut().pop();
+    Ok(last.unwrap_or(Nil))
+}
+
+// insert(list, index, value) inserts the value at the index, shifting later
+// values to the right, and returns the list.
+pub fn insert(v: &[Data]) -> Result {
+    expect_arg_count(v, 3)?;
+    let l = expect_list(&v[0])?;
+    let len = l.borrow().len();
+    let index = expect_index(&v[1], len, true)?;
+    v[0].will_change()?;
+    l.borrow_mut().insert(index, v[2].clone());
+    Ok(v[0].clone())
+}
+
+// remove(list, index) removes and returns the value at the index.
+pub fn remove(v: &[Data]) -> Result {
+    expect_arg_count(v, 2)?;
+    let l = expect_list(&v[0])?;
+    let len = l.borrow().len();
+    let index = expect_index(&v[1], len, false)?;
+    v[0].will_change()?;
+    let removed = l.borrow_mut().remove(index);
+    Ok(removed)
+}
+
+// slice(list, start[, end]) returns a new list holding the values from start
+// up to, but not including, end.
+pub fn slice(v: &[Data]) -> Result {
+    expect_arg_range(v, 2, 3)?;
+    if let NumArray(ref a) = v[0] {
+        let numbers = a.borrow();
+        let (start, end) = slice_bounds(&v[1..], numbers.len())?;
+        return Ok(Data::num_array(numbers[start..end].to_vec()));
+    }
+    let l = expect_list(&v[0])?;
+    let items = l.borrow();
+    let (start, end) = slice_bounds(&v[1..], items.len())?;
+    Ok(Data::list(items[start..end].to_vec()))
+}
+
+// slice_bounds returns the indexes a slice of something of length len starts
+// and ends at, given its start and optional end.
+fn slice_bounds(v: &[Data], len: usize) -> result::Result<(usize, usize), ExecuteError> {
+    let end = match v.get(1) {
+        Some(d) => expect_index(d, len, true)?,
+        None => len,
+    };
+    let start = expect_index(&v[0], len, true)?;
+    if start > end {
+        return Err(IndexOutOfRange {
+            index: start as f64,
+            len: len,
+        });
+    }
+    Ok((start, end))
+}
+
+// concat(a, b, ...) returns a new list holding the values of every list
+// argument in order.
+pub fn concat(v: &[Data]) -> Result {
+    let mut items = Vec::new();
+    for arg in v {
+        let l = expect_list(arg)?;
+        items.extend(l.borrow().iter().cloned());
+    }
+    Ok(Data::list(items))
+}
+
+// join(list, sep) returns a string of the list's values separated by sep.
+pub fn join(v: &[Data]) -> Result {
+    expect_arg_count(v, 2)?;
+    let l = expect_list(&v[0])?;
+    let sep = match &v[1] {
+        &Str(ref s) => s,
+        d => {
+            return Err(InvalidArgument {
+                expected: "string".to_owned(),
+                found: d.type_name(),
+            })
+        }
+    };
+
+    let parts: Vec<String> = l.borrow().iter().map(|d| d.to_string()).collect();
+    Ok(Str(parts.join(sep).into()))
+}
+
+// len(x) returns the number of characters in a string, or of elements in a
+// list or array.
+pub fn len(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    let len = match &v[0] {
+        &Str(ref s) => s.chars().count(),
+        &List(ref l) => l.borrow().len(),
+        &NumArray(ref a) => a.borrow().len(),
+        d => {
+            return Err(InvalidArgument {
+                expected: "string, list or array".to_owned(),
+                found: d.type_name(),
+            })
+        }
+    };
+    Ok(Int(len as i64))
+}
+
+// split(s, sep) returns a list of the parts of s between each sep.  Without a
+// separator, s is split on runs of whitespace, and the parts are never empty.
+pub fn split(v: &[Data]) -> Result {
+    expect_arg_range(v, 1, 2)?;
+    let s = expect_str(&v[0])?;
+    let parts: Vec<Data> = match v.get(1) {
+        Some(d) => {
+            let sep = expect_str(d)?;
+            if sep.is_empty() {
+                return Err(InvalidArgument {
+                    expected: "non-empty separator".to_owned(),
+                    found: "empty string".to_owned(),
+                });
+            }
+            s.split(sep.as_str()).map(|p| Str(p.into())).collect()
+        }
+        None => s.split_whitespace().map(|p| Str(p.into())).collect(),
+    };
+    Ok(Data::list(parts))
+}
+
+// substr(s, start, end) returns the characters of s from index start up to,
+// but not including, end, which defaults to the end of the string.  Like
+// slice, the indexes count characters from 0.
+pub fn substr(v: &[Data]) -> Result {
+    expect_arg_range(v, 2, 3)?;
+    let chars: Vec<char> = expect_str(&v[0])?.chars().collect();
+    let end = match v.get(2) {
+        Some(d) => expect_index(d, chars.len(), true)?,
+        None => chars.len(),
+    };
+    let start = expect_index(&v[1], chars.len(), true)?;
+    if start > end {
+        return Err(IndexOutOfRange {
+            index: start as f64,
+            len: chars.len(),
+        });
+    }
+    Ok(Str(chars[start..end].iter().collect::<String>().into()))
+}
+
+pub fn upper(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    Ok(Str(expect_str(&v[0])?.to_uppercase().into()))
+}
+
+pub fn lower(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    Ok(Str(expect_str(&v[0])?.to_lowercase().into()))
+}
+
+// trim returns a string without the whitespace at its start and end.
+pub fn trim(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    Ok(Str(expect_str(&v[0])?.trim().into()))
+}
+
+// map(k1, v1, k2, v2, ...) creates a new map from alternating keys and values.
+pub fn map(v: &[Data]) -> Result {
+    if v.len() % 2 != 0 {
+        return Err(WrongArgCount {
+            expected: v.len() + 1,
+            found: v.len(),
+        });
+    }
+
+    let mut entries = IndexMap::new();
+    for pair in v.chunks(2) {
+        entries.insert(expect_key(&pair[0])?, pair[1].clone());
+    }
+    Ok(Data::map(entries))
+}
+
+// get(map, key) returns the value stored under key, or nil if there isn't one.
+pub fn get(v: &[Data]) -> Result {
+    expect_arg_count(v, 2)?;
+    let m = expect_map(&v[0])?;
+    let key = expect_key(&v[1])?;
+    let val = m.borrow().get(&key).cloned();
+    Ok(val.unwrap_or(Nil))
+}
+
+// put(map, key, value) stores the value under key and returns the map.
+pub fn put(v: &[Data]) -> Result {
+    expect_arg_count(v, 3)?;
+    let m = expect_map(&v[0])?;
+    let key = expect_key(&v[1])?;
+    v[0].will_change()?;
+    m.borrow_mut().insert(key, v[2].clone());
+    Ok(v[0].clone())
+}
+
+// keys(map) returns a list of the map's keys in insertion order.
+pub fn keys(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    let m = expect_map(&v[0])?;
+    let keys = m.borrow().keys().map(|k| Str(k.as_str().into())).collect();
+    Ok(Data::list(keys))
+}
+
+// values(map) returns a list of the map's values in insertion order.
+pub fn values(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    let m = expect_map(&v[0])?;
+    let values = m.borrow().values().cloned().collect();
+    Ok(Data::list(values))
+}
+
+// has(map, key) returns whether the map holds a value under key.
+pub fn has(v: &[Data]) -> Result {
+    expect_arg_count(v, 2)?;
+    let m = expect_map(&v[0])?;
+    let key = expect_key(&v[1])?;
+    let found = m.borrow().contains_key(&key);
+    Ok(Boolean(found))
+}
+
+// delete(map, key) removes and returns the value stored under key, or nil if
+// there isn't one.  The order of the remaining entries is preserved.
+pub fn delete(v: &[Data]) -> Result {
+    expect_arg_count(v, 2)?;
+    let m = expect_map(&v[0])?;
+    let key = expect_key(&v[1])?;
+    v[0].will_change()?;
+    let removed = m.borrow_mut().shift_remove(&key);
+    Ok(removed.unwrap_or(Nil))
+}
+
+// merge(a, b, ...) returns a new map holding the entries of every map
+// argument.  Keys found in later maps overwrite the values of earlier ones.
+pub fn merge(v: &[Data]) -> Result {
+    let mut entries = IndexMap::new();
+    for arg in v {
+        let m = expect_map(arg)?;
+        for (key, val) in m.borrow().iter() {
+            entries.insert(key.clone(), val.clone());
+        }
+    }
+    Ok(Data::map(entries))
+}
+
+// entries(map) returns a list of [key, value] lists in insertion order.
+pub fn entries(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    let m = expect_map(&v[0])?;
+    let entries = m.borrow()
+        .iter()
+        .map(|(key, val)| Data::list(vec![Str(key.as_str().into()), val.clone()]))
+        .collect();
+    Ok(Data::list(entries))
+}
+
+// set(list) creates a new set holding the unique values of the list or range.
+pub fn set(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    let items = match &v[0] {
+        &List(ref l) => l.borrow().clone(),
+        &Set(ref s) => s.borrow().to_vec(),
+        d @ &Range(..) => d.iter().unwrap().collect(),
+        d => {
+            return Err(InvalidArgument {
+                expected: "list".to_owned(),
+                found: d.type_name(),
+            })
+        }
+    };
+    Ok(Data::set(items))
+}
+
+// union(a, b, ...) returns a new set holding the values found in any of the
+// sets.
+pub fn union(v: &[Data]) -> Result {
+    let mut items = Vec::new();
+    for arg in v {
+        let s = expect_set(arg)?;
+        items.extend(s.borrow().iter().cloned());
+    }
+    Ok(Data::set(items))
+}
+
+// intersection(a, b, ...) returns a new set holding the values found in every
+// one of the sets.
+pub fn intersection(v: &[Data]) -> Result {
+    let sets = v.iter().map(expect_set).collect::<result::Result<Vec<_>, _>>()?;
+    let items = match sets.split_first() {
+        Some((first, rest)) => {
+            first.borrow()
+                .iter()
+                .filter(|item| rest.iter().all(|s| s.borrow().contains(item)))
+                .cloned()
+                .collect()
+        }
+        None => vec![],
+    };
+    Ok(Data::set(items))
+}
+
+// difference(a, b, ...) returns a new set holding the values of a that aren't
+// found in any of the other sets.
+pub fn difference(v: &[Data]) -> Result {
+    if v.is_empty() {
+        return Err(WrongArgCount {
+            expected: 1,
+            found: 0,
+        });
+    }
+
+    let sets = v.iter().map(expect_set).collect::<result::Result<Vec<_>, _>>()?;
+    let items = sets[0]
+        .borrow()
+        .iter()
+        .filter(|item| sets[1..].iter().all(|s| !s.borrow().contains(item)))
+        .cloned()
+        .collect();
+    Ok(Data::set(items))
+}
+
+// copy(v) returns a copy of a list, map or set that can be changed without
+// affecting the original.  Only the outer container is copied.  Any other value
+// is returned as is.
+pub fn copy(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    Ok(v[0].shallow_copy())
+}
+
+// parse_number(s[, strict]) converts a string to an int, or to a number if it
+// isn't a whole number, giving nil if it isn't a number at all.  If strict is
+// true, invalid input is an error instead.
+pub fn parse_number(v: &[Data]) -> Result {
+    expect_arg_range(v, 1, 2)?;
+    let s = expect_str(&v[0])?;
+    parsed_or_nil(parse_num_str(&s), &s, v.get(1))
+}
+
+fn parse_num_str(s: &str) -> Option<Data> {
+    let text = s.trim();
+    match text.parse() {
+        Ok(i) => Some(Int(i)),
+        Err(_) => text.parse::<f64>().ok().filter(|n| n.is_finite()).map(Number),
+    }
+}
+
+// parse_int(s[, radix[, strict]]) converts a string of digits in the given
+// radix, 10 by default, to an int.  Like parse_number, it gives nil for invalid
+// input unless strict is true.
+pub fn parse_int(v: &[Data]) -> Result {
+    expect_arg_range(v, 1, 3)?;
+    let s = expect_str(&v[0])?;
+    let radix = match v.get(1) {
+        None | Some(&Nil) => 10,
+        Some(d) => {
+            match d.to_f64() {
+                Some(r) if r.fract() == 0.0 && 2.0 <= r && r <= 36.0 => r as u32,
+                _ => {
+                    return Err(InvalidArgument {
+                        expected: "radix from 2 to 36".to_owned(),
+                        found: d.to_string(),
+                    })
+                }
+            }
+        }
+    };
+    let parsed = i64::from_str_radix(s.trim(), radix).ok().map(Int);
+    parsed_or_nil(parsed, &s, v.get(2))
+}
+
+// num(x[, strict]) converts x to an int or a number.  Ints and numbers are
+// returned as they are, and booleans give 1 or 0.  Strings are converted like
+// parse_number, giving nil if they aren't a number, or an error if strict is
+// true.  Any other value is an error.
+pub fn num(v: &[Data]) -> Result {
+    expect_arg_range(v, 1, 2)?;
+    match &v[0] {
+        &Int(_) | &Number(_) => Ok(v[0].clone()),
+        &Boolean(b) => Ok(Int(b as i64)),
+        &Str(ref s) => parsed_or_nil(parse_num_str(s), s, v.get(1)),
+        d => {
+            Err(InvalidArgument {
+                expected: "number, string or boolean".to_owned(),
+                found: d.type_name(),
+            })
+        }
+    }
+}
+
+// str(x) returns x as a string, written the way println writes it.
+pub fn str(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    match &v[0] {
+        &Str(_) => Ok(v[0].clone()),
+        d => Ok(Str(d.to_string().into())),
+    }
+}
+
+// bool(x[, strict]) converts x to a boolean.  The strings "true" and "false"
+// give true and false, ignoring case and surrounding whitespace, and any other
+// string gives nil, or an error if strict is true.  Other values are true
+// unless they're nil or false, just like in an if.
+pub fn bool(v: &[Data]) -> Result {
+    expect_arg_range(v, 1, 2)?;
+    let s = match &v[0] {
+        &Str(ref s) => s,
+        d => return Ok(Boolean(d.to_bool())),
+    };
+    match s.trim().to_lowercase().as_str() {
+        "true" => Ok(Boolean(true)),
+        "false" => Ok(Boolean(false)),
+        _ if v.get(1).map_or(false, |d| d.to_bool()) => {
+            Err(InvalidArgument {
+                expected: "true or false".to_owned(),
+                found: format!("{:?}", s),
+            })
+        }
+        _ => Ok(Nil),
+    }
+}
+
+// approx_eq(a, b[, eps]) returns whether two numbers are equal to within eps,
+// which defaults to the tolerance of the ~= operator.  The tolerance is
+// relative for numbers larger than 1.
+pub fn approx_eq(v: &[Data]) -> Result {
+    expect_arg_range(v, 2, 3)?;
+    let eps = match v.get(2) {
+        None => binary_op::DEFAULT_EPSILON,
+        Some(d) => {
+            match d.to_f64() {
+                Some(eps) if eps >= 0.0 => eps,
+                _ => {
+                    return Err(InvalidArgument {
+                        expected: "tolerance of at least 0".to_owned(),
+                        found: d.to_string(),
+                    })
+                }
+            }
+        }
+    };
+    Ok(Boolean(binary_op::approx_eq(expect_number(&v[0])?, expect_number(&v[1])?, eps)))
+}
+
+// min(a, b, ...) returns the smallest of its arguments, which must all be
+// numbers or all be strings.  Given a single list, set or range, it returns
+// the smallest value in it, or nil if it's empty.
+pub fn min(v: &[Data]) -> Result {
+    extreme(v, Ordering::Less)
+}
+
+// max(a, b, ...) returns the largest of its arguments, like min.
+pub fn max(v: &[Data]) -> Result {
+    extreme(v, Ordering::Greater)
+}
+
+// extreme returns the first value that's ordered before or after every other,
+// as given by keep.
+fn extreme(v: &[Data], keep: Ordering) -> Result {
+    if v.is_empty() {
+        return Err(WrongArgCount {
+            expected: 1,
+            found: 0,
+        });
+    }
+
+    let values: Vec<Data> = match v[0].iter() {
+        Some(items) if v.len() == 1 => items.collect(),
+        _ => v.to_vec(),
+    };
+    let mut best = match values.first() {
+        Some(d) => d,
+        None => return Ok(Nil),
+    };
+    for d in &values {
+        if compare(d, best)? == keep {
+            best = d;
+        }
+    }
+    Ok(best.clone())
+}
+
+// clamp(x, lo, hi) returns lo if x is less than lo, hi if it's greater than hi,
+// or else x itself.
+pub fn clamp(v: &[Data]) -> Result {
+    expect_arg_count(v, 3)?;
+    let (x, lo, hi) = (&v[0], &v[1], &v[2]);
+    if compare(hi, lo)? == Ordering::Less {
+        return Err(InvalidArgument {
+            expected: format!("upper bound of at least {}", lo),
+            found: hi.to_string(),
+        });
+    }
+
+    if compare(x, lo)? == Ordering::Less {
+        Ok(lo.clone())
+    } else if compare(x, hi)? == Ordering::Greater {
+        Ok(hi.clone())
+    } else {
+        Ok(x.clone())
+    }
+}
+
+// The bulk numeric builtins below work on lists of numbers and on arrays.  They
+// copy the numbers of a list into a contiguous buffer first, so the arithmetic
+// runs over plain floats, or ints when every value is one, without going
+// through the interpreter for each value.  Arrays are already stored that way.
+// Like the arithmetic operators, they work with ints as long as every value is
+// an int and fall back to floats on overflow.
+
+// sum(list) returns the sum of a list of numbers or an array, which is 0 for an
+// empty list.
+pub fn sum(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    let nums = expect_numbers(&v[0])?;
+    let total = as_ints(&v[0]).and_then(|ints| {
+        ints.iter().fold(Some(0i64), |acc, &i| acc.and_then(|acc| acc.checked_add(i)))
+    });
+    Ok(total.map_or_else(|| Number(nums.iter().sum()), Int))
+}
+
+// mean(list) returns the average of a list of numbers, or nil if it's empty.
+pub fn mean(v: &[Data]) -> Result {
+    expect_arg_count(v, 1)?;
+    let nums = expect_numbers(&v[0])?;
+    if nums.is_empty() {
+        return Ok(Nil);
+    }
+    Ok(Number(nums.iter().sum::<f64>() / nums.len() as f64))
+}
+
+// min_of(list) returns the smallest number in a list, or nil if it's empty.
+// Unlike min, it only accepts a list of numbers, which makes it faster.
+pub fn min_of(v: &[Data]) -> Result {
+    extreme_of(v, Ordering::Less)
+}
+
+// max_of(list) returns the largest number in a list, like min_of.
+pub fn max_of(v: &[Data]) -> Result {
+    extreme_of(v, Ordering::Greater)
+}
+
+fn extreme_of(v: &[Data], keep: Ordering) -> Result {
+    expect_arg_count(v, 1)?;
+    let nums = expect_numbers(&v[0])?;
+    let mut best = match nums.first() {
+        Some(_) => 0,
+        None => return Ok(Nil),
+    };
+    for (i, n) in nums.iter().enumerate() {
+        if n.partial_cmp(&nums[best]) == Some(keep) {
+            best = i;
+        }
+    }
+    match v[0] {
+        List(ref l) => Ok(l.borrow()[best].clone()),
+        _ => Ok(Number(nums[best])),
+    }
+}
+
+// dot(a, b) returns the dot product of two lists of numbers or arrays of the
+// same length.
+pub fn dot(v: &[Data]) -> Result {
+    expect_arg_count(v, 2)?;
+    let (a, b) = same_length_numbers(&v[0], &v[1])?;
+    if let (Some(a), Some(b)) = (as_ints(&v[0]), as_ints(&v[1])) {
+        let total = a.iter().zip(&b).fold(Some(0i64), |acc, (&x, &y)| {
+            acc.and_then(|acc| x.checked_mul(y).and_then(|xy| acc.checked_add(xy)))
+        });
+        if let Some(total) = total {
+            return Ok(Int(total));
+        }
+    }
+    Ok(Number(a.iter().zip(&b).map(|(x, y)| x * y).sum()))
+}
+
+// add_lists(a, b) returns a new list holding the sums of the numbers at the
+// same index in two lists or arrays of the same length.  If either is an
+// array, the result is an array too.
+pub fn add_lists(v: &[Data]) -> Result {
+    expect_arg_count(v, 2)?;
+    let (a, b) = same_length_numbers(&v[0], &v[1])?;
+    if let (&NumArray(_), _) | (_, &NumArray(_)) = (&v[0], &v[1]) {
+        return Ok(Data::num_array(a.iter().zip(&b).map(|(x, y)| x + y).collect()));
+    }
+    let sums = match (as_ints(&v[0]), as_ints(&v[1])) {
+        (Some(ia), Some(ib)) => {
+            ia.iter()
+                .zip(&ib)
+                .map(|(&x, &y)| x.checked_add(y).map_or(Number(x as f64 + y as f64), Int))
+                .collect()
+        }
+        _ => a.iter().zip(&b).map(|(x, y)| Number(x + y)).collect(),
+    };
+    Ok(Data::list(sums))
+}
+
+// expect_numbers copies the values of a list of numbers or an array into a
+// contiguous buffer of floats.
+fn expect_numbers(d: &Data) -> result::Result<Vec<f64>, ExecuteError> {
+    let l = match d {
+        &NumArray(ref a) => return Ok(a.borrow().clone()),
+        &List(ref l) => l,
+        d => {
+            return Err(InvalidArgument {
+                expected: "list or array".to_owned(),
+                found: d.type_name(),
+            })
+        }
+    };
+    let l = l.borrow();
+    let mut nums = Vec::with_capacity(l.len());
+    for d in l.iter() {
+        nums.push(d.to_f64().ok_or_else(|| {
+            InvalidArgument {
+                expected: "list of numbers".to_owned(),
+                found: format!("list containing {}", d.type_name()),
+            }
+        })?);
+    }
+    Ok(nums)
+}
+
+// as_ints returns the values of a list if they're all ints.
+fn as_ints(d: &Data) -> Option<Vec<i64>> {
+    let l = match d {
+        &List(ref l) => l.borrow(),
+        _ => return None,
+    };
+    l.iter()
+        .map(|d| match d {
+            &Int(i) => Some(i),
+            _ => None,
+        })
+        .collect()
+}
+
+// same_length_numbers returns the numbers in two lists, failing unless both
+// hold the same number of them.
+fn same_length_numbers(a: &Data,
+                       b: &Data)
+                       -> result::Result<(Vec<f64>, Vec<f64>), ExecuteError> {
+    let (a, b) = (expect_numbers(a)?, expect_numbers(b)?);
+    if a.len() != b.len() {
+        return Err(InvalidArgument {
+            expected: format!("list of {} numbers", a.len()),
+            found: format!("list of {}", b.len()),
+        });
+    }
+    Ok((a, b))
+}
+
+// compare orders a before or after b, failing if they can't be ordered.  The
+// error names the kind of b, which a was expected to match.
+fn compare(a: &Data, b: &Data) -> result::Result<Ordering, ExecuteError> {
+    if let Some(o) = a.compare(b) {
+        return Ok(o);
+    }
+    let (expected, found) = match b {
+        &Str(_) => ("string", a),
+        _ if b.to_f64().is_some() => ("number", a),
+        _ => ("number or string", b),
+    };
+    Err(InvalidArgument {
+        expected: expected.to_owned(),
+        found: found.type_name(),
+    })
+}
+
+fn parsed_or_nil(parsed: Option<Data>, s: &str, strict: Option<&Data>) -> Result {
+    match parsed {
+        Some(d) => Ok(d),
+        None if strict.map_or(false, |d| d.to_bool()) => Err(InvalidNumber(s.to_owned())),
+        None => Ok(Nil),
+    }
+}
+
+pub fn expect_arg_count(v: &[Data], n: usize) -> result::Result<(), ExecuteError> {
+    if v.len() != n {
+        return Err(WrongArgCount {
+            expected: n,
+            found: v.len(),
+        });
+    }
+    Ok(())
+}
+
+// expect_arg_range checks that there are from min to max arguments.
+pub fn expect_arg_range(v: &[Data], min: usize, max: usize) -> result::Result<(), ExecuteError> {
+    if v.len() < min || v.len() > max {
+        return Err(WrongArgCount {
+            expected: if v.len() < min { min } else { max },
+            found: v.len(),
+        });
+    }
+    Ok(())
+}
+
+pub fn expect_str(d: &Data) -> result::Result<String, ExecuteError> {
+    match d {
+        &Str(ref s) => Ok(s.to_string()),
+        d => {
+            Err(InvalidArgument {
+                expected: "string".to_owned(),
+                found: d.type_name(),
+            })
+        }
+    }
+}
+
+pub fn expect_number(d: &Data) -> result::Result<f64, ExecuteError> {
+    d.to_f64().ok_or_else(|| {
+        InvalidArgument {
+            expected: "number".to_owned(),
+            found: d.type_name(),
+        }
+    })
+}
+
+// expect_int converts d to an int, which it must be or be a whole number equal
+// to.
+fn expect_int(d: &Data) -> result::Result<i64, ExecuteError> {
+    match d {
+        &Int(i) => Ok(i),
+        &Number(n) if n.fract() == 0.0 && n.abs() < 9223372036854775808.0 => Ok(n as i64),
+        d => {
+            Err(InvalidArgument {
+                expected: "int".to_owned(),
+                found: d.to_string(),
+            })
+        }
+    }
+}
+
+fn expect_list(d: &Data) -> result::Result<Rc<RefCell<Vec<Data>>>, ExecuteError> {
+    match d {
+        &List(ref l) => Ok(l.clone()),
+        d => {
+            Err(InvalidArgument {
+                expected: "list".to_owned(),
+                found: d.type_name(),
+            })
+        }
+    }
+}
+
+fn expect_map(d: &Data) -> result::Result<Rc<RefCell<IndexMap<String, Data>>>, ExecuteError> {
+    match d {
+        &Map(ref m) => Ok(m.clone()),
+        d => {
+            Err(InvalidArgument {
+                expected: "map".to_owned(),
+                found: d.type_name(),
+            })
+        }
+    }
+}
+
+fn expect_set(d: &Data) -> result::Result<Rc<RefCell<ValueSet>>, ExecuteError> {
+    match d {
+        &Set(ref s) => Ok(s.clone()),
+        d => {
+            Err(InvalidArgument {
+                expected: "set".to_owned(),
+                found: d.type_name(),
+            })
+        }
+    }
+}
+
+fn expect_key(d: &Data) -> result::Result<String, ExecuteError> {
+    match d {
+        &Str(ref s) => Ok(s.to_string()),
+        d => {
+            Err(InvalidArgument {
+                expected: "string".to_owned(),
+                found: d.type_name(),
+            })
+        }
+    }
+}
+
+fn expect_values(d: &Data) -> result::Result<Box<dyn Iterator<Item = Data>>, ExecuteError> {
+    seq::values(d).ok_or_else(|| NotIterable(d.type_name()))
+}
+
+// expect_count converts d to a number of values, which must be a whole number
+// of at least 0.
+fn expect_count(d: &Data) -> result::Result<usize, ExecuteError> {
+    match d.to_f64() {
+        Some(n) if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+        _ => {
+            Err(InvalidArgument {
+                expected: "count of at least 0".to_owned(),
+                found: d.to_string(),
+            })
+        }
+    }
+}
+
+// expect_index converts d to an index into a list of length len.  If
+// allow_end is true, the index may also point one past the last value.
+pub fn expect_index(d: &Data, len: usize, allow_end: bool) -> result::Result<usize, ExecuteError> {
+    match d.to_f64() {
+        Some(n) => {
+            let limit = if allow_end { len + 1 } else { len };
+            if n < 0.0 || n.fract() != 0.0 || n >= limit as f64 {
+                return Err(IndexOutOfRange {
+                    index: n,
+                    len: len,
+                });
+            }
+            Ok(n as usize)
+        }
+        None => {
+            Err(InvalidArgument {
+                expected: "number".to_owned(),
+                found: d.type_name(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data::Data;
+    use program::Program;
+    use super::*;
+
+    fn nums(v: &[f64]) -> Data {
+        Data::list(v.iter().map(|&n| Number(n)).collect())
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let l = nums(&[1.0]);
+        assert_eq!(push(&vec![l.clone(), Number(2.0), Number(3.0)]),
+                   Ok(nums(&[1.0, 2.0, 3.0])));
+        assert_eq!(l, nums(&[1.0, 2.0, 3.0]));
+
+        assert_eq!(pop(&vec![l.clone()]), Ok(Number(3.0)));
+        assert_eq!(l, nums(&[1.0, 2.0]));
+        assert_eq!(pop(&vec![nums(&[])]), Ok(Nil));
+
+        assert_eq!(push(&vec![Number(1.0)]),
+                   Err(InvalidArgument {
+                       expected: "list".to_owned(),
+                       found: "number".to_owned(),
+                   }));
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let l = nums(&[1.0, 3.0]);
+        assert_eq!(insert(&vec![l.clone(), Number(1.0), Number(2.0)]),
+                   Ok(nums(&[1.0, 2.0, 3.0])));
+        assert_eq!(insert(&vec![l.clone(), Number(3.0), Number(4.0)]),
+                   Ok(nums(&[1.0, 2.0, 3.0, 4.0])));
+        assert_eq!(insert(&vec![l.clone(), Number(5.0), Number(6.0)]),
+                   Err(IndexOutOfRange {
+                       index: 5.0,
+                       len: 4,
+                   }));
+
+        assert_eq!(remove(&vec![l.clone(), Number(0.0)]), Ok(Number(1.0)));
+        assert_eq!(l, nums(&[2.0, 3.0, 4.0]));
+        assert_eq!(remove(&vec![l.clone(), Number(3.0)]),
+                   Err(IndexOutOfRange {
+                       index: 3.0,
+                       len: 3,
+                   }));
+        assert_eq!(remove(&vec![l.clone(), Number(-1.0)]),
+                   Err(IndexOutOfRange {
+                       index: -1.0,
+                       len: 3,
+                   }));
+        assert_eq!(remove(&vec![l.clone(), Number(0.5)]),
+                   Err(IndexOutOfRange {
+                       index: 0.5,
+                       len: 3,
+                   }));
+        assert_eq!(remove(&vec![l.clone()]),
+                   Err(WrongArgCount {
+                       expected: 2,
+                       found: 1,
+                   }));
+    }
+
+    #[test]
+    fn test_slice() {
+        let l = nums(&[1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(slice(&vec![l.clone(), Number(1.0), Number(3.0)]),
+                   Ok(nums(&[2.0, 3.0])));
+        assert_eq!(slice(&vec![l.clone(), Number(2.0)]), Ok(nums(&[3.0, 4.0])));
+        assert_eq!(slice(&vec![l.clone(), Number(4.0)]), Ok(nums(&[])));
+        assert_eq!(slice(&vec![l.clone(), Number(3.0), Number(2.0)]),
+                   Err(IndexOutOfRange {
+                       index: 3.0,
+                       len: 4,
+                   }));
+        assert_eq!(slice(&vec![l.clone(), Number(0.0), Number(5.0)]),
+                   Err(IndexOutOfRange {
+                       index: 5.0,
+                       len: 4,
+                   }));
+    }
+
+    #[test]
+    fn test_concat_join() {
+        let joined = concat(&vec![nums(&[1.0]), nums(&[]), nums(&[2.0, 3.0])]).unwrap();
+        assert_eq!(joined, nums(&[1.0, 2.0, 3.0]));
+        assert_eq!(join(&vec![joined, Str(", ".into())]),
+                   Ok(Str("1, 2, 3".into())));
+        assert_eq!(join(&vec![nums(&[]), Str("-".into())]),
+                   Ok(Str("".into())));
+    }
+
+    fn s(s: &str) -> Data {
+        Str(s.into())
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(len(&vec![s("héllo")]), Ok(Int(5)));
+        assert_eq!(len(&vec![nums(&[1.0, 2.0])]), Ok(Int(2)));
+        assert_eq!(len(&vec![Data::num_array(vec![1.0])]), Ok(Int(1)));
+        assert_eq!(len(&vec![Int(1)]),
+                   Err(InvalidArgument {
+                       expected: "string, list or array".to_owned(),
+                       found: "int".to_owned(),
+                   }));
+
+        let list = |parts: &[&str]| Data::list(parts.iter().map(|p| s(p)).collect());
+        assert_eq!(split(&vec![s("a,b,,c"), s(",")]), Ok(list(&["a", "b", "", "c"])));
+        assert_eq!(split(&vec![s("  a b\n c ")]), Ok(list(&["a", "b", "c"])));
+        assert_eq!(split(&vec![s("")]), Ok(list(&[])));
+        assert!(split(&vec![s("abc"), s("")]).is_err());
+
+        assert_eq!(substr(&vec![s("héllo"), Int(1), Int(3)]), Ok(s("él")));
+        assert_eq!(substr(&vec![s("héllo"), Int(5)]), Ok(s("")));
+        assert_eq!(substr(&vec![s("héllo"), Int(2), Int(6)]),
+                   Err(IndexOutOfRange {
+                       index: 6.0,
+                       len: 5,
+                   }));
+
+        assert_eq!(upper(&vec![s("Straße")]), Ok(s("STRASSE")));
+        assert_eq!(lower(&vec![s("ÉCOLE")]), Ok(s("école")));
+        assert_eq!(trim(&vec![s("\t hi there \n")]), Ok(s("hi there")));
+    }
+
+    #[test]
+    fn test_copy() {
+        let inner = nums(&[1.0]);
+        let values = vec![
+            Data::list(vec![inner.clone()]),
+            map(&vec![s("a"), inner.clone()]).unwrap(),
+            Data::set(vec![inner.clone()]),
+        ];
+        for d in values {
+            assert!(d.is_ref_type());
+            let c = copy(&vec![d.clone()]).unwrap();
+            assert_eq!(c, d);
+
+            // Changing the copy leaves the original alone, but containers
+            // inside it are still shared.
+            match c {
+                List(ref l) => l.borrow_mut().push(Nil),
+                Set(ref s) => {
+                    s.borrow_mut().insert(Nil);
+                }
+                Map(ref m) => {
+                    m.borrow_mut().insert("b".to_owned(), Nil);
+                }
+                _ => unreachable!(),
+            }
+            assert!(c != d);
+            push(&vec![inner.clone(), Number(2.0)]).unwrap();
+            let first = match c {
+                List(ref l) => l.borrow()[0].clone(),
+                Set(ref s) => s.borrow().iter().next().unwrap().clone(),
+                Map(ref m) => m.borrow()["a"].clone(),
+                _ => unreachable!(),
+            };
+            assert_eq!(first, inner);
+        }
+        assert_eq!(inner, nums(&[1.0, 2.0, 2.0, 2.0]));
+
+        for d in vec![Nil, Boolean(true), Int(1), Number(1.5), s("a"), Range(0.0, 1.0)] {
+            assert!(!d.is_ref_type());
+            assert_eq!(copy(&vec![d.clone()]), Ok(d));
+        }
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number(&vec![s(" 42 ")]), Ok(Int(42)));
+        assert_eq!(parse_number(&vec![s("-1.5")]), Ok(Number(-1.5)));
+        assert_eq!(parse_number(&vec![s("1e3")]), Ok(Number(1000.0)));
+        assert_eq!(parse_number(&vec![s("12abc")]), Ok(Nil));
+        assert_eq!(parse_number(&vec![s("inf")]), Ok(Nil));
+        assert_eq!(parse_number(&vec![s("")]), Ok(Nil));
+        assert_eq!(parse_number(&vec![s("12abc"), Boolean(true)]),
+                   Err(InvalidNumber("12abc".to_owned())));
+        assert_eq!(parse_number(&vec![Int(1)]),
+                   Err(InvalidArgument {
+                       expected: "string".to_owned(),
+                       found: "int".to_owned(),
+                   }));
+
+        assert_eq!(parse_int(&vec![s("-17")]), Ok(Int(-17)));
+        assert_eq!(parse_int(&vec![s("ff"), Int(16)]), Ok(Int(255)));
+        assert_eq!(parse_int(&vec![s("z"), Int(36)]), Ok(Int(35)));
+        assert_eq!(parse_int(&vec![s("1.5")]), Ok(Nil));
+        assert_eq!(parse_int(&vec![s("12"), Int(2)]), Ok(Nil));
+        assert_eq!(parse_int(&vec![s("12"), Nil, Boolean(true)]), Ok(Int(12)));
+        assert_eq!(parse_int(&vec![s("12"), Int(2), Boolean(true)]),
+                   Err(InvalidNumber("12".to_owned())));
+        assert_eq!(parse_int(&vec![s("1"), Int(37)]),
+                   Err(InvalidArgument {
+                       expected: "radix from 2 to 36".to_owned(),
+                       found: "37".to_owned(),
+                   }));
+        assert_eq!(parse_int(&vec![]),
+                   Err(WrongArgCount {
+                       expected: 1,
+                       found: 0,
+                   }));
+    }
+
+    #[test]
+    fn test_min_max_clamp() {
+        assert_eq!(min(&vec![Int(3), Number(1.5), Int(2)]), Ok(Number(1.5)));
+        assert_eq!(max(&vec![Int(3), Number(1.5), Int(2)]), Ok(Int(3)));
+        assert_eq!(max(&vec![s("pear"), s("apple")]), Ok(s("pear")));
+        assert_eq!(min(&vec![nums(&[2.0, -1.0])]), Ok(Number(-1.0)));
+        assert_eq!(max(&vec![Data::Range(0.0, 3.0)]), Ok(Int(2)));
+        assert_eq!(min(&vec![nums(&[])]), Ok(Nil));
+        assert_eq!(min(&vec![Int(1), s("a")]),
+                   Err(InvalidArgument {
+                       expected: "number".to_owned(),
+                       found: "string".to_owned(),
+                   }));
+        assert_eq!(max(&vec![Nil, Int(1)]),
+                   Err(InvalidArgument {
+                       expected: "number or string".to_owned(),
+                       found: "nil".to_owned(),
+                   }));
+        assert_eq!(min(&vec![]),
+                   Err(WrongArgCount {
+                       expected: 1,
+                       found: 0,
+                   }));
+
+        assert_eq!(clamp(&vec![Int(5), Int(0), Int(3)]), Ok(Int(3)));
+        assert_eq!(clamp(&vec![Number(-0.5), Int(0), Int(3)]), Ok(Int(0)));
+        assert_eq!(clamp(&vec![Int(2), Int(0), Int(3)]), Ok(Int(2)));
+        assert_eq!(clamp(&vec![s("m"), s("a"), s("f")]), Ok(s("f")));
+        assert_eq!(clamp(&vec![Int(2), Int(3), Int(0)]),
+                   Err(InvalidArgument {
+                       expected: "upper bound of at least 3".to_owned(),
+                       found: "0".to_owned(),
+                   }));
+    }
+
+    #[test]
+    fn test_bulk_numeric() {
+        assert_eq!(sum(&vec![Data::list(vec![Int(1), Int(2), Int(3)])]), Ok(Int(6)));
+        assert_eq!(sum(&vec![Data::list(vec![Int(1), Number(0.5)])]), Ok(Number(1.5)));
+        assert_eq!(sum(&vec![Data::list(vec![Int(i64::max_value()), Int(1)])]),
+                   Ok(Number(i64::max_value() as f64 + 1.0)));
+        assert_eq!(sum(&vec![nums(&[])]), Ok(Int(0)));
+        assert_eq!(sum(&vec![Data::list(vec![Int(1), s("2")])]),
+                   Err(InvalidArgument {
+                       expected: "list of numbers".to_owned(),
+                       found: "list containing string".to_owned(),
+                   }));
+
+        assert_eq!(mean(&vec![Data::list(vec![Int(1), Int(2)])]), Ok(Number(1.5)));
+        assert_eq!(mean(&vec![nums(&[])]), Ok(Nil));
+
+        assert_eq!(min_of(&vec![Data::list(vec![Int(3), Number(-1.5), Int(2)])]),
+                   Ok(Number(-1.5)));
+        assert_eq!(max_of(&vec![Data::list(vec![Int(3), Number(-1.5), Int(2)])]), Ok(Int(3)));
+        assert_eq!(max_of(&vec![nums(&[])]), Ok(Nil));
+
+        let ints = |v: &[i64]| Data::list(v.iter().map(|&i| Int(i)).collect());
+        assert_eq!(dot(&vec![ints(&[1, 2, 3]), ints(&[4, 5, 6])]), Ok(Int(32)));
+        assert_eq!(dot(&vec![nums(&[0.5, 2.0]), ints(&[4, 1])]), Ok(Number(4.0)));
+        assert_eq!(dot(&vec![ints(&[1, 2]), ints(&[1])]),
+                   Err(InvalidArgument {
+                       expected: "list of 2 numbers".to_owned(),
+                       found: "list of 1".to_owned(),
+                   }));
+
+        assert_eq!(add_lists(&vec![ints(&[1, 2]), ints(&[3, 4])]), Ok(ints(&[4, 6])));
+        assert_eq!(add_lists(&vec![ints(&[1, 2]), nums(&[0.5, 0.5])]), Ok(nums(&[1.5, 2.5])));
+        assert!(add_lists(&vec![ints(&[1]), Int(1)]).is_err());
+    }
+
+    #[test]
+    fn test_num_array() {
+        let a = array(&vec![Data::list(vec![Int(1), Number(2.5), Int(-3)])]).unwrap();
+        assert_eq!(a, Data::num_array(vec![1.0, 2.5, -3.0]));
+        assert_eq!(array(&vec![Data::Range(0.0, 3.0)]), Ok(Data::num_array(vec![0.0, 1.0, 2.0])));
+        assert_eq!(array(&vec![Data::list(vec![Nil])]),
+                   Err(InvalidArgument {
+                       expected: "number".to_owned(),
+                       found: "nil".to_owned(),
+                   }));
+        assert_eq!(to_list(&vec![a.clone()]), Ok(nums(&[1.0, 2.5, -3.0])));
+        assert_eq!(slice(&vec![a.clone(), Int(1)]), Ok(Data::num_array(vec![2.5, -3.0])));
+
+        assert_eq!(sum(&vec![a.clone()]), Ok(Number(0.5)));
+        assert_eq!(min_of(&vec![a.clone()]), Ok(Number(-3.0)));
+        assert_eq!(dot(&vec![a.clone(), nums(&[2.0, 2.0, 1.0])]), Ok(Number(4.0)));
+        assert_eq!(add_lists(&vec![nums(&[1.0, 1.0, 1.0]), a.clone()]),
+                   Ok(Data::num_array(vec![2.0, 3.5, -2.0])));
+    }
+
+    #[test]
+    fn test_type_of() {
+        assert_eq!(type_of(&vec![Int(1)]), Ok(s("int")));
+        assert_eq!(type_of(&vec![nums(&[])]), Ok(s("list")));
+        assert_eq!(type_of(&vec![Builtin("len".to_owned())]), Ok(s("function")));
+        assert!(type_of(&vec![]).is_err());
+    }
+
+    #[test]
+    fn test_sequences() {
+        let ints = |v: &[i64]| Data::list(v.iter().map(|&i| Int(i)).collect());
+        let collect = |d: Result| to_list(&vec![d.unwrap()]).unwrap();
+
+        assert_eq!(collect(take(&vec![Data::Range(0.0, 1e18), Int(3)])), ints(&[0, 1, 2]));
+        assert_eq!(collect(drop(&vec![ints(&[1, 2, 3]), Int(1)])), ints(&[2, 3]));
+        assert!(zip(&vec![ints(&[1]), s("ab")]).is_err());
+        assert_eq!(collect(zip(&vec![ints(&[1, 2, 3]), Data::Range(5.0, 7.0)])),
+                   Data::list(vec![ints(&[1, 5]), ints(&[2, 6])]));
+        assert_eq!(collect(enumerate(&vec![Data::num_array(vec![0.5])])),
+                   Data::list(vec![Data::list(vec![Int(0), Number(0.5)])]));
+        assert_eq!(take(&vec![ints(&[]), Int(-1)]).unwrap_err(),
+                   InvalidArgument {
+                       expected: "count of at least 0".to_owned(),
+                       found: "-1".to_owned(),
+                   });
+
+        // A sequence is used up as its values are produced, so taking some of
+        // them leaves the rest.
+        let rest = drop(&vec![Data::Range(0.0, 6.0), Int(2)]).unwrap();
+        assert_eq!(collect(take(&vec![rest.clone(), Int(2)])), ints(&[2, 3]));
+        assert_eq!(collect(Ok(rest.clone())), ints(&[4, 5]));
+        assert_eq!(collect(Ok(rest)), ints(&[]));
+    }
+
+    #[test]
+    fn test_range() {
+        let ints = |v: &[i64]| Data::list(v.iter().map(|&i| Int(i)).collect());
+        let collect = |d: Result| to_list(&vec![d.unwrap()]).unwrap();
+
+        assert_eq!(range(&vec![Int(3)]), Ok(Data::Range(0.0, 3.0)));
+        assert_eq!(range(&vec![Int(2), Int(5)]), Ok(Data::Range(2.0, 5.0)));
+        assert_eq!(collect(range(&vec![Int(0), Int(10), Int(3)])), ints(&[0, 3, 6, 9]));
+        assert_eq!(collect(range(&vec![Int(5), Int(0), Int(-2)])), ints(&[5, 3, 1]));
+        assert_eq!(collect(range(&vec![Int(0), Int(5), Int(-1)])), ints(&[]));
+        assert_eq!(collect(range(&vec![Int(0), Int(1), Number(0.25)])),
+                   Data::list(vec![Number(0.0), Number(0.25), Number(0.5), Number(0.75)]));
+        assert_eq!(collect(take(&vec![range(&vec![Int(0), Number(1e18), Int(7)]).unwrap(),
+                                      Int(2)])),
+                   ints(&[0, 7]));
+
+        assert_eq!(range(&vec![Int(0), Int(5), Int(0)]).unwrap_err(),
+                   InvalidArgument {
+                       expected: "step other than 0".to_owned(),
+                       found: "0".to_owned(),
+                   });
+        assert!(range(&vec![s("a")]).is_err());
+        assert!(range(&vec![]).is_err());
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(num(&vec![s(" 42 ")]), Ok(Int(42)));
+        assert_eq!(num(&vec![s("2.5")]), Ok(Number(2.5)));
+        assert_eq!(num(&vec![Number(2.5)]), Ok(Number(2.5)));
+        assert_eq!(num(&vec![Boolean(true)]), Ok(Int(1)));
+        assert_eq!(num(&vec![s("abc")]), Ok(Nil));
+        assert_eq!(num(&vec![s("abc"), Boolean(true)]), Err(InvalidNumber("abc".to_owned())));
+        assert_eq!(num(&vec![Nil]),
+                   Err(InvalidArgument {
+                       expected: "number, string or boolean".to_owned(),
+                       found: "nil".to_owned(),
+                   }));
+
+        assert_eq!(str(&vec![Number(2.5)]), Ok(s("2.5")));
+        assert_eq!(str(&vec![nums(&[1.0])]), Ok(s("[1]")));
+        assert_eq!(str(&vec![s("hi")]), Ok(s("hi")));
+
+        assert_eq!(bool(&vec![s(" TRUE\n")]), Ok(Boolean(true)));
+        assert_eq!(bool(&vec![s("false")]), Ok(Boolean(false)));
+        assert_eq!(bool(&vec![s("yes")]), Ok(Nil));
+        assert_eq!(bool(&vec![s("yes"), Boolean(true)]),
+                   Err(InvalidArgument {
+                       expected: "true or false".to_owned(),
+                       found: "\"yes\"".to_owned(),
+                   }));
+        assert_eq!(bool(&vec![Int(0)]), Ok(Boolean(true)));
+        assert_eq!(bool(&vec![Nil]), Ok(Boolean(false)));
+    }
+
+    #[test]
+    fn test_assertions() {
+        assert_eq!(assert(&vec![Int(0)]), Ok(Nil));
+        assert_eq!(assert(&vec![Nil]),
+                   Err(AssertionFailed("condition is false".to_owned())));
+        assert_eq!(assert(&vec![Boolean(false), s("too big")]),
+                   Err(AssertionFailed("too big".to_owned())));
+        assert_eq!(assert_eq(&vec![Int(1), Number(1.0)]), Ok(Nil));
+        assert_eq!(assert_eq(&vec![s("a"), Nil]),
+                   Err(AssertionFailed("expected Nil, found Str(\"a\")".to_owned())));
+    }
+
+    #[test]
+    fn test_approx_eq() {
+        assert_eq!(approx_eq(&vec![Number(0.1 + 0.2), Number(0.3)]), Ok(Boolean(true)));
+        assert_eq!(approx_eq(&vec![Number(1.0), Int(1)]), Ok(Boolean(true)));
+        assert_eq!(approx_eq(&vec![Number(1.0), Number(1.05), Number(0.1)]), Ok(Boolean(true)));
+        assert_eq!(approx_eq(&vec![Number(1.0), Number(1.05), Number(0.01)]),
+                   Ok(Boolean(false)));
+        assert_eq!(approx_eq(&vec![Number(1.0), Number(1.0), Number(-1.0)]),
+                   Err(InvalidArgument {
+                       expected: "tolerance of at least 0".to_owned(),
+                       found: "-1".to_owned(),
+                   }));
+        assert_eq!(approx_eq(&vec![Number(1.0), s("1")]),
+                   Err(InvalidArgument {
+                       expected: "number".to_owned(),
+                       found: "string".to_owned(),
+                   }));
+    }
+
+    #[test]
+    fn test_render() {
+        let engine = Engine::new();
+        let mut ctx = Context::new();
+        ctx.set_var("greeting", s("Hi"));
+        let vars = map(&vec![s("name"), s("Ann"), s("n"), Int(2)]).unwrap();
+        let mut render = |template: &str, eval: bool| {
+            Render.call(&engine, &mut ctx, &[s(template), vars.clone(), Boolean(eval)])
+        };
+        let invalid = |msg: &str| Err(InvalidTemplate(msg.to_owned()));
+
+        assert_eq!(render("{{ name }} has {{n}}", false), Ok(s("Ann has 2")));
+        assert_eq!(render("no placeholders", false), Ok(s("no placeholders")));
+        assert_eq!(render("{{greeting}}", false), invalid("no value for \"greeting\""));
+        assert_eq!(render("{{n * 10}}", false), invalid("no value for \"n * 10\""));
+        assert_eq!(render("{{ name", false), invalid("unclosed \"{{\""));
+
+        assert_eq!(render("{{n * 10}} {{ (x = n + 1) * x }}", true), Ok(s("20 9")));
+        assert_eq!(render("{{ 1 2 }}", true), invalid("can't parse \"1 2\""));
+        assert_eq!(render("{{}}", true), invalid("can't parse \"\""));
+
+        // Expressions can't reach the program's variables or builtins.
+        assert_eq!(render("{{greeting}}", true), invalid("\"greeting\" isn't in the map"));
+        assert_eq!(render("{{list(n)}}", true), invalid("\"list\" isn't in the map"));
+        assert_eq!(render("{{ (greeting = n) + (name = 1) }}", true), Ok(s("3")));
+        assert_eq!(ctx.var("greeting"), Some(s("Hi")));
+        assert_eq!(vars, map(&vec![s("name"), s("Ann"), s("n"), Int(2)]).unwrap());
+    }
+
+    #[test]
+    fn test_render_nested() {
+        let mut p = Program::new();
+        assert_eq!(p.run_source("inner = map(\"x\", 1)\n\
+                                 outer = map(\"r\", render, \"t\", \"x is {{x}}\", \"m\", inner)\n\
+                                 render(\"<{{ r(t, m) }}>\", outer, true)"),
+                   Ok(s("<x is 1>")));
+    }
+
+    #[test]
+    fn test_map_get_put() {
+        let m = map(&vec![s("b"), Number(1.0), s("a"), Number(2.0)]).unwrap();
+        assert_eq!(get(&vec![m.clone(), s("a")]), Ok(Number(2.0)));
+        assert_eq!(get(&vec![m.clone(), s("c")]), Ok(Nil));
+
+        put(&vec![m.clone(), s("c"), Number(3.0)]).unwrap();
+        put(&vec![m.clone(), s("b"), Number(4.0)]).unwrap();
+        assert_eq!(get(&vec![m.clone(), s("c")]), Ok(Number(3.0)));
+        assert_eq!(keys(&vec![m.clone()]),
+                   Ok(Data::list(vec![s("b"), s("a"), s("c")])));
+        assert_eq!(values(&vec![m.clone()]), Ok(nums(&[4.0, 2.0, 3.0])));
+
+        assert_eq!(map(&vec![s("a")]),
+                   Err(WrongArgCount {
+                       expected: 2,
+                       found: 1,
+                   }));
+        assert_eq!(get(&vec![m.clone(), Number(1.0)]),
+                   Err(InvalidArgument {
+                       expected: "string".to_owned(),
+                       found: "number".to_owned(),
+                   }));
+    }
+
+    #[test]
+    fn test_has_delete() {
+        let m = map(&vec![s("a"), Number(1.0), s("b"), Number(2.0), s("c"), Number(3.0)]).unwrap();
+        assert_eq!(has(&vec![m.clone(), s("a")]), Ok(Boolean(true)));
+        assert_eq!(delete(&vec![m.clone(), s("a")]), Ok(Number(1.0)));
+        assert_eq!(delete(&vec![m.clone(), s("a")]), Ok(Nil));
+        assert_eq!(has(&vec![m.clone(), s("a")]), Ok(Boolean(false)));
+        assert_eq!(keys(&vec![m.clone()]), Ok(Data::list(vec![s("b"), s("c")])));
+    }
+
+    #[test]
+    fn test_merge_entries() {
+        let a = map(&vec![s("x"), Number(1.0), s("y"), Number(2.0)]).unwrap();
+        let b = map(&vec![s("z"), Number(3.0), s("x"), Number(4.0)]).unwrap();
+        let merged = merge(&vec![a.clone(), b]).unwrap();
+        assert_eq!(get(&vec![a.clone(), s("x")]), Ok(Number(1.0)));
+        assert_eq!(entries(&vec![merged]),
+                   Ok(Data::list(vec![
+            Data::list(vec![s("x"), Number(4.0)]),
+            Data::list(vec![s("y"), Number(2.0)]),
+            Data::list(vec![s("z"), Number(3.0)]),
+        ])));
+    }
+
+    #[test]
+    fn test_set() {
+        let a = set(&vec![nums(&[1.0, 2.0, 2.0, 3.0])]).unwrap();
+        assert_eq!(a, Data::set(vec![Number(1.0), Number(2.0), Number(3.0)]));
+
+        let b = set(&vec![nums(&[3.0, 4.0, 2.0])]).unwrap();
+        assert_eq!(union(&vec![a.clone(), b.clone()]),
+                   Ok(Data::set(vec![Number(1.0), Number(2.0), Number(3.0), Number(4.0)])));
+        assert_eq!(intersection(&vec![a.clone(), b.clone()]),
+                   Ok(Data::set(vec![Number(2.0), Number(3.0)])));
+        assert_eq!(difference(&vec![a.clone(), b.clone()]),
+                   Ok(Data::set(vec![Number(1.0)])));
+        assert_eq!(union(&vec![a.clone(), nums(&[])]),
+                   Err(InvalidArgument {
+                       expected: "set".to_owned(),
+                       found: "list".to_owned(),
+                   }));
+        assert_eq!(set(&vec![Range(1.0, 3.0)]), Ok(Data::set(vec![Int(1), Int(2)])));
+
+        // Sets are equal whatever order their values were added in.
+        let mut p = Program::new();
+        assert_eq!(p.run_source("set(list(1, 2)) == set(list(2, 1))"), Ok(Boolean(true)));
+        assert_eq!(p.run_source("set(list(1, 2)) == set(list(1, 2, 3))"), Ok(Boolean(false)));
+        assert_eq!(p.run_source("list(1) in set(list(list(2), list(1)))"), Ok(Boolean(true)));
+    }
+
+    #[test]
+    fn test_to_list() {
+        assert_eq!(to_list(&vec![Range(-1.0, 2.0)]),
+                   Ok(Data::list(vec![Int(-1), Int(0), Int(1)])));
+        assert_eq!(to_list(&vec![Range(0.5, 2.0)]), Ok(nums(&[0.5, 1.5])));
+        assert_eq!(to_list(&vec![Range(2.0, 2.0)]), Ok(nums(&[])));
+        assert_eq!(to_list(&vec![Data::set(vec![Nil])]), Ok(Data::list(vec![Nil])));
+        assert_eq!(to_list(&vec![Nil]), Err(NotIterable("nil".to_owned())));
+    }
+}
@@ -0,0 +1,51 @@
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A CancelToken stops a running program from another thread.  Cancelling it
+// makes the evaluation running at the time, or the next one to start, fail
+// with Cancelled the next time it calls a function or goes round a loop.
+// Tokens are cheap to clone, and every clone cancels the same program.
+#[derive(Clone,Debug,Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    // reset lets evaluations run again after a cancellation.
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    // sleep waits for d, waking early if the token is cancelled.
+    pub fn sleep(&self, d: Duration) {
+        let end = Instant::now() + d;
+        loop {
+            let now = Instant::now();
+            if now >= end || self.is_cancelled() {
+                return;
+            }
+            thread::sleep(cmp::min(end - now, Duration::from_millis(20)));
+        }
+    }
+}
+
+// flag returns the flag behind token, for signal handlers to set.
+#[cfg(feature = "signals")]
+pub fn flag(token: &CancelToken) -> Arc<AtomicBool> {
+    token.cancelled.clone()
+}
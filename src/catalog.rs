@@ -0,0 +1,240 @@
+// The catalog module lets hosts show errors and other diagnostics in their
+// users' language.  A Catalog holds translated messages keyed by code, and
+// falls back to gate's own English message for codes it doesn't have.
+
+use std::collections::HashMap;
+
+use analysis::{Branch, Lint};
+use error::{Error, ExecuteError, LoadError, ParseError, TokenError, Warning};
+
+// A Diagnostic is anything gate reports with a code: errors, warnings and
+// lints.  args gives the values its message mentions, in the order the English
+// message mentions them, for translations to put wherever their grammar needs.
+pub trait Diagnostic {
+    fn code(&self) -> &'static str;
+    // message returns the English message, without the code.
+    fn message(&self) -> String;
+    fn args(&self) -> Vec<String>;
+}
+
+// A Catalog maps codes to messages, such as "E0007" to
+// "variable \"{0}\" non définie".  A message refers to its diagnostic's args
+// by their index, so the same arg can appear any number of times.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Catalog {
+    messages: HashMap<String, String>,
+}
+
+impl Catalog {
+    pub fn new() -> Self {
+        Catalog { messages: HashMap::new() }
+    }
+
+    pub fn insert(&mut self, code: &str, message: &str) {
+        self.messages.insert(code.to_owned(), message.to_owned());
+    }
+
+    // message returns the catalog's message for d with its args filled in, or
+    // d's English message if the catalog doesn't have one.
+    pub fn message<D: Diagnostic + ?Sized>(&self, d: &D) -> String {
+        let template = match self.messages.get(d.code()) {
+            Some(template) => template,
+            None => return d.message(),
+        };
+        let mut message = template.clone();
+        for (i, arg) in d.args().iter().enumerate() {
+            message = message.replace(&format!("{{{}}}", i), arg);
+        }
+        message
+    }
+}
+
+impl Diagnostic for ParseError {
+    fn code(&self) -> &'static str {
+        ParseError::code(self)
+    }
+
+    fn message(&self) -> String {
+        Error::Parse(self.clone()).message()
+    }
+
+    fn args(&self) -> Vec<String> {
+        match self {
+            &ParseError::ScanError(TokenError::UnexpectedChar(c)) => vec![c.to_string()],
+            &ParseError::ScanError(TokenError::IncompleteString) => vec![],
+            &ParseError::ScanError(TokenError::InvalidEscape(ref s)) |
+            &ParseError::ScanError(TokenError::InvalidNumber(ref s)) => vec![s.clone()],
+            &ParseError::Unexpected(ref token) => vec![format!("{:?}", token)],
+            &ParseError::UnexpectedEOF | &ParseError::TooDeep => vec![],
+            &ParseError::Internal(ref s) => vec![s.clone()],
+        }
+    }
+}
+
+// The args of an InvalidOperation are the operands' types and the operator,
+// then the operands' values, which are empty when they aren't known.
+impl Diagnostic for ExecuteError {
+    fn code(&self) -> &'static str {
+        ExecuteError::code(self)
+    }
+
+    fn message(&self) -> String {
+        self.to_string()
+    }
+
+    fn args(&self) -> Vec<String> {
+        match self {
+            &ExecuteError::UndefinedVar(ref s) |
+            &ExecuteError::UndefinedFunc(ref s) |
+            &ExecuteError::ReentrantCall(ref s) |
+            &ExecuteError::NotCallable(ref s) |
+            &ExecuteError::NotAllowed(ref s) |
+            &ExecuteError::NotIterable(ref s) |
+            &ExecuteError::InvalidTemplate(ref s) |
+            &ExecuteError::InvalidNumber(ref s) |
+            &ExecuteError::AssignToConst(ref s) |
+            &ExecuteError::ReadOnly(ref s) |
+            &ExecuteError::Io(ref s) |
+            &ExecuteError::Database(ref s) |
+            &ExecuteError::Internal(ref s) |
+            &ExecuteError::AssertionFailed(ref s) => vec![s.clone()],
+            &ExecuteError::UnknownLiteralPrefix(c) => vec![c.to_string()],
+            &ExecuteError::AmbiguousName { ref name, ref namespaces } => {
+                vec![name.clone(), namespaces.join(", ")]
+            }
+            &ExecuteError::UndefinedField { ref type_name, ref field } => {
+                vec![field.clone(), type_name.clone()]
+            }
+            &ExecuteError::Break |
+            &ExecuteError::Continue |
+            &ExecuteError::Cancelled |
+            &ExecuteError::TooDeep => vec![],
+            &ExecuteError::InvalidOperation { ref left, ref op, ref right, ref values, .. } => {
+                let (l, r) = values.clone().unwrap_or_default();
+                vec![left.clone(), op.to_string(), right.clone(), l, r]
+            }
+            &ExecuteError::InvalidUnaryOperation { ref op, ref operand } => {
+                vec![op.to_string(), operand.clone()]
+            }
+            &ExecuteError::WrongArgCount { expected, found } => {
+                vec![expected.to_string(), found.to_string()]
+            }
+            &ExecuteError::InvalidArgument { ref expected, ref found } => {
+                vec![expected.clone(), found.clone()]
+            }
+            &ExecuteError::IndexOutOfRange { index, len } => {
+                vec![index.to_string(), len.to_string()]
+            }
+            &ExecuteError::InvalidIndex { ref type_name, ref index } => {
+                vec![type_name.clone(), index.clone()]
+            }
+            &ExecuteError::Thrown(ref d) => vec![d.to_string()],
+            &ExecuteError::Exit(status) => vec![status.to_string()],
+        }
+    }
+}
+
+impl Diagnostic for LoadError {
+    fn code(&self) -> &'static str {
+        LoadError::code(self)
+    }
+
+    fn message(&self) -> String {
+        self.to_string()
+    }
+
+    fn args(&self) -> Vec<String> {
+        match self {
+            &LoadError::UnsupportedVersion(v) => vec![v.to_string()],
+            &LoadError::WrongGateVersion(ref v) => vec![v.clone()],
+            &LoadError::NotCompiled | &LoadError::Corrupt => vec![],
+        }
+    }
+}
+
+impl Diagnostic for Error {
+    fn code(&self) -> &'static str {
+        Error::code(self)
+    }
+
+    fn message(&self) -> String {
+        Error::message(self)
+    }
+
+    fn args(&self) -> Vec<String> {
+        match self {
+            &Error::Parse(ref e) => e.args(),
+            &Error::Execute(ref e) => e.args(),
+            &Error::Load(ref e) => e.args(),
+            &Error::Internal(ref s) => vec![s.clone()],
+        }
+    }
+}
+
+impl Diagnostic for Warning {
+    fn code(&self) -> &'static str {
+        Warning::code(self)
+    }
+
+    fn message(&self) -> String {
+        self.to_string()
+    }
+
+    fn args(&self) -> Vec<String> {
+        match self {
+            &Warning::ShadowedBuiltin(ref s) => vec![s.clone()],
+        }
+    }
+}
+
+// The args of a DeadBranch are "if", "else" or "while", then the function
+// holding it, which is empty at the top level, then the condition's value.
+impl Diagnostic for Lint {
+    fn code(&self) -> &'static str {
+        Lint::code(self)
+    }
+
+    fn message(&self) -> String {
+        self.to_string()
+    }
+
+    fn args(&self) -> Vec<String> {
+        match self {
+            &Lint::UnusedFunction(ref name) => vec![name.clone()],
+            &Lint::DeadBranch { ref func, branch } => {
+                let (kind, always) = match branch {
+                    Branch::IfBody => ("if", "false"),
+                    Branch::IfElse => ("else", "true"),
+                    Branch::WhileBody => ("while", "false"),
+                };
+                vec![kind.to_owned(), func.clone().unwrap_or_default(), always.to_owned()]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use program::Program;
+
+    #[test]
+    fn test_message() {
+        let mut catalog = Catalog::new();
+        catalog.insert("E0007", "variable \"{0}\" non définie");
+        catalog.insert("E0018", "opération invalide : {3} {1} {4} ({0} {1} {2})");
+
+        let mut p = Program::new();
+        let err = p.run_source("y = x").unwrap_err();
+        assert_eq!(catalog.message(&err), "variable \"x\" non définie");
+        let err = p.run_source("1 + \"a\"").unwrap_err();
+        assert_eq!(catalog.message(&err),
+                   "opération invalide : 1 + \"a\" (int + string)");
+
+        let err = p.run_source("f()").unwrap_err();
+        assert_eq!(catalog.message(&err), "undefined function \"f\"");
+        assert_eq!(catalog.message(&Warning::ShadowedBuiltin("len".to_owned())),
+                   "function \"len\" shadows a builtin of the same name");
+    }
+}
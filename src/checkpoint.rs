@@ -0,0 +1,203 @@
+// The checkpoint module keeps an undo log of a program's variables so they can
+// be put back later, which gives hosts all-or-nothing evaluation.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use indexmap::IndexMap;
+
+use data::{Data, Instance};
+use scope::{self, Scope};
+use set::ValueSet;
+
+// A Checkpoint records the contents of each scope, list, map, set, array and
+// instance the first time it's changed after the checkpoint is taken.
+// Restoring it puts those contents back into the same scopes and containers,
+// so values shared between variables stay shared.  Scopes created after the
+// checkpoint, like those of function calls, aren't recorded, since nothing
+// can reach them once everything else is restored.
+//
+// Taking a checkpoint costs nothing, and evaluating while it's in effect costs
+// time and memory in proportion to the scopes and containers that change.
+// Dropping it without restoring it keeps the changes.
+pub struct Checkpoint {
+    // The checkpoint's position in LOGS, counting from 1.
+    depth: usize,
+}
+
+pub struct Log {
+    // The scopes created before the checkpoint was taken have lower ids.
+    first_new_scope: u64,
+    // The addresses of the scopes and containers already saved.
+    seen: HashSet<usize>,
+    saved: Vec<Saved>,
+}
+
+pub enum Saved {
+    Scope(Rc<RefCell<Scope>>, Scope),
+    Items(Rc<RefCell<Vec<Data>>>, Vec<Data>),
+    Set(Rc<RefCell<ValueSet>>, ValueSet),
+    Numbers(Rc<RefCell<Vec<f64>>>, Vec<f64>),
+    Entries(Rc<RefCell<IndexMap<String, Data>>>, IndexMap<String, Data>),
+    Instance(Rc<RefCell<Instance>>, Instance),
+}
+
+thread_local! {
+    // The logs of the checkpoints in effect on this thread, innermost last.
+    // Every change is recorded in each of them.
+    static LOGS: RefCell<Vec<Log>> = RefCell::new(vec![]);
+}
+
+impl Checkpoint {
+    pub fn new() -> Self {
+        LOGS.with(|logs| {
+            let mut logs = logs.borrow_mut();
+            logs.push(Log {
+                first_new_scope: scope::created(),
+                seen: HashSet::new(),
+                saved: vec![],
+            });
+            Checkpoint { depth: logs.len() }
+        })
+    }
+
+    // restore puts back the contents of every scope and container that has
+    // changed since the checkpoint was taken.
+    pub fn restore(self) {
+        let log = match self.close() {
+            Some(log) => log,
+            None => return,
+        };
+        for saved in log.saved {
+            match saved {
+                Saved::Scope(rc, scope) => *rc.borrow_mut() = scope,
+                Saved::Items(rc, items) => *rc.borrow_mut() = items,
+                Saved::Set(rc, set) => *rc.borrow_mut() = set,
+                Saved::Entries(rc, entries) => *rc.borrow_mut() = entries,
+                Saved::Numbers(rc, numbers) => *rc.borrow_mut() = numbers,
+                Saved::Instance(rc, instance) => *rc.borrow_mut() = instance,
+            }
+        }
+    }
+
+    // close stops recording changes for this checkpoint and any taken after
+    // it, returning its log.
+    fn close(&self) -> Option<Log> {
+        LOGS.with(|logs| {
+            let mut logs = logs.borrow_mut();
+            if logs.len() < self.depth {
+                return None;
+            }
+            logs.truncate(self.depth);
+            logs.pop()
+        })
+    }
+}
+
+impl Drop for Checkpoint {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+// Undoable is implemented by the contents of each kind of scope or container
+// that can change in place.
+pub trait Undoable: Clone {
+    fn saved(rc: Rc<RefCell<Self>>, contents: Self) -> Saved;
+
+    // is_new returns whether the contents were created after the checkpoint
+    // with the log was taken, so they don't need saving.
+    fn is_new(&self, _log: &Log) -> bool {
+        false
+    }
+}
+
+// save records the contents of rc, which is about to change, in the log of
+// every checkpoint in effect that hasn't recorded them yet.
+pub fn save<T: Undoable>(rc: &Rc<RefCell<T>>) {
+    LOGS.with(|logs| {
+        let mut logs = logs.borrow_mut();
+        for log in logs.iter_mut() {
+            if rc.borrow().is_new(log) || !log.seen.insert(rc.as_ptr() as usize) {
+                continue;
+            }
+            let contents = rc.borrow().clone();
+            log.saved.push(T::saved(rc.clone(), contents));
+        }
+    })
+}
+
+impl Undoable for Scope {
+    fn saved(rc: Rc<RefCell<Self>>, contents: Self) -> Saved {
+        Saved::Scope(rc, contents)
+    }
+
+    fn is_new(&self, log: &Log) -> bool {
+        self.id() >= log.first_new_scope
+    }
+}
+
+impl Undoable for Vec<Data> {
+    fn saved(rc: Rc<RefCell<Self>>, contents: Self) -> Saved {
+        Saved::Items(rc, contents)
+    }
+}
+
+impl Undoable for ValueSet {
+    fn saved(rc: Rc<RefCell<Self>>, contents: Self) -> Saved {
+        Saved::Set(rc, contents)
+    }
+}
+
+impl Undoable for Vec<f64> {
+    fn saved(rc: Rc<RefCell<Self>>, contents: Self) -> Saved {
+        Saved::Numbers(rc, contents)
+    }
+}
+
+impl Undoable for IndexMap<String, Data> {
+    fn saved(rc: Rc<RefCell<Self>>, contents: Self) -> Saved {
+        Saved::Entries(rc, contents)
+    }
+}
+
+impl Undoable for Instance {
+    fn saved(rc: Rc<RefCell<Self>>, contents: Self) -> Saved {
+        Saved::Instance(rc, contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use data::Data::*;
+
+    #[test]
+    fn test_nested_checkpoints() {
+        let l = Data::list(vec![Int(1)]);
+        let push = |n| {
+            l.will_change().unwrap();
+            if let List(ref items) = l {
+                items.borrow_mut().push(Int(n));
+            }
+        };
+
+        let outer = Checkpoint::new();
+        push(2);
+        let inner = Checkpoint::new();
+        push(3);
+        inner.restore();
+        assert_eq!(l, Data::list(vec![Int(1), Int(2)]));
+        push(4);
+        outer.restore();
+        assert_eq!(l, Data::list(vec![Int(1)]));
+
+        // Dropping a checkpoint keeps the changes and stops recording them.
+        drop(Checkpoint::new());
+        push(5);
+        assert!(LOGS.with(|logs| logs.borrow().is_empty()));
+        assert_eq!(l, Data::list(vec![Int(1), Int(5)]));
+    }
+}
@@ -0,0 +1,742 @@
+// The compiled module stores parsed programs in a compact binary format, so a
+// large script can be run without scanning and parsing its source again.
+//
+// A compiled file starts with a header: the bytes "GATEC", a format version,
+// the version of gate that compiled it, a hash of the source and a checksum of
+// the rest of the file.  Then come the number of top-level expressions and the
+// expressions themselves.  Each expression is a tag byte followed by its
+// fields.  Ints, lengths and floats are little-endian, and strings are a length
+// followed by UTF-8 bytes.
+
+use std::result;
+
+use binary_op::BinaryOp;
+use error::{LoadError, ParseError};
+use expr::Expression;
+use expr::Expression::*;
+use parser::Parser;
+use stack;
+use unary_op::UnaryOp;
+
+pub type Result<T> = result::Result<T, LoadError>;
+
+const MAGIC: &[u8] = b"GATEC";
+
+// VERSION is bumped whenever the encoding of any expression changes.
+pub const VERSION: u16 = 3;
+
+const BINARY_OPS: [BinaryOp; 22] = [BinaryOp::Add,
+                                    BinaryOp::Sub,
+                                    BinaryOp::Mul,
+                                    BinaryOp::Div,
+                                    BinaryOp::Mod,
+                                    BinaryOp::Eq,
+                                    BinaryOp::NotEq,
+                                    BinaryOp::Lt,
+                                    BinaryOp::LtEq,
+                                    BinaryOp::Gt,
+                                    BinaryOp::GtEq,
+                                    BinaryOp::In,
+                                    BinaryOp::Range,
+                                    BinaryOp::BitAnd,
+                                    BinaryOp::BitOr,
+                                    BinaryOp::BitXor,
+                                    BinaryOp::Shl,
+                                    BinaryOp::Shr,
+                                    BinaryOp::And,
+                                    BinaryOp::Or,
+                                    BinaryOp::ApproxEq,
+                                    BinaryOp::Coalesce];
+
+const UNARY_OPS: [UnaryOp; 2] = [UnaryOp::Neg, UnaryOp::BitNot];
+
+// A Header describes where a compiled program came from.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Header {
+    // The version of gate that compiled the program.
+    pub gate_version: String,
+    // The source_hash of the program's source, which a host caching compiled
+    // programs can compare against the source's current hash.
+    pub source_hash: u64,
+    checksum: u64,
+}
+
+// compile parses source and encodes it.
+pub fn compile(source: &str) -> result::Result<Vec<u8>, ParseError> {
+    let mut exprs = vec![];
+    for expr in Parser::new(source) {
+        exprs.push(expr?);
+    }
+    Ok(encode(&exprs, source_hash(source)))
+}
+
+// is_compiled returns whether bytes look like a compiled program rather than
+// source.
+pub fn is_compiled(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// source_hash returns the hash of source that's stored in its compiled form.
+pub fn source_hash(source: &str) -> u64 {
+    hash(source.as_bytes())
+}
+
+// encode encodes a program, recording source_hash as the hash of its source.
+pub fn encode(exprs: &[Expression], source_hash: u64) -> Vec<u8> {
+    let mut body = Encoder { out: vec![] };
+    body.exprs(exprs);
+
+    let mut e = Encoder { out: MAGIC.to_vec() };
+    e.out.extend_from_slice(&[VERSION as u8, (VERSION >> 8) as u8]);
+    e.string(env!("CARGO_PKG_VERSION"));
+    e.int(source_hash as i64);
+    e.int(hash(&body.out) as i64);
+    e.out.extend(body.out);
+    e.out
+}
+
+// read_header reads the header of a compiled program without checking the
+// rest of it.
+pub fn read_header(bytes: &[u8]) -> Result<Header> {
+    read_header_at(bytes).map(|(header, _)| header)
+}
+
+// read_header_at reads the header and returns it along with the position of
+// the program that follows it.
+fn read_header_at(bytes: &[u8]) -> Result<(Header, usize)> {
+    if !is_compiled(bytes) {
+        return Err(LoadError::NotCompiled);
+    }
+
+    let mut d = Decoder {
+        bytes: bytes,
+        pos: MAGIC.len(),
+    };
+    let version = d.byte()? as u16 | (d.byte()? as u16) << 8;
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    let header = Header {
+        gate_version: d.string()?,
+        source_hash: d.int()? as u64,
+        checksum: d.int()? as u64,
+    };
+    Ok((header, d.pos))
+}
+
+// decode decodes a compiled program after checking that it's intact and that
+// it was compiled by this version of gate, so a cached program is never run
+// by an interpreter it wasn't built for.
+pub fn decode(bytes: &[u8]) -> Result<Vec<Expression>> {
+    let (header, pos) = read_header_at(bytes)?;
+    if header.gate_version != env!("CARGO_PKG_VERSION") {
+        return Err(LoadError::WrongGateVersion(header.gate_version));
+    }
+    if hash(&bytes[pos..]) != header.checksum {
+        return Err(LoadError::Corrupt);
+    }
+    decode_body(bytes, pos)
+}
+
+// decode_trusted decodes a compiled program without checking its checksum or
+// which version of gate compiled it.  It's faster than decode, but should
+// only be used on programs the host compiled itself with this version of gate.
+pub fn decode_trusted(bytes: &[u8]) -> Result<Vec<Expression>> {
+    let (_, pos) = read_header_at(bytes)?;
+    decode_body(bytes, pos)
+}
+
+fn decode_body(bytes: &[u8], pos: usize) -> Result<Vec<Expression>> {
+    let _guard = stack::enter();
+    let mut d = Decoder {
+        bytes: bytes,
+        pos: pos,
+    };
+    let exprs = d.exprs()?;
+    if d.pos != bytes.len() {
+        return Err(LoadError::Corrupt);
+    }
+    Ok(exprs)
+}
+
+// hash is the 64-bit FNV-1a hash of bytes.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for &b in bytes {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+struct Encoder {
+    out: Vec<u8>,
+}
+
+impl Encoder {
+    fn byte(&mut self, b: u8) {
+        self.out.push(b);
+    }
+
+    fn len(&mut self, n: usize) {
+        self.int(n as i64);
+    }
+
+    fn int(&mut self, i: i64) {
+        for shift in 0..8 {
+            self.out.push((i >> (shift * 8)) as u8);
+        }
+    }
+
+    fn string(&mut self, s: &str) {
+        self.len(s.len());
+        self.out.extend_from_slice(s.as_bytes());
+    }
+
+    fn strings(&mut self, strings: &[String]) {
+        self.len(strings.len());
+        for s in strings {
+            self.string(s);
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[Expression]) {
+        self.len(exprs.len());
+        for e in exprs {
+            self.expr(e);
+        }
+    }
+
+    fn opt_expr(&mut self, e: &Option<Box<Expression>>) {
+        match e {
+            &Some(ref e) => {
+                self.byte(1);
+                self.expr(e);
+            }
+            &None => self.byte(0),
+        }
+    }
+
+    fn binary_op(&mut self, op: &BinaryOp) {
+        let i = BINARY_OPS.iter().position(|o| o == op).unwrap_or(0);
+        self.byte(i as u8);
+    }
+
+    fn expr(&mut self, e: &Expression) {
+        match e {
+            &NilLiteral => self.byte(0),
+            &BooleanLiteral(b) => {
+                self.byte(1);
+                self.byte(b as u8);
+            }
+            &NumberLiteral(n) => {
+                self.byte(2);
+                self.int(n.to_bits() as i64);
+            }
+            &IntLiteral(i) => {
+                self.byte(3);
+                self.int(i);
+            }
+            &StrLiteral(ref s) => {
+                self.byte(4);
+                self.string(s);
+            }
+            &CustomLiteral { prefix, ref text } => {
+                self.byte(5);
+                self.int(prefix as i64);
+                self.string(text);
+            }
+            &Variable(ref name) => {
+                self.byte(6);
+                self.string(name);
+            }
+            &ListLiteral(ref items) => {
+                self.byte(7);
+                self.exprs(items);
+            }
+            &MapLiteral(ref entries) => {
+                self.byte(8);
+                self.len(entries.len());
+                for &(ref k, ref v) in entries {
+                    self.expr(k);
+                    self.expr(v);
+                }
+            }
+            &ParenExpr(ref e) => {
+                self.byte(9);
+                self.expr(e);
+            }
+            &Block(ref body) => {
+                self.byte(10);
+                self.exprs(body);
+            }
+            &DoBlock(ref body) => {
+                self.byte(11);
+                self.exprs(body);
+            }
+            &Assignment { ref left, ref right } => {
+                self.byte(12);
+                self.string(left);
+                self.expr(right);
+            }
+            &Declaration { ref left, ref right, constant } => {
+                self.byte(27);
+                self.string(left);
+                self.expr(right);
+                self.byte(constant as u8);
+            }
+            &IndexAssignment { ref object, ref index, ref right } => {
+                self.byte(13);
+                self.expr(object);
+                self.expr(index);
+                self.expr(right);
+            }
+            &FunctionDef { ref name, ref params, ref body } => {
+                self.byte(14);
+                self.string(name);
+                self.strings(params);
+                self.expr(body);
+            }
+            &Lambda { ref params, ref body } => {
+                self.byte(15);
+                self.strings(params);
+                self.expr(body);
+            }
+            &FunctionCall { ref func, ref args } => {
+                self.byte(16);
+                self.expr(func);
+                self.exprs(args);
+            }
+            &FieldAccess { ref object, ref field } => {
+                self.byte(17);
+                self.expr(object);
+                self.string(field);
+            }
+            &Index { ref object, ref index } => {
+                self.byte(18);
+                self.expr(object);
+                self.expr(index);
+            }
+            &UnaryExpr { ref op, ref operand } => {
+                self.byte(19);
+                let i = UNARY_OPS.iter().position(|o| o == op).unwrap_or(0);
+                self.byte(i as u8);
+                self.expr(operand);
+            }
+            &BinaryExpr { ref left, ref op, ref right, .. } => {
+                self.byte(20);
+                self.expr(left);
+                self.binary_op(op);
+                self.expr(right);
+            }
+            &ChainedComparison { ref first, ref rest } => {
+                self.byte(21);
+                self.expr(first);
+                self.len(rest.len());
+                for &(ref op, ref e) in rest {
+                    self.binary_op(op);
+                    self.expr(e);
+                }
+            }
+            &IfExpr { ref cond, ref body, ref else_branch } => {
+                self.byte(22);
+                self.expr(cond);
+                self.expr(body);
+                self.opt_expr(else_branch);
+            }
+            &WhileLoop { ref cond, ref body, ref else_branch } => {
+                self.byte(23);
+                self.expr(cond);
+                self.expr(body);
+                self.opt_expr(else_branch);
+            }
+            &ForLoop { ref var, ref iter, ref body } => {
+                self.byte(24);
+                self.string(var);
+                self.expr(iter);
+                self.expr(body);
+            }
+            &Break => self.byte(25),
+            &Continue => self.byte(26),
+            &Throw(ref value) => {
+                self.byte(28);
+                self.expr(value);
+            }
+            &TryCatch { ref body, ref var, ref handler } => {
+                self.byte(29);
+                self.expr(body);
+                self.string(var);
+                self.expr(handler);
+            }
+            &StructDef { ref name, ref fields } => {
+                self.byte(30);
+                self.string(name);
+                self.strings(fields);
+            }
+            &FieldAssignment { ref object, ref field, ref right } => {
+                self.byte(31);
+                self.expr(object);
+                self.string(field);
+                self.expr(right);
+            }
+            &DoWhile { ref body, ref cond } => {
+                self.byte(32);
+                self.expr(body);
+                self.expr(cond);
+            }
+            &CompoundAssignment { ref target, ref op, ref value, .. } => {
+                self.byte(34);
+                self.expr(target);
+                self.binary_op(op);
+                self.expr(value);
+            }
+            &Switch { ref value, ref cases, ref default } => {
+                self.byte(33);
+                self.expr(value);
+                self.len(cases.len());
+                for &(ref candidates, ref body) in cases {
+                    self.exprs(candidates);
+                    self.expr(body);
+                }
+                self.opt_expr(default);
+            }
+        }
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn byte(&mut self) -> Result<u8> {
+        match self.bytes.get(self.pos) {
+            Some(&b) => {
+                self.pos += 1;
+                Ok(b)
+            }
+            None => Err(LoadError::Corrupt),
+        }
+    }
+
+    // len reads a length, which can't be more than the number of bytes left
+    // since everything counted takes at least one byte.
+    fn len(&mut self) -> Result<usize> {
+        let n = self.int()?;
+        if n < 0 || n as usize > self.bytes.len() - self.pos {
+            return Err(LoadError::Corrupt);
+        }
+        Ok(n as usize)
+    }
+
+    fn int(&mut self) -> Result<i64> {
+        let mut i = 0;
+        for shift in 0..8 {
+            i |= (self.byte()? as i64) << (shift * 8);
+        }
+        Ok(i)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let n = self.len()?;
+        let bytes = self.bytes[self.pos..self.pos + n].to_vec();
+        self.pos += n;
+        String::from_utf8(bytes).map_err(|_| LoadError::Corrupt)
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>> {
+        let n = self.len()?;
+        let mut strings = Vec::with_capacity(n);
+        for _ in 0..n {
+            strings.push(self.string()?);
+        }
+        Ok(strings)
+    }
+
+    fn exprs(&mut self) -> Result<Vec<Expression>> {
+        let n = self.len()?;
+        let mut exprs = Vec::with_capacity(n);
+        for _ in 0..n {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+
+    fn boxed(&mut self) -> Result<Box<Expression>> {
+        self.expr().map(Box::new)
+    }
+
+    fn opt_expr(&mut self) -> Result<Option<Box<Expression>>> {
+        match self.byte()? {
+            0 => Ok(None),
+            1 => self.boxed().map(Some),
+            _ => Err(LoadError::Corrupt),
+        }
+    }
+
+    fn binary_op(&mut self) -> Result<BinaryOp> {
+        BINARY_OPS.get(self.byte()? as usize).cloned().ok_or(LoadError::Corrupt)
+    }
+
+    fn expr(&mut self) -> Result<Expression> {
+        // The parser doesn't nest expressions this deeply.
+        if stack::exceeded() {
+            return Err(LoadError::Corrupt);
+        }
+        let e = match self.byte()? {
+            0 => NilLiteral,
+            1 => BooleanLiteral(self.byte()? != 0),
+            2 => NumberLiteral(f64::from_bits(self.int()? as u64)),
+            3 => IntLiteral(self.int()?),
+            4 => StrLiteral(self.string()?.into()),
+            5 => {
+                let prefix = ::std::char::from_u32(self.int()? as u32);
+                CustomLiteral {
+                    prefix: prefix.ok_or(LoadError::Corrupt)?,
+                    text: self.string()?,
+                }
+            }
+            6 => Variable(self.string()?),
+            7 => ListLiteral(self.exprs()?),
+            8 => {
+                let n = self.len()?;
+                let mut entries = Vec::with_capacity(n);
+                for _ in 0..n {
+                    entries.push((self.expr()?, self.expr()?));
+                }
+                MapLiteral(entries)
+            }
+            9 => ParenExpr(self.boxed()?),
+            10 => Block(self.exprs()?),
+            11 => DoBlock(self.exprs()?),
+            12 => {
+                Assignment {
+                    left: self.string()?,
+                    right: self.boxed()?,
+                }
+            }
+            13 => {
+                IndexAssignment {
+                    object: self.boxed()?,
+                    index: self.boxed()?,
+                    right: self.boxed()?,
+                }
+            }
+            14 => {
+                FunctionDef {
+                    name: self.string()?,
+                    params: self.strings()?,
+                    body: self.boxed()?,
+                }
+            }
+            15 => {
+                Lambda {
+                    params: self.strings()?,
+                    body: self.boxed()?,
+                }
+            }
+            16 => {
+                FunctionCall {
+                    func: self.boxed()?,
+                    args: self.exprs()?,
+                }
+            }
+            17 => {
+                FieldAccess {
+                    object: self.boxed()?,
+                    field: self.string()?,
+                }
+            }
+            18 => {
+                Index {
+                    object: self.boxed()?,
+                    index: self.boxed()?,
+                }
+            }
+            19 => {
+                let op = UNARY_OPS.get(self.byte()? as usize).cloned();
+                UnaryExpr {
+                    op: op.ok_or(LoadError::Corrupt)?,
+                    operand: self.boxed()?,
+                }
+            }
+            20 => {
+                BinaryExpr {
+                    left: self.boxed()?,
+                    op: self.binary_op()?,
+                    right: self.boxed()?,
+                    span: None,
+                }
+            }
+            21 => {
+                let first = self.boxed()?;
+                let n = self.len()?;
+                let mut rest = Vec::with_capacity(n);
+                for _ in 0..n {
+                    rest.push((self.binary_op()?, self.expr()?));
+                }
+                ChainedComparison {
+                    first: first,
+                    rest: rest,
+                }
+            }
+            22 => {
+                IfExpr {
+                    cond: self.boxed()?,
+                    body: self.boxed()?,
+                    else_branch: self.opt_expr()?,
+                }
+            }
+            23 => {
+                WhileLoop {
+                    cond: self.boxed()?,
+                    body: self.boxed()?,
+                    else_branch: self.opt_expr()?,
+                }
+            }
+            24 => {
+                ForLoop {
+                    var: self.string()?,
+                    iter: self.boxed()?,
+                    body: self.boxed()?,
+                }
+            }
+            25 => Break,
+            26 => Continue,
+            27 => {
+                Declaration {
+                    left: self.string()?,
+                    right: self.boxed()?,
+                    constant: self.byte()? != 0,
+                }
+            }
+            28 => Throw(self.boxed()?),
+            29 => {
+                TryCatch {
+                    body: self.boxed()?,
+                    var: self.string()?,
+                    handler: self.boxed()?,
+                }
+            }
+            30 => {
+                StructDef {
+                    name: self.string()?,
+                    fields: self.strings()?,
+                }
+            }
+            31 => {
+                FieldAssignment {
+                    object: self.boxed()?,
+                    field: self.string()?,
+                    right: self.boxed()?,
+                }
+            }
+            32 => {
+                DoWhile {
+                    body: self.boxed()?,
+                    cond: self.boxed()?,
+                }
+            }
+            33 => {
+                let value = self.boxed()?;
+                let n = self.len()?;
+                let mut cases = Vec::with_capacity(n);
+                for _ in 0..n {
+                    cases.push((self.exprs()?, self.expr()?));
+                }
+                Switch {
+                    value: value,
+                    cases: cases,
+                    default: self.opt_expr()?,
+                }
+            }
+            34 => {
+                let target = self.boxed()?;
+                match *target {
+                    Variable(_) | Index { .. } | FieldAccess { .. } => {}
+                    _ => return Err(LoadError::Corrupt),
+                }
+                CompoundAssignment {
+                    target: target,
+                    op: self.binary_op()?,
+                    value: self.boxed()?,
+                    span: None,
+                }
+            }
+            _ => return Err(LoadError::Corrupt),
+        };
+        Ok(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use error::LoadError;
+    use parser::Parser;
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let source = r#"
+            fn fib(n) { if n < 2 n else fib(n - 1) + fib(n - 2) }
+            m = { a: [1, 2.5, "s", nil, true], b: -x }
+            l = []  l[0] = 0 < a <= 1  let f = |x| ~x ^ 3 << 1
+            for i in 0..3 { while i > 0 { i -= 1  break } else { continue } }
+            do { math.sqrt(2)[0] }  const c = 1  try throw 1 catch e e
+            struct P { x }  P(1).x = 2  do { x -= 1 } while x > 0
+            switch x { case 1, 2: "low" case 3: {} default: nil }
+        "#;
+        // Spans aren't kept, so the decoded program is compared by encoding it
+        // again.
+        let exprs: Vec<_> = Parser::new(source).map(|e| e.unwrap()).collect();
+        let decoded = decode(&encode(&exprs, 0)).unwrap();
+        assert_eq!(encode(&decoded, 0), encode(&exprs, 0));
+
+        let bytes = compile(source).unwrap();
+        assert_eq!(decode(&bytes).unwrap().len(), 13);
+        let (header, pos) = read_header_at(&bytes).unwrap();
+        assert_eq!(header.gate_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(header.source_hash, source_hash(source));
+        assert_eq!(header.checksum, hash(&bytes[pos..]));
+        assert_eq!(read_header(&bytes), Ok(header));
+    }
+
+    #[test]
+    fn test_errors() {
+        let bytes = compile("x = [1, 2]").unwrap();
+        assert_eq!(decode(b"x = 1"), Err(LoadError::NotCompiled));
+        assert_eq!(decode_trusted(&bytes[..bytes.len() - 1]), Err(LoadError::Corrupt));
+
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert_eq!(decode_trusted(&extra), Err(LoadError::Corrupt));
+
+        let mut newer = bytes.clone();
+        newer[5] = 99;
+        assert_eq!(decode(&newer), Err(LoadError::UnsupportedVersion(99)));
+
+        // The gate version starts after the magic, format version and length.
+        let mut other_gate = bytes.clone();
+        other_gate[15] = b'9';
+        let version = String::from_utf8(other_gate[15..15 + other_gate[7] as usize].to_vec());
+        assert_eq!(decode(&other_gate), Err(LoadError::WrongGateVersion(version.unwrap())));
+        assert!(decode_trusted(&other_gate).is_ok());
+
+        // A change to the program is caught by the checksum.  Without checking
+        // it, damaged input gives an error or a different program, but never
+        // a panic.
+        let (_, body) = read_header_at(&bytes).unwrap();
+        for i in body..bytes.len() {
+            let mut damaged = bytes.clone();
+            damaged[i] ^= 0xff;
+            assert_eq!(decode(&damaged), Err(LoadError::Corrupt));
+        }
+        for i in 7..bytes.len() {
+            let mut damaged = bytes.clone();
+            damaged[i] ^= 0xff;
+            let _ = decode_trusted(&damaged);
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::time::{Duration, Instant};
+
+use audit::{AuditEvent, AuditLog};
+use bound::BoundExpr;
+use cancel::CancelToken;
+use checkpoint::Checkpoint;
+use cst::Span;
+use data::Data;
+use error::{ExecuteError, Warning};
+use expr::{self, Expression};
+use handles::Handles;
+use rng::{Rng, SplitMix64};
+use scope::{Env, FrozenPolicy, ScopeTree};
+#[cfg(feature = "signals")]
+use signals::Signals;
+use store::StoreKind;
+
+// An ErrorTrace records where a runtime error happened, for hosts that log
+// failures to diagnose later.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ErrorTrace {
+    // The names of the functions that were running, innermost first.
+    // Anonymous functions are named "<fn>".
+    pub stack: Vec<String>,
+    // The variables in scope where the error happened, innermost first.
+    pub vars: Vec<(String, Data)>,
+}
+
+// A Context holds the variables of a running program.  Contexts are cheap to
+// create, so a host can keep one per script while sharing a single Engine.
+pub struct Context {
+    scopes: ScopeTree,
+    warnings: Vec<Warning>,
+    // Output written by the program is collected here while capturing, and
+    // goes to writer otherwise.
+    output: Option<String>,
+    // Output goes here when it's set and the program isn't capturing, and to
+    // stdout otherwise.
+    writer: Option<Box<dyn Write>>,
+    // Input read by the program comes from here when it's set, and from stdin
+    // otherwise.
+    input: Option<Box<dyn BufRead>>,
+    // Set when auditing is enabled.
+    audit: Option<AuditLog>,
+    rng: Box<dyn Rng>,
+    // When the context was created, which clock counts from.
+    started: Instant,
+    // What sleep calls to wait.
+    sleep: Box<dyn FnMut(Duration)>,
+    cancel: CancelToken,
+    #[cfg(feature = "signals")]
+    signals: Signals,
+    // Whether read_file and write_file may be called.
+    fs_allowed: bool,
+    // Whether the net builtins may be called.
+    net_allowed: bool,
+    handles: Handles,
+    // Whether errors are traced, and the trace of the last one.
+    tracing: bool,
+    trace: Option<ErrorTrace>,
+    // The source the code being run was parsed from, if it's known, and the
+    // number of sources given out so far.
+    source: Option<u64>,
+    sources: u64,
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Context::with_store(StoreKind::Hash)
+    }
+
+    // with_store creates a context whose scopes keep their variables in the
+    // given kind of store.
+    pub fn with_store(kind: StoreKind) -> Self {
+        // Sleeping scripts wake up when they're cancelled.
+        let cancel = CancelToken::new();
+        let sleep_cancel = cancel.clone();
+        Context {
+            scopes: ScopeTree::new(kind),
+            warnings: vec![],
+            output: None,
+            writer: None,
+            input: None,
+            audit: None,
+            rng: Box::new(SplitMix64::from_time()),
+            started: Instant::now(),
+            sleep: Box::new(move |d| sleep_cancel.sleep(d)),
+            cancel: cancel,
+            #[cfg(feature = "signals")]
+            signals: Signals::default(),
+            fs_allowed: false,
+            net_allowed: false,
+            handles: Handles::new(),
+            tracing: false,
+            trace: None,
+            source: None,
+            sources: 0,
+        }
+    }
+
+    pub fn var(&self, name: &str) -> Option<Data> {
+        self.scopes.var(name)
+    }
+
+    // freeze_globals installs read-only globals beneath every scope, such as
+    // configuration or constants shared by all scripts, replacing any
+    // installed before.  policy decides whether scripts may hide them with
+    // variables of their own.
+    pub fn freeze_globals(&mut self, vars: HashMap<String, Data>, policy: FrozenPolicy) {
+        self.scopes.freeze(vars, policy)
+    }
+
+    pub fn globals(&self) -> Vec<(String, Data)> {
+        self.scopes.globals()
+    }
+
+    pub fn set_var(&mut self, name: &str, val: Data) {
+        self.scopes.set_var(name, val)
+    }
+
+    pub fn declare_var(&mut self, name: &str, val: Data) {
+        self.scopes.declare_var(name, val)
+    }
+
+    // assign_var assigns to a variable the way a script does, failing if the
+    // variable is a constant.  set_var lets the host change constants.
+    pub fn assign_var(&mut self, name: &str, val: Data) -> Result<(), ExecuteError> {
+        self.scopes.assign_var(name, val)
+    }
+
+    // define_var declares a variable the way a script does, failing if the
+    // current scope has a constant with the same name.
+    pub fn define_var(&mut self,
+                      name: &str,
+                      val: Data,
+                      constant: bool)
+                      -> Result<(), ExecuteError> {
+        self.scopes.define_var(name, val, constant)
+    }
+
+    pub fn warn(&mut self, w: Warning) {
+        self.warnings.push(w);
+    }
+
+    // take_warnings returns the warnings raised since the last call.
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        self.warnings.drain(..).collect()
+    }
+
+    // write sends s to the program's output.
+    pub fn write(&mut self, s: &str) -> Result<(), ExecuteError> {
+        if let Some(ref mut buf) = self.output {
+            buf.push_str(s);
+            return Ok(());
+        }
+        // Flush straight away so that output without a newline, like a
+        // prompt, shows up before the program waits for input.
+        let res = match self.writer {
+            Some(ref mut w) => w.write_all(s.as_bytes()).and_then(|_| w.flush()),
+            None => {
+                let stdout = io::stdout();
+                let mut lock = stdout.lock();
+                lock.write_all(s.as_bytes()).and_then(|_| lock.flush())
+            }
+        };
+        res.map_err(|e| ExecuteError::Io(e.to_string()))
+    }
+
+    // set_output makes the program write its output to w instead of stdout.
+    // Capturing output still takes precedence.
+    pub fn set_output<W: Write + 'static>(&mut self, w: W) {
+        self.writer = Some(Box::new(w));
+    }
+
+    // set_input makes the program read its input from r instead of stdin.
+    pub fn set_input<R: BufRead + 'static>(&mut self, r: R) {
+        self.input = Some(Box::new(r));
+    }
+
+    // read_line reads the next line of the program's input, without its line
+    // ending.  It returns None at the end of the input.
+    pub fn read_line(&mut self) -> Result<Option<String>, ExecuteError> {
+        let mut line = String::new();
+        let read = match self.input {
+            Some(ref mut r) => r.read_line(&mut line),
+            None => {
+                let stdin = io::stdin();
+                let mut lock = stdin.lock();
+                lock.read_line(&mut line)
+            }
+        };
+        match read {
+            Ok(0) => Ok(None),
+            Ok(_) => {
+                if line.ends_with('\n') {
+                    line.pop();
+                    if line.ends_with('\r') {
+                        line.pop();
+                    }
+                }
+                Ok(Some(line))
+            }
+            Err(e) => Err(ExecuteError::Io(e.to_string())),
+        }
+    }
+
+    // capture_output makes everything the program writes from now on collect
+    // in a buffer instead of going to stdout.
+    pub fn capture_output(&mut self) {
+        if self.output.is_none() {
+            self.output = Some(String::new());
+        }
+    }
+
+    // take_output returns the output captured since the last call.  It's empty
+    // unless capture_output was called.
+    pub fn take_output(&mut self) -> String {
+        match self.output {
+            Some(ref mut buf) => buf.split_off(0),
+            None => String::new(),
+        }
+    }
+
+    // end_capture stops collecting output, returning whatever was captured
+    // and not yet taken.  Output goes to stdout again afterwards.
+    pub fn end_capture(&mut self) -> String {
+        self.output.take().unwrap_or_default()
+    }
+
+    // enable_audit starts recording every builtin call, along with any file
+    // and environment access that builtins report.  The arguments of the
+    // builtins named in redacted are left out of the log.
+    pub fn enable_audit(&mut self, redacted: &[&str]) {
+        self.audit = Some(AuditLog::new(redacted));
+    }
+
+    // rng returns the generator random and random_int draw from.
+    pub fn rng(&mut self) -> &mut dyn Rng {
+        &mut *self.rng
+    }
+
+    // seed_rng restarts the random numbers from seed, so the same seed always
+    // gives the same numbers.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.set_rng(SplitMix64::new(seed));
+    }
+
+    // set_rng replaces the generator the context draws random numbers from.
+    pub fn set_rng<R: Rng + 'static>(&mut self, rng: R) {
+        self.rng = Box::new(rng);
+    }
+
+    // elapsed returns the time since the context was created.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    // sleep waits for d, or does whatever the host set with set_sleep.
+    pub fn sleep(&mut self, d: Duration) {
+        (self.sleep)(d)
+    }
+
+    // cancel_token returns a token that cancels the program's evaluations,
+    // which can be sent to another thread.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    // check_cancelled fails with Cancelled if the program has been cancelled.
+    pub fn check_cancelled(&self) -> Result<(), ExecuteError> {
+        if self.cancel.is_cancelled() {
+            return Err(ExecuteError::Cancelled);
+        }
+        Ok(())
+    }
+
+    // handle_signal makes func the handler for the named signal, which
+    // cancels the program when it arrives.
+    #[cfg(feature = "signals")]
+    pub fn handle_signal(&mut self, name: &str, func: Data) -> Result<(), ExecuteError> {
+        self.signals.handle(name, func, &self.cancel)
+    }
+
+    // take_signals returns the name and handler of each signal that has
+    // arrived since it was last called.
+    #[cfg(feature = "signals")]
+    pub fn take_signals(&mut self) -> Vec<(String, Data)> {
+        self.signals.take_pending()
+    }
+
+    // set_sleep replaces what sleep does.  Sandboxes can make it return
+    // straight away, or limit how long a script may wait.
+    pub fn set_sleep<F: FnMut(Duration) + 'static>(&mut self, f: F) {
+        self.sleep = Box::new(f);
+    }
+
+    // allow_fs sets whether the program may read and write files, which it
+    // can't by default.
+    pub fn allow_fs(&mut self, allow: bool) {
+        self.fs_allowed = allow;
+    }
+
+    pub fn fs_allowed(&self) -> bool {
+        self.fs_allowed
+    }
+
+    // allow_net sets whether the program may use sockets, which it can't by
+    // default.
+    pub fn allow_net(&mut self, allow: bool) {
+        self.net_allowed = allow;
+    }
+
+    pub fn net_allowed(&self) -> bool {
+        self.net_allowed
+    }
+
+    // trace_errors sets whether runtime errors are traced, which they aren't
+    // by default.
+    pub fn trace_errors(&mut self, enable: bool) {
+        self.tracing = enable;
+        self.trace = None;
+    }
+
+    // trace_error records that res failed here, if errors are traced and it
+    // failed with an error rather than a break, continue, exit or
+    // cancellation.  The variables in scope are recorded at the innermost
+    // place it's called, and each function the error leaves is added to the
+    // stack.
+    pub fn trace_error(&mut self, res: &expr::Result, function: Option<&str>) {
+        match res {
+            &Ok(_) |
+            &Err(ExecuteError::Break) |
+            &Err(ExecuteError::Continue) |
+            &Err(ExecuteError::Exit(_)) |
+            &Err(ExecuteError::Cancelled) => return,
+            &Err(_) if !self.tracing => return,
+            &Err(_) => {}
+        }
+
+        let scopes = &self.scopes;
+        let trace = self.trace.get_or_insert_with(|| {
+            ErrorTrace {
+                stack: vec![],
+                vars: scopes.visible(),
+            }
+        });
+        if let Some(name) = function {
+            trace.stack.push(if name.is_empty() { "<fn>" } else { name }.to_owned());
+        }
+    }
+
+    // take_error_trace returns the trace of the last runtime error, if errors
+    // are traced, and clears it.
+    pub fn take_error_trace(&mut self) -> Option<ErrorTrace> {
+        self.trace.take()
+    }
+
+    // new_source returns an id for a source that's about to be run, different
+    // from every other one the context has given out.
+    pub fn new_source(&mut self) -> u64 {
+        self.sources += 1;
+        self.sources
+    }
+
+    // source returns the id of the source the code being run was parsed
+    // from, or None if it isn't known.
+    pub fn source(&self) -> Option<u64> {
+        self.source
+    }
+
+    // set_source sets the source of the code about to be run and returns the
+    // previous one.
+    pub fn set_source(&mut self, source: Option<u64>) -> Option<u64> {
+        mem::replace(&mut self.source, source)
+    }
+
+    // span returns span if the source of the code being run is known, since
+    // it's only meaningful next to that source, and None otherwise.
+    pub fn span(&self, span: Option<Span>) -> Option<Span> {
+        match self.source {
+            Some(_) => span,
+            None => None,
+        }
+    }
+
+    // handles returns the host objects builtins have given the program.
+    pub fn handles(&mut self) -> &mut Handles {
+        &mut self.handles
+    }
+
+    pub fn is_audited(&self) -> bool {
+        self.audit.is_some()
+    }
+
+    // audit records event if auditing is enabled.  Builtins that access files
+    // or the environment should call it.
+    pub fn audit(&mut self, event: AuditEvent) {
+        if let Some(ref mut log) = self.audit {
+            log.events.push(event);
+        }
+    }
+
+    pub fn audit_call(&mut self, name: &str, args: &[Data]) {
+        if let Some(ref mut log) = self.audit {
+            log.builtin_call(name, args);
+        }
+    }
+
+    // audit_log returns the events recorded so far, which is empty unless
+    // enable_audit was called.
+    pub fn audit_log(&self) -> &[AuditEvent] {
+        match self.audit {
+            Some(ref log) => &log.events,
+            None => &[],
+        }
+    }
+
+    // enter_call hides every scope except the global one, then brings back
+    // env, the scopes a function was defined in, and pushes a new scope for
+    // the call.  The hidden scopes are returned so they can be passed to
+    // exit_call when the function returns.
+    pub fn enter_call(&mut self, env: &[Env]) -> Vec<Env> {
+        let hidden = self.scopes.frames.split_off(1);
+        self.scopes.frames.extend(env.iter().cloned());
+        self.new_scope();
+        hidden
+    }
+
+    // exit_call drops the function's scopes and restores the hidden ones.
+    pub fn exit_call(&mut self, hidden: Vec<Env>) {
+        self.scopes.frames.truncate(1);
+        self.scopes.frames.extend(hidden);
+    }
+
+    // bind pairs e with every scope in use, so it can be evaluated later
+    // against the variables it sees now.
+    pub fn bind(&self, e: Expression) -> BoundExpr {
+        BoundExpr::new(e, self.scopes.frames.clone())
+    }
+
+    // enter_env replaces every scope, including the global one, with env, the
+    // scopes of a bound expression.  The replaced scopes are returned so they
+    // can be passed to exit_env afterwards.
+    pub fn enter_env(&mut self, env: &[Env]) -> Vec<Env> {
+        mem::replace(&mut self.scopes.frames, env.to_vec())
+    }
+
+    pub fn exit_env(&mut self, frames: Vec<Env>) {
+        self.scopes.frames = frames;
+    }
+
+    // env returns the scopes in use other than the global one, for a function
+    // defined here to keep.
+    pub fn env(&self) -> Vec<Env> {
+        self.scopes.locals()
+    }
+
+    pub fn new_scope(&mut self) {
+        let env = self.scopes.new_env();
+        self.scopes.frames.push(env);
+    }
+
+    // pop_scope drops the innermost scope.  The global scope is never dropped.
+    pub fn pop_scope(&mut self) {
+        if self.scopes.frames.len() > 1 {
+            self.scopes.frames.pop();
+        }
+    }
+
+    // checkpoint starts recording changes to variables, and to whatever they
+    // hold, so they can be undone if an evaluation fails.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint::new()
+    }
+
+    // reset_scopes drops every scope except the global one, as if no block or
+    // function were running.  It's used to recover after a panic.
+    pub fn reset_scopes(&mut self) {
+        self.scopes.frames.truncate(1);
+    }
+}
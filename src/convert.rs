@@ -0,0 +1,691 @@
+use std::convert::TryFrom;
+
+use indexmap::IndexMap;
+use serde::de::{self, Deserializer, IntoDeserializer, Unexpected, Visitor};
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::ser::{self, Serialize};
+
+use data::{Data, Visit};
+use data::Data::*;
+use error::ConvertError;
+
+// Data can be deserialized into any Rust type implementing Deserialize, which
+// lets hosts read scripts' values, such as configuration maps, straight into
+// their own structs.
+impl<'de> Deserializer<'de> for Data {
+    type Error = ConvertError;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, ConvertError>
+        where V: Visitor<'de>
+    {
+        match self {
+            Nil => visitor.visit_unit(),
+            Boolean(b) => visitor.visit_bool(b),
+            // Whole numbers are passed on as integers so they can fill integer
+            // fields.
+            Number(n) if n.fract() == 0.0 && n >= 0.0 && n < 18446744073709551616.0 => {
+                visitor.visit_u64(n as u64)
+            }
+            Number(n) if n.fract() == 0.0 && n >= -9223372036854775808.0 && n < 0.0 => {
+                visitor.visit_i64(n as i64)
+            }
+            Number(n) => visitor.visit_f64(n),
+            Int(i) => visitor.visit_i64(i),
+            Str(s) => visitor.visit_str(&s),
+            List(l) => {
+                let items = l.borrow().clone();
+                SeqDeserializer::new(items.into_iter()).deserialize_any(visitor)
+            }
+            Set(s) => {
+                let items = s.borrow().to_vec();
+                SeqDeserializer::new(items.into_iter()).deserialize_any(visitor)
+            }
+            d @ Range(..) | d @ NumArray(_) => {
+                SeqDeserializer::new(d.iter().unwrap()).deserialize_any(visitor)
+            }
+            Map(m) => {
+                let entries = m.borrow().clone();
+                MapDeserializer::new(entries.into_iter()).deserialize_any(visitor)
+            }
+            Struct(s) => {
+                let entries = s.borrow().fields.clone();
+                MapDeserializer::new(entries.into_iter()).deserialize_any(visitor)
+            }
+            Function(_) | Builtin(_) => {
+                Err(de::Error::invalid_type(Unexpected::Other("function"), &visitor))
+            }
+            Type(_) => Err(de::Error::invalid_type(Unexpected::Other("type"), &visitor)),
+            Seq(_) => Err(de::Error::invalid_type(Unexpected::Other("sequence"), &visitor)),
+        }
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, ConvertError>
+        where V: Visitor<'de>
+    {
+        match self {
+            Nil => visitor.visit_none(),
+            d => visitor.visit_some(d),
+        }
+    }
+
+    // Unit variants can be written as strings.
+    fn deserialize_enum<V>(self,
+                           _: &'static str,
+                           _: &'static [&'static str],
+                           visitor: V)
+                           -> Result<V::Value, ConvertError>
+        where V: Visitor<'de>
+    {
+        match self {
+            Str(s) => visitor.visit_enum(s.into_deserializer()),
+            d => Err(de::Error::invalid_type(Unexpected::Other(&d.type_name()), &visitor)),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, ConvertError> for Data {
+    type Deserializer = Data;
+
+    fn into_deserializer(self) -> Data {
+        self
+    }
+}
+
+// Data can also be serialized, so hosts can write scripts' values out in any
+// format serde supports, such as JSON.  Map entries are written in the order
+// they were inserted, and sets, arrays and ranges are written as sequences.  Struct
+// instances are written as maps of their fields.
+impl Serialize for Data {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let _visit = match Visit::container(self) {
+            Some(None) => {
+                return Err(ser::Error::custom("can't serialize a value that holds itself"))
+            }
+            visit => visit,
+        };
+        match self {
+            &Nil => serializer.serialize_unit(),
+            &Boolean(b) => serializer.serialize_bool(b),
+            &Number(n) => serializer.serialize_f64(n),
+            &Int(i) => serializer.serialize_i64(i),
+            &Str(ref s) => serializer.serialize_str(s),
+            &List(ref l) => serializer.collect_seq(l.borrow().iter()),
+            &Set(ref s) => serializer.collect_seq(s.borrow().iter()),
+            &NumArray(ref a) => serializer.collect_seq(a.borrow().iter()),
+            &Range(..) => serializer.collect_seq(self.iter().unwrap()),
+            &Map(ref m) => serializer.collect_map(m.borrow().iter()),
+            &Struct(ref s) => serializer.collect_map(s.borrow().fields.iter()),
+            &Function(_) | &Builtin(_) => Err(ser::Error::custom("can't serialize a function")),
+            &Type(_) => Err(ser::Error::custom("can't serialize a type")),
+            &Seq(_) => Err(ser::Error::custom("can't serialize a sequence")),
+        }
+    }
+}
+
+// to_data converts any Rust value implementing Serialize into gate data.
+// Structs and maps become maps, sequences become lists, integers become ints
+// and other numbers become f64s.  Enum variants carrying data become single-entry maps keyed by the
+// variant's name.
+pub fn to_data<T: ?Sized + Serialize>(value: &T) -> Result<Data, ConvertError> {
+    value.serialize(DataSerializer)
+}
+
+struct DataSerializer;
+
+impl ser::Serializer for DataSerializer {
+    type Ok = Data;
+    type Error = ConvertError;
+
+    type SerializeSeq = SeqSerializer;
+    type SerializeTuple = SeqSerializer;
+    type SerializeTupleStruct = SeqSerializer;
+    type SerializeTupleVariant = VariantSerializer<SeqSerializer>;
+    type SerializeMap = MapSerializer;
+    type SerializeStruct = MapSerializer;
+    type SerializeStructVariant = VariantSerializer<MapSerializer>;
+
+    fn serialize_bool(self, v: bool) -> Result<Data, ConvertError> {
+        Ok(Boolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Data, ConvertError> {
+        Ok(Int(v as i64))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Data, ConvertError> {
+        Ok(Int(v as i64))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Data, ConvertError> {
+        Ok(Int(v as i64))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Data, ConvertError> {
+        Ok(Int(v as i64))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Data, ConvertError> {
+        Ok(Int(v as i64))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Data, ConvertError> {
+        Ok(Int(v as i64))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Data, ConvertError> {
+        Ok(Int(v as i64))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Data, ConvertError> {
+        if v > i64::MAX as u64 {
+            return Ok(Number(v as f64));
+        }
+        Ok(Int(v as i64))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Data, ConvertError> {
+        Ok(Number(v as f64))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Data, ConvertError> {
+        Ok(Number(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Data, ConvertError> {
+        Ok(Str(v.to_string().into()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Data, ConvertError> {
+        Ok(Str(v.into()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Data, ConvertError> {
+        Ok(Data::list(v.iter().map(|&b| Int(b as i64)).collect()))
+    }
+
+    fn serialize_none(self) -> Result<Data, ConvertError> {
+        Ok(Nil)
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Data, ConvertError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Data, ConvertError> {
+        Ok(Nil)
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Data, ConvertError> {
+        Ok(Nil)
+    }
+
+    fn serialize_unit_variant(self,
+                              _: &'static str,
+                              _: u32,
+                              variant: &'static str)
+                              -> Result<Data, ConvertError> {
+        Ok(Str(variant.into()))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self,
+                                                       _: &'static str,
+                                                       value: &T)
+                                                       -> Result<Data, ConvertError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self,
+                                                        _: &'static str,
+                                                        _: u32,
+                                                        variant: &'static str,
+                                                        value: &T)
+                                                        -> Result<Data, ConvertError> {
+        let mut m = IndexMap::new();
+        m.insert(variant.to_owned(), to_data(value)?);
+        Ok(Data::map(m))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SeqSerializer, ConvertError> {
+        Ok(SeqSerializer { items: Vec::with_capacity(len.unwrap_or(0)) })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SeqSerializer, ConvertError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self,
+                              _: &'static str,
+                              len: usize)
+                              -> Result<SeqSerializer, ConvertError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self,
+                               _: &'static str,
+                               _: u32,
+                               variant: &'static str,
+                               len: usize)
+                               -> Result<VariantSerializer<SeqSerializer>, ConvertError> {
+        Ok(VariantSerializer {
+            variant: variant,
+            inner: self.serialize_seq(Some(len))?,
+        })
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<MapSerializer, ConvertError> {
+        Ok(MapSerializer {
+            entries: IndexMap::new(),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<MapSerializer, ConvertError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(self,
+                                _: &'static str,
+                                _: u32,
+                                variant: &'static str,
+                                len: usize)
+                                -> Result<VariantSerializer<MapSerializer>, ConvertError> {
+        Ok(VariantSerializer {
+            variant: variant,
+            inner: self.serialize_map(Some(len))?,
+        })
+    }
+}
+
+struct SeqSerializer {
+    items: Vec<Data>,
+}
+
+impl ser::SerializeSeq for SeqSerializer {
+    type Ok = Data;
+    type Error = ConvertError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), ConvertError> {
+        self.items.push(to_data(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Data, ConvertError> {
+        Ok(Data::list(self.items))
+    }
+}
+
+impl ser::SerializeTuple for SeqSerializer {
+    type Ok = Data;
+    type Error = ConvertError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), ConvertError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Data, ConvertError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqSerializer {
+    type Ok = Data;
+    type Error = ConvertError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), ConvertError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Data, ConvertError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+struct MapSerializer {
+    entries: IndexMap<String, Data>,
+    // The key waiting for its value.
+    key: Option<String>,
+}
+
+impl ser::SerializeMap for MapSerializer {
+    type Ok = Data;
+    type Error = ConvertError;
+
+    // Map keys are always strings in gate, so numbers and booleans used as
+    // keys are converted to their text.
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), ConvertError> {
+        self.key = Some(match to_data(key)? {
+            Str(s) => s.to_string(),
+            d @ Number(_) | d @ Int(_) | d @ Boolean(_) => d.to_string(),
+            d => {
+                return Err(ConvertError::Invalid(format!("map keys must be strings, found {}",
+                                                         d.type_name())))
+            }
+        });
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), ConvertError> {
+        let key = self.key.take().unwrap_or_default();
+        self.entries.insert(key, to_data(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Data, ConvertError> {
+        Ok(Data::map(self.entries))
+    }
+}
+
+impl ser::SerializeStruct for MapSerializer {
+    type Ok = Data;
+    type Error = ConvertError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self,
+                                              key: &'static str,
+                                              value: &T)
+                                              -> Result<(), ConvertError> {
+        self.entries.insert(key.to_owned(), to_data(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Data, ConvertError> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+// VariantSerializer wraps the value of an enum variant in a map keyed by the
+// variant's name.
+struct VariantSerializer<S> {
+    variant: &'static str,
+    inner: S,
+}
+
+impl ser::SerializeTupleVariant for VariantSerializer<SeqSerializer> {
+    type Ok = Data;
+    type Error = ConvertError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), ConvertError> {
+        ser::SerializeSeq::serialize_element(&mut self.inner, value)
+    }
+
+    fn end(self) -> Result<Data, ConvertError> {
+        let mut m = IndexMap::new();
+        m.insert(self.variant.to_owned(), ser::SerializeSeq::end(self.inner)?);
+        Ok(Data::map(m))
+    }
+}
+
+impl ser::SerializeStructVariant for VariantSerializer<MapSerializer> {
+    type Ok = Data;
+    type Error = ConvertError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self,
+                                              key: &'static str,
+                                              value: &T)
+                                              -> Result<(), ConvertError> {
+        ser::SerializeStruct::serialize_field(&mut self.inner, key, value)
+    }
+
+    fn end(self) -> Result<Data, ConvertError> {
+        let mut m = IndexMap::new();
+        m.insert(self.variant.to_owned(), ser::SerializeMap::end(self.inner)?);
+        Ok(Data::map(m))
+    }
+}
+
+// The From and TryFrom conversions cover the simple values hosts pass most
+// often, without going through serde.  nil converts to and from None, and
+// other values to and from Some.
+
+impl From<bool> for Data {
+    fn from(b: bool) -> Data {
+        Boolean(b)
+    }
+}
+
+impl From<i64> for Data {
+    fn from(i: i64) -> Data {
+        Int(i)
+    }
+}
+
+impl From<f64> for Data {
+    fn from(n: f64) -> Data {
+        Number(n)
+    }
+}
+
+impl<'a> From<&'a str> for Data {
+    fn from(s: &'a str) -> Data {
+        Str(s.into())
+    }
+}
+
+impl From<String> for Data {
+    fn from(s: String) -> Data {
+        Str(s.into())
+    }
+}
+
+impl<T: Into<Data>> From<Option<T>> for Data {
+    fn from(o: Option<T>) -> Data {
+        o.map_or(Nil, Into::into)
+    }
+}
+
+impl TryFrom<Data> for bool {
+    type Error = ConvertError;
+
+    fn try_from(d: Data) -> Result<bool, ConvertError> {
+        match d {
+            Boolean(b) => Ok(b),
+            d => Err(wrong_type("a boolean", &d)),
+        }
+    }
+}
+
+// Whole numbers convert to i64 too, as they do when deserializing.
+impl TryFrom<Data> for i64 {
+    type Error = ConvertError;
+
+    fn try_from(d: Data) -> Result<i64, ConvertError> {
+        match d {
+            Int(i) => Ok(i),
+            Number(n) if n.fract() == 0.0 && n >= -9223372036854775808.0 &&
+                         n < 9223372036854775808.0 => Ok(n as i64),
+            d => Err(wrong_type("an int", &d)),
+        }
+    }
+}
+
+impl TryFrom<Data> for f64 {
+    type Error = ConvertError;
+
+    fn try_from(d: Data) -> Result<f64, ConvertError> {
+        match d {
+            Number(n) => Ok(n),
+            Int(i) => Ok(i as f64),
+            d => Err(wrong_type("a number", &d)),
+        }
+    }
+}
+
+impl TryFrom<Data> for String {
+    type Error = ConvertError;
+
+    fn try_from(d: Data) -> Result<String, ConvertError> {
+        match d {
+            Str(s) => Ok(s.to_string()),
+            d => Err(wrong_type("a string", &d)),
+        }
+    }
+}
+
+impl<T: TryFrom<Data, Error = ConvertError>> TryFrom<Data> for Option<T> {
+    type Error = ConvertError;
+
+    fn try_from(d: Data) -> Result<Option<T>, ConvertError> {
+        match d {
+            Nil => Ok(None),
+            d => T::try_from(d).map(Some),
+        }
+    }
+}
+
+fn wrong_type(expected: &str, found: &Data) -> ConvertError {
+    ConvertError::Invalid(format!("expected {}, found {}", expected, found.type_name()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::convert::TryFrom;
+
+    use serde::Deserialize;
+
+    use data::Data;
+    use data::Data::*;
+    use error::ConvertError;
+    use program::Program;
+    use super::to_data;
+
+    #[derive(Debug,Deserialize,PartialEq)]
+    struct Config {
+        name: String,
+        port: u16,
+        ratio: f64,
+        tags: Vec<String>,
+        limits: HashMap<String, i32>,
+        debug: Option<bool>,
+        mode: Mode,
+    }
+
+    #[derive(Debug,Deserialize,PartialEq)]
+    enum Mode {
+        Fast,
+        Safe,
+    }
+
+    #[test]
+    fn test_var_as() {
+        let mut p = Program::new();
+        let limits = vec![("low".to_owned(), Number(-3.0))];
+        p.set_var("config",
+                  Data::map(vec![
+            ("name".to_owned(), Str("gate".into())),
+            ("port".to_owned(), Number(8080.0)),
+            ("ratio".to_owned(), Number(1.0)),
+            ("tags".to_owned(), Data::list(vec![Str("a".into())])),
+            ("limits".to_owned(), Data::map(limits.into_iter().collect())),
+            ("debug".to_owned(), Nil),
+            ("mode".to_owned(), Str("Safe".into())),
+        ].into_iter().collect()));
+
+        let mut limits = HashMap::new();
+        limits.insert("low".to_owned(), -3);
+        assert_eq!(p.var_as::<Config>("config").unwrap(),
+                   Config {
+                       name: "gate".to_owned(),
+                       port: 8080,
+                       ratio: 1.0,
+                       tags: vec!["a".to_owned()],
+                       limits: limits,
+                       debug: None,
+                       mode: Mode::Safe,
+                   });
+        assert!(Mode::deserialize(Str("Fast".into())).is_ok());
+
+        // Failed conversions explain what went wrong.
+        p.set_var("x", Number(1.5));
+        assert_eq!(p.var_as::<u16>("x").unwrap_err().to_string(),
+                   "invalid type: floating point `1.5`, expected u16");
+        assert_eq!(p.var_as::<u16>("y").unwrap_err().to_string(),
+                   "undefined variable \"y\"");
+    }
+
+    #[derive(Serialize)]
+    struct Request {
+        path: &'static str,
+        retries: u8,
+        headers: Vec<(String, String)>,
+        body: Option<String>,
+        method: Method,
+    }
+
+    #[derive(Serialize)]
+    enum Method {
+        Get,
+        Post { length: usize },
+    }
+
+    #[test]
+    fn test_set_var_from() {
+        let mut p = Program::new();
+        let req = Request {
+            path: "/",
+            retries: 3,
+            headers: vec![("Accept".to_owned(), "*/*".to_owned())],
+            body: None,
+            method: Method::Get,
+        };
+        p.set_var_from("req", &req)
+            .unwrap();
+        assert_eq!(p.var("req").unwrap().to_string(),
+                   "{path: /, retries: 3, headers: [[Accept, */*]], body: nil, method: Get}");
+
+        p.set_var_from("method", &Method::Post { length: 5 }).unwrap();
+        assert_eq!(p.var("method").unwrap().to_string(), "{Post: {length: 5}}");
+
+        let mut ids = BTreeMap::new();
+        ids.insert(1, "one");
+        assert_eq!(to_data(&ids).unwrap().to_string(), "{1: one}");
+
+        // Converting back gives the original value.
+        p.set_var_from("tags", &vec!["a", "b"]).unwrap();
+        assert_eq!(p.var_as::<Vec<String>>("tags").unwrap(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_serialize() {
+        let entries = vec![
+            ("z".to_owned(), Int(1)),
+            ("a".to_owned(), Data::set(vec![Number(1.5), Nil])),
+            ("m".to_owned(), Data::Range(0.0, 2.0)),
+        ];
+        let m = Data::map(entries.into_iter().collect());
+
+        // Maps keep their order, and sets and ranges become lists.
+        let data = to_data(&m).unwrap();
+        assert_eq!(data.to_string(), "{z: 1, a: [1.5, nil], m: [0, 1]}");
+        assert!(to_data(&Data::Builtin("list".to_owned())).is_err());
+    }
+
+    #[test]
+    fn test_from_try_from() {
+        let mut p = Program::new();
+        p.set_var("name", "gate".into());
+        p.set_var("port", Data::from(8080i64));
+        p.set_var("ratio", 0.5.into());
+        p.set_var("debug", Some(true).into());
+        p.set_var("mode", Data::from(None::<String>));
+        assert_eq!(p.run_source("list(name, port, ratio, debug, mode)").unwrap().to_string(),
+                   "[gate, 8080, 0.5, true, nil]");
+
+        assert_eq!(String::try_from(p.var("name").unwrap()), Ok("gate".to_owned()));
+        assert_eq!(i64::try_from(p.var("port").unwrap()), Ok(8080));
+        assert_eq!(i64::try_from(Number(3.0)), Ok(3));
+        assert_eq!(f64::try_from(p.var("port").unwrap()), Ok(8080.0));
+        assert_eq!(Option::<bool>::try_from(p.var("debug").unwrap()), Ok(Some(true)));
+        assert_eq!(Option::<String>::try_from(p.var("mode").unwrap()), Ok(None));
+
+        assert_eq!(bool::try_from(Int(1)),
+                   Err(ConvertError::Invalid("expected a boolean, found int".to_owned())));
+        assert!(i64::try_from(Number(0.5)).is_err());
+        assert!(Option::<String>::try_from(Int(1)).is_err());
+    }
+}
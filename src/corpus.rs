@@ -0,0 +1,155 @@
+// The corpus module checks the parser against a corpus of programs, each with
+// a snapshot of what it should parse to, so a change to the grammar shows up
+// as a change to the snapshots it affects.
+//
+// A corpus is a directory of `.gate` files.  Each file's snapshot is kept
+// beside it with the extension `.snap`, and holds the expressions the file
+// parses to, or the error it fails with.  Files in a directory named `invalid`
+// must fail to parse, and every other file must parse.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use parser::Parser;
+
+// A Mismatch is a file in the corpus that doesn't match its snapshot or
+// doesn't parse the way its directory says it should.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Mismatch {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {}", self.path.display(), self.message)
+    }
+}
+
+// snapshot returns what source parses to: each expression in turn, followed
+// by the parse error if there is one.
+pub fn snapshot(source: &str) -> String {
+    let mut out = String::new();
+    for res in Parser::new(source) {
+        match res {
+            Ok(e) => out.push_str(&format!("{:#?}\n", e)),
+            Err(e) => {
+                out.push_str(&format!("error: {:?}\n", e));
+                break;
+            }
+        }
+    }
+    out
+}
+
+// cases returns the programs in the corpus at dir, in order.
+pub fn cases(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            paths.extend(cases(&path)?);
+        } else if path.extension().map_or(false, |ext| ext == "gate") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+// verify checks every program in the corpus at dir, and returns the ones that
+// don't match their snapshots or don't parse the way they should.
+pub fn verify(dir: &Path) -> io::Result<Vec<Mismatch>> {
+    let mut mismatches = vec![];
+    for path in cases(dir)? {
+        let actual = snapshot(&fs::read_to_string(&path)?);
+        let mismatch = |message: String| {
+            Mismatch {
+                path: path.clone(),
+                message: message,
+            }
+        };
+
+        let failed = actual.lines().last().map_or(false, |l| l.starts_with("error: "));
+        if failed != is_invalid(&path) {
+            let message = if failed {
+                "fails to parse but isn't in an invalid directory"
+            } else {
+                "parses but is in an invalid directory"
+            };
+            mismatches.push(mismatch(message.to_owned()));
+            continue;
+        }
+
+        match fs::read_to_string(path.with_extension("snap")) {
+            Ok(ref expected) if *expected == actual => {}
+            Ok(expected) => mismatches.push(mismatch(describe_diff(&expected, &actual))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                mismatches.push(mismatch("has no snapshot".to_owned()))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(mismatches)
+}
+
+// update writes the snapshot of every program in the corpus at dir whose
+// snapshot is missing or out of date, which records new programs and accepts
+// intended changes.  It returns the programs whose snapshots it wrote.
+pub fn update(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut written = vec![];
+    for path in cases(dir)? {
+        let actual = snapshot(&fs::read_to_string(&path)?);
+        let snap = path.with_extension("snap");
+        if fs::read_to_string(&snap).ok().as_ref() != Some(&actual) {
+            fs::write(&snap, actual)?;
+            written.push(path);
+        }
+    }
+    Ok(written)
+}
+
+fn is_invalid(path: &Path) -> bool {
+    path.parent().and_then(Path::file_name).map_or(false, |name| name == "invalid")
+}
+
+// describe_diff describes the first line where two snapshots differ.
+fn describe_diff(expected: &str, actual: &str) -> String {
+    let (mut expected, mut actual) = (expected.lines(), actual.lines());
+    let mut line = 1;
+    loop {
+        match (expected.next(), actual.next()) {
+            (Some(e), Some(a)) if e == a => line += 1,
+            (e, a) => {
+                return format!("snapshot differs at line {}\n- {}\n+ {}",
+                               line,
+                               e.unwrap_or("<end>"),
+                               a.unwrap_or("<end>"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        assert_eq!(snapshot("x = 1"),
+                   "Assignment {\n    left: \"x\",\n    right: IntLiteral(\n        1,\n    ),\n}\n");
+        assert_eq!(snapshot("1  )  2"),
+                   "IntLiteral(\n    1,\n)\nerror: Unexpected(CloseParen)\n");
+    }
+
+    #[test]
+    fn test_describe_diff() {
+        assert_eq!(describe_diff("a\nb\nc\n", "a\nx\nc\n"),
+                   "snapshot differs at line 2\n- b\n+ x");
+        assert_eq!(describe_diff("a\n", "a\nb\n"), "snapshot differs at line 2\n- <end>\n+ b");
+        assert!(is_invalid(Path::new("corpus/invalid/eof.gate")));
+        assert!(!is_invalid(Path::new("invalid/valid/eof.gate")));
+    }
+}
@@ -0,0 +1,747 @@
+// The cst module parses source into a concrete syntax tree, which keeps every
+// token, space and comment of the source along with its position.  Unlike the
+// AST, it can be turned back into the exact source it came from, which makes
+// it the basis for tools that edit code, such as refactorings.
+//
+// Binary operators are grouped by precedence the same way as in the AST, so
+// the operands of a Binary node are the ones it's evaluated with.  A chain of
+// comparisons, like `a < b < c`, is grouped from the left.
+
+use std::fmt;
+use std::result;
+
+use binary_op::BinaryOp;
+use error::ParseError;
+use scanner::{Lexeme, Scanner, Token, Trivia};
+use stack;
+
+pub type Result<T> = result::Result<T, ParseError>;
+
+// A Span is a range of byte offsets into the source.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn contains(&self, offset: usize) -> bool {
+        self.start <= offset && offset < self.end
+    }
+
+    // position returns the line and column the span starts at in source, both
+    // counting from 1.
+    pub fn position(&self, source: &str) -> (usize, usize) {
+        let before = &source[..self.start];
+        let line = before.matches('\n').count() + 1;
+        let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+        (line, column)
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Kind {
+    // The whole source.  Its last child is a leaf without a token holding the
+    // trivia at the end of the input.
+    Program,
+    Literal,
+    Variable,
+    Paren,
+    Block,
+    DoBlock,
+    List,
+    Map,
+    Unary,
+    Binary,
+    Assignment,
+    // A declaration like `let x = 1` or `const x = 1`.  The name is a leaf,
+    // not a variable node.
+    Let,
+    Call,
+    Field,
+    Index,
+    If,
+    // A conditional expression like `a ? b : c`.
+    Conditional,
+    While,
+    // A loop like `do { ... } while cond`.  Its nodes are the block and the
+    // condition.
+    DoWhile,
+    For,
+    // A switch.  Its nodes are the value and a Case node for each case.
+    Switch,
+    // A case of a switch, or its default.  The last node is the body.
+    Case,
+    Fn,
+    // An anonymous function.  Its nodes are the parameters and the body.
+    Lambda,
+    Params,
+    Break,
+    Continue,
+    Throw,
+    // A struct definition.  Its only node holds the fields.
+    Struct,
+    // A try expression.  Its nodes are the body and the handler, with the
+    // name of the error variable as a leaf between them.
+    Try,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Node {
+    pub kind: Kind,
+    // The span of the node's text, not including the trivia before it.
+    pub span: Span,
+    pub children: Vec<Element>,
+}
+
+// A Leaf is a lexeme in the tree.  Its span covers the lexeme's text, not the
+// trivia before it.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Leaf {
+    pub lexeme: Lexeme,
+    pub span: Span,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Element {
+    Node(Node),
+    Leaf(Leaf),
+}
+
+impl Node {
+    fn new(kind: Kind, children: Vec<Element>) -> Self {
+        let start = children.first().map_or(0, |c| c.span().start);
+        let end = children.last().map_or(start, |c| c.span().end);
+        Node {
+            kind: kind,
+            span: Span {
+                start: start,
+                end: end,
+            },
+            children: children,
+        }
+    }
+
+    // child_nodes returns the children that are nodes.
+    pub fn child_nodes(&self) -> Vec<&Node> {
+        self.children
+            .iter()
+            .filter_map(|c| match c {
+                &Element::Node(ref n) => Some(n),
+                &Element::Leaf(_) => None,
+            })
+            .collect()
+    }
+
+    // leaves returns every leaf under the node, in source order.
+    pub fn leaves(&self) -> Vec<&Leaf> {
+        let mut leaves = vec![];
+        for c in &self.children {
+            match c {
+                &Element::Node(ref n) => leaves.extend(n.leaves()),
+                &Element::Leaf(ref l) => leaves.push(l),
+            }
+        }
+        leaves
+    }
+
+    // node_at returns the innermost node whose span contains offset.
+    pub fn node_at(&self, offset: usize) -> Option<&Node> {
+        if !self.span.contains(offset) {
+            return None;
+        }
+
+        for n in self.child_nodes() {
+            if let Some(inner) = n.node_at(offset) {
+                return Some(inner);
+            }
+        }
+        Some(self)
+    }
+}
+
+impl Element {
+    pub fn span(&self) -> Span {
+        match self {
+            &Element::Node(ref n) => n.span,
+            &Element::Leaf(ref l) => l.span,
+        }
+    }
+}
+
+// Displaying a node gives back its source, including the trivia before it.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for l in self.leaves() {
+            for t in &l.lexeme.leading {
+                match t {
+                    &Trivia::Whitespace(ref s) |
+                    &Trivia::Comment(ref s) => write!(f, "{}", s)?,
+                }
+            }
+            write!(f, "{}", l.lexeme.text)?;
+        }
+        Ok(())
+    }
+}
+
+// parse parses source into a tree whose root has the kind Program.
+pub fn parse(source: &str) -> Result<Node> {
+    let _guard = stack::enter();
+    let mut leaves = vec![];
+    let mut offset = 0;
+    for lexeme in Scanner::new(source).lexemes() {
+        let lexeme = lexeme.map_err(ParseError::ScanError)?;
+        for t in &lexeme.leading {
+            match t {
+                &Trivia::Whitespace(ref s) |
+                &Trivia::Comment(ref s) => offset += s.len(),
+            }
+        }
+        let start = offset;
+        offset += lexeme.text.len();
+        leaves.push(Leaf {
+            lexeme: lexeme,
+            span: Span {
+                start: start,
+                end: offset,
+            },
+        });
+    }
+
+    let mut parser = CstParser {
+        leaves: leaves.into_iter().rev().collect(),
+    };
+    let mut children = vec![];
+    while parser.peek().is_some() {
+        children.push(Element::Node(parser.parse_expr()?));
+    }
+    // Only the leaf holding the trailing trivia is left.
+    children.extend(parser.leaves.pop().map(Element::Leaf));
+
+    let mut root = Node::new(Kind::Program, children);
+    root.span = Span {
+        start: 0,
+        end: source.len(),
+    };
+    Ok(root)
+}
+
+// CstParser follows the same grammar as Parser, but keeps every leaf.
+struct CstParser {
+    // The remaining leaves, last one first.
+    leaves: Vec<Leaf>,
+}
+
+impl CstParser {
+    fn peek(&self) -> Option<&Token> {
+        self.leaves.last().and_then(|l| l.lexeme.token.as_ref())
+    }
+
+    fn next(&mut self) -> Result<Leaf> {
+        if self.peek().is_none() {
+            return Err(ParseError::UnexpectedEOF);
+        }
+        Ok(self.leaves.pop().unwrap())
+    }
+
+    // expect reads the next leaf, which must hold the given token.
+    fn expect(&mut self, token: Token) -> Result<Element> {
+        let leaf = self.next()?;
+        if leaf.lexeme.token.as_ref() != Some(&token) {
+            return Err(ParseError::Unexpected(leaf.lexeme.token.unwrap()));
+        }
+        Ok(Element::Leaf(leaf))
+    }
+
+    fn parse_expr(&mut self) -> Result<Node> {
+        let cond = self.parse_binary()?;
+        if self.peek() != Some(&Token::Question) {
+            return Ok(cond);
+        }
+
+        let mut children = vec![Element::Node(cond), Element::Leaf(self.next()?)];
+        children.push(Element::Node(self.parse_expr()?));
+        children.push(self.expect(Token::Colon)?);
+        children.push(Element::Node(self.parse_expr()?));
+        Ok(Node::new(Kind::Conditional, children))
+    }
+
+    // parse_binary parses an expression other than a conditional one.
+    fn parse_binary(&mut self) -> Result<Node> {
+        let lhs = self.parse_operand()?;
+
+        if let Some(op) = self.peek().and_then(|t| t.to_binary_op()) {
+            let leaf = self.next()?;
+            let rhs = self.parse_binary()?;
+            return Ok(group(lhs, op, leaf, rhs));
+        }
+
+        let can_assign = lhs.kind == Kind::Variable || lhs.kind == Kind::Index ||
+                         lhs.kind == Kind::Field;
+        let is_assign_op = self.peek() == Some(&Token::Eq) ||
+                           self.peek().and_then(|t| t.compound_op()).is_some();
+        if can_assign && is_assign_op {
+            let eq = Element::Leaf(self.next()?);
+            let rhs = Element::Node(self.parse_expr()?);
+            return Ok(Node::new(Kind::Assignment, vec![Element::Node(lhs), eq, rhs]));
+        }
+
+        Ok(lhs)
+    }
+
+    fn parse_operand(&mut self) -> Result<Node> {
+        if stack::exceeded() {
+            return Err(ParseError::TooDeep);
+        }
+        let leaf = self.next()?;
+        let token = leaf.lexeme.token.clone().unwrap();
+        let mut children = vec![Element::Leaf(leaf)];
+
+        let kind = match token {
+            Token::Nil | Token::Boolean(_) | Token::Number(_) | Token::Int(_) | Token::String(_) |
+            Token::CustomLiteral(..) => Kind::Literal,
+            Token::Identifier(_) => Kind::Variable,
+            Token::Break => Kind::Break,
+            Token::Continue => Kind::Continue,
+            Token::Minus | Token::Tilde => {
+                children.push(Element::Node(self.parse_operand()?));
+                return Ok(Node::new(Kind::Unary, children));
+            }
+            Token::OpenParen => {
+                children.push(Element::Node(self.parse_expr()?));
+                children.push(self.expect(Token::CloseParen)?);
+                Kind::Paren
+            }
+            Token::OpenCurly => self.parse_curly_rest(&mut children)?,
+            Token::OpenBracket => {
+                self.parse_list_rest(&Token::CloseBracket, &mut children)?;
+                Kind::List
+            }
+            Token::Do => {
+                let mut block = vec![self.expect(Token::OpenCurly)?];
+                self.parse_block_rest(&mut block)?;
+                children.push(Element::Node(Node::new(Kind::Block, block)));
+                if self.peek() == Some(&Token::While) {
+                    children.push(Element::Leaf(self.next()?));
+                    children.push(Element::Node(self.parse_expr()?));
+                    Kind::DoWhile
+                } else {
+                    Kind::DoBlock
+                }
+            }
+            Token::If | Token::While => {
+                children.push(Element::Node(self.parse_expr()?));
+                children.push(Element::Node(self.parse_expr()?));
+                if self.peek() == Some(&Token::Else) {
+                    children.push(Element::Leaf(self.next()?));
+                    children.push(Element::Node(self.parse_expr()?));
+                }
+                if token == Token::If { Kind::If } else { Kind::While }
+            }
+            Token::For => {
+                let var = self.next()?;
+                match var.lexeme.token {
+                    Some(Token::Identifier(_)) => children.push(Element::Leaf(var)),
+                    t => return Err(ParseError::Unexpected(t.unwrap())),
+                }
+                children.push(self.expect(Token::In)?);
+                children.push(Element::Node(self.parse_expr()?));
+                children.push(Element::Node(self.parse_expr()?));
+                Kind::For
+            }
+            Token::Throw => {
+                children.push(Element::Node(self.parse_expr()?));
+                Kind::Throw
+            }
+            Token::Struct => {
+                let name = self.next()?;
+                match name.lexeme.token {
+                    Some(Token::Identifier(_)) => children.push(Element::Leaf(name)),
+                    t => return Err(ParseError::Unexpected(t.unwrap())),
+                }
+                let mut fields = vec![self.expect(Token::OpenCurly)?];
+                self.parse_list_rest(&Token::CloseCurly, &mut fields)?;
+                children.push(Element::Node(Node::new(Kind::Params, fields)));
+                Kind::Struct
+            }
+            Token::Switch => {
+                children.push(Element::Node(self.parse_expr()?));
+                children.push(self.expect(Token::OpenCurly)?);
+                while self.peek() != Some(&Token::CloseCurly) {
+                    let keyword = self.next()?;
+                    let mut case = vec![];
+                    match keyword.lexeme.token {
+                        Some(Token::Case) => {
+                            case.push(Element::Leaf(keyword));
+                            self.parse_list_rest(&Token::Colon, &mut case)?;
+                        }
+                        Some(Token::Default) => {
+                            case.push(Element::Leaf(keyword));
+                            case.push(self.expect(Token::Colon)?);
+                        }
+                        t => return Err(ParseError::Unexpected(t.unwrap())),
+                    }
+                    case.push(Element::Node(self.parse_expr()?));
+                    children.push(Element::Node(Node::new(Kind::Case, case)));
+                }
+                children.push(self.expect(Token::CloseCurly)?);
+                Kind::Switch
+            }
+            Token::Try => {
+                children.push(Element::Node(self.parse_expr()?));
+                children.push(self.expect(Token::Catch)?);
+                let var = self.next()?;
+                match var.lexeme.token {
+                    Some(Token::Identifier(_)) => children.push(Element::Leaf(var)),
+                    t => return Err(ParseError::Unexpected(t.unwrap())),
+                }
+                children.push(Element::Node(self.parse_expr()?));
+                Kind::Try
+            }
+            Token::Let | Token::Const => {
+                let name = self.next()?;
+                match name.lexeme.token {
+                    Some(Token::Identifier(_)) => children.push(Element::Leaf(name)),
+                    t => return Err(ParseError::Unexpected(t.unwrap())),
+                }
+                children.push(self.expect(Token::Eq)?);
+                children.push(Element::Node(self.parse_expr()?));
+                Kind::Let
+            }
+            Token::Fn if self.peek() == Some(&Token::OpenParen) => {
+                let mut params = vec![self.expect(Token::OpenParen)?];
+                self.parse_list_rest(&Token::CloseParen, &mut params)?;
+                children.push(Element::Node(Node::new(Kind::Params, params)));
+                children.push(Element::Node(self.parse_expr()?));
+                Kind::Lambda
+            }
+            Token::Pipe | Token::DoublePipe => {
+                let mut params = vec![children.pop().unwrap()];
+                if token == Token::Pipe {
+                    self.parse_pipe_params(&mut params)?;
+                }
+                children.push(Element::Node(Node::new(Kind::Params, params)));
+                children.push(Element::Node(self.parse_expr()?));
+                Kind::Lambda
+            }
+            Token::Fn => {
+                let name = self.next()?;
+                match name.lexeme.token {
+                    Some(Token::Identifier(_)) => children.push(Element::Leaf(name)),
+                    t => return Err(ParseError::Unexpected(t.unwrap())),
+                }
+                let mut params = vec![self.expect(Token::OpenParen)?];
+                self.parse_list_rest(&Token::CloseParen, &mut params)?;
+                children.push(Element::Node(Node::new(Kind::Params, params)));
+                children.push(Element::Node(self.parse_expr()?));
+                Kind::Fn
+            }
+            t => return Err(ParseError::Unexpected(t)),
+        };
+
+        self.parse_postfix(Node::new(kind, children))
+    }
+
+    // parse_curly_rest parses the rest of a block or map literal, returning
+    // which one it was.  The key of a map entry is kept as a bare leaf if it's
+    // a plain name, since it doesn't refer to a variable.
+    fn parse_curly_rest(&mut self, children: &mut Vec<Element>) -> Result<Kind> {
+        if self.peek() == Some(&Token::CloseCurly) {
+            children.push(Element::Leaf(self.next()?));
+            return Ok(Kind::Block);
+        }
+
+        let first = self.parse_expr()?;
+        if self.peek() != Some(&Token::Colon) {
+            children.push(Element::Node(first));
+            self.parse_block_rest(children)?;
+            return Ok(Kind::Block);
+        }
+
+        let mut key = first;
+        loop {
+            children.push(match key.kind {
+                Kind::Variable => key.children.remove(0),
+                _ => Element::Node(key),
+            });
+            children.push(self.expect(Token::Colon)?);
+            children.push(Element::Node(self.parse_expr()?));
+
+            let leaf = self.next()?;
+            match leaf.lexeme.token {
+                Some(Token::Comma) => children.push(Element::Leaf(leaf)),
+                Some(Token::CloseCurly) => {
+                    children.push(Element::Leaf(leaf));
+                    return Ok(Kind::Map);
+                }
+                t => return Err(ParseError::Unexpected(t.unwrap())),
+            }
+            key = self.parse_expr()?;
+        }
+    }
+
+    // parse_pipe_params parses the parameter names of a lambda like `|x, y| x`
+    // up to and including the closing pipe.  They can't be parsed as
+    // expressions, which would take the pipe for an operator.
+    fn parse_pipe_params(&mut self, children: &mut Vec<Element>) -> Result<()> {
+        if self.peek() == Some(&Token::Pipe) {
+            children.push(Element::Leaf(self.next()?));
+            return Ok(());
+        }
+
+        loop {
+            let name = self.next()?;
+            match name.lexeme.token {
+                Some(Token::Identifier(_)) => {
+                    children.push(Element::Node(Node::new(Kind::Variable,
+                                                          vec![Element::Leaf(name)])))
+                }
+                t => return Err(ParseError::Unexpected(t.unwrap())),
+            }
+            let leaf = self.next()?;
+            match leaf.lexeme.token {
+                Some(Token::Comma) => children.push(Element::Leaf(leaf)),
+                Some(Token::Pipe) => {
+                    children.push(Element::Leaf(leaf));
+                    return Ok(());
+                }
+                t => return Err(ParseError::Unexpected(t.unwrap())),
+            }
+        }
+    }
+
+    // parse_block_rest parses the expressions of a block up to and including
+    // the closing curly.
+    fn parse_block_rest(&mut self, children: &mut Vec<Element>) -> Result<()> {
+        while self.peek() != Some(&Token::CloseCurly) {
+            children.push(Element::Node(self.parse_expr()?));
+        }
+        children.push(self.expect(Token::CloseCurly)?);
+        Ok(())
+    }
+
+    // parse_list_rest parses comma-separated expressions up to and including
+    // the closing token.
+    fn parse_list_rest(&mut self, until: &Token, children: &mut Vec<Element>) -> Result<()> {
+        if self.peek() == Some(until) {
+            children.push(Element::Leaf(self.next()?));
+            return Ok(());
+        }
+
+        loop {
+            children.push(Element::Node(self.parse_expr()?));
+            let leaf = self.next()?;
+            let done = leaf.lexeme.token.as_ref() == Some(until);
+            match leaf.lexeme.token {
+                Some(Token::Comma) => children.push(Element::Leaf(leaf)),
+                Some(_) if done => {
+                    children.push(Element::Leaf(leaf));
+                    return Ok(());
+                }
+                t => return Err(ParseError::Unexpected(t.unwrap())),
+            }
+        }
+    }
+
+    fn parse_postfix(&mut self, mut node: Node) -> Result<Node> {
+        loop {
+            match node.kind {
+                Kind::Variable | Kind::Call | Kind::Field | Kind::Index => {}
+                _ => return Ok(node),
+            }
+
+            let mut children = vec![Element::Node(node)];
+            let kind = match self.peek() {
+                Some(&Token::OpenParen) => {
+                    children.push(Element::Leaf(self.next()?));
+                    self.parse_list_rest(&Token::CloseParen, &mut children)?;
+                    Kind::Call
+                }
+                Some(&Token::Dot) => {
+                    children.push(Element::Leaf(self.next()?));
+                    let field = self.next()?;
+                    match field.lexeme.token {
+                        Some(Token::Identifier(_)) => children.push(Element::Leaf(field)),
+                        t => return Err(ParseError::Unexpected(t.unwrap())),
+                    }
+                    Kind::Field
+                }
+                Some(&Token::OpenBracket) => {
+                    children.push(Element::Leaf(self.next()?));
+                    children.push(Element::Node(self.parse_expr()?));
+                    children.push(self.expect(Token::CloseBracket)?);
+                    Kind::Index
+                }
+                _ => {
+                    return match children.pop() {
+                        Some(Element::Node(n)) => Ok(n),
+                        _ => unreachable!(),
+                    }
+                }
+            };
+            node = Node::new(kind, children);
+        }
+    }
+}
+
+// group builds the node for `lhs op rhs`, where rhs was parsed as far to the
+// right as it goes, regrouping it by precedence like Parser::apply_precedence.
+fn group(lhs: Node, op: BinaryOp, leaf: Leaf, rhs: Node) -> Node {
+    if rhs.kind == Kind::Binary && !op.binds_right(&binary_op(&rhs)) {
+        // The operator binds tighter than the one on the right, so it takes
+        // the right side's left operand instead.
+        let mut children = rhs.children.into_iter();
+        let (left_r, op_r, right_r) = (children.next(), children.next(), children.next());
+        let left_r = match left_r {
+            Some(Element::Node(n)) => n,
+            _ => unreachable!(),
+        };
+        let left = group(lhs, op, leaf, left_r);
+        return Node::new(Kind::Binary,
+                         vec![Element::Node(left), op_r.unwrap(), right_r.unwrap()]);
+    }
+
+    Node::new(Kind::Binary,
+              vec![Element::Node(lhs), Element::Leaf(leaf), Element::Node(rhs)])
+}
+
+// binary_op returns the operator of a Binary node.
+fn binary_op(n: &Node) -> BinaryOp {
+    match n.children.get(1) {
+        Some(&Element::Leaf(ref l)) => {
+            l.lexeme.token.as_ref().and_then(|t| t.to_binary_op()).unwrap()
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use error::ParseError;
+    use scanner::Token;
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let sources = vec![
+            "",
+            "# just a comment\n",
+            "x = 1 +  2 # sum\n",
+            "fn add(a, b) {\n  a + b\n}\nadd(1, -x)[0].y\n",
+            "if a < b { do { c } } else while d { break }  l = [1, 2]  l[0] = \"s\"\n",
+            "for x in l {\n  continue\n}\n",
+            "x += 1  l[0] *=2\n",
+            "flags = ~a & (b | c ^ d) << 2\n",
+            "s = \"\"\"\n  a \"q\"\n  \"\"\"\n",
+            "m = { a: 1, \"b c\" : {},(k):{ d: [] } }\n",
+            "g = || 1  h = fn (x) { x }  f = | a ,b | a+b\n",
+            "let  x=1 { const x = x }\n",
+            "try{ throw  1 }catch e  e\n",
+            "struct P {x,y }  p.x = 1\n",
+            "x = a ?b: c?  d :e\n",
+            "do{ i += 1 }while  i<3\n",
+            "switch x {\n  case 1,2 : a\n  default:b }\n",
+        ];
+
+        for src in sources {
+            assert_eq!(parse(src).unwrap().to_string(), src);
+        }
+    }
+
+    #[test]
+    fn test_structure() {
+        let root = parse("x = f(1)  # call\n").unwrap();
+        assert_eq!(root.kind, Kind::Program);
+        assert_eq!(root.span, Span { start: 0, end: 17 });
+
+        let exprs = root.child_nodes();
+        assert_eq!(exprs.len(), 1);
+        assert_eq!(exprs[0].kind, Kind::Assignment);
+        assert_eq!(exprs[0].span, Span { start: 0, end: 8 });
+
+        let call = exprs[0].child_nodes()[1];
+        assert_eq!(call.kind, Kind::Call);
+        assert_eq!(call.to_string(), " f(1)");
+        assert_eq!(call.span, Span { start: 4, end: 8 });
+
+        assert_eq!(root.node_at(4).unwrap().kind, Kind::Variable);
+        assert_eq!(root.node_at(6).unwrap().kind, Kind::Literal);
+        assert_eq!(root.node_at(7).unwrap().kind, Kind::Call);
+        assert_eq!(root.node_at(12).unwrap().kind, Kind::Program);
+
+        let leaves = root.leaves();
+        assert_eq!(leaves[0].lexeme.token, Some(Token::Identifier("x".to_owned())));
+        assert_eq!(leaves[0].span, Span { start: 0, end: 1 });
+        assert_eq!(leaves.last().unwrap().lexeme.token, None);
+    }
+
+    #[test]
+    fn test_grouping() {
+        use expr::Expression;
+        use parser::Parser;
+
+        // groups shows how an expression's operators are grouped, with each
+        // binary operation in parentheses.
+        fn ast_groups(e: &Expression) -> String {
+            match e {
+                &Expression::BinaryExpr { ref left, ref op, ref right, .. } => {
+                    format!("({} {} {})", ast_groups(left), op, ast_groups(right))
+                }
+                &Expression::ChainedComparison { ref first, ref rest } => {
+                    rest.iter().fold(ast_groups(first), |l, &(ref op, ref r)| {
+                        format!("({} {} {})", l, op, ast_groups(r))
+                    })
+                }
+                &Expression::UnaryExpr { ref op, ref operand } => {
+                    format!("{}{}", op, ast_groups(operand))
+                }
+                &Expression::ParenExpr(ref e) => ast_groups(e),
+                &Expression::Variable(ref name) => name.clone(),
+                &Expression::IntLiteral(i) => i.to_string(),
+                e => panic!("unexpected expression {:?}", e),
+            }
+        }
+
+        fn cst_groups(n: &Node) -> String {
+            let text = |i: usize| match n.children[i] {
+                Element::Leaf(ref l) => l.lexeme.text.clone(),
+                Element::Node(ref n) => cst_groups(n),
+            };
+            match n.kind {
+                Kind::Binary => format!("({} {} {})", text(0), text(1), text(2)),
+                Kind::Unary => format!("{}{}", text(0), text(1)),
+                Kind::Paren => text(1),
+                _ => text(0),
+            }
+        }
+
+        let sources = vec![
+            "1 * 2 + 3",
+            "a + b * c - d",
+            "a - b - c",
+            "a / b / c * d",
+            "-a * b % c",
+            "(a + b) * c",
+            "a < b < c",
+            "a + b < c <= d",
+            "a < b + c < d",
+            "a == b < c",
+            "a && b < c || d ?? e",
+            "a << b + c & d | e ^ f",
+            "a .. b + 1 in c",
+        ];
+        for src in sources {
+            let ast = Parser::new(src).next().unwrap().unwrap();
+            let cst = parse(src).unwrap();
+            assert_eq!(cst_groups(cst.child_nodes()[0]), ast_groups(&ast), "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse("(1"), Err(ParseError::UnexpectedEOF));
+        assert_eq!(parse("(1 }"), Err(ParseError::Unexpected(Token::CloseCurly)));
+        assert_eq!(parse("fn 1() {}"), Err(ParseError::Unexpected(Token::Int(1))));
+    }
+}
@@ -1,16 +1,269 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fmt;
+use std::iter;
+use std::rc::Rc;
+use std::result;
+
+use indexmap::IndexMap;
+
+use checkpoint;
+use error::ExecuteError;
+use function::Function as Func;
+use scope;
+use seq::Sequence;
+use set::ValueSet;
 
 use Data::*;
 
-#[derive(Clone,Debug,PartialEq)]
+#[derive(Clone)]
 pub enum Data {
     Nil,
     Boolean(bool),
     Number(f64),
-    Str(String),
+    Int(i64),
+    // Strings can't be changed in place, so copies of a string share its
+    // storage.  Every value of the same string literal shares it too.
+    Str(Rc<str>),
+    List(Rc<RefCell<Vec<Data>>>),
+    Map(Rc<RefCell<IndexMap<String, Data>>>),
+    Set(Rc<RefCell<ValueSet>>),
+    // A NumArray holds numbers packed together as floats, which takes less
+    // memory than a list and lets builtins work on them without converting
+    // each one.
+    NumArray(Rc<RefCell<Vec<f64>>>),
+    // Range holds the numbers from start up to, but not including, end.  The
+    // numbers are ints if start is a whole number.
+    Range(f64, f64),
+    // A lazy sequence, produced by builtins like take and zip.
+    Seq(Rc<Sequence>),
+    Function(Rc<Func>),
+    Builtin(String),
+    // A struct type, defined with `struct Point { x, y }`.  Calling it with a
+    // value for each field creates an instance.
+    Type(Rc<StructType>),
+    Struct(Rc<RefCell<Instance>>),
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct StructType {
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+// An Instance is a value of a struct type.  It has exactly the type's fields,
+// in the order they were defined.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Instance {
+    pub type_name: String,
+    pub fields: IndexMap<String, Data>,
+}
+
+impl StructType {
+    // new_instance creates an instance holding values, one for each field.
+    pub fn new_instance(&self, values: Vec<Data>) -> Data {
+        let fields = self.fields.iter().cloned().zip(values).collect();
+        Struct(Rc::new(RefCell::new(Instance {
+            type_name: self.name.clone(),
+            fields: fields,
+        })))
+    }
 }
 
 impl Data {
+    pub fn list(items: Vec<Data>) -> Data {
+        List(Rc::new(RefCell::new(items)))
+    }
+
+    pub fn map(entries: IndexMap<String, Data>) -> Data {
+        Map(Rc::new(RefCell::new(entries)))
+    }
+
+    pub fn num_array(numbers: Vec<f64>) -> Data {
+        NumArray(Rc::new(RefCell::new(numbers)))
+    }
+
+    // set creates a new set from items, dropping duplicates but otherwise
+    // keeping the items in order.
+    pub fn set(items: Vec<Data>) -> Data {
+        Set(Rc::new(RefCell::new(items.into_iter().collect())))
+    }
+
+    // iter returns the items of a value that can be looped over, or None if
+    // the value isn't iterable.  Maps are iterated by key.  Containers are
+    // copied up front, so the caller is free to modify them while iterating.
+    pub fn iter(&self) -> Option<Box<dyn Iterator<Item = Data>>> {
+        match self {
+            &List(ref l) => Some(Box::new(l.borrow().clone().into_iter())),
+            &Set(ref s) => Some(Box::new(s.borrow().to_vec().into_iter())),
+            &Map(ref m) => {
+                let keys: Vec<Data> = m.borrow().keys().map(|k| Str(k.as_str().into())).collect();
+                Some(Box::new(keys.into_iter()))
+            }
+            &NumArray(ref a) => Some(Box::new(a.borrow().clone().into_iter().map(Number))),
+            &Range(start, end) => {
+                let numbers = (0u64..)
+                    .map(move |i| start + i as f64)
+                    .take_while(move |&n| n < end);
+                if start.fract() == 0.0 {
+                    Some(Box::new(numbers.map(|n| Int(n as i64))))
+                } else {
+                    Some(Box::new(numbers.map(Number)))
+                }
+            }
+            // Sequences aren't copied: the loop uses up their values.
+            &Seq(ref s) => {
+                let s = s.clone();
+                Some(Box::new(iter::from_fn(move || s.next())))
+            }
+            _ => None,
+        }
+    }
+
+    // to_f64 returns the value of a number or int as a float.
+    pub fn to_f64(&self) -> Option<f64> {
+        match self {
+            &Number(n) => Some(n),
+            &Int(i) => Some(i as f64),
+            _ => None,
+        }
+    }
+
+    // is_ref_type returns whether the value is shared rather than copied when
+    // it's assigned or passed to a function, so that changing it is visible
+    // through every variable holding it.  Lists, maps, sets and struct
+    // instances are reference types.  Every other value can't be changed in
+    // place, so sharing it is the same as copying it.
+    pub fn is_ref_type(&self) -> bool {
+        match self {
+            &List(_) | &Map(_) | &Set(_) | &NumArray(_) | &Seq(_) | &Struct(_) => true,
+            _ => false,
+        }
+    }
+
+    // shallow_copy returns a value equal to this one that doesn't share any
+    // storage with it.  Containers inside a copied container are still shared,
+    // since only the outer one is copied.  Sequences can't be copied, since
+    // their values are only produced once, so the copy shares them.
+    pub fn shallow_copy(&self) -> Data {
+        match self {
+            &List(ref l) => Data::list(l.borrow().clone()),
+            &Map(ref m) => Data::map(m.borrow().clone()),
+            &Set(ref s) => Set(Rc::new(RefCell::new(s.borrow().clone()))),
+            &NumArray(ref a) => Data::num_array(a.borrow().clone()),
+            &Struct(ref s) => Struct(Rc::new(RefCell::new(s.borrow().clone()))),
+            d => d.clone(),
+        }
+    }
+
+    // deep_copy is like shallow_copy, but copies the containers inside the
+    // value too, so the copy shares nothing that can be changed in place.  A
+    // container found more than once is copied once, so the copy holds its
+    // copies in the same places, including containers that hold themselves.
+    pub fn deep_copy(&self) -> Data {
+        self.copy_with(&mut HashMap::new())
+    }
+
+    // copy_with deep copies the value, reusing the copies already made of
+    // containers, by address.  Each container's copy is recorded before its
+    // contents are copied, so that a container inside itself is found.
+    fn copy_with(&self, copies: &mut HashMap<usize, Data>) -> Data {
+        let address = match self.address() {
+            Some(address) => address,
+            None => return self.clone(),
+        };
+        if let Some(copy) = copies.get(&address) {
+            return copy.clone();
+        }
+
+        match self {
+            &List(ref l) => {
+                let copy = Rc::new(RefCell::new(vec![]));
+                copies.insert(address, List(copy.clone()));
+                let items = l.borrow().iter().map(|d| d.copy_with(copies)).collect();
+                *copy.borrow_mut() = items;
+            }
+            &Map(ref m) => {
+                let copy = Rc::new(RefCell::new(IndexMap::new()));
+                copies.insert(address, Map(copy.clone()));
+                let entries = m.borrow()
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.copy_with(copies)))
+                    .collect();
+                *copy.borrow_mut() = entries;
+            }
+            &Set(ref s) => {
+                let copy = Rc::new(RefCell::new(ValueSet::new()));
+                copies.insert(address, Set(copy.clone()));
+                let items = s.borrow().iter().map(|d| d.copy_with(copies)).collect();
+                *copy.borrow_mut() = items;
+            }
+            &NumArray(ref a) => {
+                copies.insert(address, Data::num_array(a.borrow().clone()));
+            }
+            &Struct(ref s) => {
+                let copy = Rc::new(RefCell::new(Instance {
+                    type_name: s.borrow().type_name.clone(),
+                    fields: IndexMap::new(),
+                }));
+                copies.insert(address, Struct(copy.clone()));
+                let fields = s.borrow()
+                    .fields
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.copy_with(copies)))
+                    .collect();
+                copy.borrow_mut().fields = fields;
+            }
+            _ => {}
+        }
+        copies[&address].clone()
+    }
+
+    // address returns where a list, map, set, array or instance is stored,
+    // which tells containers apart even when they hold equal values.
+    pub fn address(&self) -> Option<usize> {
+        match self {
+            &List(ref l) => Some(l.as_ptr() as usize),
+            &Map(ref m) => Some(m.as_ptr() as usize),
+            &Set(ref s) => Some(s.as_ptr() as usize),
+            &NumArray(ref a) => Some(a.as_ptr() as usize),
+            &Struct(ref s) => Some(s.as_ptr() as usize),
+            _ => None,
+        }
+    }
+
+    // will_change is called before a list, map, set, array or instance is
+    // changed in place.  It fails with ReadOnly if the container belongs to a
+    // read-only global, and otherwise records what the container holds, so
+    // that a transactional evaluation that fails can put it back.  Native
+    // functions that change their arguments in place call it first.  It does
+    // nothing for other values.
+    pub fn will_change(&self) -> result::Result<(), ExecuteError> {
+        if let Some(address) = self.address() {
+            scope::check_writable(address)?;
+        }
+        match self {
+            &List(ref l) => checkpoint::save(l),
+            &Map(ref m) => checkpoint::save(m),
+            &Set(ref s) => checkpoint::save(s),
+            &NumArray(ref a) => checkpoint::save(a),
+            &Struct(ref s) => checkpoint::save(s),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // compare orders two values.  Ints and numbers are ordered by value and
+    // strings by their characters.  Any other pair of values isn't ordered.
+    pub fn compare(&self, other: &Data) -> Option<Ordering> {
+        match (self, other) {
+            (&Str(ref l), &Str(ref r)) => Some(l.cmp(r)),
+            (&Int(l), &Int(r)) => Some(l.cmp(&r)),
+            (l, r) => l.to_f64()?.partial_cmp(&r.to_f64()?),
+        }
+    }
+
     pub fn to_bool(&self) -> bool {
         match self {
             &Nil | &Boolean(false) => false,
@@ -23,18 +276,234 @@ impl Data {
             &Nil => "nil".to_owned(),
             &Boolean(_) => "boolean".to_owned(),
             &Number(_) => "number".to_owned(),
+            &Int(_) => "int".to_owned(),
             &Str(_) => "string".to_owned(),
+            &List(_) => "list".to_owned(),
+            &Map(_) => "map".to_owned(),
+            &Set(_) => "set".to_owned(),
+            &NumArray(_) => "array".to_owned(),
+            &Range(..) => "range".to_owned(),
+            &Seq(_) => "sequence".to_owned(),
+            &Function(_) | &Builtin(_) => "function".to_owned(),
+            &Type(_) => "type".to_owned(),
+            // An instance's type is its struct.
+            &Struct(ref s) => s.borrow().type_name.clone(),
+        }
+    }
+}
+
+// Containers can hold themselves, as after `push(l, l)`, so comparing and
+// printing them keep track of the containers they're inside of, and stop at
+// one they're already in instead of recursing forever.
+thread_local! {
+    static VISITING: RefCell<Vec<(usize, usize)>> = RefCell::new(vec![]);
+}
+
+// A Visit marks a container, or a pair of containers being compared, as being
+// inside of until it's dropped.
+pub struct Visit((usize, usize));
+
+impl Visit {
+    // container marks d as being printed if it's a container, returning
+    // Some(None) if it already is, and None if it isn't a container.
+    pub fn container(d: &Data) -> Option<Option<Visit>> {
+        match d {
+            &List(ref c) => Some(Visit::enter_one(c)),
+            &Set(ref c) => Some(Visit::enter_one(c)),
+            &Map(ref c) => Some(Visit::enter_one(c)),
+            &Struct(ref c) => Some(Visit::enter_one(c)),
+            _ => None,
+        }
+    }
+
+    // enter returns None if the pair is already being visited.  Printing
+    // visits pairs whose second container is 0.
+    fn enter(pair: (usize, usize)) -> Option<Visit> {
+        VISITING.with(|v| {
+            let mut v = v.borrow_mut();
+            if v.contains(&pair) {
+                return None;
+            }
+            v.push(pair);
+            Some(Visit(pair))
+        })
+    }
+
+    fn enter_one<T: ?Sized>(c: &Rc<T>) -> Option<Visit> {
+        Visit::enter((Rc::as_ptr(c) as *const () as usize, 0))
+    }
+
+    fn enter_pair<T: ?Sized>(a: &Rc<T>, b: &Rc<T>) -> Option<Visit> {
+        Visit::enter((Rc::as_ptr(a) as *const () as usize, Rc::as_ptr(b) as *const () as usize))
+    }
+}
+
+impl Drop for Visit {
+    fn drop(&mut self) {
+        let pair = self.0;
+        VISITING.with(|v| {
+            let mut v = v.borrow_mut();
+            if let Some(i) = v.iter().rposition(|&p| p == pair) {
+                v.remove(i);
+            }
+        })
+    }
+}
+
+// Containers are equal if they hold equal values.  Two containers that are
+// already being compared further up are taken to be equal, so containers that
+// hold themselves in the same places are equal.
+impl PartialEq for Data {
+    fn eq(&self, other: &Data) -> bool {
+        match (self, other) {
+            (&Nil, &Nil) => true,
+            (&Boolean(a), &Boolean(b)) => a == b,
+            (&Number(a), &Number(b)) => a == b,
+            (&Int(a), &Int(b)) => a == b,
+            // Ints equal the numbers with the same value, as with ==.
+            (&Int(a), &Number(b)) |
+            (&Number(b), &Int(a)) => a as f64 == b,
+            (&Str(ref a), &Str(ref b)) => a == b,
+            (&List(ref a), &List(ref b)) => {
+                Visit::enter_pair(a, b).map_or(true, |_v| *a.borrow() == *b.borrow())
+            }
+            // Sets are equal if they hold the same values, in any order.
+            (&Set(ref a), &Set(ref b)) => {
+                Visit::enter_pair(a, b).map_or(true, |_v| *a.borrow() == *b.borrow())
+            }
+            (&Map(ref a), &Map(ref b)) => {
+                Visit::enter_pair(a, b).map_or(true, |_v| *a.borrow() == *b.borrow())
+            }
+            (&NumArray(ref a), &NumArray(ref b)) => a == b,
+            (&Range(a0, a1), &Range(b0, b1)) => a0 == b0 && a1 == b1,
+            (&Seq(ref a), &Seq(ref b)) => a == b,
+            (&Function(ref a), &Function(ref b)) => a == b,
+            (&Builtin(ref a), &Builtin(ref b)) => a == b,
+            (&Type(ref a), &Type(ref b)) => a == b,
+            (&Struct(ref a), &Struct(ref b)) => {
+                Visit::enter_pair(a, b).map_or(true, |_v| *a.borrow() == *b.borrow())
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // A container that's already being printed further up is shown as
+        // `...`.
+        let visit = Visit::container(self);
+        if let Some(None) = visit {
+            return match self {
+                &List(_) => write!(f, "List([...])"),
+                &Map(_) => write!(f, "Map({{...}})"),
+                &Set(_) => write!(f, "Set({{...}})"),
+                _ => write!(f, "{} {{ ... }}", self.type_name()),
+            };
+        }
+
+        match self {
+            &Nil => write!(f, "Nil"),
+            &Boolean(b) => f.debug_tuple("Boolean").field(&b).finish(),
+            &Number(n) => f.debug_tuple("Number").field(&n).finish(),
+            &Int(i) => f.debug_tuple("Int").field(&i).finish(),
+            &Str(ref s) => f.debug_tuple("Str").field(s).finish(),
+            &List(ref l) => f.debug_tuple("List").field(&*l.borrow()).finish(),
+            &Map(ref m) => f.debug_tuple("Map").field(&*m.borrow()).finish(),
+            &Set(ref s) => f.debug_tuple("Set").field(&*s.borrow()).finish(),
+            &NumArray(ref a) => f.debug_tuple("NumArray").field(&*a.borrow()).finish(),
+            &Range(start, end) => f.debug_tuple("Range").field(&start).field(&end).finish(),
+            &Seq(_) => write!(f, "Seq(..)"),
+            &Function(ref func) if func.name.is_empty() => write!(f, "Function(<anonymous>)"),
+            &Function(ref func) => write!(f, "Function({})", func.name),
+            &Builtin(ref name) => f.debug_tuple("Builtin").field(name).finish(),
+            &Type(ref t) => write!(f, "Type({})", t.name),
+            &Struct(ref s) => {
+                let s = s.borrow();
+                let mut d = f.debug_struct(&s.type_name);
+                for (name, value) in &s.fields {
+                    d.field(name, value);
+                }
+                d.finish()
+            }
         }
     }
 }
 
 impl fmt::Display for Data {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let visit = Visit::container(self);
+        if let Some(None) = visit {
+            return match self {
+                &List(_) => write!(f, "[...]"),
+                &Map(_) | &Set(_) => write!(f, "{{...}}"),
+                _ => write!(f, "{} {{ ... }}", self.type_name()),
+            };
+        }
+
         match self {
             &Nil => write!(f, "nil"),
             &Boolean(b) => write!(f, "{}", b),
             &Number(n) => write!(f, "{}", n),
+            &Int(i) => write!(f, "{}", i),
             &Str(ref s) => write!(f, "{}", s),
+            &List(ref l) => {
+                write!(f, "[")?;
+                for (i, item) in l.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            &Map(ref m) => {
+                write!(f, "{{")?;
+                for (i, (key, val)) in m.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key, val)?;
+                }
+                write!(f, "}}")
+            }
+            &Set(ref s) => {
+                write!(f, "{{")?;
+                for (i, item) in s.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "}}")
+            }
+            &NumArray(ref a) => {
+                write!(f, "array[")?;
+                for (i, n) in a.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", n)?;
+                }
+                write!(f, "]")
+            }
+            &Range(start, end) => write!(f, "{}..{}", start, end),
+            &Seq(_) => write!(f, "<sequence>"),
+            &Function(ref func) if func.name.is_empty() => write!(f, "<fn>"),
+            &Function(ref func) => write!(f, "<fn {}>", func.name),
+            &Builtin(ref name) => write!(f, "<builtin {}>", name),
+            &Type(ref t) => write!(f, "<struct {}>", t.name),
+            &Struct(ref s) => {
+                let s = s.borrow();
+                write!(f, "{} {{", s.type_name)?;
+                for (i, (name, value)) in s.fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, " {}: {}", name, value)?;
+                }
+                write!(f, " }}")
+            }
         }
     }
 }
@@ -0,0 +1,209 @@
+// The doctest module runs the examples written in a script's comments, so a
+// library of gate functions can document itself with examples that are checked.
+//
+// An example is a comment line starting with `> ` followed by an expression,
+// like a line typed into the REPL.  The comment lines after it, up to the next
+// example or a blank comment line, are what the REPL would print: anything the
+// expression prints, then its value and type or the error it failed with.
+//
+//     # > double(2)
+//     # Int(4) : int
+
+use std::fmt;
+use std::result;
+
+use data::Data;
+use error::Error;
+use program::Program;
+use scanner::{Scanner, Trivia};
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Example {
+    // The line the example starts on, counting from 1.
+    pub line: usize,
+    pub input: String,
+    pub expected: String,
+}
+
+// A Failure is an example whose output didn't match.  It's displayed as the
+// line number and input, then the expected lines marked with - and the actual
+// ones marked with +.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Failure {
+    pub example: Example,
+    pub actual: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: > {}", self.example.line, self.example.input)?;
+        for line in self.example.expected.lines() {
+            writeln!(f, "- {}", line)?;
+        }
+        for line in self.actual.lines() {
+            writeln!(f, "+ {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+// examples returns the examples in the comments of source, in order.
+pub fn examples(source: &str) -> Vec<Example> {
+    let mut examples: Vec<Example> = vec![];
+    // Whether the previous line was part of the latest example.
+    let mut in_example = false;
+    let mut line = 1;
+    let mut last_comment_line = 0;
+
+    for lexeme in Scanner::new(source).lexemes() {
+        let lexeme = match lexeme {
+            Ok(l) => l,
+            Err(_) => break,
+        };
+
+        for trivia in &lexeme.leading {
+            match trivia {
+                &Trivia::Whitespace(ref s) => line += s.matches('\n').count(),
+                &Trivia::Comment(ref c) => {
+                    let text = c[1..].strip_prefix(' ').unwrap_or(&c[1..]).trim_end();
+                    if text.starts_with("> ") {
+                        examples.push(Example {
+                            line: line,
+                            input: text[2..].to_owned(),
+                            expected: String::new(),
+                        });
+                        in_example = true;
+                    } else if text.is_empty() || last_comment_line + 1 != line {
+                        in_example = false;
+                    } else if in_example {
+                        if let Some(example) = examples.last_mut() {
+                            example.expected.push_str(text);
+                            example.expected.push('\n');
+                        }
+                    }
+                    last_comment_line = line;
+                }
+            }
+        }
+        line += lexeme.text.matches('\n').count();
+    }
+
+    examples
+}
+
+// run runs source and then each of its examples in turn, in the same program,
+// and returns the examples that failed.  It fails if source itself can't run.
+pub fn run(source: &str) -> result::Result<Vec<Failure>, Error> {
+    let mut program = Program::new();
+    program.capture_output();
+    program.run_source(source)?;
+    program.take_output();
+
+    let mut failures = vec![];
+    for example in examples(source) {
+        let res = program.run_source(&example.input);
+        let mut actual = program.take_output();
+        actual.push_str(&render(&res));
+        actual.push('\n');
+
+        if actual != example.expected {
+            failures.push(Failure {
+                example: example,
+                actual: actual,
+            });
+        }
+    }
+    Ok(failures)
+}
+
+// update runs source's examples like run, and returns source with the
+// expected output of every failing example replaced by its actual output.
+// This records the output of new examples and accepts intended changes.
+pub fn update(source: &str) -> result::Result<String, Error> {
+    let mut lines: Vec<String> = source.lines().map(|l| l.to_owned()).collect();
+
+    // Replacing from the bottom up keeps the line numbers of the examples
+    // above valid.
+    for failure in run(source)?.into_iter().rev() {
+        let example = &failure.example;
+        let first = example.line - 1;
+        let prefix = match lines[first].find("> ") {
+            Some(i) => lines[first][..i].to_owned(),
+            None => "# ".to_owned(),
+        };
+
+        let start = first + 1;
+        let end = start + example.expected.lines().count();
+        let actual: Vec<_> = failure.actual
+            .lines()
+            .map(|l| format!("{}{}", prefix, l).trim_end().to_owned())
+            .collect();
+        lines.splice(start..end, actual);
+    }
+
+    let mut updated = lines.join("\n");
+    if source.ends_with('\n') {
+        updated.push('\n');
+    }
+    Ok(updated)
+}
+
+// render formats the result of an expression the way the REPL prints it.
+pub fn render(res: &result::Result<Data, Error>) -> String {
+    match res {
+        &Ok(ref d) => format!("{:?} : {}", d, d.type_name()),
+        &Err(ref e) => e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "# double returns twice n.\n\
+                          #\n\
+                          # > double(2)\n\
+                          # Int(4) : int\n\
+                          # > println(\"hi\")  double(0.5)\n\
+                          # hi\n\
+                          # Number(1.0) : number\n\
+                          #\n\
+                          # > double(nil)\n\
+                          # error[E0018]: invalid operation (nil * int): nil * 2\n\
+                          fn double(n) n * 2\n\
+                          \n\
+                          # > double(3)\n\
+                          # Int(7) : int\n";
+
+    #[test]
+    fn test_examples() {
+        let examples = examples(SOURCE);
+        assert_eq!(examples.len(), 4);
+        assert_eq!(examples[0],
+                   Example {
+                       line: 3,
+                       input: "double(2)".to_owned(),
+                       expected: "Int(4) : int\n".to_owned(),
+                   });
+        assert_eq!(examples[1].expected, "hi\nNumber(1.0) : number\n");
+        assert_eq!(examples[3].line, 13);
+    }
+
+    #[test]
+    fn test_run() {
+        let failures = run(SOURCE).unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].to_string(),
+                   "13: > double(3)\n- Int(7) : int\n+ Int(6) : int\n");
+
+        assert!(run("# > 1\n# Int(1) : int\nx )").is_err());
+    }
+
+    #[test]
+    fn test_update() {
+        let source = "fn f() { println(1)  2 }\n  # > f()\n  # Int(3) : int\n\n# > f() + 1\n";
+        assert_eq!(update(source).unwrap(),
+                   "fn f() { println(1)  2 }\n  # > f()\n  # 1\n  # Int(2) : int\n\n\
+                    # > f() + 1\n# 1\n# Int(3) : int\n");
+    }
+}
@@ -0,0 +1,563 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::result;
+
+use analysis;
+use builtins::{self, NativeFn};
+use context::Context;
+use data::Data;
+use error::{self, ExecuteError};
+use error::ExecuteError::*;
+use expr::{Expression, Result};
+use function::Function;
+use parser::Parser;
+use stack;
+#[cfg(feature = "net")]
+use net;
+#[cfg(feature = "signals")]
+use signals;
+#[cfg(feature = "sqlite")]
+use sqlite;
+
+// An Engine holds everything needed to evaluate expressions apart from the
+// variables, which live in a Context.  A single engine can be configured once
+// and then shared by any number of contexts.
+pub struct Engine {
+    builtins: HashMap<String, Builtin>,
+    // Maps the unqualified name of each namespaced builtin to the namespaces
+    // that provide it.
+    namespaced: HashMap<String, Vec<String>>,
+    // Maps the prefix of each custom literal to the function that builds its
+    // value from the literal's text.
+    literals: HashMap<char, Box<dyn Fn(&str) -> Result>>,
+    // The builtins scripts may call, or None if they may call any of them.
+    allowed: Option<HashSet<String>>,
+    // The optional features the host has declared, for has_feature.
+    features: HashSet<String>,
+}
+
+// A Builtin is a registered native function, along with the plugin library
+// its code is in, if any.  The library is dropped after the function, and
+// only once every function from it has been dropped, since unloading it
+// removes the code for calling and dropping them.
+struct Builtin {
+    f: Rc<RefCell<dyn NativeFn>>,
+    // A copy of f that's never called, which makes fresh copies for calls
+    // made while f is running, if f has any.
+    spare: Option<Rc<dyn NativeFn>>,
+    library: Option<Rc<dyn Any>>,
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        let mut engine = Engine {
+            builtins: HashMap::new(),
+            namespaced: HashMap::new(),
+            literals: HashMap::new(),
+            allowed: None,
+            features: HashSet::new(),
+        };
+        for &(name, f) in builtins::CORE {
+            engine.register(name, f);
+        }
+        engine.register("println", builtins::Println);
+        engine.register("print", builtins::Print);
+        engine.register("readline", builtins::Readline);
+        engine.register("random", builtins::Random);
+        engine.register("random_int", builtins::RandomInt);
+        engine.register("clock", builtins::Clock);
+        engine.register("sleep", builtins::Sleep);
+        engine.register("read_file", builtins::ReadFile);
+        engine.register("write_file", builtins::WriteFile);
+        engine.register("render", builtins::Render);
+        engine.register("has_feature", builtins::HasFeature);
+        engine.register("close", builtins::Close);
+        #[cfg(feature = "signals")]
+        engine.register("on_signal", signals::OnSignal);
+        #[cfg(feature = "net")]
+        engine.register_net();
+        #[cfg(feature = "sqlite")]
+        engine.register_sqlite();
+        engine
+    }
+
+    #[cfg(feature = "net")]
+    fn register_net(&mut self) {
+        self.register("tcp_connect", net::TcpConnect);
+        #[cfg(unix)]
+        self.register("unix_connect", net::UnixConnect);
+        self.register("listen", net::Listen);
+        self.register("accept", net::Accept);
+        self.register("send", net::Send);
+        self.register("recv", net::Recv);
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn register_sqlite(&mut self) {
+        self.register("sqlite_open", sqlite::SqliteOpen);
+        self.register("query", sqlite::Query);
+        self.register("execute", sqlite::Execute);
+    }
+
+    // register makes f callable from scripts under the given name, replacing
+    // any function previously registered under it, including core builtins.
+    // Variables defined by scripts still take precedence over every builtin.
+    pub fn register<F>(&mut self, name: &str, f: F)
+        where F: NativeFn + 'static
+    {
+        let builtin = Builtin {
+            spare: f.fresh().map(Rc::from),
+            f: Rc::new(RefCell::new(f)),
+            library: None,
+        };
+        self.builtins.insert(name.to_owned(), builtin);
+    }
+
+    // keep_loaded keeps library alive for as long as the builtin registered
+    // under name is, for builtins whose code is in a plugin.
+    pub fn keep_loaded(&mut self, name: &str, library: Rc<dyn Any>) {
+        if let Some(builtin) = self.builtins.get_mut(name) {
+            builtin.library = Some(library);
+        }
+    }
+
+    // register_in makes f callable from scripts as `namespace.name`.  Scripts
+    // can also call it by its plain name, as long as no flat builtin or other
+    // namespace uses that name and no variable shadows it.
+    pub fn register_in<F>(&mut self, namespace: &str, name: &str, f: F)
+        where F: NativeFn + 'static
+    {
+        self.register(&format!("{}.{}", namespace, name), f);
+
+        let namespaces = self.namespaced.entry(name.to_owned()).or_insert(vec![]);
+        if !namespaces.iter().any(|ns| ns == namespace) {
+            namespaces.push(namespace.to_owned());
+        }
+    }
+
+    // register_literal lets scripts write literals like `$"5.00"`, where the
+    // text of any string directly after prefix is passed to f to produce the
+    // literal's value.  Scripts need to be parsed with Engine::parser for the
+    // prefix to be recognized.  Registering '#' means `#"` no longer starts a
+    // comment.
+    pub fn register_literal<F>(&mut self, prefix: char, f: F)
+        where F: Fn(&str) -> Result + 'static
+    {
+        self.literals.insert(prefix, Box::new(f));
+    }
+
+    // parser returns a parser for input that recognizes this engine's custom
+    // literals.
+    pub fn parser<'a>(&self, input: &'a str) -> Parser<'a> {
+        Parser::with_prefixes(input, self.literals.keys().cloned().collect())
+    }
+
+    // literal produces the value of a custom literal.
+    pub fn literal(&self, prefix: char, text: &str) -> Result {
+        match self.literals.get(&prefix) {
+            Some(f) => f(text),
+            None => Err(UnknownLiteralPrefix(prefix)),
+        }
+    }
+
+    // allow_only limits scripts to calling the named builtins, so that calling
+    // any other builtin fails with NotAllowed.  Namespaced builtins are named
+    // as `namespace.name`.  Functions defined by scripts can always be called.
+    pub fn allow_only(&mut self, names: &[&str]) {
+        self.allowed = Some(names.iter().map(|&name| name.to_owned()).collect());
+    }
+
+    // set_allowed replaces the builtins scripts may call with allowed, or lets
+    // them call any builtin if it's None, and returns the previous ones.
+    pub fn set_allowed(&mut self, allowed: Option<HashSet<String>>) -> Option<HashSet<String>> {
+        mem::replace(&mut self.allowed, allowed)
+    }
+
+    pub fn is_allowed(&self, name: &str) -> bool {
+        match self.allowed {
+            Some(ref allowed) => allowed.contains(name),
+            None => true,
+        }
+    }
+
+    // is_sandboxed returns whether scripts are limited to an allow-list of
+    // builtins.
+    pub fn is_sandboxed(&self) -> bool {
+        self.allowed.is_some()
+    }
+
+    // add_feature declares that the host supports an optional feature, such as
+    // a group of builtins it registers, so scripts can check for it with
+    // has_feature.
+    pub fn add_feature(&mut self, name: &str) {
+        self.features.insert(name.to_owned());
+    }
+
+    pub fn has_feature(&self, name: &str) -> bool {
+        self.features.contains(name)
+    }
+
+    // check_calls rejects a script before it runs if it calls a builtin that
+    // isn't allowed.  Calls that can only be resolved while the script runs,
+    // such as calls through variables, are still checked when they're made.
+    pub fn check_calls(&self, exprs: &[Expression]) -> result::Result<(), ExecuteError> {
+        let defined = analysis::defined_functions(exprs);
+        for call in analysis::call_graph(exprs) {
+            if defined.contains(&call.callee) {
+                continue;
+            }
+
+            let builtin = match call.callee.find('.') {
+                Some(i) => self.lookup_in(&call.callee[..i], &call.callee[i + 1..]),
+                None => self.lookup(&call.callee),
+            };
+            if let Ok(Data::Builtin(name)) = builtin {
+                if !self.is_allowed(&name) {
+                    return Err(NotAllowed(name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // eval evaluates a top-level expression.  If it's cancelled, the
+    // cancellation is over once it's reported, so the next evaluation runs.
+    // A panic while evaluating, from a bug in gate or in a native function,
+    // is returned as Internal rather than unwinding into the caller, and
+    // leaves only the global scope in use.  Evaluations started by this one,
+    // like those of the eval builtin, leave panics, cancellation and the
+    // error trace for it to deal with.
+    pub fn eval(&self, ctx: &mut Context, e: &Expression) -> Result {
+        let guard = stack::enter();
+        if !guard.is_outermost() {
+            return e.eval(self, ctx);
+        }
+        match panic::catch_unwind(AssertUnwindSafe(|| self.eval_top(ctx, e))) {
+            Ok(res) => res,
+            Err(payload) => {
+                ctx.reset_scopes();
+                Err(Internal(error::panic_message(&*payload)))
+            }
+        }
+    }
+
+    fn eval_top(&self, ctx: &mut Context, e: &Expression) -> Result {
+        ctx.take_error_trace();
+        let res = e.eval(self, ctx);
+        ctx.trace_error(&res, None);
+        if res == Err(Cancelled) {
+            ctx.cancel_token().reset();
+            // A signal cancels the program so that its handler can run here,
+            // after the evaluation it interrupted has stopped.
+            #[cfg(feature = "signals")]
+            signals::run_handlers(self, ctx)?;
+        }
+        res
+    }
+
+    // call calls the function value f with the given arguments.
+    pub fn call(&self, ctx: &mut Context, f: &Data, args: Vec<Data>) -> Result {
+        match f {
+            &Data::Function(ref func) => self.call_func(ctx, func, args),
+            // Calling a struct type creates an instance from a value for each
+            // field.
+            &Data::Type(ref t) => {
+                if args.len() != t.fields.len() {
+                    return Err(WrongArgCount {
+                        expected: t.fields.len(),
+                        found: args.len(),
+                    });
+                }
+                Ok(t.new_instance(args))
+            }
+            &Data::Builtin(ref name) => {
+                ctx.audit_call(name, &args);
+                if !self.is_allowed(name) {
+                    return Err(NotAllowed(name.clone()));
+                }
+
+                let (f, spare) = match self.builtins.get(name) {
+                    Some(builtin) => (builtin.f.clone(), builtin.spare.clone()),
+                    None => return Err(UndefinedFunc(name.clone())),
+                };
+
+                let res = match f.try_borrow_mut() {
+                    Ok(mut f) => f.call(self, ctx, &args),
+                    Err(_) => {
+                        match spare.and_then(|spare| spare.fresh()) {
+                            Some(mut f) => f.call(self, ctx, &args),
+                            None => Err(ReentrantCall(name.clone())),
+                        }
+                    }
+                };
+                res
+            }
+            d => Err(NotCallable(d.type_name())),
+        }
+    }
+
+    fn call_func(&self, ctx: &mut Context, f: &Function, args: Vec<Data>) -> Result {
+        ctx.check_cancelled()?;
+        if args.len() != f.params.len() {
+            return Err(WrongArgCount {
+                expected: f.params.len(),
+                found: args.len(),
+            });
+        }
+
+        // The body sees the global scope, the scopes the function was defined
+        // in and its own parameters, but not the caller's local variables.
+        let hidden = ctx.enter_call(&f.env);
+        for (param, arg) in f.params.iter().zip(args) {
+            ctx.declare_var(param, arg);
+        }
+        let caller = ctx.set_source(f.source);
+        let res = f.body.eval(self, ctx);
+        ctx.set_source(caller);
+        ctx.trace_error(&res, Some(&f.name));
+        ctx.exit_call(hidden);
+
+        // A span in the error is in the source the function was parsed from,
+        // so it means nothing to a caller parsed from another one.
+        if f.source != caller {
+            return res.map_err(|e| e.without_span());
+        }
+        res
+    }
+
+    // lookup returns the builtin that a plain name refers to.
+    pub fn lookup(&self, name: &str) -> Result {
+        if self.builtins.contains_key(name) {
+            return Ok(Data::Builtin(name.to_owned()));
+        }
+
+        match self.namespaced.get(name) {
+            Some(namespaces) if namespaces.len() == 1 => {
+                Ok(Data::Builtin(format!("{}.{}", namespaces[0], name)))
+            }
+            Some(namespaces) => {
+                Err(AmbiguousName {
+                    name: name.to_owned(),
+                    namespaces: namespaces.clone(),
+                })
+            }
+            None => Err(UndefinedVar(name.to_owned())),
+        }
+    }
+
+    // lookup_in returns the builtin registered as `namespace.name`.
+    pub fn lookup_in(&self, namespace: &str, name: &str) -> Result {
+        let full_name = format!("{}.{}", namespace, name);
+        if self.builtins.contains_key(&full_name) {
+            Ok(Data::Builtin(full_name))
+        } else {
+            Err(UndefinedFunc(full_name))
+        }
+    }
+
+    pub fn is_namespace(&self, namespace: &str) -> bool {
+        self.namespaced.values().any(|namespaces| namespaces.iter().any(|ns| ns == namespace))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use context::Context;
+    use data::Data;
+    use data::Data::*;
+    use expr::Expression::*;
+    use expr::Result;
+    use super::*;
+
+    struct Counter {
+        calls: f64,
+    }
+
+    impl NativeFn for Counter {
+        fn call(&mut self, _: &Engine, ctx: &mut Context, args: &[Data]) -> Result {
+            self.calls += 1.0;
+            ctx.set_var("calls", Number(self.calls));
+            Ok(args.get(0).cloned().unwrap_or(Nil))
+        }
+    }
+
+    fn call(name: &str, args: Vec<Expression>) -> Expression {
+        FunctionCall {
+            func: Box::new(Variable(name.to_owned())),
+            args: args,
+        }
+    }
+
+    #[test]
+    fn test_native_fn_state() {
+        let mut engine = Engine::new();
+        engine.register("count", Counter { calls: 0.0 });
+
+        let mut ctx = Context::new();
+        engine.eval(&mut ctx, &call("count", vec![])).unwrap();
+        assert_eq!(engine.eval(&mut ctx, &call("count", vec![NumberLiteral(5.0)])),
+                   Ok(Number(5.0)));
+        assert_eq!(ctx.var("calls"), Some(Number(2.0)));
+
+        // Arguments are evaluated before the outer call borrows its state.
+        let nested = call("count", vec![call("count", vec![])]);
+        assert_eq!(engine.eval(&mut ctx, &nested), Ok(Nil));
+        assert_eq!(ctx.var("calls"), Some(Number(4.0)));
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let mut engine = Engine::new();
+        engine.register_in("math", "double", |args: &[Data]| match args[0] {
+            Number(n) => Ok(Number(n * 2.0)),
+            _ => Ok(Nil),
+        });
+        engine.register_in("math", "pi", |_: &[Data]| Ok(Number(3.0)));
+        engine.register_in("astro", "pi", |_: &[Data]| Ok(Number(4.0)));
+
+        let ns_call = |ns: &str, name: &str| {
+            FunctionCall {
+                func: Box::new(FieldAccess {
+                    object: Box::new(Variable(ns.to_owned())),
+                    field: name.to_owned(),
+                }),
+                args: vec![NumberLiteral(1.0)],
+            }
+        };
+
+        let mut ctx = Context::new();
+        assert_eq!(engine.eval(&mut ctx, &ns_call("math", "double")), Ok(Number(2.0)));
+        assert_eq!(engine.eval(&mut ctx, &call("double", vec![NumberLiteral(2.0)])),
+                   Ok(Number(4.0)));
+        assert_eq!(engine.eval(&mut ctx, &ns_call("astro", "pi")), Ok(Number(4.0)));
+        assert_eq!(engine.eval(&mut ctx, &call("pi", vec![])),
+                   Err(AmbiguousName {
+                       name: "pi".to_owned(),
+                       namespaces: vec!["math".to_owned(), "astro".to_owned()],
+                   }));
+        assert_eq!(engine.eval(&mut ctx, &ns_call("math", "nope")),
+                   Err(UndefinedFunc("math.nope".to_owned())));
+
+        // A variable shadows the plain name, but not the namespaced one.
+        ctx.set_var("double", Nil);
+        assert_eq!(engine.eval(&mut ctx, &ns_call("math", "double")), Ok(Number(2.0)));
+        assert_eq!(engine.eval(&mut ctx, &call("double", vec![NumberLiteral(2.0)])),
+                   Err(NotCallable("nil".to_owned())));
+
+        // So does a variable with the namespace's name.
+        ctx.set_var("math", Number(1.0));
+        assert_eq!(engine.eval(&mut ctx, &ns_call("math", "double")),
+                   Err(UndefinedField {
+                       type_name: "number".to_owned(),
+                       field: "double".to_owned(),
+                   }));
+    }
+
+    #[test]
+    fn test_reentrant_call() {
+        struct Reenter;
+        impl NativeFn for Reenter {
+            fn call(&mut self, engine: &Engine, ctx: &mut Context, _: &[Data]) -> Result {
+                engine.eval(ctx, &call("reenter", vec![]))
+            }
+        }
+
+        let mut engine = Engine::new();
+        engine.register("reenter", Reenter);
+        let mut ctx = Context::new();
+        assert_eq!(engine.eval(&mut ctx, &call("reenter", vec![])),
+                   Err(ReentrantCall("reenter".to_owned())));
+    }
+
+    #[test]
+    fn test_shared_engine() {
+        let engine = Engine::new();
+        let mut a = Context::new();
+        let mut b = Context::new();
+
+        let assign = Assignment {
+            left: "x".to_owned(),
+            right: Box::new(NumberLiteral(1.0)),
+        };
+        assert_eq!(engine.eval(&mut a, &assign), Ok(Number(1.0)));
+
+        assert_eq!(a.var("x"), Some(Number(1.0)));
+        assert_eq!(b.var("x"), None);
+        assert!(engine.eval(&mut b, &Variable("x".to_owned())).is_err());
+    }
+
+    #[test]
+    fn test_allow_only() {
+        let mut engine = Engine::new();
+        engine.register_in("math", "double", |args: &[Data]| match args[0] {
+            Number(n) => Ok(Number(n * 2.0)),
+            _ => Ok(Nil),
+        });
+        engine.allow_only(&["list", "math.double"]);
+
+        let parse = |source: &str| -> Vec<Expression> {
+            Parser::new(source).map(|e| e.unwrap()).collect()
+        };
+        assert_eq!(engine.check_calls(&parse("list(double(1), math.double(2))")), Ok(()));
+        assert_eq!(engine.check_calls(&parse("fn push(l) { l }  push(list())")), Ok(()));
+        assert_eq!(engine.check_calls(&parse("fn f(l) { push(l, 1) }")),
+                   Err(NotAllowed("push".to_owned())));
+
+        // Calls the static check can't see are caught when they're made.
+        let exprs = parse("p = push  p(list(), 1)");
+        assert_eq!(engine.check_calls(&exprs), Ok(()));
+        let mut ctx = Context::new();
+        engine.eval(&mut ctx, &exprs[0]).unwrap();
+        assert_eq!(engine.eval(&mut ctx, &exprs[1]), Err(NotAllowed("push".to_owned())));
+    }
+
+    #[test]
+    fn test_has_feature() {
+        let mut engine = Engine::new();
+        let mut ctx = Context::new();
+        let has = |engine: &Engine, ctx: &mut Context, name: &str| {
+            engine.eval(ctx, &call("has_feature", vec![StrLiteral(name.into())]))
+        };
+
+        assert_eq!(engine.eval(&mut ctx, &call("version", vec![])),
+                   Ok(Str(env!("CARGO_PKG_VERSION").into())));
+        for name in vec!["sandbox", "audit", "regex"] {
+            assert_eq!(has(&engine, &mut ctx, name), Ok(Boolean(false)));
+        }
+
+        engine.add_feature("regex");
+        engine.allow_only(&["has_feature"]);
+        ctx.enable_audit(&[]);
+        for name in vec!["sandbox", "audit", "regex"] {
+            assert_eq!(has(&engine, &mut ctx, name), Ok(Boolean(true)));
+        }
+        assert_eq!(has(&engine, &mut ctx, "sql"), Ok(Boolean(false)));
+    }
+
+    #[test]
+    fn test_custom_literals() {
+        let mut engine = Engine::new();
+        engine.register_literal('$', |text: &str| match text.parse() {
+            Ok(n) => Ok(Number(n)),
+            Err(_) => Ok(Nil),
+        });
+
+        let mut ctx = Context::new();
+        let exprs: Vec<_> = engine.parser(r#"$"5.25" + 1  $"oops""#).map(|e| e.unwrap()).collect();
+        assert_eq!(engine.eval(&mut ctx, &exprs[0]), Ok(Number(6.25)));
+        assert_eq!(engine.eval(&mut ctx, &exprs[1]), Ok(Nil));
+
+        // A plain parser doesn't know about the prefix.
+        assert!(Parser::new(r#"$"5.25""#).next().unwrap().is_err());
+
+        let lit = CustomLiteral {
+            prefix: '@',
+            text: "x".to_owned(),
+        };
+        assert_eq!(engine.eval(&mut ctx, &lit), Err(UnknownLiteralPrefix('@')));
+    }
+}
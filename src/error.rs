@@ -1,7 +1,15 @@
+use std::any::Any;
+use std::error;
 use std::fmt;
 
+use serde::{de, ser};
+
 use binary_op::BinaryOp;
+use cst::Span;
+use data::Data;
+use plugin;
 use scanner::Token;
+use unary_op::UnaryOp;
 
 use self::ExecuteError::*;
 
@@ -9,11 +17,132 @@ use self::ExecuteError::*;
 pub enum ExecuteError {
     UndefinedVar(String),
     UndefinedFunc(String),
+    ReentrantCall(String),
+    NotCallable(String),
+    // The builtin isn't in the engine's list of allowed functions.
+    NotAllowed(String),
+    NotIterable(String),
+    UnknownLiteralPrefix(char),
+    AmbiguousName { name: String, namespaces: Vec<String> },
+    UndefinedField { type_name: String, field: String },
+    // Break and Continue carry "break" and "continue" up to the enclosing loop.
+    Break,
+    Continue,
+    // left and right are the operands' type names.  values holds the operands
+    // themselves, rendered and truncated, and span is where the operation is
+    // in the source, when they're known.
     InvalidOperation {
         left: String,
         op: BinaryOp,
         right: String,
+        values: Option<(String, String)>,
+        span: Option<Span>,
     },
+    InvalidUnaryOperation { op: UnaryOp, operand: String },
+    WrongArgCount { expected: usize, found: usize },
+    InvalidArgument { expected: String, found: String },
+    IndexOutOfRange { index: f64, len: usize },
+    InvalidIndex { type_name: String, index: String },
+    InvalidTemplate(String),
+    InvalidNumber(String),
+    // The script assigned to a variable declared with const.
+    AssignToConst(String),
+    // The script assigned to or redefined a read-only global installed by the
+    // host.
+    ReadOnly(String),
+    // Reading or writing outside the program failed.
+    Io(String),
+    // Thrown carries a value thrown with "throw" up to the enclosing try.
+    Thrown(Data),
+    // Exit carries the status passed to exit up to the host, past any try,
+    // ending the program.
+    Exit(i32),
+    // An assert or assert_eq didn't hold.  It says what was expected.
+    AssertionFailed(String),
+    // The program was cancelled with its CancelToken or by a signal.  Like
+    // Exit, it goes past any try.
+    Cancelled,
+    // A database refused a query or statement.  It holds the database's
+    // message.
+    Database(String),
+    // Calls or expressions were nested so deeply that going on would use more
+    // stack than stack::limit allows.
+    TooDeep,
+    // The interpreter panicked.  It becomes Error::Internal when it reaches a
+    // Program.
+    Internal(String),
+}
+
+impl ExecuteError {
+    // code returns the error's code, which `gate explain` describes.  Codes
+    // never change or get reused, so they can be searched for and matched on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            &UndefinedVar(_) => "E0007",
+            &UndefinedFunc(_) => "E0008",
+            &ReentrantCall(_) => "E0009",
+            &NotCallable(_) => "E0010",
+            &NotAllowed(_) => "E0011",
+            &NotIterable(_) => "E0012",
+            &UnknownLiteralPrefix(_) => "E0013",
+            &AmbiguousName { .. } => "E0014",
+            &UndefinedField { .. } => "E0015",
+            &Break => "E0016",
+            &Continue => "E0017",
+            &InvalidOperation { .. } => "E0018",
+            &InvalidUnaryOperation { .. } => "E0019",
+            &WrongArgCount { .. } => "E0020",
+            &InvalidArgument { .. } => "E0021",
+            &IndexOutOfRange { .. } => "E0022",
+            &InvalidIndex { .. } => "E0023",
+            &InvalidTemplate(_) => "E0024",
+            &InvalidNumber(_) => "E0025",
+            &AssignToConst(_) => "E0026",
+            &ReadOnly(_) => "E0027",
+            &Io(_) => "E0028",
+            &Thrown(_) => "E0029",
+            &Exit(_) => "E0038",
+            &AssertionFailed(_) => "E0039",
+            &Cancelled => "E0040",
+            &Database(_) => "E0041",
+            &TooDeep => "E0042",
+            &Internal(_) => "E0034",
+        }
+    }
+
+    // at gives an InvalidOperation without a span the given one.  Other errors
+    // are returned as they are.
+    pub fn at(self, span: Option<Span>) -> ExecuteError {
+        match self {
+            InvalidOperation { left, op, right, values, span: None } => {
+                InvalidOperation {
+                    left: left,
+                    op: op,
+                    right: right,
+                    values: values,
+                    span: span,
+                }
+            }
+            e => e,
+        }
+    }
+
+    // without_span takes the span off an InvalidOperation, for when it's
+    // passed somewhere the span would point into the wrong source.
+    pub fn without_span(self) -> ExecuteError {
+        match self {
+            InvalidOperation { left, op, right, values, .. } => {
+                InvalidOperation {
+                    left: left,
+                    op: op,
+                    right: right,
+                    values: values,
+                    span: None,
+                }
+            }
+            e => e,
+        }
+    }
 }
 
 impl fmt::Display for ExecuteError {
@@ -21,8 +150,79 @@ impl fmt::Display for ExecuteError {
         match self {
             &UndefinedVar(ref s) => write!(f, "undefined variable \"{}\"", s),
             &UndefinedFunc(ref s) => write!(f, "undefined function \"{}\"", s),
-            &InvalidOperation { ref left, ref op, ref right } => {
-                write!(f, "invalid operation ({} {} {})", left, op, right)
+            &ReentrantCall(ref s) => write!(f, "function \"{}\" called itself", s),
+            &NotCallable(ref s) => write!(f, "can't call a value of type {}", s),
+            &NotAllowed(ref s) => write!(f, "calling \"{}\" isn't allowed", s),
+            &NotIterable(ref s) => write!(f, "can't loop over a value of type {}", s),
+            &UnknownLiteralPrefix(c) => write!(f, "no literal registered for prefix '{}'", c),
+            &AmbiguousName { ref name, ref namespaces } => {
+                write!(f,
+                       "ambiguous name \"{}\" (found in namespaces {})",
+                       name,
+                       namespaces.join(", "))
+            }
+            &UndefinedField { ref type_name, ref field } => {
+                write!(f, "undefined field \"{}\" on {}", field, type_name)
+            }
+            &Break => write!(f, "break outside of a loop"),
+            &Continue => write!(f, "continue outside of a loop"),
+            &InvalidOperation { ref left, ref op, ref right, ref values, .. } => {
+                write!(f, "invalid operation ({} {} {})", left, op, right)?;
+                match values {
+                    &Some((ref l, ref r)) => write!(f, ": {} {} {}", l, op, r),
+                    &None => Ok(()),
+                }
+            }
+            &InvalidUnaryOperation { ref op, ref operand } => {
+                write!(f, "invalid operation ({}{})", op, operand)
+            }
+            &WrongArgCount { expected, found } => {
+                write!(f, "wrong number of arguments (expected {}, found {})", expected, found)
+            }
+            &InvalidArgument { ref expected, ref found } => {
+                write!(f, "invalid argument (expected {}, found {})", expected, found)
+            }
+            &IndexOutOfRange { index, len } => {
+                write!(f, "index {} out of range for length {}", index, len)
+            }
+            &InvalidIndex { ref type_name, ref index } => {
+                write!(f, "can't index {} with {}", type_name, index)
+            }
+            &InvalidTemplate(ref s) => write!(f, "invalid template: {}", s),
+            &InvalidNumber(ref s) => write!(f, "can't parse \"{}\" as a number", s),
+            &AssignToConst(ref s) => write!(f, "can't assign to constant \"{}\"", s),
+            &ReadOnly(ref s) => write!(f, "can't assign to read-only global \"{}\"", s),
+            &Io(ref s) => write!(f, "i/o error: {}", s),
+            &Thrown(ref d) => write!(f, "uncaught error: {}", d),
+            &Exit(status) => write!(f, "exited with status {}", status),
+            &AssertionFailed(ref s) => write!(f, "assertion failed: {}", s),
+            &Cancelled => write!(f, "evaluation was cancelled"),
+            &Database(ref s) => write!(f, "database error: {}", s),
+            &TooDeep => write!(f, "recursion too deep"),
+            &Internal(ref s) => write!(f, "internal error: {}", s),
+        }
+    }
+}
+
+// Warnings point out likely mistakes that don't stop a program from running.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Warning {
+    ShadowedBuiltin(String),
+}
+
+impl Warning {
+    pub fn code(&self) -> &'static str {
+        match self {
+            &Warning::ShadowedBuiltin(_) => "E0035",
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Warning::ShadowedBuiltin(ref s) => {
+                write!(f, "function \"{}\" shadows a builtin of the same name", s)
             }
         }
     }
@@ -33,11 +233,251 @@ pub enum ParseError {
     ScanError(TokenError),
     Unexpected(Token),
     UnexpectedEOF,
+    // Expressions were nested so deeply that parsing them would use more
+    // stack than stack::limit allows.
+    TooDeep,
+    // The parser panicked.  It becomes Error::Internal when it reaches a
+    // Program.
+    Internal(String),
+}
+
+impl ParseError {
+    // is_incomplete returns whether the error was caused by the input ending
+    // too early, in which case more input could still make it valid.
+    pub fn is_incomplete(&self) -> bool {
+        match self {
+            &ParseError::UnexpectedEOF => true,
+            &ParseError::ScanError(TokenError::IncompleteString) => true,
+            _ => false,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            &ParseError::ScanError(TokenError::UnexpectedChar(_)) => "E0001",
+            &ParseError::ScanError(TokenError::IncompleteString) => "E0002",
+            &ParseError::ScanError(TokenError::InvalidEscape(_)) => "E0003",
+            &ParseError::ScanError(TokenError::InvalidNumber(_)) => "E0004",
+            &ParseError::Unexpected(_) => "E0005",
+            &ParseError::UnexpectedEOF => "E0006",
+            &ParseError::TooDeep => "E0043",
+            &ParseError::Internal(_) => "E0034",
+        }
+    }
 }
 
 #[derive(Clone,Debug,PartialEq)]
 pub enum TokenError {
     UnexpectedChar(char),
     IncompleteString,
-    InvalidEscape,
+    // A backslash in a string followed by something other than a known
+    // escape.  It holds the escape as written, as in `\q`.
+    InvalidEscape(String),
+    // A hex, octal or binary int with no digits or too many to fit.
+    InvalidNumber(String),
+}
+
+// ConvertError is returned when a value can't be converted between gate data
+// and a Rust type.
+#[derive(Clone,Debug,PartialEq)]
+pub enum ConvertError {
+    UndefinedVar(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ConvertError::UndefinedVar(ref s) => write!(f, "undefined variable \"{}\"", s),
+            &ConvertError::Invalid(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl error::Error for ConvertError {}
+
+impl de::Error for ConvertError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        ConvertError::Invalid(msg.to_string())
+    }
+}
+
+impl ser::Error for ConvertError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        ConvertError::Invalid(msg.to_string())
+    }
+}
+
+// Error is returned when running source from start to finish, which can fail
+// while parsing or while evaluating.  Internal means the interpreter itself
+// panicked, which is always a bug in gate or in a native function.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Error {
+    Parse(ParseError),
+    Execute(ExecuteError),
+    Load(LoadError),
+    Internal(String),
+}
+
+// Errors are displayed with their code, as in `error[E0007]: undefined variable
+// "x"`.
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error[{}]: {}", self.code(), self.message())
+    }
+}
+
+impl error::Error for Error {}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            &Error::Parse(ref e) => e.code(),
+            &Error::Execute(ref e) => e.code(),
+            &Error::Load(ref e) => e.code(),
+            &Error::Internal(_) => "E0034",
+        }
+    }
+
+    // message describes the error without its code.
+    pub fn message(&self) -> String {
+        match self {
+            &Error::Parse(ref e) => format!("can't parse source: {:?}", e),
+            &Error::Execute(ref e) => e.to_string(),
+            &Error::Load(ref e) => e.to_string(),
+            &Error::Internal(ref s) => format!("internal error: {}", s),
+        }
+    }
+
+    // span returns where in the source the error happened, if it's known.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            &Error::Execute(ExecuteError::InvalidOperation { span, .. }) => span,
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        match e {
+            ParseError::Internal(s) => Error::Internal(s),
+            e => Error::Parse(e),
+        }
+    }
+}
+
+impl From<ExecuteError> for Error {
+    fn from(e: ExecuteError) -> Self {
+        match e {
+            Internal(s) => Error::Internal(s),
+            e => Error::Execute(e),
+        }
+    }
+}
+
+// panic_message returns the message a panic was started with.
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        return (*s).to_owned();
+    }
+    match payload.downcast_ref::<String>() {
+        Some(s) => s.clone(),
+        None => "unknown panic".to_owned(),
+    }
+}
+
+impl From<LoadError> for Error {
+    fn from(e: LoadError) -> Self {
+        Error::Load(e)
+    }
+}
+
+// LoadError is returned when a compiled program can't be read.
+#[derive(Clone,Debug,PartialEq)]
+pub enum LoadError {
+    // The input doesn't start with the header of a compiled program.
+    NotCompiled,
+    // The program was compiled by a version of gate using another format.
+    UnsupportedVersion(u16),
+    // The program was compiled by another version of gate, given here.
+    WrongGateVersion(String),
+    // The input is truncated or was changed after it was compiled.
+    Corrupt,
+}
+
+impl LoadError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            &LoadError::NotCompiled => "E0030",
+            &LoadError::UnsupportedVersion(_) => "E0031",
+            &LoadError::WrongGateVersion(_) => "E0032",
+            &LoadError::Corrupt => "E0033",
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &LoadError::NotCompiled => write!(f, "not a compiled gate program"),
+            &LoadError::UnsupportedVersion(v) => {
+                write!(f, "unsupported compiled format version {}", v)
+            }
+            &LoadError::WrongGateVersion(ref v) => {
+                write!(f, "program was compiled by gate {}, so it must be compiled again", v)
+            }
+            &LoadError::Corrupt => write!(f, "compiled program is corrupt"),
+        }
+    }
+}
+
+// PluginError is returned when a plugin can't be loaded.
+#[derive(Clone,Debug,PartialEq)]
+pub enum PluginError {
+    // The library couldn't be opened, or doesn't export the functions that
+    // gate_plugin! declares.
+    Open(String),
+    // The plugin was built for another version of gate, or with other
+    // features.  It holds the version and features it was built for.
+    WrongAbi(String),
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &PluginError::Open(ref s) => write!(f, "can't load plugin: {}", s),
+            &PluginError::WrongAbi(ref abi) => {
+                write!(f,
+                       "plugin was built for gate {} rather than {}, so it must be built again",
+                       abi,
+                       plugin::ABI.trim_end_matches('\0'))
+            }
+        }
+    }
+}
+
+impl error::Error for PluginError {}
+
+// RefactorError is returned when a refactoring can't be applied to a source.
+#[derive(Clone,Debug,PartialEq)]
+pub enum RefactorError {
+    Parse(ParseError),
+    // The span doesn't point at a variable.
+    NotAVariable,
+    InvalidName(String),
+    // The new name is already used in the source, so renaming to it could
+    // change what other code refers to.
+    NameInUse(String),
+}
+
+impl fmt::Display for RefactorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &RefactorError::Parse(ref e) => write!(f, "can't parse source: {:?}", e),
+            &RefactorError::NotAVariable => write!(f, "no variable at the given position"),
+            &RefactorError::InvalidName(ref s) => write!(f, "\"{}\" isn't a valid name", s),
+            &RefactorError::NameInUse(ref s) => write!(f, "the name \"{}\" is already in use", s),
+        }
+    }
 }
@@ -0,0 +1,356 @@
+// The explain module holds the longer descriptions of error codes printed by
+// `gate explain`.  Every error, warning and lint has a code, and a code always
+// means the same thing, so codes that are no longer used keep their entry.
+
+const EXPLANATIONS: &[(&str, &str)] = &[
+    ("E0001",
+     "The source contains a character that isn't part of gate's syntax.
+
+    x = 1 @ 2
+
+Check for a stray character or one that belongs inside a string."),
+    ("E0002",
+     "A string isn't closed before the end of the source.
+
+    greeting = \"hello
+
+Add the closing quote.  Strings can't span lines, so use \\n for a newline."),
+    ("E0003",
+     "A string contains a backslash followed by something that isn't an escape.
+
+    path = \"C:\\dir\"
+
+The escapes are \\n, \\t, \\r, \\\\, \\\" and \\0.  Write \\\\ for a backslash."),
+    ("E0004",
+     "A hex, octal or binary int has no digits, or more than fit in an int.
+
+    mask = 0x
+
+Ints hold 64 bits, so a larger constant has to be a number instead."),
+    ("E0005",
+     "The parser found a token where it doesn't belong, such as an operator with
+nothing before it or a missing comma.
+
+    m = { a: 1 b: 2 }
+
+Check the code just before the token for something missing."),
+    ("E0006",
+     "The source ended in the middle of an expression, usually because a bracket,
+brace or parenthesis isn't closed.
+
+    total = sum(list(1, 2)
+
+In the REPL, an incomplete line waits for more input instead."),
+    ("E0007",
+     "A variable was used before anything was assigned to it.
+
+    > y = x + 1
+    error[E0007]: undefined variable \"x\"
+
+Check the name's spelling, and that it's assigned in a scope the use can see."),
+    ("E0008",
+     "A function was called that isn't defined by the script or the host.
+
+    > dobule(2)
+    error[E0008]: undefined function \"dobule\"
+
+Functions have to be defined before they're called."),
+    ("E0009",
+     "A native function called back into the program, which then called the same
+native function while it was still running.  Native functions can't be
+reentered.
+
+This usually means a callback passed to a host function calls that function
+again."),
+    ("E0010",
+     "Something that isn't a function was called.
+
+    > x = 5
+    > x(1)
+    error[E0010]: can't call a value of type int"),
+    ("E0011",
+     "The host only allows some builtins, and the script called one that isn't
+among them.
+
+Scripts also can't call read_file or write_file unless the host lets them
+with Program::allow_fs."),
+    ("E0012",
+     "A for loop was given a value it can't loop over.
+
+    > for x in 5 { println(x) }
+    error[E0012]: can't loop over a value of type int
+
+Lists, arrays, maps, sets, ranges and sequences can be looped over.
+Use a range such as 0..5 to count."),
+    ("E0013",
+     "A prefixed literal such as $\"5.00\" uses a prefix the host hasn't
+registered.
+
+Only the prefixes the host registers with Engine::register_literal work."),
+    ("E0014",
+     "A name without a namespace is defined in more than one imported namespace,
+so it isn't clear which was meant.
+
+Call it with its namespace, as in math.abs(x)."),
+    ("E0015",
+     "A struct doesn't have the field that was read or assigned.
+
+    > struct Point { x, y }
+    > Point(1, 2).z
+    error[E0015]: undefined field \"z\" on Point"),
+    ("E0016",
+     "A break ran outside of any loop.  The parser only accepts break inside a
+loop, so this only happens with expressions the host built itself."),
+    ("E0017",
+     "A continue ran outside of any loop, like break in E0016."),
+    ("E0018",
+     "A binary operator was used with operands it doesn't support.
+
+    > 1 + \"two\"
+    error[E0018]: invalid operation (int + string): 1 + \"two\"
+
+The message gives the operands' types and values.  Convert one of them first,
+as in str(1) or num(\"2\")."),
+    ("E0019",
+     "A unary operator was used with an operand it doesn't support.
+
+    > -\"a\"
+    error[E0019]: invalid operation (-string)"),
+    ("E0020",
+     "A function was called with the wrong number of arguments.
+
+    > fn double(n) n * 2
+    > double(1, 2)
+    error[E0020]: wrong number of arguments (expected 1, found 2)
+
+Parameters with defaults can be left out."),
+    ("E0021",
+     "A builtin was given an argument it can't use.
+
+    > max(1, \"a\")
+    error[E0021]: invalid argument (expected number, found string)"),
+    ("E0022",
+     "An index is past the end of a list, array or string.
+
+    > list(1, 2, 3)[5]
+    error[E0022]: index 5 out of range for length 3
+
+Indexes count from 0."),
+    ("E0023",
+     "A value was indexed with something it can't be indexed by, such as a list
+with a string, or something that can't be indexed at all.
+
+    > n = 5
+    > n[0]
+    error[E0023]: can't index int with int"),
+    ("E0024",
+     "A template passed to render isn't valid, because a {{ isn't closed, the map
+has no value for what's between {{ and }}, or, when evaluating expressions,
+it can't be parsed or uses a name that isn't in the map.
+
+    > render(\"Hi {{ name\", { name: \"Ann\" })
+    error[E0024]: invalid template: unclosed \"{{\""),
+    ("E0025",
+     "A string couldn't be parsed as a number.
+
+    > num(\"abc\", true)
+    error[E0025]: can't parse \"abc\" as a number
+
+Without the strict flag, num gives nil instead."),
+    ("E0026",
+     "A variable declared with const was assigned to.
+
+    > const limit = 10
+    > limit = 20
+    error[E0026]: can't assign to constant \"limit\""),
+    ("E0027",
+     "The script assigned to or redefined a global the host made read-only."),
+    ("E0028",
+     "Reading or writing outside the program failed, as when stdin is closed or
+output can't be written.  The message holds the error from the system."),
+    ("E0029",
+     "A value was thrown with throw and no try caught it.
+
+    > throw 1
+    error[E0029]: uncaught error: 1
+
+Wrap the code in try { ... } catch e { ... } to handle it."),
+    ("E0030",
+     "A file given as a compiled program doesn't start with the header written by
+gate compile."),
+    ("E0031",
+     "A compiled program uses a format this version of gate can't read.  Compile
+the source again."),
+    ("E0032",
+     "A compiled program was compiled by another version of gate.  Compile the
+source again with this one."),
+    ("E0033",
+     "A compiled program is truncated or was changed after it was compiled, so its
+checksum doesn't match."),
+    ("E0034",
+     "The interpreter panicked while running the program.  This is always a bug,
+either in gate or in a native function the host registered."),
+    ("E0035",
+     "Warning: a function was defined with the same name as a builtin, which it
+hides from then on.
+
+    fn len(x) 0
+
+Rename the function unless hiding the builtin is intended."),
+    ("E0036",
+     "Lint: a function is never called, either from the top level of the program
+or from functions that are."),
+    ("E0037",
+     "Lint: the condition of an if or while always has the same value, so one of
+its branches never runs.
+
+    if false { println(\"never\") }"),
+    ("E0038",
+     "The script called exit, which ends the program with the given status.  The
+gate command exits with that status, while hosts get this error and decide
+what to do.  try can't catch it.
+
+    if len(items) == 0 { exit(2) }"),
+    ("E0039",
+     "An assert or assert_eq in a script didn't hold.
+
+    > assert_eq(1 + 1, 3)
+    error[E0039]: assertion failed: expected Int(3), found Int(2)
+
+gate test reports these as failed tests."),
+    ("E0040",
+     "The program was stopped while it ran, either by the host through its
+CancelToken or by a signal the script handles with on_signal.  Loops and
+function calls check for it, and sleep wakes up early.  try can't catch it.
+
+Once it's reported, the program can run again."),
+    ("E0041",
+     "A database rejected a query or statement passed to query or execute, such as
+one with a syntax error, a missing table, or the wrong number of params.  The
+message holds the error from the database.
+
+    > query(db, \"SELECT * FROM nope\")
+    error[E0041]: database error: no such table: nope"),
+    ("E0042",
+     "The program recursed so deeply that going on would risk overflowing the
+stack, which would crash the host.  It usually means a function calls itself
+with no case that stops it.
+
+    > fn f(n) f(n + 1)
+    > f(0)
+    error[E0042]: recursion too deep
+
+Hosts that give gate a bigger stack can raise the limit with
+gate::stack::set_limit."),
+    ("E0043",
+     "The source nests expressions, such as parentheses, blocks or chains of
+operators, so deeply that parsing it would risk overflowing the stack."),
+];
+
+// explain returns the description of the code, which may be in lower case.
+pub fn explain(code: &str) -> Option<&'static str> {
+    let code = code.to_uppercase();
+    EXPLANATIONS.iter().find(|&&(c, _)| c == code).map(|&(_, text)| text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use analysis::{Branch, Lint};
+    use binary_op::BinaryOp;
+    use data::Data;
+    use error::{Error, ExecuteError, LoadError, ParseError, TokenError, Warning};
+    use scanner::Token;
+    use unary_op::UnaryOp;
+
+    #[test]
+    fn test_every_code_explained() {
+        let errors = vec![ParseError::ScanError(TokenError::UnexpectedChar('@')).into(),
+                          ParseError::ScanError(TokenError::IncompleteString).into(),
+                          ParseError::ScanError(TokenError::InvalidEscape("\\q".to_owned()))
+                              .into(),
+                          ParseError::ScanError(TokenError::InvalidNumber("0x".to_owned()))
+                              .into(),
+                          ParseError::Unexpected(Token::Comma).into(),
+                          ParseError::UnexpectedEOF.into(),
+                          ParseError::TooDeep.into(),
+                          LoadError::NotCompiled.into(),
+                          LoadError::UnsupportedVersion(0).into(),
+                          LoadError::WrongGateVersion(String::new()).into(),
+                          LoadError::Corrupt.into(),
+                          Error::Internal(String::new())];
+        let s = || String::new();
+        let execute_errors = vec![ExecuteError::UndefinedVar(s()),
+                                  ExecuteError::UndefinedFunc(s()),
+                                  ExecuteError::ReentrantCall(s()),
+                                  ExecuteError::NotCallable(s()),
+                                  ExecuteError::NotAllowed(s()),
+                                  ExecuteError::NotIterable(s()),
+                                  ExecuteError::UnknownLiteralPrefix('d'),
+                                  ExecuteError::AmbiguousName {
+                                      name: s(),
+                                      namespaces: vec![],
+                                  },
+                                  ExecuteError::UndefinedField {
+                                      type_name: s(),
+                                      field: s(),
+                                  },
+                                  ExecuteError::Break,
+                                  ExecuteError::Continue,
+                                  ExecuteError::InvalidOperation {
+                                      left: s(),
+                                      op: BinaryOp::Add,
+                                      right: s(),
+                                      values: None,
+                                      span: None,
+                                  },
+                                  ExecuteError::InvalidUnaryOperation {
+                                      op: UnaryOp::Neg,
+                                      operand: s(),
+                                  },
+                                  ExecuteError::WrongArgCount {
+                                      expected: 0,
+                                      found: 0,
+                                  },
+                                  ExecuteError::InvalidArgument {
+                                      expected: s(),
+                                      found: s(),
+                                  },
+                                  ExecuteError::IndexOutOfRange { index: 0.0, len: 0 },
+                                  ExecuteError::InvalidIndex {
+                                      type_name: s(),
+                                      index: s(),
+                                  },
+                                  ExecuteError::InvalidTemplate(s()),
+                                  ExecuteError::InvalidNumber(s()),
+                                  ExecuteError::AssignToConst(s()),
+                                  ExecuteError::ReadOnly(s()),
+                                  ExecuteError::Io(s()),
+                                  ExecuteError::Thrown(Data::Nil),
+                                  ExecuteError::Exit(0),
+                                  ExecuteError::AssertionFailed(s()),
+                                  ExecuteError::Cancelled,
+                                  ExecuteError::Database(s()),
+                                  ExecuteError::TooDeep];
+
+        let mut codes: Vec<_> = errors.iter().map(|e| e.code()).collect();
+        codes.extend(execute_errors.iter().map(|e| e.code()));
+        codes.push(Warning::ShadowedBuiltin(s()).code());
+        codes.push(Lint::UnusedFunction(s()).code());
+        codes.push(Lint::DeadBranch {
+                           func: None,
+                           branch: Branch::IfBody,
+                       }
+                       .code());
+
+        for code in &codes {
+            assert!(explain(code).is_some(), "{} isn't explained", code);
+        }
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), EXPLANATIONS.len());
+        assert_eq!(explain("e0007"), explain("E0007"));
+        assert_eq!(explain("E9999"), None);
+    }
+}
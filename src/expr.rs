@@ -1,11 +1,21 @@
+use std::rc::Rc;
 use std::result;
 
+use indexmap::IndexMap;
+
 use binary_op::BinaryOp;
-use data::Data;
+use builtins;
+use data::{Data, StructType};
 use data::Data::*;
 use error::ExecuteError;
 use error::ExecuteError::*;
-use program::Program;
+use error::Warning;
+use function::Function as Func;
+use context::Context;
+use cst::Span;
+use engine::Engine;
+use stack;
+use unary_op::UnaryOp;
 
 use Expression::*;
 
@@ -16,19 +26,94 @@ pub enum Expression {
     NilLiteral,
     BooleanLiteral(bool),
     NumberLiteral(f64),
-    StrLiteral(String),
+    IntLiteral(i64),
+    // The text is interned by the parser, so equal literals share it.
+    StrLiteral(Rc<str>),
+    CustomLiteral {
+        prefix: char,
+        text: String,
+    },
     Variable(String),
+    ListLiteral(Vec<Expression>),
+    // The entries of a map literal, in the order they're written.
+    MapLiteral(Vec<(Expression, Expression)>),
     ParenExpr(Box<Expression>),
     Block(Vec<Expression>),
+    DoBlock(Vec<Expression>),
     Assignment {
         left: String,
         right: Box<Expression>,
     },
-    FunctionCall { name: String, args: Vec<Expression> },
+    // A declaration, as in `let x = 1`, always creates a variable in the
+    // current scope, even if an outer scope has one with the same name.  A
+    // constant, declared with `const`, can't be assigned to afterwards.
+    Declaration {
+        left: String,
+        right: Box<Expression>,
+        constant: bool,
+    },
+    IndexAssignment {
+        object: Box<Expression>,
+        index: Box<Expression>,
+        right: Box<Expression>,
+    },
+    // Assigning to a field of a struct instance, as in `p.x = 1`.
+    FieldAssignment {
+        object: Box<Expression>,
+        field: String,
+        right: Box<Expression>,
+    },
+    // A compound assignment, as in `l[i] += 1`, applies op to the value of
+    // target, which is a variable, index or field, and value, then stores the
+    // result in target.  The object and index of target are evaluated once.
+    // span is where the operator is in the source, if it was parsed.
+    CompoundAssignment {
+        target: Box<Expression>,
+        op: BinaryOp,
+        value: Box<Expression>,
+        span: Option<Span>,
+    },
+    FunctionDef {
+        name: String,
+        params: Vec<String>,
+        body: Box<Expression>,
+    },
+    // A struct type definition, as in `struct Point { x, y }`.
+    StructDef {
+        name: String,
+        fields: Vec<String>,
+    },
+    // An anonymous function, as in `|x| x * 2` or `fn(x) { x * 2 }`.
+    Lambda {
+        params: Vec<String>,
+        body: Box<Expression>,
+    },
+    FunctionCall {
+        func: Box<Expression>,
+        args: Vec<Expression>,
+    },
+    FieldAccess {
+        object: Box<Expression>,
+        field: String,
+    },
+    Index {
+        object: Box<Expression>,
+        index: Box<Expression>,
+    },
+    UnaryExpr {
+        op: UnaryOp,
+        operand: Box<Expression>,
+    },
+    // span is where the operator is in the source, if it was parsed.
     BinaryExpr {
         left: Box<Expression>,
         op: BinaryOp,
         right: Box<Expression>,
+        span: Option<Span>,
+    },
+    ChainedComparison {
+        first: Box<Expression>,
+        rest: Vec<(BinaryOp, Expression)>,
     },
     IfExpr {
         cond: Box<Expression>,
@@ -38,80 +123,515 @@ pub enum Expression {
     WhileLoop {
         cond: Box<Expression>,
         body: Box<Expression>,
+        else_branch: Option<Box<Expression>>,
+    },
+    // A loop like `do { ... } while cond`, which runs its body before checking
+    // the condition.  The body is a do block, so the condition sees the
+    // variables it assigns.
+    DoWhile {
+        body: Box<Expression>,
+        cond: Box<Expression>,
+    },
+    ForLoop {
+        var: String,
+        iter: Box<Expression>,
+        body: Box<Expression>,
+    },
+    Break,
+    Continue,
+    // A switch compares value with the values of each case in turn, using
+    // `==`, and evaluates the body of the first case with a match.  The
+    // default runs if none match.
+    Switch {
+        value: Box<Expression>,
+        cases: Vec<(Vec<Expression>, Expression)>,
+        default: Option<Box<Expression>>,
+    },
+    Throw(Box<Expression>),
+    // If the body fails, the handler runs with var holding the error: the
+    // value thrown, or the message of any other error.
+    TryCatch {
+        body: Box<Expression>,
+        var: String,
+        handler: Box<Expression>,
     },
 }
 
 impl Expression {
-    pub fn eval(&self, p: &mut Program) -> Result {
+    pub fn eval(&self, engine: &Engine, ctx: &mut Context) -> Result {
+        if stack::exceeded() {
+            return Err(ExecuteError::TooDeep);
+        }
         match self {
             &NilLiteral => Ok(Nil),
             &BooleanLiteral(b) => Ok(Boolean(b)),
             &NumberLiteral(n) => Ok(Number(n)),
+            &IntLiteral(i) => Ok(Int(i)),
             &StrLiteral(ref s) => Ok(Str(s.clone())),
+            &CustomLiteral { prefix, ref text } => engine.literal(prefix, text),
             &Variable(ref name) => {
-                match p.var(name) {
+                match ctx.var(name) {
                     Some(d) => Ok(d.clone()),
-                    None => Err(UndefinedVar(name.clone())),
+                    None => engine.lookup(name),
+                }
+            }
+            &ListLiteral(ref items) => {
+                let mut values = Vec::with_capacity(items.len());
+                for item in items {
+                    values.push(item.eval(engine, ctx)?);
+                }
+                Ok(Data::list(values))
+            }
+            &MapLiteral(ref entries) => {
+                let mut map = IndexMap::new();
+                for &(ref key, ref value) in entries {
+                    match key.eval(engine, ctx)? {
+                        Str(k) => map.insert(k.to_string(), value.eval(engine, ctx)?),
+                        k => {
+                            return Err(InvalidIndex {
+                                type_name: "map".to_owned(),
+                                index: k.type_name(),
+                            })
+                        }
+                    };
                 }
+                Ok(Data::map(map))
             }
-            &ParenExpr(ref expr) => expr.eval(p),
+            &ParenExpr(ref expr) => expr.eval(engine, ctx),
             &Block(ref exprs) => {
                 let mut last_result = Ok(Data::Nil);
 
-                p.new_scope();
+                ctx.new_scope();
                 for expr in exprs {
-                    last_result = expr.eval(p);
+                    last_result = expr.eval(engine, ctx);
+                    if last_result.is_err() {
+                        ctx.trace_error(&last_result, None);
+                        break;
+                    }
                 }
-                p.pop_scope();
+                ctx.pop_scope();
 
                 last_result
             }
+            &DoBlock(ref exprs) => {
+                // Unlike a plain block, a do block runs in the current scope.
+                let mut last_result = Nil;
+                for expr in exprs {
+                    last_result = expr.eval(engine, ctx)?;
+                }
+                Ok(last_result)
+            }
             &Assignment { ref left, ref right } => {
-                let res = right.eval(p)?;
-                p.set_var(left, res.clone());
+                let res = right.eval(engine, ctx)?;
+                ctx.assign_var(left, res.clone())?;
+                Ok(res)
+            }
+            &Declaration { ref left, ref right, constant } => {
+                let res = right.eval(engine, ctx)?;
+                ctx.define_var(left, res.clone(), constant)?;
+                Ok(res)
+            }
+            &IndexAssignment { ref object, ref index, ref right } => {
+                let (obj, idx) = (object.eval(engine, ctx)?, index.eval(engine, ctx)?);
+                let res = right.eval(engine, ctx)?;
+                set_index(&obj, &idx, res.clone())?;
                 Ok(res)
             }
-            &FunctionCall { ref name, ref args } => {
-                let f = match name.as_ref() {
-                    "println" => println,
-                    _ => return Err(UndefinedFunc(name.clone())),
+            &FunctionDef { ref name, ref params, ref body } => {
+                // Variables are looked up before builtins, so the new function
+                // will take precedence from here on.
+                if ctx.var(name).is_none() && engine.lookup(name).is_ok() {
+                    ctx.warn(Warning::ShadowedBuiltin(name.clone()));
+                }
+
+                let f = Function(Rc::new(Func {
+                    name: name.clone(),
+                    params: params.clone(),
+                    body: *body.clone(),
+                    env: ctx.env(),
+                    source: ctx.source(),
+                }));
+                ctx.define_var(name, f.clone(), false)?;
+                Ok(f)
+            }
+            &FieldAssignment { ref object, ref field, ref right } => {
+                let obj = object.eval(engine, ctx)?;
+                let res = right.eval(engine, ctx)?;
+                set_field(&obj, field, res.clone())?;
+                Ok(res)
+            }
+            &CompoundAssignment { ref target, ref op, ref value, span } => {
+                let span = ctx.span(span);
+                let apply = |old: &Data, value: &Data| op.eval(old, value).map_err(|e| e.at(span));
+                match **target {
+                    Index { ref object, ref index } => {
+                        let (obj, idx) = (object.eval(engine, ctx)?, index.eval(engine, ctx)?);
+                        let res = apply(&get_index(&obj, &idx)?, &value.eval(engine, ctx)?)?;
+                        set_index(&obj, &idx, res.clone())?;
+                        Ok(res)
+                    }
+                    FieldAccess { ref object, ref field } => {
+                        let obj = object.eval(engine, ctx)?;
+                        let res = apply(&get_field(&obj, field)?, &value.eval(engine, ctx)?)?;
+                        set_field(&obj, field, res.clone())?;
+                        Ok(res)
+                    }
+                    Variable(ref name) => {
+                        let old = target.eval(engine, ctx)?;
+                        let res = apply(&old, &value.eval(engine, ctx)?)?;
+                        ctx.assign_var(name, res.clone())?;
+                        Ok(res)
+                    }
+                    _ => Err(Internal("can't assign to this expression".to_owned())),
+                }
+            }
+            &StructDef { ref name, ref fields } => {
+                let t = Type(Rc::new(StructType {
+                    name: name.clone(),
+                    fields: fields.clone(),
+                }));
+                ctx.define_var(name, t.clone(), false)?;
+                Ok(t)
+            }
+            &Lambda { ref params, ref body } => {
+                Ok(Function(Rc::new(Func {
+                    name: String::new(),
+                    params: params.clone(),
+                    body: *body.clone(),
+                    env: ctx.env(),
+                    source: ctx.source(),
+                })))
+            }
+            &FunctionCall { ref func, ref args } => {
+                let f = match **func {
+                    Variable(ref name) => {
+                        match func.eval(engine, ctx) {
+                            Err(UndefinedVar(_)) => return Err(UndefinedFunc(name.clone())),
+                            res => res?,
+                        }
+                    }
+                    ref e => e.eval(engine, ctx)?,
                 };
 
                 let mut new_args = Vec::new();
                 for item in args.iter() {
-                    new_args.push(item.eval(p)?);
+                    new_args.push(item.eval(engine, ctx)?);
+                }
+
+                engine.call(ctx, &f, new_args)
+            }
+            &FieldAccess { ref object, ref field } => {
+                // A name that isn't a variable but is a builtin namespace
+                // refers to the namespace's builtins.
+                if let Variable(ref name) = **object {
+                    if ctx.var(name).is_none() && engine.is_namespace(name) {
+                        return engine.lookup_in(name, field);
+                    }
                 }
 
-                f(&new_args)
+                get_field(&object.eval(engine, ctx)?, field)
             }
-            &BinaryExpr { ref left, ref op, ref right } => {
-                let (left_data, right_data) = (left.eval(p)?, right.eval(p)?);
-                op.eval(&left_data, &right_data)
+            &Index { ref object, ref index } => {
+                let (obj, idx) = (object.eval(engine, ctx)?, index.eval(engine, ctx)?);
+                get_index(&obj, &idx)
+            }
+            &UnaryExpr { ref op, ref operand } => op.eval(&operand.eval(engine, ctx)?),
+            &BinaryExpr { ref left, op: BinaryOp::And, ref right, .. } => {
+                // The right operand is only evaluated when it decides the
+                // result.
+                if !left.eval(engine, ctx)?.to_bool() {
+                    return Ok(Boolean(false));
+                }
+                Ok(Boolean(right.eval(engine, ctx)?.to_bool()))
+            }
+            &BinaryExpr { ref left, op: BinaryOp::Or, ref right, .. } => {
+                if left.eval(engine, ctx)?.to_bool() {
+                    return Ok(Boolean(true));
+                }
+                Ok(Boolean(right.eval(engine, ctx)?.to_bool()))
+            }
+            &BinaryExpr { ref left, op: BinaryOp::Coalesce, ref right, .. } => {
+                match left.eval(engine, ctx)? {
+                    Nil => right.eval(engine, ctx),
+                    d => Ok(d),
+                }
+            }
+            &BinaryExpr { ref left, ref op, ref right, span } => {
+                let (left_data, right_data) = (left.eval(engine, ctx)?, right.eval(engine, ctx)?);
+                op.eval(&left_data, &right_data).map_err(|e| e.at(ctx.span(span)))
+            }
+            &ChainedComparison { ref first, ref rest } => {
+                // Each operand is evaluated at most once, and evaluation stops
+                // at the first comparison that fails.
+                let mut left_data = first.eval(engine, ctx)?;
+                for &(ref op, ref right) in rest {
+                    let right_data = right.eval(engine, ctx)?;
+                    if !op.eval(&left_data, &right_data)?.to_bool() {
+                        return Ok(Boolean(false));
+                    }
+                    left_data = right_data;
+                }
+                Ok(Boolean(true))
             }
             &IfExpr { ref cond, ref body, ref else_branch } => {
-                if cond.eval(p)?.to_bool() {
-                    body.eval(p)
+                if cond.eval(engine, ctx)?.to_bool() {
+                    body.eval(engine, ctx)
                 } else if let &Some(ref b) = else_branch {
-                    b.eval(p)
+                    b.eval(engine, ctx)
                 } else {
                     Ok(Nil)
                 }
             }
-            &WhileLoop { ref cond, ref body } => {
-                let mut last_data = Ok(Nil);
-                while cond.eval(p)?.to_bool() {
-                    last_data = body.eval(p);
+            &WhileLoop { ref cond, ref body, ref else_branch } => {
+                let mut last_data = Nil;
+                while cond.eval(engine, ctx)?.to_bool() {
+                    ctx.check_cancelled()?;
+                    match body.eval(engine, ctx) {
+                        Ok(d) => last_data = d,
+                        // Breaking out of the loop skips the else branch.
+                        Err(ExecuteError::Break) => return Ok(last_data),
+                        Err(ExecuteError::Continue) => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                // The else branch runs once the condition is false and gives
+                // the loop its value.
+                if let &Some(ref b) = else_branch {
+                    b.eval(engine, ctx)
+                } else {
+                    Ok(last_data)
+                }
+            }
+            &DoWhile { ref body, ref cond } => {
+                let mut last_data = Nil;
+                loop {
+                    ctx.check_cancelled()?;
+                    match body.eval(engine, ctx) {
+                        Ok(d) => last_data = d,
+                        Err(ExecuteError::Break) => return Ok(last_data),
+                        Err(ExecuteError::Continue) => {}
+                        Err(e) => return Err(e),
+                    }
+                    if !cond.eval(engine, ctx)?.to_bool() {
+                        return Ok(last_data);
+                    }
+                }
+            }
+            &Switch { ref value, ref cases, ref default } => {
+                // The value is only evaluated once, and the cases only until
+                // one matches.
+                let value = value.eval(engine, ctx)?;
+                for &(ref candidates, ref body) in cases {
+                    for candidate in candidates {
+                        let candidate = candidate.eval(engine, ctx)?;
+                        if BinaryOp::Eq.eval(&value, &candidate)?.to_bool() {
+                            return body.eval(engine, ctx);
+                        }
+                    }
+                }
+                match default {
+                    &Some(ref d) => d.eval(engine, ctx),
+                    &None => Ok(Nil),
+                }
+            }
+            &ForLoop { ref var, ref iter, ref body } => {
+                // The values are copied up front, so changing a list while
+                // looping over it doesn't affect the loop.
+                let iter = iter.eval(engine, ctx)?;
+                let items = match iter.iter() {
+                    Some(items) => items,
+                    None => return Err(NotIterable(iter.type_name())),
+                };
+
+                let mut last_data = Nil;
+                for item in items {
+                    ctx.check_cancelled()?;
+                    // Each iteration gets its own scope holding the variable.
+                    ctx.new_scope();
+                    ctx.declare_var(var, item);
+                    let res = body.eval(engine, ctx);
+                    ctx.trace_error(&res, None);
+                    ctx.pop_scope();
+
+                    match res {
+                        Ok(d) => last_data = d,
+                        Err(ExecuteError::Break) => break,
+                        Err(ExecuteError::Continue) => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(last_data)
+            }
+            &Expression::Break => Err(ExecuteError::Break),
+            &Expression::Continue => Err(ExecuteError::Continue),
+            &Throw(ref value) => Err(Thrown(value.eval(engine, ctx)?)),
+            &TryCatch { ref body, ref var, ref handler } => {
+                let err = match body.eval(engine, ctx) {
+                    Ok(d) => return Ok(d),
+                    // Break and continue aren't errors, so they go on to the
+                    // enclosing loop, and exit ends the whole program.
+                    Err(ExecuteError::Break) => return Err(ExecuteError::Break),
+                    Err(ExecuteError::Continue) => return Err(ExecuteError::Continue),
+                    Err(ExecuteError::Exit(status)) => return Err(ExecuteError::Exit(status)),
+                    Err(ExecuteError::Cancelled) => return Err(ExecuteError::Cancelled),
+                    Err(Thrown(d)) => d,
+                    Err(e) => Str(e.to_string().into()),
+                };
+                // The error was caught, so it won't need to be diagnosed.
+                ctx.take_error_trace();
+
+                ctx.new_scope();
+                ctx.declare_var(var, err);
+                let res = handler.eval(engine, ctx);
+                ctx.pop_scope();
+                res
+            }
+        }
+    }
+
+    // children returns the expressions directly inside this one, in the order
+    // they appear in the source.
+    pub fn children(&self) -> Vec<&Expression> {
+        match self {
+            &NilLiteral | &BooleanLiteral(_) | &NumberLiteral(_) | &IntLiteral(_) |
+            &StrLiteral(_) |
+            &CustomLiteral { .. } | &Variable(_) | &StructDef { .. } | &Expression::Break |
+            &Expression::Continue => vec![],
+            &ListLiteral(ref items) | &Block(ref items) | &DoBlock(ref items) => {
+                items.iter().collect()
+            }
+            &MapLiteral(ref entries) => {
+                entries.iter().flat_map(|&(ref k, ref v)| vec![k, v]).collect()
+            }
+            &ParenExpr(ref e) |
+            &Assignment { right: ref e, .. } |
+            &Declaration { right: ref e, .. } |
+            &FunctionDef { body: ref e, .. } |
+            &Lambda { body: ref e, .. } |
+            &FieldAccess { object: ref e, .. } |
+            &UnaryExpr { operand: ref e, .. } => vec![e],
+            &IndexAssignment { ref object, ref index, ref right } => vec![object, index, right],
+            &FieldAssignment { ref object, ref right, .. } => vec![object, right],
+            &CompoundAssignment { ref target, ref value, .. } => vec![target, value],
+            &FunctionCall { ref func, ref args } => {
+                let mut children = vec![&**func];
+                children.extend(args);
+                children
+            }
+            &Index { ref object, ref index } => vec![object, index],
+            &BinaryExpr { ref left, ref right, .. } => vec![left, right],
+            &ChainedComparison { ref first, ref rest } => {
+                let mut children = vec![&**first];
+                children.extend(rest.iter().map(|&(_, ref e)| e));
+                children
+            }
+            &IfExpr { ref cond, ref body, ref else_branch } |
+            &WhileLoop { ref cond, ref body, ref else_branch } => {
+                let mut children = vec![&**cond, body];
+                children.extend(else_branch.iter().map(|e| &**e));
+                children
+            }
+            &DoWhile { ref body, ref cond } => vec![body, cond],
+            &ForLoop { ref iter, ref body, .. } => vec![iter, body],
+            &Switch { ref value, ref cases, ref default } => {
+                let mut children = vec![&**value];
+                for &(ref candidates, ref body) in cases {
+                    children.extend(candidates);
+                    children.push(body);
                 }
-                last_data
+                children.extend(default.iter().map(|e| &**e));
+                children
             }
+            &Throw(ref e) => vec![e],
+            &TryCatch { ref body, ref handler, .. } => vec![body, handler],
+        }
+    }
+}
+
+fn get_index(obj: &Data, idx: &Data) -> Result {
+    match (obj, idx) {
+        (&List(ref l), &Number(_)) |
+        (&List(ref l), &Int(_)) => {
+            let l = l.borrow();
+            let i = builtins::expect_index(idx, l.len(), false)?;
+            Ok(l[i].clone())
+        }
+        (&NumArray(ref a), &Number(_)) |
+        (&NumArray(ref a), &Int(_)) => {
+            let a = a.borrow();
+            let i = builtins::expect_index(idx, a.len(), false)?;
+            Ok(Number(a[i]))
+        }
+        // Like get, a missing key gives nil.
+        (&Map(ref m), &Str(ref k)) => Ok(m.borrow().get(&**k).cloned().unwrap_or(Nil)),
+        _ => {
+            Err(InvalidIndex {
+                type_name: obj.type_name(),
+                index: idx.type_name(),
+            })
         }
     }
 }
 
-pub fn println(v: &Vec<Data>) -> Result {
-    for item in v {
-        print!("{}", item);
+fn set_index(obj: &Data, idx: &Data, res: Data) -> result::Result<(), ExecuteError> {
+    // Lists and maps are shared, so updating the value in place is seen
+    // through every variable holding it.
+    obj.will_change()?;
+    match (obj, idx) {
+        (&List(ref l), &Number(_)) |
+        (&List(ref l), &Int(_)) => {
+            let mut l = l.borrow_mut();
+            let i = builtins::expect_index(idx, l.len(), false)?;
+            l[i] = res;
+        }
+        (&NumArray(ref a), &Number(_)) |
+        (&NumArray(ref a), &Int(_)) => {
+            let n = res.to_f64().ok_or_else(|| {
+                InvalidArgument {
+                    expected: "number".to_owned(),
+                    found: res.type_name(),
+                }
+            })?;
+            let mut a = a.borrow_mut();
+            let i = builtins::expect_index(idx, a.len(), false)?;
+            a[i] = n;
+        }
+        (&Map(ref m), &Str(ref k)) => {
+            m.borrow_mut().insert(k.to_string(), res);
+        }
+        _ => {
+            return Err(InvalidIndex {
+                type_name: obj.type_name(),
+                index: idx.type_name(),
+            })
+        }
+    }
+    Ok(())
+}
+
+fn get_field(obj: &Data, field: &str) -> Result {
+    if let &Struct(ref s) = obj {
+        if let Some(value) = s.borrow().fields.get(field) {
+            return Ok(value.clone());
+        }
+    }
+    Err(UndefinedField {
+        type_name: obj.type_name(),
+        field: field.to_owned(),
+    })
+}
+
+fn set_field(obj: &Data, field: &str, res: Data) -> result::Result<(), ExecuteError> {
+    obj.will_change()?;
+    if let &Struct(ref s) = obj {
+        if let Some(value) = s.borrow_mut().fields.get_mut(field) {
+            *value = res;
+            return Ok(());
+        }
     }
-    println!("");
-    Ok(Data::Nil)
+    Err(UndefinedField {
+        type_name: obj.type_name(),
+        field: field.to_owned(),
+    })
 }
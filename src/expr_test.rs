@@ -1,7 +1,14 @@
+use indexmap::IndexMap;
+
+use audit::AuditEvent;
+use binary_op::BinaryOp;
 use binary_op::BinaryOp::*;
+use data::Data;
 use data::Data::*;
 use error::ExecuteError::*;
+use error::Warning;
 use program::*;
+use unary_op::UnaryOp::*;
 
 use expr::*;
 use expr::Expression::*;
@@ -27,6 +34,7 @@ fn test_variables() {
                 left: Box::new(Variable("y".to_owned())),
                 op: Add,
                 right: Box::new(NumberLiteral(1.0)),
+                span: None,
             }),
         },
     ];
@@ -46,18 +54,18 @@ fn test_variables() {
 fn test_undefined_var() {
     let ast = Variable("foo".to_owned());
     let mut p = Program::new();
-    let res = ast.eval(&mut p);
+    let res = p.eval(&ast);
     assert_eq!(Err(UndefinedVar("foo".to_owned())), res);
 }
 
 #[test]
 fn test_undefined_func() {
     let ast = FunctionCall {
-        name: "foo".to_owned(),
+        func: Box::new(Variable("foo".to_owned())),
         args: vec![],
     };
     let mut p = Program::new();
-    let res = ast.eval(&mut p);
+    let res = p.eval(&ast);
     assert_eq!(Err(UndefinedFunc("foo".to_owned())), res);
 }
 
@@ -66,7 +74,7 @@ fn test_paren_expr() {
     let expr = Expression::ParenExpr(Box::new(Expression::BooleanLiteral(true)));
 
     let mut p = Program::new();
-    assert_eq!(expr.eval(&mut p).unwrap(), Boolean(true));
+    assert_eq!(p.eval(&expr).unwrap(), Boolean(true));
 }
 
 #[test]
@@ -78,7 +86,7 @@ fn test_block() {
     ]);
 
     let mut p = Program::new();
-    assert_eq!(block.eval(&mut p).unwrap(), Number(3.0));
+    assert_eq!(p.eval(&block).unwrap(), Number(3.0));
 }
 
 #[test]
@@ -99,11 +107,11 @@ fn test_block_scope() {
     };
 
     let mut p = Program::new();
-    assert_eq!(Err(UndefinedVar("x".to_owned())), var.eval(&mut p));
-    assert_eq!(Ok(Number(1.0)), block.eval(&mut p));
-    assert_eq!(Err(UndefinedVar("x".to_owned())), var.eval(&mut p));
-    assert_eq!(Ok(Boolean(true)), assign.eval(&mut p));
-    assert_eq!(Ok(Boolean(true)), var.eval(&mut p));
+    assert_eq!(Err(UndefinedVar("x".to_owned())), p.eval(&var));
+    assert_eq!(Ok(Number(1.0)), p.eval(&block));
+    assert_eq!(Err(UndefinedVar("x".to_owned())), p.eval(&var));
+    assert_eq!(Ok(Boolean(true)), p.eval(&assign));
+    assert_eq!(Ok(Boolean(true)), p.eval(&var));
 }
 
 #[test]
@@ -125,7 +133,7 @@ fn test_if_expr() {
             else_branch: else_branch.map(|e| Box::new(e)),
         };
 
-        assert_eq!(x.eval(&mut p).unwrap(), exp);
+        assert_eq!(p.eval(&x).unwrap(), exp);
     }
 }
 
@@ -143,6 +151,7 @@ fn test_while_loop() {
                 left: Box::new(Variable("x".to_owned())),
                 op: Lt,
                 right: Box::new(NumberLiteral(5.0)),
+                span: None,
             }),
             body: Box::new(Assignment {
                 left: "x".to_owned(),
@@ -150,11 +159,1097 @@ fn test_while_loop() {
                     left: Box::new(Variable("x".to_owned())),
                     op: Add,
                     right: Box::new(NumberLiteral(1.0)),
+                    span: None,
                 }),
             }),
+            else_branch: None,
         })
         .unwrap();
 
     assert_eq!(out, Number(5.0));
     assert_eq!(p.eval(&Variable("x".to_owned())).unwrap(), Number(5.0));
 }
+
+#[test]
+fn test_do_while() {
+    let mut p = Program::new();
+
+    // The body runs once even though the condition is false, and its
+    // variables are seen by the condition.
+    let out = p.eval(&DoWhile {
+            body: Box::new(DoBlock(vec![
+                Assignment {
+                    left: "x".to_owned(),
+                    right: Box::new(IntLiteral(1)),
+                },
+            ])),
+            cond: Box::new(BinaryExpr {
+                left: Box::new(Variable("x".to_owned())),
+                op: Gt,
+                right: Box::new(IntLiteral(1)),
+                span: None,
+            }),
+        })
+        .unwrap();
+    assert_eq!(out, Int(1));
+    assert_eq!(p.eval(&Variable("x".to_owned())).unwrap(), Int(1));
+
+    let out = p.eval(&DoWhile {
+        body: Box::new(Expression::Break),
+        cond: Box::new(BooleanLiteral(true)),
+    });
+    assert_eq!(out, Ok(Nil));
+}
+
+#[test]
+fn test_switch() {
+    let mut p = Program::new();
+    let switch = |n| {
+        Switch {
+            value: Box::new(IntLiteral(n)),
+            cases: vec![(vec![NumberLiteral(1.0), IntLiteral(2)], StrLiteral("low".into())),
+                        (vec![IntLiteral(3), Variable("undefined".to_owned())],
+                         StrLiteral("three".into()))],
+            default: Some(Box::new(StrLiteral("other".into()))),
+        }
+    };
+
+    // Cases compare with ==, so an int matches an equal number, and the
+    // cases after the first match aren't evaluated.
+    assert_eq!(p.eval(&switch(1)), Ok(Str("low".into())));
+    assert_eq!(p.eval(&switch(2)), Ok(Str("low".into())));
+    assert_eq!(p.eval(&switch(3)), Ok(Str("three".into())));
+    assert_eq!(p.eval(&switch(4)), Err(UndefinedVar("undefined".to_owned())));
+
+    let out = p.eval(&Switch {
+        value: Box::new(IntLiteral(1)),
+        cases: vec![],
+        default: None,
+    });
+    assert_eq!(out, Ok(Nil));
+}
+
+#[test]
+fn test_while_else() {
+    let mut p = Program::new();
+
+    let out = p.eval(&WhileLoop {
+            cond: Box::new(BooleanLiteral(false)),
+            body: Box::new(NumberLiteral(1.0)),
+            else_branch: Some(Box::new(NumberLiteral(2.0))),
+        })
+        .unwrap();
+    assert_eq!(out, Number(2.0));
+
+    // An error in the body stops the loop before the else branch runs.
+    let out = p.eval(&WhileLoop {
+        cond: Box::new(BooleanLiteral(true)),
+        body: Box::new(Variable("undefined".to_owned())),
+        else_branch: Some(Box::new(NumberLiteral(2.0))),
+    });
+    assert_eq!(out, Err(UndefinedVar("undefined".to_owned())));
+}
+
+#[test]
+fn test_chained_comparison() {
+    let mut p = Program::new();
+
+    let cases = vec![
+        (1.0, Lt, 2.0, LtEq, 2.0, Boolean(true)),
+        (1.0, Lt, 2.0, Lt, 2.0, Boolean(false)),
+        (3.0, Gt, 2.0, GtEq, 2.0, Boolean(true)),
+        (3.0, Gt, 4.0, Gt, 2.0, Boolean(false)),
+    ];
+
+    for (a, op_a, b, op_b, c, exp) in cases {
+        let x = ChainedComparison {
+            first: Box::new(NumberLiteral(a)),
+            rest: vec![(op_a, NumberLiteral(b)), (op_b, NumberLiteral(c))],
+        };
+
+        assert_eq!(p.eval(&x).unwrap(), exp);
+    }
+
+    // Operands after a failed comparison aren't evaluated.
+    let x = ChainedComparison {
+        first: Box::new(NumberLiteral(1.0)),
+        rest: vec![(Lt, NumberLiteral(0.0)), (Lt, Variable("undefined".to_owned()))],
+    };
+    assert_eq!(p.eval(&x).unwrap(), Boolean(false));
+}
+
+#[test]
+fn test_logical_operators() {
+    let mut p = Program::new();
+
+    let cases = vec![
+        (BooleanLiteral(true), And, NumberLiteral(0.0), Boolean(true)),
+        (NilLiteral, And, BooleanLiteral(true), Boolean(false)),
+        (NilLiteral, Or, BooleanLiteral(true), Boolean(true)),
+        (BooleanLiteral(false), Or, NilLiteral, Boolean(false)),
+        (NilLiteral, Coalesce, IntLiteral(1), Int(1)),
+        (BooleanLiteral(false), Coalesce, IntLiteral(1), Boolean(false)),
+    ];
+
+    for (left, op, right, exp) in cases {
+        let x = BinaryExpr {
+            left: Box::new(left),
+            op: op,
+            right: Box::new(right),
+            span: None,
+        };
+
+        assert_eq!(p.eval(&x).unwrap(), exp);
+    }
+
+    // The right operand isn't evaluated once the left decides the result.
+    let undefined = || Box::new(Variable("undefined".to_owned()));
+    let x = BinaryExpr {
+        left: Box::new(BooleanLiteral(false)),
+        op: And,
+        right: undefined(),
+        span: None,
+    };
+    assert_eq!(p.eval(&x).unwrap(), Boolean(false));
+    let x = BinaryExpr {
+        left: Box::new(BooleanLiteral(true)),
+        op: Or,
+        right: undefined(),
+        span: None,
+    };
+    assert_eq!(p.eval(&x).unwrap(), Boolean(true));
+    let x = BinaryExpr {
+        left: Box::new(IntLiteral(0)),
+        op: Coalesce,
+        right: undefined(),
+        span: None,
+    };
+    assert_eq!(p.eval(&x).unwrap(), Int(0));
+    let x = BinaryExpr {
+        left: Box::new(BooleanLiteral(true)),
+        op: And,
+        right: undefined(),
+        span: None,
+    };
+    assert_eq!(p.eval(&x), Err(UndefinedVar("undefined".to_owned())));
+}
+
+#[test]
+fn test_unary_expr() {
+    let mut p = Program::new();
+    p.set_var("x", Number(3.0));
+
+    let x = UnaryExpr {
+        op: Neg,
+        operand: Box::new(BinaryExpr {
+            left: Box::new(Variable("x".to_owned())),
+            op: Add,
+            right: Box::new(NumberLiteral(1.0)),
+            span: None,
+        }),
+    };
+    assert_eq!(p.eval(&x).unwrap(), Number(-4.0));
+
+    let x = UnaryExpr {
+        op: Neg,
+        operand: Box::new(NilLiteral),
+    };
+    assert_eq!(p.eval(&x),
+               Err(InvalidUnaryOperation {
+                   op: Neg,
+                   operand: "nil".to_owned(),
+               }));
+}
+
+#[test]
+fn test_list_index() {
+    let mut p = Program::new();
+    p.set_var("x", Number(2.0));
+    p.eval(&Assignment {
+            left: "a".to_owned(),
+            right: Box::new(ListLiteral(vec![NumberLiteral(1.0), Variable("x".to_owned())])),
+        })
+        .unwrap();
+
+    let index = |i| {
+        Index {
+            object: Box::new(Variable("a".to_owned())),
+            index: Box::new(i),
+        }
+    };
+    assert_eq!(p.eval(&index(NumberLiteral(0.0))), Ok(Number(1.0)));
+    assert_eq!(p.eval(&index(NumberLiteral(1.0))), Ok(Number(2.0)));
+    assert_eq!(p.eval(&index(NumberLiteral(2.0))),
+               Err(IndexOutOfRange {
+                   index: 2.0,
+                   len: 2,
+               }));
+    assert_eq!(p.eval(&index(NumberLiteral(-1.0))),
+               Err(IndexOutOfRange {
+                   index: -1.0,
+                   len: 2,
+               }));
+    assert_eq!(p.eval(&index(StrLiteral("0".into()))),
+               Err(InvalidIndex {
+                   type_name: "list".to_owned(),
+                   index: "string".to_owned(),
+               }));
+
+    let x = Index {
+        object: Box::new(NumberLiteral(1.0)),
+        index: Box::new(NumberLiteral(0.0)),
+    };
+    assert_eq!(p.eval(&x),
+               Err(InvalidIndex {
+                   type_name: "number".to_owned(),
+                   index: "number".to_owned(),
+               }));
+}
+
+#[test]
+fn test_index_assignment() {
+    let mut p = Program::new();
+    p.set_var("l", Data::list(vec![Nil, Nil]));
+    p.set_var("m", Data::map(IndexMap::new()));
+    p.set_var("alias", p.var("l").unwrap());
+
+    let assign = |name: &str, index, right| {
+        IndexAssignment {
+            object: Box::new(Variable(name.to_owned())),
+            index: Box::new(index),
+            right: Box::new(right),
+        }
+    };
+
+    assert_eq!(p.eval(&assign("l", NumberLiteral(1.0), NumberLiteral(5.0))),
+               Ok(Number(5.0)));
+    assert_eq!(p.var("alias").unwrap().to_string(), "[nil, 5]");
+    assert_eq!(p.eval(&assign("l", NumberLiteral(2.0), NilLiteral)),
+               Err(IndexOutOfRange {
+                   index: 2.0,
+                   len: 2,
+               }));
+
+    p.eval(&assign("m", StrLiteral("a".into()), NumberLiteral(1.0))).unwrap();
+    p.eval(&assign("m", StrLiteral("b".into()), NumberLiteral(2.0))).unwrap();
+    p.eval(&assign("m", StrLiteral("a".into()), NumberLiteral(3.0))).unwrap();
+    assert_eq!(p.var("m").unwrap().to_string(), "{a: 3, b: 2}");
+    assert_eq!(p.eval(&assign("m", NumberLiteral(0.0), NilLiteral)),
+               Err(InvalidIndex {
+                   type_name: "map".to_owned(),
+                   index: "number".to_owned(),
+               }));
+
+    p.set_var("a", Data::num_array(vec![1.0, 2.0]));
+    assert_eq!(p.eval(&assign("a", IntLiteral(0), IntLiteral(7))), Ok(Int(7)));
+    assert_eq!(p.eval(&Index {
+                   object: Box::new(Variable("a".to_owned())),
+                   index: Box::new(IntLiteral(0)),
+               }),
+               Ok(Number(7.0)));
+    assert_eq!(p.eval(&assign("a", IntLiteral(1), StrLiteral("x".into()))),
+               Err(InvalidArgument {
+                   expected: "number".to_owned(),
+                   found: "string".to_owned(),
+               }));
+    assert_eq!(p.var("a").unwrap().to_string(), "array[7, 2]");
+}
+
+#[test]
+fn test_do_block_scope() {
+    let block = DoBlock(vec![
+        Assignment {
+            left: "x".to_owned(),
+            right: Box::new(NumberLiteral(1.0)),
+        },
+        NumberLiteral(2.0),
+    ]);
+
+    let mut p = Program::new();
+    assert_eq!(Ok(Number(2.0)), p.eval(&block));
+    assert_eq!(Ok(Number(1.0)), p.eval(&Variable("x".to_owned())));
+}
+
+#[test]
+fn test_function_call() {
+    let mut p = Program::new();
+
+    // fn add(a, b) { c = a + b  c }
+    p.eval(&FunctionDef {
+            name: "add".to_owned(),
+            params: vec!["a".to_owned(), "b".to_owned()],
+            body: Box::new(Block(vec![
+                Assignment {
+                    left: "c".to_owned(),
+                    right: Box::new(BinaryExpr {
+                        left: Box::new(Variable("a".to_owned())),
+                        op: Add,
+                        right: Box::new(Variable("b".to_owned())),
+                        span: None,
+                    }),
+                },
+                Variable("c".to_owned()),
+            ])),
+        })
+        .unwrap();
+
+    p.set_var("a", Number(10.0));
+    let call = FunctionCall {
+        func: Box::new(Variable("add".to_owned())),
+        args: vec![NumberLiteral(1.0), NumberLiteral(2.0)],
+    };
+    assert_eq!(p.eval(&call), Ok(Number(3.0)));
+
+    // Parameters shadow globals and locals don't leak out of the call.
+    assert_eq!(p.var("a"), Some(Number(10.0)));
+    assert_eq!(p.var("b"), None);
+    assert_eq!(p.var("c"), None);
+
+    let call = FunctionCall {
+        func: Box::new(Variable("add".to_owned())),
+        args: vec![NumberLiteral(1.0)],
+    };
+    assert_eq!(p.eval(&call),
+               Err(WrongArgCount {
+                   expected: 2,
+                   found: 1,
+               }));
+}
+
+#[test]
+fn test_function_scope() {
+    let mut p = Program::new();
+    let assign_y = Expression::Assignment {
+        left: "y".to_owned(),
+        right: Box::new(NumberLiteral(2.0)),
+    };
+
+    // fn get() x
+    p.eval(&FunctionDef {
+            name: "get".to_owned(),
+            params: vec![],
+            body: Box::new(Variable("x".to_owned())),
+        })
+        .unwrap();
+
+    let call = |name: &str| {
+        FunctionCall {
+            func: Box::new(Variable(name.to_owned())),
+            args: vec![],
+        }
+    };
+
+    // Globals are visible.
+    p.set_var("x", Number(1.0));
+    assert_eq!(p.eval(&call("get")), Ok(Number(1.0)));
+
+    // fn get_y() y
+    p.eval(&FunctionDef {
+            name: "get_y".to_owned(),
+            params: vec![],
+            body: Box::new(Variable("y".to_owned())),
+        })
+        .unwrap();
+
+    // The caller's block scope isn't visible...
+    let block = Block(vec![assign_y.clone(), call("get_y")]);
+    assert_eq!(p.eval(&block), Err(UndefinedVar("y".to_owned())));
+
+    // ...but the scope a function is defined in is.
+    let block = Block(vec![
+        assign_y,
+        FunctionDef {
+            name: "get_y".to_owned(),
+            params: vec![],
+            body: Box::new(Variable("y".to_owned())),
+        },
+        call("get_y"),
+    ]);
+    assert_eq!(p.eval(&block), Ok(Number(2.0)));
+}
+
+#[test]
+fn test_declaration() {
+    let mut p = Program::new();
+    let assign = |right: i64| {
+        Assignment {
+            left: "x".to_owned(),
+            right: Box::new(IntLiteral(right)),
+        }
+    };
+    let declare = |right: i64| {
+        Declaration {
+            left: "x".to_owned(),
+            right: Box::new(IntLiteral(right)),
+            constant: false,
+        }
+    };
+
+    // An assignment in a block changes the outer variable, while a
+    // declaration shadows it until the block ends.
+    p.eval(&declare(1)).unwrap();
+    assert_eq!(p.eval(&Block(vec![declare(2), assign(3)])), Ok(Int(3)));
+    assert_eq!(p.var("x"), Some(Int(1)));
+    assert_eq!(p.eval(&Block(vec![assign(4)])), Ok(Int(4)));
+    assert_eq!(p.var("x"), Some(Int(4)));
+
+    // Declaring a variable again in the same scope replaces it.
+    p.eval(&declare(5)).unwrap();
+    assert_eq!(p.var("x"), Some(Int(5)));
+}
+
+#[test]
+fn test_const() {
+    let mut p = Program::new();
+    let assign = |right: i64| {
+        Assignment {
+            left: "x".to_owned(),
+            right: Box::new(IntLiteral(right)),
+        }
+    };
+    let declare = |right: i64, constant: bool| {
+        Declaration {
+            left: "x".to_owned(),
+            right: Box::new(IntLiteral(right)),
+            constant: constant,
+        }
+    };
+    let err = Err(AssignToConst("x".to_owned()));
+
+    // const x = 1
+    p.eval(&declare(1, true)).unwrap();
+    assert_eq!(p.eval(&assign(2)), err);
+    assert_eq!(p.eval(&Block(vec![assign(2)])), err);
+    assert_eq!(p.eval(&declare(2, false)), err);
+    assert_eq!(p.eval(&FunctionDef {
+                   name: "x".to_owned(),
+                   params: vec![],
+                   body: Box::new(NilLiteral),
+               }),
+               err);
+    assert_eq!(p.var("x"), Some(Int(1)));
+
+    // A block can declare its own variable with the same name.
+    assert_eq!(p.eval(&Block(vec![declare(2, false), assign(3)])), Ok(Int(3)));
+    assert_eq!(p.var("x"), Some(Int(1)));
+
+    // The host can still change it.
+    p.set_var("x", Int(4));
+    assert_eq!(p.var("x"), Some(Int(4)));
+}
+
+#[test]
+fn test_struct() {
+    let mut p = Program::new();
+    let field = |field: &str| {
+        FieldAccess {
+            object: Box::new(Variable("p".to_owned())),
+            field: field.to_owned(),
+        }
+    };
+    let assign_field = |field: &str, right: i64| {
+        FieldAssignment {
+            object: Box::new(Variable("p".to_owned())),
+            field: field.to_owned(),
+            right: Box::new(IntLiteral(right)),
+        }
+    };
+
+    // struct Point { x, y }  p = Point(1, 2)
+    p.eval(&StructDef {
+            name: "Point".to_owned(),
+            fields: vec!["x".to_owned(), "y".to_owned()],
+        })
+        .unwrap();
+    let point = p.eval(&FunctionCall {
+            func: Box::new(Variable("Point".to_owned())),
+            args: vec![IntLiteral(1), IntLiteral(2)],
+        })
+        .unwrap();
+    p.set_var("p", point.clone());
+    assert_eq!(point.type_name(), "Point");
+    assert_eq!(point.to_string(), "Point { x: 1, y: 2 }");
+
+    assert_eq!(p.eval(&field("y")), Ok(Int(2)));
+    assert_eq!(p.eval(&assign_field("x", 3)), Ok(Int(3)));
+    assert_eq!(p.eval(&field("x")), Ok(Int(3)));
+
+    // Instances have only the fields of their struct.
+    let err = Err(UndefinedField {
+        type_name: "Point".to_owned(),
+        field: "z".to_owned(),
+    });
+    assert_eq!(p.eval(&field("z")), err);
+    assert_eq!(p.eval(&assign_field("z", 1)), err);
+    assert_eq!(p.eval(&FunctionCall {
+                   func: Box::new(Variable("Point".to_owned())),
+                   args: vec![IntLiteral(1)],
+               }),
+               Err(WrongArgCount {
+                   expected: 2,
+                   found: 1,
+               }));
+}
+
+#[test]
+fn test_try_catch() {
+    let mut p = Program::new();
+    let try_catch = |body: Expression| {
+        TryCatch {
+            body: Box::new(body),
+            var: "e".to_owned(),
+            handler: Box::new(Variable("e".to_owned())),
+        }
+    };
+
+    // try { throw "boom" } catch e e
+    let thrown = try_catch(Block(vec![Throw(Box::new(StrLiteral("boom".into())))]));
+    assert_eq!(p.eval(&thrown), Ok(Str("boom".into())));
+    assert_eq!(p.var("e"), None);
+
+    // Other errors are caught as their message.
+    assert_eq!(p.eval(&try_catch(Variable("y".to_owned()))),
+               Ok(Str("undefined variable \"y\"".into())));
+    assert_eq!(p.eval(&try_catch(IntLiteral(1))), Ok(Int(1)));
+    assert_eq!(p.eval(&Throw(Box::new(IntLiteral(5)))), Err(Thrown(Int(5))));
+
+    // while true { try break catch e e }
+    let while_loop = WhileLoop {
+        cond: Box::new(BooleanLiteral(true)),
+        body: Box::new(try_catch(Expression::Break)),
+        else_branch: None,
+    };
+    assert_eq!(p.eval(&while_loop), Ok(Nil));
+}
+
+#[test]
+fn test_closures() {
+    let mut p = Program::new();
+    let var = |name: &str| Variable(name.to_owned());
+    let call = |name: &str| {
+        FunctionCall {
+            func: Box::new(Variable(name.to_owned())),
+            args: vec![],
+        }
+    };
+    let add_one = |name: &str| {
+        Assignment {
+            left: name.to_owned(),
+            right: Box::new(BinaryExpr {
+                left: Box::new(Variable(name.to_owned())),
+                op: Add,
+                right: Box::new(IntLiteral(1)),
+                span: None,
+            }),
+        }
+    };
+
+    // fns = { n = 0  [|| n = n + 1, || n] }  inc = fns[0]  get = fns[1]
+    p.eval(&Assignment {
+            left: "fns".to_owned(),
+            right: Box::new(Block(vec![
+                Assignment {
+                    left: "n".to_owned(),
+                    right: Box::new(IntLiteral(0)),
+                },
+                ListLiteral(vec![
+                    Lambda {
+                        params: vec![],
+                        body: Box::new(add_one("n")),
+                    },
+                    Lambda {
+                        params: vec![],
+                        body: Box::new(var("n")),
+                    },
+                ]),
+            ])),
+        })
+        .unwrap();
+    for (i, name) in vec!["inc", "get"].into_iter().enumerate() {
+        p.eval(&Assignment {
+                left: name.to_owned(),
+                right: Box::new(Index {
+                    object: Box::new(var("fns")),
+                    index: Box::new(IntLiteral(i as i64)),
+                }),
+            })
+            .unwrap();
+    }
+
+    // Both functions share n, which outlives the block it was declared in.
+    assert_eq!(p.eval(&call("inc")), Ok(Int(1)));
+    assert_eq!(p.eval(&call("inc")), Ok(Int(2)));
+    assert_eq!(p.eval(&call("get")), Ok(Int(2)));
+    assert_eq!(p.var("n"), None);
+}
+
+#[test]
+fn test_function_values() {
+    let mut p = Program::new();
+
+    // fn twice(f, x) f(f(x))
+    let twice = p.eval(&FunctionDef {
+            name: "twice".to_owned(),
+            params: vec!["f".to_owned(), "x".to_owned()],
+            body: Box::new(FunctionCall {
+                func: Box::new(Variable("f".to_owned())),
+                args: vec![FunctionCall {
+                               func: Box::new(Variable("f".to_owned())),
+                               args: vec![Variable("x".to_owned())],
+                           }],
+            }),
+        })
+        .unwrap();
+    assert_eq!(twice.type_name(), "function");
+    assert_eq!(p.var("twice"), Some(twice));
+
+    // fn inc(x) x + 1
+    p.eval(&FunctionDef {
+            name: "inc".to_owned(),
+            params: vec!["x".to_owned()],
+            body: Box::new(BinaryExpr {
+                left: Box::new(Variable("x".to_owned())),
+                op: Add,
+                right: Box::new(NumberLiteral(1.0)),
+                span: None,
+            }),
+        })
+        .unwrap();
+
+    // Functions can be assigned and passed around like any other value.
+    p.eval(&Assignment {
+            left: "g".to_owned(),
+            right: Box::new(Variable("inc".to_owned())),
+        })
+        .unwrap();
+    let call = FunctionCall {
+        func: Box::new(Variable("twice".to_owned())),
+        args: vec![Variable("g".to_owned()), NumberLiteral(1.0)],
+    };
+    assert_eq!(p.eval(&call), Ok(Number(3.0)));
+
+    // Builtins are values too.
+    let call = FunctionCall {
+        func: Box::new(Variable("twice".to_owned())),
+        args: vec![Variable("list".to_owned()), NumberLiteral(1.0)],
+    };
+    assert_eq!(p.eval(&call).unwrap().to_string(), "[[1]]");
+
+    let call = FunctionCall {
+        func: Box::new(ParenExpr(Box::new(NumberLiteral(1.0)))),
+        args: vec![],
+    };
+    assert_eq!(p.eval(&call), Err(NotCallable("number".to_owned())));
+}
+
+#[test]
+fn test_lambda() {
+    let mut p = Program::new();
+
+    // g = { n = 10  |x| x + n }
+    p.eval(&Assignment {
+            left: "g".to_owned(),
+            right: Box::new(Block(vec![
+                Assignment {
+                    left: "n".to_owned(),
+                    right: Box::new(IntLiteral(10)),
+                },
+                Lambda {
+                    params: vec!["x".to_owned()],
+                    body: Box::new(BinaryExpr {
+                        left: Box::new(Variable("x".to_owned())),
+                        op: Add,
+                        right: Box::new(Variable("n".to_owned())),
+                        span: None,
+                    }),
+                },
+            ])),
+        })
+        .unwrap();
+    let g = p.var("g").unwrap();
+    assert_eq!(g.type_name(), "function");
+    assert_eq!(g.to_string(), "<fn>");
+
+    // The lambda keeps the block's variable after the block has ended.
+    let call = FunctionCall {
+        func: Box::new(Variable("g".to_owned())),
+        args: vec![IntLiteral(1)],
+    };
+    assert_eq!(p.eval(&call), Ok(Int(11)));
+    assert_eq!(p.var("n"), None);
+}
+
+#[test]
+fn test_shadowed_builtin() {
+    let mut p = Program::new();
+
+    // fn join(a, b) a
+    let def = FunctionDef {
+        name: "join".to_owned(),
+        params: vec!["a".to_owned(), "b".to_owned()],
+        body: Box::new(Variable("a".to_owned())),
+    };
+    p.eval(&def).unwrap();
+    assert_eq!(p.take_warnings(),
+               vec![Warning::ShadowedBuiltin("join".to_owned())]);
+    assert_eq!(p.take_warnings(), vec![]);
+
+    let call = FunctionCall {
+        func: Box::new(Variable("join".to_owned())),
+        args: vec![NumberLiteral(1.0), NumberLiteral(2.0)],
+    };
+    assert_eq!(p.eval(&call), Ok(Number(1.0)));
+
+    // Redefining the function doesn't warn again.
+    p.eval(&def).unwrap();
+    assert_eq!(p.take_warnings(), vec![]);
+}
+
+#[test]
+fn test_for_loop() {
+    let mut p = Program::new();
+    p.set_var("sum", Number(0.0));
+    p.set_var("l", Data::list(vec![Number(1.0), Number(2.0), Number(3.0), Number(4.0)]));
+
+    // for x in l { if x == 2 continue  if x == 4 break  sum = sum + x }
+    let x_is = |n| {
+        BinaryExpr {
+            left: Box::new(Variable("x".to_owned())),
+            op: Eq,
+            right: Box::new(NumberLiteral(n)),
+            span: None,
+        }
+    };
+    let for_loop = ForLoop {
+        var: "x".to_owned(),
+        iter: Box::new(Variable("l".to_owned())),
+        body: Box::new(Block(vec![
+            IfExpr {
+                cond: Box::new(x_is(2.0)),
+                body: Box::new(Expression::Continue),
+                else_branch: None,
+            },
+            IfExpr {
+                cond: Box::new(x_is(4.0)),
+                body: Box::new(Expression::Break),
+                else_branch: None,
+            },
+            Assignment {
+                left: "sum".to_owned(),
+                right: Box::new(BinaryExpr {
+                    left: Box::new(Variable("sum".to_owned())),
+                    op: Add,
+                    right: Box::new(Variable("x".to_owned())),
+                    span: None,
+                }),
+            },
+        ])),
+    };
+
+    assert_eq!(p.eval(&for_loop), Ok(Number(4.0)));
+    assert_eq!(p.var("sum"), Some(Number(4.0)));
+    // The loop variable only exists inside the loop.
+    assert_eq!(p.var("x"), None);
+
+    // Looping over a map gives its keys.
+    let mut m = IndexMap::new();
+    m.insert("a".to_owned(), Nil);
+    p.set_var("l", Data::map(m));
+    let keys = ForLoop {
+        var: "k".to_owned(),
+        iter: Box::new(Variable("l".to_owned())),
+        body: Box::new(Variable("k".to_owned())),
+    };
+    assert_eq!(p.eval(&keys), Ok(Str("a".into())));
+
+    p.set_var("l", Number(1.0));
+    assert_eq!(p.eval(&for_loop), Err(NotIterable("number".to_owned())));
+}
+
+#[test]
+fn test_range() {
+    let mut p = Program::new();
+    p.set_var("sum", Number(0.0));
+
+    // for i in 1..n { sum = sum + i }
+    let for_loop = ForLoop {
+        var: "i".to_owned(),
+        iter: Box::new(BinaryExpr {
+            left: Box::new(NumberLiteral(1.0)),
+            op: BinaryOp::Range,
+            right: Box::new(Variable("n".to_owned())),
+            span: None,
+        }),
+        body: Box::new(Assignment {
+            left: "sum".to_owned(),
+            right: Box::new(BinaryExpr {
+                left: Box::new(Variable("sum".to_owned())),
+                op: Add,
+                right: Box::new(Variable("i".to_owned())),
+                span: None,
+            }),
+        }),
+    };
+
+    p.set_var("n", Number(5.0));
+    assert_eq!(p.eval(&for_loop), Ok(Number(10.0)));
+    assert_eq!(p.var("sum"), Some(Number(10.0)));
+
+    // An empty range runs the body zero times.
+    p.set_var("n", Number(1.0));
+    assert_eq!(p.eval(&for_loop), Ok(Nil));
+
+    p.set_var("n", Str("5".into()));
+    assert_eq!(p.eval(&for_loop),
+               Err(InvalidOperation {
+                   left: "number".to_owned(),
+                   op: BinaryOp::Range,
+                   right: "string".to_owned(),
+                   values: Some(("1".to_owned(), "\"5\"".to_owned())),
+                   span: None,
+               }));
+}
+
+#[test]
+fn test_capture_output() {
+    let mut p = Program::new();
+    p.capture_output();
+
+    let println = |args| {
+        FunctionCall {
+            func: Box::new(Variable("println".to_owned())),
+            args: args,
+        }
+    };
+    p.eval(&println(vec![StrLiteral("x = ".into()), NumberLiteral(1.5)])).unwrap();
+    p.eval(&println(vec![])).unwrap();
+
+    assert_eq!(p.take_output(), "x = 1.5\n\n");
+    assert_eq!(p.take_output(), "");
+}
+
+#[test]
+fn test_audit_log() {
+    let mut p = Program::new();
+    p.capture_output();
+    let call = |name: &str, args| {
+        FunctionCall {
+            func: Box::new(Variable(name.to_owned())),
+            args: args,
+        }
+    };
+
+    p.eval(&call("list", vec![])).unwrap();
+    assert_eq!(p.audit_log(), &[]);
+
+    p.enable_audit(&["println"]);
+    p.eval(&FunctionDef {
+            name: "f".to_owned(),
+            params: vec![],
+            body: Box::new(call("list", vec![NumberLiteral(1.0)])),
+        })
+        .unwrap();
+    p.eval(&call("f", vec![])).unwrap();
+    p.eval(&call("println", vec![StrLiteral("secret".into())])).unwrap();
+
+    // Calls to functions defined by the script aren't builtin calls.
+    assert_eq!(p.audit_log(),
+               &[AuditEvent::BuiltinCall {
+                     name: "list".to_owned(),
+                     args: Some(vec![Number(1.0)]),
+                 },
+                 AuditEvent::BuiltinCall {
+                     name: "println".to_owned(),
+                     args: None,
+                 }]);
+}
+
+#[test]
+fn test_break_continue() {
+    let mut p = Program::new();
+    p.set_var("i", Number(0.0));
+    p.set_var("sum", Number(0.0));
+
+    let inc = |name: &str, by: Expression| {
+        Assignment {
+            left: name.to_owned(),
+            right: Box::new(BinaryExpr {
+                left: Box::new(Variable(name.to_owned())),
+                op: Add,
+                right: Box::new(by),
+                span: None,
+            }),
+        }
+    };
+    let i_is = |op, n| {
+        BinaryExpr {
+            left: Box::new(Variable("i".to_owned())),
+            op: op,
+            right: Box::new(NumberLiteral(n)),
+            span: None,
+        }
+    };
+
+    // while true { i = i + 1  if i == 5 break  if i % 2 == 0 continue  sum = sum + i } else -1
+    let out = p.eval(&WhileLoop {
+            cond: Box::new(BooleanLiteral(true)),
+            body: Box::new(Block(vec![
+                inc("i", NumberLiteral(1.0)),
+                IfExpr {
+                    cond: Box::new(i_is(Eq, 5.0)),
+                    body: Box::new(Expression::Break),
+                    else_branch: None,
+                },
+                IfExpr {
+                    cond: Box::new(BinaryExpr {
+                        left: Box::new(i_is(Mod, 2.0)),
+                        op: Eq,
+                        right: Box::new(NumberLiteral(0.0)),
+                        span: None,
+                    }),
+                    body: Box::new(Expression::Continue),
+                    else_branch: None,
+                },
+                inc("sum", Variable("i".to_owned())),
+            ])),
+            else_branch: Some(Box::new(NumberLiteral(-1.0))),
+        })
+        .unwrap();
+
+    // The loop evaluates to the last completed iteration, when i was 3.
+    assert_eq!(out, Number(4.0));
+    assert_eq!(p.var("i"), Some(Number(5.0)));
+    assert_eq!(p.var("sum"), Some(Number(4.0)));
+}
+
+#[test]
+fn test_int() {
+    let mut p = Program::new();
+    p.set_var("l", Data::list(vec![Str("a".into()), Str("b".into())]));
+
+    // for i in 0..10 { n = n + step }
+    let count = |op, step| {
+        ForLoop {
+            var: "i".to_owned(),
+            iter: Box::new(BinaryExpr {
+                left: Box::new(IntLiteral(0)),
+                op: BinaryOp::Range,
+                right: Box::new(IntLiteral(10)),
+                span: None,
+            }),
+            body: Box::new(Assignment {
+                left: "n".to_owned(),
+                right: Box::new(BinaryExpr {
+                    left: Box::new(Variable("n".to_owned())),
+                    op: op,
+                    right: Box::new(step),
+                    span: None,
+                }),
+            }),
+        }
+    };
+
+    p.set_var("n", Int(0));
+    assert_eq!(p.eval(&count(Add, Variable("i".to_owned()))), Ok(Int(45)));
+    // Adding a float to an int gives a float.
+    p.set_var("n", Int(0));
+    assert_eq!(p.eval(&count(Add, NumberLiteral(0.5))), Ok(Number(5.0)));
+
+    // Ints and whole floats can both index lists.
+    for idx in vec![IntLiteral(1), NumberLiteral(1.0)] {
+        assert_eq!(p.eval(&Index {
+                       object: Box::new(Variable("l".to_owned())),
+                       index: Box::new(idx),
+                   }),
+                   Ok(Str("b".into())));
+    }
+}
+
+#[test]
+fn test_map_literal() {
+    let mut p = Program::new();
+    p.set_var("k", Str("key".into()));
+
+    // { z: 1, (k): 2, z: 3 }
+    let lit = MapLiteral(vec![
+        (StrLiteral("z".into()), IntLiteral(1)),
+        (ParenExpr(Box::new(Variable("k".to_owned()))), IntLiteral(2)),
+        (StrLiteral("z".into()), IntLiteral(3)),
+    ]);
+
+    // Entries keep the order they're written in, and a repeated key replaces
+    // the earlier value without moving it.
+    let m = p.eval(&lit).unwrap();
+    assert_eq!(m.to_string(), "{z: 3, key: 2}");
+
+    let lit = MapLiteral(vec![(IntLiteral(1), IntLiteral(2))]);
+    assert_eq!(p.eval(&lit),
+               Err(InvalidIndex {
+                   type_name: "map".to_owned(),
+                   index: "int".to_owned(),
+               }));
+}
+
+#[test]
+fn test_reference_semantics() {
+    let mut p = Program::new();
+    let var = |name: &str| Variable(name.to_owned());
+    let call = |name: &str, args: Vec<Expression>| {
+        FunctionCall {
+            func: Box::new(Variable(name.to_owned())),
+            args: args,
+        }
+    };
+    let assign = |name: &str, right: Expression| {
+        Assignment {
+            left: name.to_owned(),
+            right: Box::new(right),
+        }
+    };
+
+    // a = [1]  b = a  c = copy(a)  n = 1  m = n
+    p.eval(&assign("a", ListLiteral(vec![IntLiteral(1)]))).unwrap();
+    p.eval(&assign("b", var("a"))).unwrap();
+    p.eval(&assign("c", call("copy", vec![var("a")]))).unwrap();
+    p.eval(&assign("n", IntLiteral(1))).unwrap();
+    p.eval(&assign("m", var("n"))).unwrap();
+
+    // fn f(l, x) { push(l, 2)  x = 5 }  f(b, m)
+    p.eval(&FunctionDef {
+            name: "f".to_owned(),
+            params: vec!["l".to_owned(), "x".to_owned()],
+            body: Box::new(Block(vec![call("push", vec![var("l"), IntLiteral(2)]),
+                                      assign("x", IntLiteral(5))])),
+        })
+        .unwrap();
+    p.eval(&call("f", vec![var("b"), var("m")])).unwrap();
+
+    // The list is shared by a, b and the function's parameter, while the copy
+    // and the int aren't.
+    let list = |items: &[i64]| Data::list(items.iter().map(|&i| Int(i)).collect());
+    assert_eq!(p.var("a"), Some(list(&[1, 2])));
+    assert_eq!(p.var("b"), Some(list(&[1, 2])));
+    assert_eq!(p.var("c"), Some(list(&[1])));
+    assert_eq!(p.var("m"), Some(Int(1)));
+    assert!(p.var("a").unwrap().is_ref_type());
+    assert!(!p.var("n").unwrap().is_ref_type());
+}
+
+#[test]
+fn test_cyclic_containers() {
+    let mut p = Program::new();
+    p.run_source("l = list(1)  push(l, l)  k = list(1)  push(k, k)").unwrap();
+    p.run_source("m = map()  put(m, \"self\", m)").unwrap();
+
+    // A container inside itself is printed as `...`.
+    let l = p.var("l").unwrap();
+    assert_eq!(l.to_string(), "[1, [...]]");
+    assert_eq!(format!("{:?}", l), "List([Int(1), List([...])])");
+    assert_eq!(p.var("m").unwrap().to_string(), "{self: {...}}");
+    assert_eq!(p.run_source("str(l)"), Ok(Str("[1, [...]]".into())));
+
+    // Containers holding themselves in the same places are equal.
+    assert_eq!(p.run_source("l == k"), Ok(Boolean(true)));
+    assert_eq!(p.run_source("push(k, 2)  l == k"), Ok(Boolean(false)));
+    assert!(::convert::to_data(&l).is_err());
+}
@@ -0,0 +1,29 @@
+use std::rc::Rc;
+
+use expr::Expression;
+use scope::Env;
+
+// A Function is a function defined by a script.  Anonymous functions have an
+// empty name.  A function keeps the local scopes it was defined in, which it
+// shares with the code around it and with other functions defined there.
+#[derive(Clone,Debug)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Expression,
+    pub env: Vec<Env>,
+    // The source the function was parsed from, as given by
+    // Context::new_source, if it's known.
+    pub source: Option<u64>,
+}
+
+// Functions are equal if they have the same code and were defined in the same
+// scopes.  The scopes are compared by identity, since a function can be stored
+// in a scope it was defined in.
+impl PartialEq for Function {
+    fn eq(&self, other: &Function) -> bool {
+        self.name == other.name && self.params == other.params && self.body == other.body &&
+        self.env.len() == other.env.len() &&
+        self.env.iter().zip(&other.env).all(|(a, b)| Rc::ptr_eq(a, b))
+    }
+}
@@ -0,0 +1,70 @@
+// The handles module lets builtins give scripts host objects, such as sockets
+// and database connections, which can't be held in a Data.  The context keeps
+// each object, and scripts hold an int that refers to it, like a file
+// descriptor, which they pass back to the builtins that use it.  close works
+// on any of them.
+
+use std::any::Any;
+use std::collections::HashMap;
+
+use data::Data;
+
+// Handles holds a program's host objects.  They're dropped, closing any
+// sockets or files they hold, when they're closed or the program is dropped.
+#[derive(Default)]
+pub struct Handles {
+    last_id: i64,
+    open: HashMap<i64, Box<dyn Any>>,
+}
+
+impl Handles {
+    pub fn new() -> Self {
+        Handles::default()
+    }
+
+    // add keeps object and returns the handle scripts refer to it by.
+    pub fn add<T: Any>(&mut self, object: T) -> Data {
+        self.last_id += 1;
+        self.open.insert(self.last_id, Box::new(object));
+        Data::Int(self.last_id)
+    }
+
+    // get returns the object d refers to, or None if d isn't the handle of an
+    // open object of type T.
+    pub fn get<T: Any>(&mut self, d: &Data) -> Option<&mut T> {
+        match d {
+            &Data::Int(id) => self.open.get_mut(&id).and_then(|o| o.downcast_mut()),
+            _ => None,
+        }
+    }
+
+    // close drops the object d refers to, returning whether there was one.
+    pub fn close(&mut self, d: &Data) -> bool {
+        match d {
+            &Data::Int(id) => self.open.remove(&id).is_some(),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handles() {
+        let mut handles = Handles::new();
+        let a = handles.add(String::from("a"));
+        let b = handles.add(5u8);
+        assert_ne!(a, b);
+
+        handles.get::<String>(&a).unwrap().push('!');
+        assert_eq!(handles.get::<String>(&a).map(|s| s.clone()), Some("a!".to_owned()));
+        assert_eq!(handles.get::<String>(&b), None);
+        assert_eq!(handles.get::<String>(&Data::Str("1".into())), None);
+
+        assert!(handles.close(&a));
+        assert!(!handles.close(&a));
+        assert_eq!(handles.get::<String>(&a), None);
+    }
+}
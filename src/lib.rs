@@ -1,20 +1,86 @@
+extern crate indexmap;
+#[macro_use]
+extern crate serde;
+#[cfg(test)]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "plugins")]
+extern crate libloading;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
+#[cfg(feature = "signals")]
+extern crate signal_hook;
+
+pub mod analysis;
+mod audit;
 mod binary_op;
+mod bound;
+mod builtins;
+mod cancel;
+mod catalog;
+mod checkpoint;
+pub mod compiled;
+mod context;
+mod convert;
+pub mod corpus;
+pub mod cst;
 mod data;
+pub mod doctest;
+mod engine;
 mod error;
+pub mod explain;
 mod expr;
+mod function;
+mod handles;
+#[cfg(feature = "net")]
+mod net;
+pub mod notebook;
 mod parser;
+pub mod plugin;
 mod program;
+mod rng;
+pub mod refactor;
 mod scanner;
 mod scope;
+mod seq;
+mod set;
+#[cfg(feature = "signals")]
+mod signals;
+mod snapshot;
+pub mod stack;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod store;
+pub mod testing;
+mod unary_op;
 
 #[cfg(test)]
 mod expr_test;
 #[cfg(test)]
 mod parser_test;
 
+pub use audit::AuditEvent;
 pub use binary_op::BinaryOp;
+pub use bound::BoundExpr;
+pub use builtins::NativeFn;
+pub use cancel::CancelToken;
+pub use catalog::{Catalog, Diagnostic};
+pub use context::{Context, ErrorTrace};
+pub use convert::to_data;
 pub use data::Data;
-pub use error::{ExecuteError, ParseError, TokenError};
+pub use engine::Engine;
+pub use error::{ConvertError, Error, ExecuteError, LoadError, ParseError, PluginError,
+                RefactorError, TokenError, Warning};
 pub use expr::Expression;
+pub use function::Function;
+pub use handles::Handles;
 pub use parser::Parser;
-pub use program::Program;
+pub use scanner::{Lexeme, Lexemes, Scanner, Token, Trivia};
+pub use scope::FrozenPolicy;
+pub use seq::Sequence;
+pub use set::ValueSet;
+pub use program::{Program, Results};
+pub use rng::{Rng, SplitMix64};
+pub use snapshot::{Change, Snapshot, diff_snapshots};
+pub use store::StoreKind;
+pub use unary_op::UnaryOp;
@@ -0,0 +1,412 @@
+// The net module gives scripts TCP and Unix sockets, for small network probes
+// and glue daemons.  It's only built with the "net" feature, and scripts can't
+// use it unless the host calls allow_net.
+//
+// Sockets are handles, which scripts close with close.  accept, send and recv
+// wait in short slices, so a cancelled program stops waiting straight away.
+// Connecting can't be interrupted, so it always has a timeout.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::result;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use audit::AuditEvent;
+use builtins::{expect_arg_count, expect_arg_range, expect_number, expect_str, NativeFn};
+use cancel::CancelToken;
+use context::Context;
+use data::Data;
+use data::Data::*;
+use engine::Engine;
+use error::ExecuteError;
+use error::ExecuteError::*;
+use expr::Result;
+
+// The longest a wait goes without checking whether it's been cancelled.
+const SLICE: Duration = Duration::from_millis(20);
+
+// The most recv returns at once.
+const RECV_SIZE: usize = 64 * 1024;
+
+// How long tcp_connect tries each address when it isn't given a timeout.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+enum Socket {
+    Stream(Box<dyn Stream>),
+    Listener(Box<dyn Listener>),
+}
+
+trait Stream: Read + Write {
+    fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, t)
+    }
+
+    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, t)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, t)
+    }
+
+    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, t)
+    }
+}
+
+// Listeners don't block, so accept_stream fails with WouldBlock when there's
+// no connection waiting.
+trait Listener {
+    fn accept_stream(&self) -> io::Result<Box<dyn Stream>>;
+}
+
+impl Listener for TcpListener {
+    fn accept_stream(&self) -> io::Result<Box<dyn Stream>> {
+        let (stream, _) = self.accept()?;
+        stream.set_nonblocking(false)?;
+        Ok(Box::new(stream))
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    fn accept_stream(&self) -> io::Result<Box<dyn Stream>> {
+        let (stream, _) = self.accept()?;
+        stream.set_nonblocking(false)?;
+        Ok(Box::new(stream))
+    }
+}
+
+// expect_stream returns the connection whose handle is d.
+fn expect_stream<'a>(ctx: &'a mut Context,
+                     d: &Data)
+                     -> result::Result<&'a mut dyn Stream, ExecuteError> {
+    match ctx.handles().get(d) {
+        Some(&mut Socket::Stream(ref mut s)) => Ok(&mut **s),
+        _ => Err(not_socket("connection", d)),
+    }
+}
+
+// expect_listener returns the listener whose handle is d.
+fn expect_listener<'a>(ctx: &'a mut Context,
+                       d: &Data)
+                       -> result::Result<&'a dyn Listener, ExecuteError> {
+    match ctx.handles().get(d) {
+        Some(&mut Socket::Listener(ref l)) => Ok(&**l),
+        _ => Err(not_socket("listener", d)),
+    }
+}
+
+fn not_socket(kind: &str, d: &Data) -> ExecuteError {
+    InvalidArgument {
+        expected: format!("open {}", kind),
+        found: d.to_string(),
+    }
+}
+
+// tcp_connect(addr[, timeout]) connects to addr, such as "example.com:80", and
+// returns the connection.  It gives up on each address addr resolves to after
+// timeout milliseconds, or after 30 seconds if no timeout is given.
+pub struct TcpConnect;
+
+impl NativeFn for TcpConnect {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_range(v, 1, 2)?;
+        let addr = expect_net_access(ctx, "tcp_connect", &v[0], false)?;
+        let timeout = expect_timeout(v.get(1))?.unwrap_or(CONNECT_TIMEOUT);
+        let stream = connect_timeout(&addr, timeout).map_err(|e| Io(format!("{}: {}", addr, e)))?;
+        Ok(ctx.handles().add(Socket::Stream(Box::new(stream))))
+    }
+}
+
+// connect_timeout tries each of the addresses addr resolves to in turn.
+fn connect_timeout(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no addresses found");
+    for a in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&a, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+// unix_connect(path) connects to the Unix socket at path and returns the
+// connection.
+#[cfg(unix)]
+pub struct UnixConnect;
+
+#[cfg(unix)]
+impl NativeFn for UnixConnect {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_count(v, 1)?;
+        let path = expect_net_access(ctx, "unix_connect", &v[0], false)?;
+        let stream = UnixStream::connect(&path).map_err(|e| Io(format!("{}: {}", path, e)))?;
+        Ok(ctx.handles().add(Socket::Stream(Box::new(stream))))
+    }
+}
+
+// listen(addr) listens for connections on addr, such as "127.0.0.1:8080", or
+// on a Unix socket for an addr like "unix:/tmp/gate.sock", and returns the
+// listener.
+pub struct Listen;
+
+impl NativeFn for Listen {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_count(v, 1)?;
+        let addr = expect_net_access(ctx, "listen", &v[0], true)?;
+        let listener = bind(&addr).map_err(|e| Io(format!("{}: {}", addr, e)))?;
+        Ok(ctx.handles().add(Socket::Listener(listener)))
+    }
+}
+
+fn bind(addr: &str) -> io::Result<Box<dyn Listener>> {
+    if let Some(path) = addr.strip_prefix("unix:") {
+        return bind_unix(path);
+    }
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    Ok(Box::new(listener))
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str) -> io::Result<Box<dyn Listener>> {
+    let listener = UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+    Ok(Box::new(listener))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_: &str) -> io::Result<Box<dyn Listener>> {
+    Err(io::Error::new(io::ErrorKind::Other, "Unix sockets aren't supported"))
+}
+
+// accept(listener[, timeout]) waits for a connection to the listener and
+// returns it, or returns nil if none arrives within timeout milliseconds.
+pub struct Accept;
+
+impl NativeFn for Accept {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_range(v, 1, 2)?;
+        let deadline = expect_timeout(v.get(1))?.map(|t| Instant::now() + t);
+        let cancel = ctx.cancel_token();
+        loop {
+            match expect_listener(ctx, &v[0])?.accept_stream() {
+                Ok(stream) => return Ok(ctx.handles().add(Socket::Stream(stream))),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(Io(e.to_string())),
+            }
+            match next_slice(&cancel, deadline)? {
+                Some(slice) => thread::sleep(slice),
+                None => return Ok(Nil),
+            }
+        }
+    }
+}
+
+// send(conn, s[, timeout]) sends the string s over the connection.  It fails
+// if it can't send all of it within timeout milliseconds.
+pub struct Send;
+
+impl NativeFn for Send {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_range(v, 2, 3)?;
+        let s = expect_str(&v[1])?;
+        let deadline = expect_timeout(v.get(2))?.map(|t| Instant::now() + t);
+        let cancel = ctx.cancel_token();
+        let stream = expect_stream(ctx, &v[0])?;
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            let slice = match next_slice(&cancel, deadline)? {
+                Some(slice) => slice,
+                None => return Err(Io("timed out before sending everything".to_owned())),
+            };
+            stream.set_write_timeout(Some(slice)).map_err(|e| Io(e.to_string()))?;
+            match stream.write(bytes) {
+                Ok(0) => return Err(Io("connection closed while sending".to_owned())),
+                Ok(n) => bytes = &bytes[n..],
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut ||
+                              e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(Io(e.to_string())),
+            }
+        }
+        Ok(Nil)
+    }
+}
+
+// recv(conn[, timeout]) waits for data from the connection and returns what
+// has arrived as a string, or "" once the other end has closed it.  It returns
+// nil if nothing arrives within timeout milliseconds.
+pub struct Recv;
+
+impl NativeFn for Recv {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_range(v, 1, 2)?;
+        let deadline = expect_timeout(v.get(1))?.map(|t| Instant::now() + t);
+        let cancel = ctx.cancel_token();
+        let stream = expect_stream(ctx, &v[0])?;
+        let mut buf = vec![0; RECV_SIZE];
+        loop {
+            let slice = match next_slice(&cancel, deadline)? {
+                Some(slice) => slice,
+                None => return Ok(Nil),
+            };
+            stream.set_read_timeout(Some(slice)).map_err(|e| Io(e.to_string()))?;
+            match stream.read(&mut buf) {
+                Ok(n) => return Ok(Str(String::from_utf8_lossy(&buf[..n]).into())),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(Io(e.to_string())),
+            }
+        }
+    }
+}
+
+// expect_net_access returns the address a net builtin was given, after
+// checking that the program may use the network and recording the access.
+fn expect_net_access(ctx: &mut Context,
+                     name: &str,
+                     addr: &Data,
+                     listen: bool)
+                     -> result::Result<String, ExecuteError> {
+    if !ctx.net_allowed() {
+        return Err(NotAllowed(name.to_owned()));
+    }
+    let addr = expect_str(addr)?;
+    ctx.audit(AuditEvent::NetAccess {
+        addr: addr.clone(),
+        listen: listen,
+    });
+    Ok(addr)
+}
+
+// expect_timeout converts an optional timeout in milliseconds to a Duration.
+// A missing or nil timeout means waiting for as long as it takes.
+fn expect_timeout(d: Option<&Data>) -> result::Result<Option<Duration>, ExecuteError> {
+    let ms = match d {
+        None | Some(&Nil) => return Ok(None),
+        Some(d) => expect_number(d)?,
+    };
+    if ms < 0.0 || ms.is_nan() {
+        return Err(InvalidArgument {
+            expected: "timeout of at least 0".to_owned(),
+            found: ms.to_string(),
+        });
+    }
+    Ok(Some(Duration::from_micros((ms * 1000.0) as u64)))
+}
+
+// next_slice returns how long to wait before checking again, or None if the
+// deadline has passed.  It fails if the program has been cancelled.
+fn next_slice(cancel: &CancelToken,
+              deadline: Option<Instant>)
+              -> result::Result<Option<Duration>, ExecuteError> {
+    if cancel.is_cancelled() {
+        return Err(Cancelled);
+    }
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return Ok(Some(SLICE)),
+    };
+    let now = Instant::now();
+    if now >= deadline {
+        return Ok(None);
+    }
+    Ok(Some(cmp::min(SLICE, deadline - now)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    use error::Error;
+    use program::Program;
+
+    #[test]
+    fn test_tcp() {
+        let mut p = Program::new();
+        assert_eq!(p.run_source("has_feature(\"net\")"), Ok(Boolean(false)));
+        assert_eq!(p.run_source("tcp_connect(\"127.0.0.1:1\")"),
+                   Err(Error::Execute(NotAllowed("tcp_connect".to_owned()))));
+
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let echo = thread::spawn(move || {
+            let (mut conn, _) = server.accept().unwrap();
+            let mut buf = [0; 4];
+            conn.read_exact(&mut buf).unwrap();
+            conn.write_all(&buf).unwrap();
+        });
+
+        p.allow_net(true);
+        p.enable_audit(&[]);
+        p.set_var("addr", Str(addr.as_str().into()));
+        assert_eq!(p.run_source("c = tcp_connect(addr, 5000)  send(c, \"ping\")  \
+                                 list(recv(c, 5000), recv(c, 5000))"),
+                   Ok(Data::list(vec![Str("ping".into()), Str("".into())])));
+        echo.join().unwrap();
+        assert_eq!(p.audit_log()[1],
+                   AuditEvent::NetAccess {
+                       addr: addr,
+                       listen: false,
+                   });
+        assert_eq!(p.run_source("close(c)"), Ok(Nil));
+        assert!(p.run_source("recv(c)").is_err());
+    }
+
+    #[test]
+    fn test_send_timeout() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut p = Program::new();
+        p.allow_net(true);
+        p.set_var("addr", Str(server.local_addr().unwrap().to_string().into()));
+        p.set_var("s", Str("x".repeat(64 << 20).into()));
+
+        // Nothing reads from the connection, so s can't all be sent.
+        assert_eq!(p.run_source("c = tcp_connect(addr)  send(c, s, 50)"),
+                   Err(Error::Execute(Io("timed out before sending everything".to_owned()))));
+        drop(server);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix() {
+        let path = env::temp_dir().join(format!("gate-net-test-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let mut p = Program::new();
+        p.allow_net(true);
+        p.set_var("addr", Str(format!("unix:{}", path.display()).into()));
+        assert_eq!(p.run_source("l = listen(addr)  accept(l, 10)"), Ok(Nil));
+
+        let client = thread::spawn(move || {
+            let mut conn = UnixStream::connect(&path).unwrap();
+            conn.write_all(b"hi").unwrap();
+            fs::remove_file(&path).unwrap();
+        });
+        assert_eq!(p.run_source("c = accept(l, 5000)  recv(c, 5000)"), Ok(Str("hi".into())));
+        client.join().unwrap();
+        assert_eq!(p.run_source("recv(l)"),
+                   Err(Error::Execute(InvalidArgument {
+                       expected: "open connection".to_owned(),
+                       found: "1".to_owned(),
+                   })));
+        assert!(p.run_source("accept(l, -1)").is_err());
+    }
+}
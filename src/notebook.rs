@@ -0,0 +1,140 @@
+// The notebook module runs code a cell at a time for notebook-style front ends.
+// A Kernel keeps one program alive between cells, so the variables and
+// functions a cell defines can be used by the cells run after it, and reports
+// what each cell printed, its value and any error separately.
+
+use data::Data;
+use error::Warning;
+use program::Program;
+use snapshot::{self, Change};
+
+pub struct Kernel {
+    program: Program,
+    count: usize,
+}
+
+// A Reply is the result of running a cell.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Reply {
+    // The number of cells run so far, counting this one.
+    pub count: usize,
+    // Everything the cell printed.
+    pub output: String,
+    pub warnings: Vec<Warning>,
+    // The global variables the cell created, changed or removed.
+    pub changes: Vec<Change>,
+    // The value of the cell's last expression, if the cell succeeded.
+    pub value: Option<Data>,
+    pub error: Option<CellError>,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct CellError {
+    // The error's code, which `gate explain` describes.
+    pub code: &'static str,
+    pub message: String,
+    // Where in the cell the error happened, when it's known, as a line and
+    // column counting from 1, and the text found there.
+    pub position: Option<(usize, usize)>,
+    pub text: Option<String>,
+}
+
+impl Kernel {
+    pub fn new() -> Self {
+        let mut program = Program::new();
+        program.capture_output();
+        Kernel {
+            program: program,
+            count: 0,
+        }
+    }
+
+    // execute runs the code of a cell.  Like a cell in a notebook, it stops at
+    // the first error, keeping whatever the expressions before it did.
+    pub fn execute(&mut self, code: &str) -> Reply {
+        self.count += 1;
+        let before = self.program.state_snapshot();
+        let res = self.program.run_source(code);
+        let changes = snapshot::diff_snapshots(&before, &self.program.state_snapshot());
+        let output = self.program.take_output();
+        let warnings = self.program.take_warnings();
+
+        let (value, error) = match res {
+            Ok(d) => (Some(d), None),
+            Err(e) => {
+                let span = e.span();
+                let error = CellError {
+                    code: e.code(),
+                    message: e.message(),
+                    position: span.map(|s| s.position(code)),
+                    text: span.map(|s| code[s.start..s.end].to_owned()),
+                };
+                (None, Some(error))
+            }
+        };
+
+        Reply {
+            count: self.count,
+            output: output,
+            warnings: warnings,
+            changes: changes,
+            value: value,
+            error: error,
+        }
+    }
+
+    // reset forgets everything the cells run so far have defined and starts
+    // counting them again.
+    pub fn reset(&mut self) {
+        *self = Kernel::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_execute() {
+        let mut kernel = Kernel::new();
+        let reply = kernel.execute("fn double(n) n * 2  println(\"hi\")  x = 2");
+        assert_eq!(reply.count, 1);
+        assert_eq!(reply.output, "hi\n");
+        assert_eq!(reply.value, Some(Data::Int(2)));
+        assert_eq!(reply.error, None);
+
+        let reply = kernel.execute("double(x)");
+        assert_eq!(reply.count, 2);
+        assert_eq!(reply.value, Some(Data::Int(4)));
+        assert_eq!(reply.changes, vec![]);
+
+        let reply = kernel.execute("x = 3  y = 1");
+        let changes: Vec<_> = reply.changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(changes, vec!["~ x = Int(3) (was Int(2))", "+ y = Int(1)"]);
+
+        let reply = kernel.execute("println(1)\nx * nil\nprintln(2)");
+        assert_eq!(reply.output, "1\n");
+        assert_eq!(reply.value, None);
+        assert_eq!(reply.error,
+                   Some(CellError {
+                       code: "E0018",
+                       message: "invalid operation (int * nil): 3 * nil".to_owned(),
+                       position: Some((2, 3)),
+                       text: Some("*".to_owned()),
+                   }));
+
+        // The operation that failed is in another cell, so there's no position
+        // in this one.
+        let reply = kernel.execute("double(nil)");
+        assert_eq!(reply.error.as_ref().map(|e| (e.position, e.text.clone())),
+                   Some((None, None)));
+
+        kernel.reset();
+        let reply = kernel.execute("x");
+        assert_eq!(reply.count, 1);
+        assert_eq!(reply.error.unwrap().message, "undefined variable \"x\"");
+
+        let reply = kernel.execute("fn len(x) 0");
+        assert_eq!(reply.warnings, vec![Warning::ShadowedBuiltin("len".to_owned())]);
+    }
+}
@@ -1,26 +1,105 @@
-use std::iter::Peekable;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
 use std::result;
 
 use binary_op::BinaryOp;
-use error::ParseError;
+use cst::Span;
+use error::{self, ParseError};
 use expr::Expression;
-use scanner::{Scanner, Token};
+use scanner::{self, Scanner, Token};
+use stack;
+use unary_op::UnaryOp;
 
 pub type Result<T> = result::Result<T, ParseError>;
 
 pub struct Parser<'a> {
-    scanner: Peekable<Scanner<'a>>,
+    scanner: Tokens<'a>,
+    // The number of loop bodies enclosing the current position, used to
+    // reject "break" and "continue" outside of a loop.
+    loop_depth: usize,
+    // The text of the string literals read so far, so that equal literals
+    // share their storage and compare quickly.
+    strings: HashMap<String, Rc<str>>,
+}
+
+// Tokens is a peekable stream of tokens that also knows where the token most
+// recently read is, which Peekable hides.
+struct Tokens<'a> {
+    scanner: Scanner<'a>,
+    peeked: Option<Option<scanner::Result<Token>>>,
+    // Where the token most recently returned by next is.
+    span: Span,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(scanner: Scanner<'a>) -> Self {
+        Tokens {
+            scanner: scanner,
+            peeked: None,
+            span: Span { start: 0, end: 0 },
+        }
+    }
+
+    fn peek(&mut self) -> Option<&scanner::Result<Token>> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.scanner.next());
+        }
+        match self.peeked {
+            Some(ref token) => token.as_ref(),
+            None => None,
+        }
+    }
+
+    fn next(&mut self) -> Option<scanner::Result<Token>> {
+        let token = match self.peeked.take() {
+            Some(token) => token,
+            None => self.scanner.next(),
+        };
+        self.span = self.scanner.span();
+        token
+    }
+
+    // span returns where the token most recently returned by next is.
+    fn span(&self) -> Span {
+        self.span
+    }
 }
 
 impl<'a> Parser<'a> {
     pub fn new(input: &'a str) -> Self {
-        Parser { scanner: Scanner::new(input).peekable() }
+        Parser {
+            scanner: Tokens::new(Scanner::new(input)),
+            loop_depth: 0,
+            strings: HashMap::new(),
+        }
+    }
+
+    // with_prefixes creates a parser that reads strings directly after any of
+    // the prefix characters as custom literals.  Engine::parser uses the
+    // prefixes registered on the engine.
+    pub fn with_prefixes(input: &'a str, prefixes: Vec<char>) -> Self {
+        Parser {
+            scanner: Tokens::new(Scanner::with_prefixes(input, prefixes)),
+            loop_depth: 0,
+            strings: HashMap::new(),
+        }
+    }
+
+    // intern returns the shared text of a string literal.
+    fn intern(&mut self, s: String) -> Rc<str> {
+        if let Some(text) = self.strings.get(&s) {
+            return text.clone();
+        }
+        let text: Rc<str> = Rc::from(s.as_str());
+        self.strings.insert(s, text.clone());
+        text
     }
 
     // Assuming we've read an open paren, parse the inner expression and the
     // closing paren.
     fn parse_paren_expr(&mut self) -> Result<Expression> {
-        let inner = match self.next() {
+        let inner = match self.parse_expr() {
             Some(Ok(expr)) => expr,
             Some(Err(e)) => return Err(e),
             None => return Err(ParseError::UnexpectedEOF),
@@ -34,11 +113,36 @@ impl<'a> Parser<'a> {
         }
     }
 
+    // Assuming we've read an open curly, parse a block or a map literal along
+    // with the closing curly.  The two are told apart by the token following
+    // the first inner expression: `{ a: ... }` is a map, while `{}` and
+    // `{ a ... }` are blocks.
+    fn parse_curly(&mut self) -> Result<Expression> {
+        if let Some(&Ok(Token::CloseCurly)) = self.scanner.peek() {
+            self.scanner.next();
+            return Ok(Expression::Block(vec![]));
+        }
+
+        let first = match self.parse_expr() {
+            Some(Ok(expr)) => expr,
+            Some(Err(e)) => return Err(e),
+            None => return Err(ParseError::UnexpectedEOF),
+        };
+        match self.scanner.peek() {
+            Some(&Ok(Token::Colon)) => self.parse_map(first),
+            _ => self.parse_block_rest(vec![first]),
+        }
+    }
+
     // Assuming we've read an open curly, parse the inner block and the closing
-    // curly.
+    // curly.  `do { ... }` is always a block.
     fn parse_block(&mut self) -> Result<Expression> {
-        let mut body = vec![];
+        self.parse_block_rest(vec![])
+    }
 
+    // parse_block_rest parses the rest of a block whose first expressions have
+    // already been read.
+    fn parse_block_rest(&mut self, mut body: Vec<Expression>) -> Result<Expression> {
         loop {
             match self.scanner.peek().cloned() {
                 None => return Err(ParseError::UnexpectedEOF),
@@ -48,7 +152,7 @@ impl<'a> Parser<'a> {
                     return Ok(Expression::Block(body));
                 }
                 _ => {
-                    match self.next() {
+                    match self.parse_expr() {
                         Some(Ok(expr)) => body.push(expr),
                         Some(Err(e)) => return Err(e),
                         None => return Err(ParseError::UnexpectedEOF),
@@ -58,34 +162,296 @@ impl<'a> Parser<'a> {
         }
     }
 
-    // Assuming we've parsed an identifier, parse the rest of the expression.
-    fn parse_identifier(&mut self, name: String) -> Result<Expression> {
-        match self.scanner.peek() {
-            Some(&Ok(Token::OpenParen)) => self.scanner.next(),
-            _ => return Ok(Expression::Variable(name)),
+    // parse_map parses the entries of a map literal, given its first key, up to
+    // and including the closing curly.  A key that's a plain name, as in
+    // `{ a: 1 }`, stands for the name itself rather than a variable.
+    fn parse_map(&mut self, first_key: Expression) -> Result<Expression> {
+        let mut entries = vec![];
+        let mut key = first_key;
+
+        loop {
+            match self.scanner.next() {
+                Some(Ok(Token::Colon)) => {}
+                Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+                Some(Err(e)) => return Err(ParseError::ScanError(e)),
+                None => return Err(ParseError::UnexpectedEOF),
+            }
+            let value = match self.parse_expr() {
+                Some(Ok(expr)) => expr,
+                Some(Err(e)) => return Err(e),
+                None => return Err(ParseError::UnexpectedEOF),
+            };
+            let key_expr = match key {
+                Expression::Variable(name) => Expression::StrLiteral(self.intern(name)),
+                k => k,
+            };
+            entries.push((key_expr, value));
+
+            match self.scanner.next() {
+                Some(Ok(Token::Comma)) => {}
+                Some(Ok(Token::CloseCurly)) => return Ok(Expression::MapLiteral(entries)),
+                Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+                Some(Err(e)) => return Err(ParseError::ScanError(e)),
+                None => return Err(ParseError::UnexpectedEOF),
+            }
+            key = match self.parse_expr() {
+                Some(Ok(expr)) => expr,
+                Some(Err(e)) => return Err(e),
+                None => return Err(ParseError::UnexpectedEOF),
+            };
+        }
+    }
+
+    // Assuming we've read a "do", parse the block that follows it, and the
+    // condition if it's followed by a "while".
+    fn parse_do(&mut self) -> Result<Expression> {
+        match self.scanner.next() {
+            Some(Ok(Token::OpenCurly)) => {}
+            Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+            Some(Err(e)) => return Err(ParseError::ScanError(e)),
+            None => return Err(ParseError::UnexpectedEOF),
+        }
+
+        // Whether the block is a loop body isn't known until after it, so
+        // it's parsed as one and checked for a stray break once it's known.
+        self.loop_depth += 1;
+        let block = self.parse_block();
+        self.loop_depth -= 1;
+
+        let body = match block? {
+            Expression::Block(body) => Expression::DoBlock(body),
+            e => e,
+        };
+
+        if self.scanner.peek() != Some(&Ok(Token::While)) {
+            return match loose_jump(&body) {
+                Some(token) if self.loop_depth == 0 => Err(ParseError::Unexpected(token)),
+                _ => Ok(body),
+            };
+        }
+        self.scanner.next();
+
+        let cond = match self.parse_expr() {
+            None => return Err(ParseError::UnexpectedEOF),
+            Some(Err(e)) => return Err(e),
+            Some(Ok(expr)) => expr,
+        };
+
+        Ok(Expression::DoWhile {
+            body: Box::new(body),
+            cond: Box::new(cond),
+        })
+    }
+
+    // Assuming we've read a "fn", parse the function's name, its parameter list
+    // and its body.  A "fn" followed directly by a parameter list, as in
+    // `fn(x) { x * 2 }`, is an anonymous function.
+    fn parse_fn(&mut self) -> Result<Expression> {
+        let name = match self.scanner.next() {
+            Some(Ok(Token::Identifier(name))) => name,
+            Some(Ok(Token::OpenParen)) => {
+                let params = self.parse_param_list(&Token::CloseParen)?;
+                return self.parse_lambda_body(params);
+            }
+            Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+            Some(Err(e)) => return Err(ParseError::ScanError(e)),
+            None => return Err(ParseError::UnexpectedEOF),
+        };
+
+        match self.scanner.next() {
+            Some(Ok(Token::OpenParen)) => {}
+            Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+            Some(Err(e)) => return Err(ParseError::ScanError(e)),
+            None => return Err(ParseError::UnexpectedEOF),
+        }
+
+        let params = self.parse_param_list(&Token::CloseParen)?;
+        let body = self.parse_fn_body()?;
+
+        Ok(Expression::FunctionDef {
+            name: name,
+            params: params,
+            body: Box::new(body),
+        })
+    }
+
+    // Assuming we've read a "struct", parse the struct's name and its list of
+    // fields.
+    fn parse_struct(&mut self) -> Result<Expression> {
+        let name = match self.scanner.next() {
+            Some(Ok(Token::Identifier(name))) => name,
+            Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+            Some(Err(e)) => return Err(ParseError::ScanError(e)),
+            None => return Err(ParseError::UnexpectedEOF),
+        };
+
+        match self.scanner.next() {
+            Some(Ok(Token::OpenCurly)) => {}
+            Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+            Some(Err(e)) => return Err(ParseError::ScanError(e)),
+            None => return Err(ParseError::UnexpectedEOF),
+        }
+
+        Ok(Expression::StructDef {
+            name: name,
+            fields: self.parse_param_list(&Token::CloseCurly)?,
+        })
+    }
+
+    // Assuming we've read a "let" or "const", parse the name being declared and
+    // its value.
+    fn parse_let(&mut self, constant: bool) -> Result<Expression> {
+        let name = match self.scanner.next() {
+            Some(Ok(Token::Identifier(name))) => name,
+            Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+            Some(Err(e)) => return Err(ParseError::ScanError(e)),
+            None => return Err(ParseError::UnexpectedEOF),
         };
 
-        match self.parse_expr_list(&Token::CloseParen) {
-            Ok(args) => {
-                Ok(Expression::FunctionCall {
-                    name: name,
-                    args: args,
+        match self.scanner.next() {
+            Some(Ok(Token::Eq)) => {}
+            Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+            Some(Err(e)) => return Err(ParseError::ScanError(e)),
+            None => return Err(ParseError::UnexpectedEOF),
+        }
+
+        match self.parse_expr() {
+            Some(Ok(value)) => {
+                Ok(Expression::Declaration {
+                    left: name,
+                    right: Box::new(value),
+                    constant: constant,
                 })
             }
-            Err(e) => Err(e),
+            Some(Err(e)) => Err(e),
+            None => Err(ParseError::UnexpectedEOF),
+        }
+    }
+
+    // parse_fn_body parses the body of a named or anonymous function.
+    fn parse_fn_body(&mut self) -> Result<Expression> {
+        // A function body can't break out of a loop surrounding the function.
+        let loop_depth = self.loop_depth;
+        self.loop_depth = 0;
+        let body = self.parse_expr();
+        self.loop_depth = loop_depth;
+
+        match body {
+            None => Err(ParseError::UnexpectedEOF),
+            Some(Err(e)) => Err(e),
+            Some(Ok(expr)) => Ok(expr),
+        }
+    }
+
+    // parse_lambda_body parses the body of an anonymous function whose
+    // parameters have already been read.
+    fn parse_lambda_body(&mut self, params: Vec<String>) -> Result<Expression> {
+        let body = self.parse_fn_body()?;
+        Ok(Expression::Lambda {
+            params: params,
+            body: Box::new(body),
+        })
+    }
+
+    // parse_param_list parses a comma-separated list of parameter names until
+    // the specified token is found.
+    fn parse_param_list(&mut self, until: &Token) -> Result<Vec<String>> {
+        let mut params = Vec::new();
+
+        if let Some(&Ok(ref t)) = self.scanner.peek() {
+            if t == until {
+                self.scanner.next();
+                return Ok(params);
+            }
+        }
+
+        loop {
+            match self.scanner.next() {
+                Some(Ok(Token::Identifier(name))) => params.push(name),
+                Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+                Some(Err(e)) => return Err(ParseError::ScanError(e)),
+                None => return Err(ParseError::UnexpectedEOF),
+            }
+
+            match self.scanner.next() {
+                Some(Ok(Token::Comma)) => continue,
+                Some(Ok(ref t)) if t == until => return Ok(params),
+                Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+                Some(Err(e)) => return Err(ParseError::ScanError(e)),
+                None => return Err(ParseError::UnexpectedEOF),
+            }
+        }
+    }
+
+    // Assuming we've parsed an expression, parse any calls, field accesses and
+    // indexes that follow it, as in `f(1)(2)`, `math.sqrt(2)` or `a[0][1]`.
+    // Only variables, calls, field accesses and indexes can be followed this
+    // way, so a literal or block followed by a parenthesized expression isn't
+    // mistaken for a call.
+    fn parse_postfix(&mut self, mut expr: Expression) -> Result<Expression> {
+        loop {
+            match expr {
+                Expression::Variable(_) |
+                Expression::FunctionCall { .. } |
+                Expression::FieldAccess { .. } |
+                Expression::Index { .. } => {}
+                _ => return Ok(expr),
+            }
+
+            match self.scanner.peek() {
+                Some(&Ok(Token::OpenParen)) => {
+                    self.scanner.next();
+                    expr = Expression::FunctionCall {
+                        func: Box::new(expr),
+                        args: self.parse_expr_list(&Token::CloseParen)?,
+                    };
+                }
+                Some(&Ok(Token::Dot)) => {
+                    self.scanner.next();
+                    let field = match self.scanner.next() {
+                        Some(Ok(Token::Identifier(name))) => name,
+                        Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+                        Some(Err(e)) => return Err(ParseError::ScanError(e)),
+                        None => return Err(ParseError::UnexpectedEOF),
+                    };
+                    expr = Expression::FieldAccess {
+                        object: Box::new(expr),
+                        field: field,
+                    };
+                }
+                Some(&Ok(Token::OpenBracket)) => {
+                    self.scanner.next();
+                    let index = match self.parse_expr() {
+                        Some(Ok(e)) => e,
+                        Some(Err(e)) => return Err(e),
+                        None => return Err(ParseError::UnexpectedEOF),
+                    };
+                    match self.scanner.next() {
+                        Some(Ok(Token::CloseBracket)) => {}
+                        Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+                        Some(Err(e)) => return Err(ParseError::ScanError(e)),
+                        None => return Err(ParseError::UnexpectedEOF),
+                    }
+                    expr = Expression::Index {
+                        object: Box::new(expr),
+                        index: Box::new(index),
+                    };
+                }
+                _ => return Ok(expr),
+            }
         }
     }
 
     // Assuming we've read an "if", parse the condition, the body and the else
     // branch, if present.
     fn parse_if(&mut self) -> Result<Expression> {
-        let condition = match self.next() {
+        let condition = match self.parse_expr() {
             None => return Err(ParseError::UnexpectedEOF),
             Some(Err(e)) => return Err(e),
             Some(Ok(expr)) => expr,
         };
 
-        let body = match self.next() {
+        let body = match self.parse_expr() {
             None => return Err(ParseError::UnexpectedEOF),
             Some(Err(e)) => return Err(e),
             Some(Ok(expr)) => expr,
@@ -94,7 +460,7 @@ impl<'a> Parser<'a> {
         let else_branch = match self.scanner.peek() {
             Some(&Ok(Token::Else)) => {
                 self.scanner.next();
-                match self.next() {
+                match self.parse_expr() {
                     None => return Err(ParseError::UnexpectedEOF),
                     Some(Err(e)) => return Err(e),
                     Some(Ok(expr)) => Some(Box::new(expr)),
@@ -110,23 +476,178 @@ impl<'a> Parser<'a> {
         })
     }
 
-    // Assuming we've read a "while", parse the condition and the body.
+    // Assuming we've read a "while", parse the condition, the body and the else
+    // branch, if present.
     fn parse_while(&mut self) -> Result<Expression> {
-        let condition = match self.next() {
+        let condition = match self.parse_expr() {
             None => return Err(ParseError::UnexpectedEOF),
             Some(Err(e)) => return Err(e),
             Some(Ok(expr)) => expr,
         };
 
-        let body = match self.next() {
+        self.loop_depth += 1;
+        let body = self.parse_expr();
+        self.loop_depth -= 1;
+
+        let body = match body {
             None => return Err(ParseError::UnexpectedEOF),
             Some(Err(e)) => return Err(e),
             Some(Ok(expr)) => expr,
         };
 
+        let else_branch = match self.scanner.peek() {
+            Some(&Ok(Token::Else)) => {
+                self.scanner.next();
+                match self.parse_expr() {
+                    None => return Err(ParseError::UnexpectedEOF),
+                    Some(Err(e)) => return Err(e),
+                    Some(Ok(expr)) => Some(Box::new(expr)),
+                }
+            }
+            _ => None,
+        };
+
         Ok(Expression::WhileLoop {
             cond: Box::new(condition),
             body: Box::new(body),
+            else_branch: else_branch,
+        })
+    }
+
+    // Assuming we've read a "for", parse the loop variable, the expression
+    // being looped over and the body.
+    fn parse_for(&mut self) -> Result<Expression> {
+        let var = match self.scanner.next() {
+            Some(Ok(Token::Identifier(name))) => name,
+            Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+            Some(Err(e)) => return Err(ParseError::ScanError(e)),
+            None => return Err(ParseError::UnexpectedEOF),
+        };
+
+        match self.scanner.next() {
+            Some(Ok(Token::In)) => {}
+            Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+            Some(Err(e)) => return Err(ParseError::ScanError(e)),
+            None => return Err(ParseError::UnexpectedEOF),
+        }
+
+        let iter = match self.parse_expr() {
+            None => return Err(ParseError::UnexpectedEOF),
+            Some(Err(e)) => return Err(e),
+            Some(Ok(expr)) => expr,
+        };
+
+        self.loop_depth += 1;
+        let body = self.parse_expr();
+        self.loop_depth -= 1;
+
+        let body = match body {
+            None => return Err(ParseError::UnexpectedEOF),
+            Some(Err(e)) => return Err(e),
+            Some(Ok(expr)) => expr,
+        };
+
+        Ok(Expression::ForLoop {
+            var: var,
+            iter: Box::new(iter),
+            body: Box::new(body),
+        })
+    }
+
+    // Assuming we've read a "try", parse the body, the "catch", the name of the
+    // error variable and the handler.
+    fn parse_try(&mut self) -> Result<Expression> {
+        let body = match self.parse_expr() {
+            None => return Err(ParseError::UnexpectedEOF),
+            Some(Err(e)) => return Err(e),
+            Some(Ok(expr)) => expr,
+        };
+
+        match self.scanner.next() {
+            Some(Ok(Token::Catch)) => {}
+            Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+            Some(Err(e)) => return Err(ParseError::ScanError(e)),
+            None => return Err(ParseError::UnexpectedEOF),
+        }
+
+        let var = match self.scanner.next() {
+            Some(Ok(Token::Identifier(name))) => name,
+            Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+            Some(Err(e)) => return Err(ParseError::ScanError(e)),
+            None => return Err(ParseError::UnexpectedEOF),
+        };
+
+        let handler = match self.parse_expr() {
+            None => return Err(ParseError::UnexpectedEOF),
+            Some(Err(e)) => return Err(e),
+            Some(Ok(expr)) => expr,
+        };
+
+        Ok(Expression::TryCatch {
+            body: Box::new(body),
+            var: var,
+            handler: Box::new(handler),
+        })
+    }
+
+    // Assuming we've read a "switch", parse the value being switched on and
+    // the cases in braces after it.
+    fn parse_switch(&mut self) -> Result<Expression> {
+        let value = match self.parse_expr() {
+            None => return Err(ParseError::UnexpectedEOF),
+            Some(Err(e)) => return Err(e),
+            Some(Ok(expr)) => expr,
+        };
+
+        match self.scanner.next() {
+            Some(Ok(Token::OpenCurly)) => {}
+            Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+            Some(Err(e)) => return Err(ParseError::ScanError(e)),
+            None => return Err(ParseError::UnexpectedEOF),
+        }
+
+        let mut cases = vec![];
+        let mut default = None;
+        loop {
+            // The default has to be the last case.
+            let candidates = match self.scanner.next() {
+                Some(Ok(Token::CloseCurly)) => break,
+                Some(Ok(Token::Case)) if default.is_none() => {
+                    if let Some(&Ok(Token::Colon)) = self.scanner.peek() {
+                        return Err(ParseError::Unexpected(Token::Colon));
+                    }
+                    Some(self.parse_expr_list(&Token::Colon)?)
+                }
+                Some(Ok(Token::Default)) if default.is_none() => {
+                    match self.scanner.next() {
+                        Some(Ok(Token::Colon)) => {}
+                        Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+                        Some(Err(e)) => return Err(ParseError::ScanError(e)),
+                        None => return Err(ParseError::UnexpectedEOF),
+                    }
+                    None
+                }
+                Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+                Some(Err(e)) => return Err(ParseError::ScanError(e)),
+                None => return Err(ParseError::UnexpectedEOF),
+            };
+
+            let body = match self.parse_expr() {
+                None => return Err(ParseError::UnexpectedEOF),
+                Some(Err(e)) => return Err(e),
+                Some(Ok(expr)) => expr,
+            };
+
+            match candidates {
+                Some(candidates) => cases.push((candidates, body)),
+                None => default = Some(Box::new(body)),
+            }
+        }
+
+        Ok(Expression::Switch {
+            value: Box::new(value),
+            cases: cases,
+            default: default,
         })
     }
 
@@ -146,7 +667,7 @@ impl<'a> Parser<'a> {
         }
 
         loop {
-            match self.next() {
+            match self.parse_expr() {
                 Some(Ok(expr)) => expressions.push(expr),
                 Some(Err(e)) => return Err(e),
                 None => return Err(ParseError::UnexpectedEOF),
@@ -162,40 +683,30 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn apply_precedence(&mut self,
-                        lhs: Box<Expression>,
-                        op: BinaryOp,
-                        rhs: Box<Expression>)
-                        -> Expression {
-        match *rhs {
-            Expression::BinaryExpr { left: ref lhs_r, op: ref op_r, right: ref rhs_r } => {
-                if op_r.precendence() < op.precendence() {
-                    return Expression::BinaryExpr {
-                        left: Box::new(Expression::BinaryExpr {
-                            left: lhs.clone(),
-                            op: op,
-                            right: lhs_r.clone(),
-                        }),
-                        op: op_r.clone(),
-                        right: rhs_r.clone(),
-                    };
-                }
-            }
-            _ => {}
-        }
+    // parse_expr parses an expression, including a conditional one.
+    fn parse_expr(&mut self) -> Option<Result<Expression>> {
+        let cond = match self.parse_binary()? {
+            Ok(e) => e,
+            Err(e) => return Some(Err(e)),
+        };
 
-        Expression::BinaryExpr {
-            left: lhs,
-            op: op,
-            right: rhs,
+        // A conditional expression, as in `a < b ? a : b`, binds more loosely
+        // than any operator.
+        match self.scanner.peek() {
+            Some(&Ok(Token::Question)) => {}
+            _ => return Some(Ok(cond)),
         }
+        self.scanner.next();
+        Some(self.parse_conditional(cond))
     }
-}
-
-impl<'a> Iterator for Parser<'a> {
-    type Item = Result<Expression>;
 
-    fn next(&mut self) -> Option<Self::Item> {
+    // parse_operand parses an expression that can be the operand of a binary
+    // or unary operator, along with any calls and field accesses that follow
+    // it.
+    fn parse_operand(&mut self) -> Option<Result<Expression>> {
+        if stack::exceeded() {
+            return Some(Err(ParseError::TooDeep));
+        }
         let token = match self.scanner.next() {
             None => return None,
             Some(Err(e)) => return Some(Err(ParseError::ScanError(e))),
@@ -206,18 +717,74 @@ impl<'a> Iterator for Parser<'a> {
             Token::Nil => Ok(Expression::NilLiteral),
             Token::Boolean(b) => Ok(Expression::BooleanLiteral(b)),
             Token::Number(n) => Ok(Expression::NumberLiteral(n)),
-            Token::String(s) => Ok(Expression::StrLiteral(s)),
+            Token::Int(i) => Ok(Expression::IntLiteral(i)),
+            Token::String(s) => Ok(Expression::StrLiteral(self.intern(s))),
+            Token::CustomLiteral(prefix, text) => {
+                Ok(Expression::CustomLiteral {
+                    prefix: prefix,
+                    text: text,
+                })
+            }
             Token::OpenParen => self.parse_paren_expr(),
-            Token::OpenCurly => self.parse_block(),
-            Token::Identifier(s) => self.parse_identifier(s),
+            Token::OpenCurly => self.parse_curly(),
+            Token::OpenBracket => {
+                self.parse_expr_list(&Token::CloseBracket).map(Expression::ListLiteral)
+            }
+            Token::Identifier(s) => Ok(Expression::Variable(s)),
             Token::If => self.parse_if(),
             Token::While => self.parse_while(),
+            Token::For => self.parse_for(),
+            Token::Do => self.parse_do(),
+            Token::Fn => self.parse_fn(),
+            Token::Let => self.parse_let(false),
+            Token::Const => self.parse_let(true),
+            Token::Try => self.parse_try(),
+            Token::Struct => self.parse_struct(),
+            Token::Switch => self.parse_switch(),
+            Token::Throw => {
+                match self.parse_expr() {
+                    Some(Ok(value)) => Ok(Expression::Throw(Box::new(value))),
+                    Some(Err(e)) => Err(e),
+                    None => Err(ParseError::UnexpectedEOF),
+                }
+            }
+            // `|x, y| x + y` is an anonymous function, and `|| x` is one with no
+            // parameters.
+            Token::Pipe => {
+                self.parse_param_list(&Token::Pipe).and_then(|p| self.parse_lambda_body(p))
+            }
+            Token::DoublePipe => self.parse_lambda_body(vec![]),
+            Token::Break if self.loop_depth > 0 => Ok(Expression::Break),
+            Token::Continue if self.loop_depth > 0 => Ok(Expression::Continue),
+            // A minus sign applies to the operand right after it, so `-x * 2`
+            // negates x before multiplying.  The same goes for `~`.
+            Token::Minus | Token::Tilde => {
+                let op = if token == Token::Minus { UnaryOp::Neg } else { UnaryOp::BitNot };
+                return Some(match self.parse_operand() {
+                    None => Err(ParseError::UnexpectedEOF),
+                    Some(Ok(e)) => {
+                        Ok(Expression::UnaryExpr {
+                            op: op,
+                            operand: Box::new(e),
+                        })
+                    }
+                    Some(Err(e)) => Err(e),
+                })
+            }
             t => Err(ParseError::Unexpected(t)),
         };
 
-        let lhs = match expr_res {
-            Ok(e) => e,
-            Err(e) => return Some(Err(e)),
+        Some(expr_res.and_then(|e| self.parse_postfix(e)))
+    }
+
+    // parse_binary parses an expression other than a conditional one: an
+    // operand, possibly followed by binary operators or an assignment.  The
+    // right side of an assignment can be a conditional expression.
+    fn parse_binary(&mut self) -> Option<Result<Expression>> {
+        let lhs = match self.parse_operand() {
+            None => return None,
+            Some(Ok(e)) => e,
+            Some(Err(e)) => return Some(Err(e)),
         };
 
         // Copy the next token because we might be part of a larger expression.
@@ -229,32 +796,215 @@ impl<'a> Iterator for Parser<'a> {
         // Binary expression.
         if let Some(op) = next.to_binary_op() {
             self.scanner.next();
-            let rhs = match self.next() {
+            let span = self.scanner.span();
+            let rhs = match self.parse_binary() {
                 Some(Ok(e)) => e,
                 Some(Err(e)) => return Some(Err(e)),
                 None => return Some(Err(ParseError::UnexpectedEOF)),
             };
 
-            return Some(Ok(self.apply_precedence(Box::new(lhs), op, Box::new(rhs))));
+            return Some(Ok(self.apply_precedence(Box::new(lhs), op, span, Box::new(rhs))));
         }
 
-        // Assignment.
-        if next == Token::Eq {
-            if let Expression::Variable(v) = lhs {
-                self.scanner.next();
-                let rhs = match self.next() {
-                    Some(Ok(e)) => e,
-                    Some(Err(e)) => return Some(Err(e)),
-                    None => return Some(Err(ParseError::UnexpectedEOF)),
-                };
-
-                return Some(Ok(Expression::Assignment {
-                    left: v,
-                    right: Box::new(rhs),
+        // Assignment.  A compound assignment like `l[i] += 1` works like
+        // `l[i] = l[i] + (1)`, except that l and i are only evaluated once.
+        let compound = next.compound_op();
+        if next == Token::Eq || compound.is_some() {
+            match lhs {
+                Expression::Variable(_) |
+                Expression::Index { .. } |
+                Expression::FieldAccess { .. } => {}
+                _ => return Some(Ok(lhs)),
+            }
+
+            self.scanner.next();
+            let span = self.scanner.span();
+            let rhs = match self.parse_expr() {
+                Some(Ok(e)) => e,
+                Some(Err(e)) => return Some(Err(e)),
+                None => return Some(Err(ParseError::UnexpectedEOF)),
+            };
+            if let Some(op) = compound {
+                return Some(Ok(Expression::CompoundAssignment {
+                    target: Box::new(lhs),
+                    op: op,
+                    value: Box::new(rhs),
+                    span: Some(span),
                 }));
             }
+
+            return Some(Ok(match lhs {
+                Expression::Index { object, index } => {
+                    Expression::IndexAssignment {
+                        object: object,
+                        index: index,
+                        right: Box::new(rhs),
+                    }
+                }
+                Expression::FieldAccess { object, field } => {
+                    Expression::FieldAssignment {
+                        object: object,
+                        field: field,
+                        right: Box::new(rhs),
+                    }
+                }
+                Expression::Variable(v) => {
+                    Expression::Assignment {
+                        left: v,
+                        right: Box::new(rhs),
+                    }
+                }
+                _ => unreachable!(),
+            }));
         }
 
         Some(Ok(lhs))
     }
+
+    // Assuming we've read a condition and a "?", parse the value for when it
+    // holds, the ":" and the value for when it doesn't.  `a ? b : c` is the
+    // same as `if a b else c`.
+    fn parse_conditional(&mut self, cond: Expression) -> Result<Expression> {
+        let body = match self.parse_expr() {
+            None => return Err(ParseError::UnexpectedEOF),
+            Some(Err(e)) => return Err(e),
+            Some(Ok(expr)) => expr,
+        };
+
+        match self.scanner.next() {
+            Some(Ok(Token::Colon)) => {}
+            Some(Ok(t)) => return Err(ParseError::Unexpected(t)),
+            Some(Err(e)) => return Err(ParseError::ScanError(e)),
+            None => return Err(ParseError::UnexpectedEOF),
+        }
+
+        let else_branch = match self.parse_expr() {
+            None => return Err(ParseError::UnexpectedEOF),
+            Some(Err(e)) => return Err(e),
+            Some(Ok(expr)) => expr,
+        };
+
+        Ok(Expression::IfExpr {
+            cond: Box::new(cond),
+            body: Box::new(body),
+            else_branch: Some(Box::new(else_branch)),
+        })
+    }
+
+    // apply_precedence combines lhs and rhs with op, which is at span in the
+    // source, regrouping rhs if op binds tighter than its operator.
+    fn apply_precedence(&mut self,
+                        lhs: Box<Expression>,
+                        op: BinaryOp,
+                        span: Span,
+                        rhs: Box<Expression>)
+                        -> Expression {
+        // A comparison whose right side is another comparison, like `a < b <
+        // c`, forms a chain rather than comparing a boolean.
+        if op.is_comparison() {
+            match *rhs {
+                Expression::BinaryExpr { left: ref lhs_r, op: ref op_r, right: ref rhs_r, .. }
+                    if op_r.is_comparison() => {
+                    return Expression::ChainedComparison {
+                        first: lhs,
+                        rest: vec![(op, *lhs_r.clone()), (op_r.clone(), *rhs_r.clone())],
+                    };
+                }
+                Expression::ChainedComparison { first: ref first_r, rest: ref rest_r } => {
+                    let mut rest = vec![(op, *first_r.clone())];
+                    rest.extend(rest_r.iter().cloned());
+                    return Expression::ChainedComparison {
+                        first: lhs,
+                        rest: rest,
+                    };
+                }
+                _ => {}
+            }
+        }
+
+        match *rhs {
+            Expression::BinaryExpr { left: ref lhs_r,
+                                     op: ref op_r,
+                                     right: ref rhs_r,
+                                     span: span_r } => {
+                if !op.binds_right(op_r) {
+                    // The operator binds tighter than the one on the right, so
+                    // it takes the right side's left operand instead.  That
+                    // operand may itself need regrouping, as in `a < b < c &&
+                    // d`.
+                    let left = self.apply_precedence(lhs.clone(), op, span, lhs_r.clone());
+                    return Expression::BinaryExpr {
+                        left: Box::new(left),
+                        op: op_r.clone(),
+                        right: rhs_r.clone(),
+                        span: span_r,
+                    };
+                }
+            }
+            Expression::ChainedComparison { first: ref first_r, rest: ref rest_r } => {
+                if let Some(&(ref op_r, _)) = rest_r.first() {
+                    if !op.binds_right(op_r) {
+                        return Expression::ChainedComparison {
+                            first: Box::new(Expression::BinaryExpr {
+                                left: lhs.clone(),
+                                op: op,
+                                right: first_r.clone(),
+                                span: Some(span),
+                            }),
+                            rest: rest_r.clone(),
+                        };
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Expression::BinaryExpr {
+            left: lhs,
+            op: op,
+            right: rhs,
+            span: Some(span),
+        }
+    }
+}
+
+impl<'a> Iterator for Parser<'a> {
+    type Item = Result<Expression>;
+
+    // next parses the next top-level expression.  A panic while parsing, which
+    // is always a bug, is returned as ParseError::Internal, after which there
+    // are no more expressions.
+    fn next(&mut self) -> Option<Self::Item> {
+        let guard = stack::enter();
+        if !guard.is_outermost() {
+            return self.parse_expr();
+        }
+        match panic::catch_unwind(AssertUnwindSafe(|| self.parse_expr())) {
+            Ok(res) => res,
+            Err(payload) => {
+                self.scanner = Tokens::new(Scanner::new(""));
+                Some(Err(ParseError::Internal(error::panic_message(&*payload))))
+            }
+        }
+    }
+}
+
+// loose_jump returns the token of a break or continue in e that would leave a
+// loop around e, rather than one inside e.
+fn loose_jump(e: &Expression) -> Option<Token> {
+    match e {
+        &Expression::Break => Some(Token::Break),
+        &Expression::Continue => Some(Token::Continue),
+        // A function's body can't break out of the loop it's defined in.
+        &Expression::FunctionDef { .. } |
+        &Expression::Lambda { .. } => None,
+        &Expression::WhileLoop { ref cond, ref else_branch, .. } => {
+            let mut outside = vec![&**cond];
+            outside.extend(else_branch.iter().map(|e| &**e));
+            outside.into_iter().filter_map(loose_jump).next()
+        }
+        &Expression::ForLoop { ref iter, .. } => loose_jump(iter),
+        &Expression::DoWhile { ref cond, .. } => loose_jump(cond),
+        _ => e.children().into_iter().filter_map(loose_jump).next(),
+    }
 }
@@ -1,20 +1,53 @@
 use binary_op::BinaryOp;
+use cst::Span;
+use error::ParseError;
 use expr::Expression;
+use scanner::Token;
+use unary_op::UnaryOp;
 
 use parser::*;
 
+use std::rc::Rc;
+
+// at returns the span of an operator from start to end.
+fn at(start: usize, end: usize) -> Option<Span> {
+    Some(Span {
+        start: start,
+        end: end,
+    })
+}
+
 #[test]
 fn test_literal() {
-    let mut parser = Parser::new(r#"nil true false 1 "foo""#);
+    let mut parser = Parser::new(r#"nil true false 1 2.5 "foo""#);
     assert_eq!(parser.next(), Some(Ok(Expression::NilLiteral)));
     assert_eq!(parser.next(), Some(Ok(Expression::BooleanLiteral(true))));
     assert_eq!(parser.next(), Some(Ok(Expression::BooleanLiteral(false))));
-    assert_eq!(parser.next(), Some(Ok(Expression::NumberLiteral(1.0))));
+    assert_eq!(parser.next(), Some(Ok(Expression::IntLiteral(1))));
+    assert_eq!(parser.next(), Some(Ok(Expression::NumberLiteral(2.5))));
     assert_eq!(parser.next(),
-               Some(Ok(Expression::StrLiteral("foo".to_owned()))));
+               Some(Ok(Expression::StrLiteral("foo".into()))));
     assert_eq!(parser.next(), None);
 }
 
+#[test]
+fn test_interned_strings() {
+    let mut parser = Parser::new(r#""foo" { foo: 1 } "bar""#);
+    let strings: Vec<_> = parser.by_ref().map(|e| e.unwrap()).collect();
+    match (&strings[0], &strings[1], &strings[2]) {
+        (&Expression::StrLiteral(ref a),
+         &Expression::MapLiteral(ref m),
+         &Expression::StrLiteral(ref c)) => {
+            match m[0].0 {
+                Expression::StrLiteral(ref b) => assert!(Rc::ptr_eq(a, b)),
+                ref e => panic!("unexpected key {:?}", e),
+            }
+            assert!(!Rc::ptr_eq(a, c));
+        }
+        _ => panic!("unexpected expressions {:?}", strings),
+    }
+}
+
 #[test]
 fn test_parenthesis() {
     let mut parser = Parser::new(r#"(nil)(((true)))"#);
@@ -38,43 +71,90 @@ fn test_identifier_and_function_call() {
     assert_eq!(parser.next(), Some(Ok(foo_var.clone())));
     assert_eq!(parser.next(),
                Some(Ok(Expression::FunctionCall {
-                   name: "foo".to_owned(),
+                   func: Box::new(Expression::Variable("foo".to_owned())),
                    args: vec![],
                })));
     assert_eq!(parser.next(),
                Some(Ok(Expression::FunctionCall {
-                   name: "foo".to_owned(),
+                   func: Box::new(Expression::Variable("foo".to_owned())),
                    args: vec![foo_var.clone()],
                })));
     assert_eq!(parser.next(),
                Some(Ok(Expression::FunctionCall {
-                   name: "foo".to_owned(),
+                   func: Box::new(Expression::Variable("foo".to_owned())),
                    args: vec![foo_var.clone(), foo_var.clone()],
                })));
     assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new(r#"foo(1)(2) {} (3)"#);
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::FunctionCall {
+                   func: Box::new(Expression::FunctionCall {
+                       func: Box::new(foo_var.clone()),
+                       args: vec![Expression::IntLiteral(1)],
+                   }),
+                   args: vec![Expression::IntLiteral(2)],
+               })));
+    assert_eq!(parser.next(), Some(Ok(Expression::Block(vec![]))));
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::ParenExpr(Box::new(Expression::IntLiteral(3))))));
+    assert_eq!(parser.next(), None);
 }
 
 #[test]
 fn test_binary_expr() {
     let mut parser = Parser::new(r#"1 + 2 - 3 * 4 / 5"#);
 
+    // Operators that bind equally tightly group from the left.
     assert_eq!(parser.next(),
                Some(Ok(Expression::BinaryExpr {
-                   left: Box::new(Expression::NumberLiteral(1.0)),
-                   op: BinaryOp::Add,
+                   left: Box::new(Expression::BinaryExpr {
+                       left: Box::new(Expression::IntLiteral(1)),
+                       op: BinaryOp::Add,
+                       right: Box::new(Expression::IntLiteral(2)),
+                       span: at(2, 3),
+                   }),
+                   op: BinaryOp::Sub,
                    right: Box::new(Expression::BinaryExpr {
-                       left: Box::new(Expression::NumberLiteral(2.0)),
-                       op: BinaryOp::Sub,
-                       right: Box::new(Expression::BinaryExpr {
-                           left: Box::new(Expression::NumberLiteral(3.0)),
+                       left: Box::new(Expression::BinaryExpr {
+                           left: Box::new(Expression::IntLiteral(3)),
                            op: BinaryOp::Mul,
-                           right: Box::new(Expression::BinaryExpr {
-                               left: Box::new(Expression::NumberLiteral(4.0)),
-                               op: BinaryOp::Div,
-                               right: Box::new(Expression::NumberLiteral(5.0)),
-                           }),
+                           right: Box::new(Expression::IntLiteral(4)),
+                           span: at(10, 11),
                        }),
+                       op: BinaryOp::Div,
+                       right: Box::new(Expression::IntLiteral(5)),
+                       span: at(14, 15),
                    }),
+                   span: at(6, 7),
+               })));
+    assert_eq!(parser.next(), None);
+}
+
+#[test]
+fn test_modulo() {
+    let mut parser = Parser::new("17 % 4");
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::BinaryExpr {
+                   left: Box::new(Expression::IntLiteral(17)),
+                   op: BinaryOp::Mod,
+                   right: Box::new(Expression::IntLiteral(4)),
+                   span: at(3, 4),
+               })));
+    assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new("x % 2 == 0");
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::BinaryExpr {
+                   left: Box::new(Expression::BinaryExpr {
+                       left: Box::new(Expression::Variable("x".to_owned())),
+                       op: BinaryOp::Mod,
+                       right: Box::new(Expression::IntLiteral(2)),
+                       span: at(2, 3),
+                   }),
+                   op: BinaryOp::Eq,
+                   right: Box::new(Expression::IntLiteral(0)),
+                   span: at(6, 8),
                })));
     assert_eq!(parser.next(), None);
 }
@@ -86,11 +166,22 @@ fn test_binary_op() {
         ("-", BinaryOp::Sub),
         ("*", BinaryOp::Mul),
         ("/", BinaryOp::Div),
+        ("%", BinaryOp::Mod),
         ("==", BinaryOp::Eq),
+        ("!=", BinaryOp::NotEq),
         ("<", BinaryOp::Lt),
         ("<=", BinaryOp::LtEq),
         (">", BinaryOp::Gt),
         (">=", BinaryOp::GtEq),
+        ("in", BinaryOp::In),
+        ("..", BinaryOp::Range),
+        ("&&", BinaryOp::And),
+        ("||", BinaryOp::Or),
+        ("&", BinaryOp::BitAnd),
+        ("|", BinaryOp::BitOr),
+        ("^", BinaryOp::BitXor),
+        ("<<", BinaryOp::Shl),
+        (">>", BinaryOp::Shr),
     ];
 
     for (s, op) in cases {
@@ -99,9 +190,10 @@ fn test_binary_op() {
         let mut parser = Parser::new(&expr_str);
         assert_eq!(parser.next(),
                    Some(Ok(Expression::BinaryExpr {
-                       left: Box::new(Expression::NumberLiteral(1.0)),
+                       left: Box::new(Expression::IntLiteral(1)),
                        op: op,
-                       right: Box::new(Expression::NumberLiteral(2.0)),
+                       right: Box::new(Expression::IntLiteral(2)),
+                       span: at(2, 2 + s.len()),
                    })));
         assert_eq!(parser.next(), None);
     }
@@ -113,9 +205,9 @@ fn test_block() {
 
     assert_eq!(parser.next(),
                Some(Ok(Expression::Block(vec![
-        Expression::NumberLiteral(1.0),
+        Expression::IntLiteral(1),
         Expression::Block(vec![]),
-        Expression::NumberLiteral(2.0),
+        Expression::IntLiteral(2),
     ]))));
     assert_eq!(parser.next(), None);
 }
@@ -133,6 +225,57 @@ fn test_assignment() {
                    }),
                })));
     assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new(r#"a[0]["k"] = 1  f(x) = 2"#);
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::IndexAssignment {
+                   object: Box::new(Expression::Index {
+                       object: Box::new(Expression::Variable("a".to_owned())),
+                       index: Box::new(Expression::IntLiteral(0)),
+                   }),
+                   index: Box::new(Expression::StrLiteral("k".into())),
+                   right: Box::new(Expression::IntLiteral(1)),
+               })));
+    // Other expressions can't be assigned to.
+    assert!(parser.next().is_some());
+    assert_eq!(parser.next(), Some(Err(ParseError::Unexpected(Token::Eq))));
+}
+
+#[test]
+fn test_compound_assignment() {
+    let var = |name: &str| Box::new(Expression::Variable(name.to_owned()));
+    let mut parser = Parser::new("x -= y * 2");
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::CompoundAssignment {
+                   target: var("x"),
+                   op: BinaryOp::Sub,
+                   value: Box::new(Expression::BinaryExpr {
+                       left: var("y"),
+                       op: BinaryOp::Mul,
+                       right: Box::new(Expression::IntLiteral(2)),
+                       span: at(7, 8),
+                   }),
+                   span: at(2, 4),
+               })));
+    assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new("l[i] %= 3");
+    let item = Expression::Index {
+        object: var("l"),
+        index: var("i"),
+    };
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::CompoundAssignment {
+                   target: Box::new(item),
+                   op: BinaryOp::Mod,
+                   value: Box::new(Expression::IntLiteral(3)),
+                   span: at(5, 7),
+               })));
+    assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new("1 += 2");
+    assert!(parser.next().is_some());
+    assert_eq!(parser.next(), Some(Err(ParseError::Unexpected(Token::PlusEq))));
 }
 
 #[test]
@@ -154,14 +297,37 @@ fn test_if_expr() {
 
 #[test]
 fn test_while_loop() {
-    let mut parser = Parser::new("while true {}");
+    let mut parser = Parser::new("while true {} while false {} else 1");
 
     assert_eq!(parser.next(),
                Some(Ok(Expression::WhileLoop {
                    cond: Box::new(Expression::BooleanLiteral(true)),
                    body: Box::new(Expression::Block(vec![])),
+                   else_branch: None,
+               })));
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::WhileLoop {
+                   cond: Box::new(Expression::BooleanLiteral(false)),
+                   body: Box::new(Expression::Block(vec![])),
+                   else_branch: Some(Box::new(Expression::IntLiteral(1))),
+               })));
+    assert_eq!(parser.next(), None);
+}
+
+#[test]
+fn test_for_loop() {
+    let mut parser = Parser::new("for x in l { break }");
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::ForLoop {
+                   var: "x".to_owned(),
+                   iter: Box::new(Expression::Variable("l".to_owned())),
+                   body: Box::new(Expression::Block(vec![Expression::Break])),
                })));
     assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new("for x l {}");
+    assert_eq!(parser.next(),
+               Some(Err(ParseError::Unexpected(Token::Identifier("l".to_owned())))));
 }
 
 #[test]
@@ -170,23 +336,626 @@ fn test_precedence() {
 
     assert_eq!(parser.next(),
                Some(Ok(Expression::BinaryExpr {
-                   left: Box::new(Expression::NumberLiteral(1.0)),
+                   left: Box::new(Expression::IntLiteral(1)),
                    op: BinaryOp::Add,
                    right: Box::new(Expression::BinaryExpr {
-                       left: Box::new(Expression::NumberLiteral(2.0)),
+                       left: Box::new(Expression::IntLiteral(2)),
                        op: BinaryOp::Mul,
-                       right: Box::new(Expression::NumberLiteral(3.0)),
+                       right: Box::new(Expression::IntLiteral(3)),
+                       span: at(6, 7),
                    }),
+                   span: at(2, 3),
                })));
     assert_eq!(parser.next(),
                Some(Ok(Expression::BinaryExpr {
                    left: Box::new(Expression::BinaryExpr {
-                       left: Box::new(Expression::NumberLiteral(1.0)),
+                       left: Box::new(Expression::IntLiteral(1)),
                        op: BinaryOp::Mul,
-                       right: Box::new(Expression::NumberLiteral(2.0)),
+                       right: Box::new(Expression::IntLiteral(2)),
+                       span: at(13, 14),
+                   }),
+                   op: BinaryOp::Add,
+                   right: Box::new(Expression::IntLiteral(3)),
+                   span: at(17, 18),
+               })));
+    assert_eq!(parser.next(), None);
+}
+
+#[test]
+fn test_bitwise_precedence() {
+    let bin = |left, (op, start, end), right| {
+        Expression::BinaryExpr {
+            left: Box::new(left),
+            op: op,
+            right: Box::new(right),
+            span: at(start, end),
+        }
+    };
+    let var = |name: &str| Expression::Variable(name.to_owned());
+
+    // Bitwise operators bind more tightly than comparisons, so this checks
+    // whether a bit is clear.
+    let mut parser = Parser::new("x & 1 == 0");
+    assert_eq!(parser.next(),
+               Some(Ok(bin(bin(var("x"), (BinaryOp::BitAnd, 2, 3), Expression::IntLiteral(1)),
+                           (BinaryOp::Eq, 6, 8),
+                           Expression::IntLiteral(0)))));
+
+    // | binds loosest, then ^, then &, then shifts, which bind more loosely
+    // than arithmetic.
+    let mut parser = Parser::new("a | b ^ c & d << e + f");
+    let sum = bin(var("e"), (BinaryOp::Add, 19, 20), var("f"));
+    let shift = bin(var("d"), (BinaryOp::Shl, 14, 16), sum);
+    assert_eq!(parser.next(),
+               Some(Ok(bin(var("a"),
+                           (BinaryOp::BitOr, 2, 3),
+                           bin(var("b"),
+                               (BinaryOp::BitXor, 6, 7),
+                               bin(var("c"), (BinaryOp::BitAnd, 10, 11), shift))))));
+
+    let mut parser = Parser::new("~x & -y");
+    assert_eq!(parser.next(),
+               Some(Ok(bin(Expression::UnaryExpr {
+                               op: UnaryOp::BitNot,
+                               operand: Box::new(var("x")),
+                           },
+                           (BinaryOp::BitAnd, 3, 4),
+                           Expression::UnaryExpr {
+                               op: UnaryOp::Neg,
+                               operand: Box::new(var("y")),
+                           }))));
+    assert_eq!(parser.next(), None);
+}
+
+#[test]
+fn test_chained_comparison() {
+    let mut parser = Parser::new("1 < x <= 10  0 > 2 * x > -1 >= y");
+
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::ChainedComparison {
+                   first: Box::new(Expression::IntLiteral(1)),
+                   rest: vec![
+                       (BinaryOp::Lt, Expression::Variable("x".to_owned())),
+                       (BinaryOp::LtEq, Expression::IntLiteral(10)),
+                   ],
+               })));
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::ChainedComparison {
+                   first: Box::new(Expression::IntLiteral(0)),
+                   rest: vec![
+                       (BinaryOp::Gt, Expression::BinaryExpr {
+                           left: Box::new(Expression::IntLiteral(2)),
+                           op: BinaryOp::Mul,
+                           right: Box::new(Expression::Variable("x".to_owned())),
+                           span: at(19, 20),
+                       }),
+                       (BinaryOp::Gt, Expression::IntLiteral(-1)),
+                       (BinaryOp::GtEq, Expression::Variable("y".to_owned())),
+                   ],
+               })));
+    assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new("2 * x < 3 < 4");
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::ChainedComparison {
+                   first: Box::new(Expression::BinaryExpr {
+                       left: Box::new(Expression::IntLiteral(2)),
+                       op: BinaryOp::Mul,
+                       right: Box::new(Expression::Variable("x".to_owned())),
+                       span: at(2, 3),
+                   }),
+                   rest: vec![
+                       (BinaryOp::Lt, Expression::IntLiteral(3)),
+                       (BinaryOp::Lt, Expression::IntLiteral(4)),
+                   ],
+               })));
+    assert_eq!(parser.next(), None);
+}
+
+#[test]
+fn test_logical_precedence() {
+    let var = |name: &str| Box::new(Expression::Variable(name.to_owned()));
+    let mut parser = Parser::new("a || b && c == d  0 < x < 10 && y");
+
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::BinaryExpr {
+                   left: var("a"),
+                   op: BinaryOp::Or,
+                   right: Box::new(Expression::BinaryExpr {
+                       left: var("b"),
+                       op: BinaryOp::And,
+                       right: Box::new(Expression::BinaryExpr {
+                           left: var("c"),
+                           op: BinaryOp::Eq,
+                           right: var("d"),
+                           span: at(12, 14),
+                       }),
+                       span: at(7, 9),
+                   }),
+                   span: at(2, 4),
+               })));
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::BinaryExpr {
+                   left: Box::new(Expression::ChainedComparison {
+                       first: Box::new(Expression::IntLiteral(0)),
+                       rest: vec![
+                           (BinaryOp::Lt, Expression::Variable("x".to_owned())),
+                           (BinaryOp::Lt, Expression::IntLiteral(10)),
+                       ],
+                   }),
+                   op: BinaryOp::And,
+                   right: var("y"),
+                   span: at(29, 31),
+               })));
+    assert_eq!(parser.next(), None);
+}
+
+#[test]
+fn test_range() {
+    let var = |name: &str| Box::new(Expression::Variable(name.to_owned()));
+    let mut parser = Parser::new("x in 0..n + 1");
+
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::BinaryExpr {
+                   left: var("x"),
+                   op: BinaryOp::In,
+                   right: Box::new(Expression::BinaryExpr {
+                       left: Box::new(Expression::IntLiteral(0)),
+                       op: BinaryOp::Range,
+                       right: Box::new(Expression::BinaryExpr {
+                           left: var("n"),
+                           op: BinaryOp::Add,
+                           right: Box::new(Expression::IntLiteral(1)),
+                           span: at(10, 11),
+                       }),
+                       span: at(6, 8),
+                   }),
+                   span: at(2, 4),
+               })));
+    assert_eq!(parser.next(), None);
+}
+
+#[test]
+fn test_unary_minus() {
+    let neg = |e| {
+        Expression::UnaryExpr {
+            op: UnaryOp::Neg,
+            operand: Box::new(e),
+        }
+    };
+    // A minus after a complete expression is subtraction, so each case gets
+    // its own parser.
+    let mut parser = Parser::new("-x * 2");
+
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::BinaryExpr {
+                   left: Box::new(neg(Expression::Variable("x".to_owned()))),
+                   op: BinaryOp::Mul,
+                   right: Box::new(Expression::IntLiteral(2)),
+                   span: at(3, 4),
+               })));
+
+    let mut parser = Parser::new("-(1 + 2)");
+    assert_eq!(parser.next(),
+               Some(Ok(neg(Expression::ParenExpr(Box::new(Expression::BinaryExpr {
+                   left: Box::new(Expression::IntLiteral(1)),
+                   op: BinaryOp::Add,
+                   right: Box::new(Expression::IntLiteral(2)),
+                   span: at(4, 5),
+               }))))));
+
+    let mut parser = Parser::new("- -f(1) -1");
+    assert_eq!(parser.next(),
+               Some(Ok(neg(neg(Expression::FunctionCall {
+                   func: Box::new(Expression::Variable("f".to_owned())),
+                   args: vec![Expression::IntLiteral(1)],
+               })))));
+    assert_eq!(parser.next(), Some(Ok(Expression::IntLiteral(-1))));
+    assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new("-");
+    assert_eq!(parser.next(), Some(Err(ParseError::UnexpectedEOF)));
+}
+
+#[test]
+fn test_list_literal_and_index() {
+    let mut parser = Parser::new("[] [1, x]  [1] [2]  a[0][i + 1]");
+
+    assert_eq!(parser.next(), Some(Ok(Expression::ListLiteral(vec![]))));
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::ListLiteral(vec![
+        Expression::IntLiteral(1),
+        Expression::Variable("x".to_owned()),
+    ]))));
+    // A literal followed by brackets is two lists, not an index.
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::ListLiteral(vec![Expression::IntLiteral(1)]))));
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::ListLiteral(vec![Expression::IntLiteral(2)]))));
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::Index {
+                   object: Box::new(Expression::Index {
+                       object: Box::new(Expression::Variable("a".to_owned())),
+                       index: Box::new(Expression::IntLiteral(0)),
+                   }),
+                   index: Box::new(Expression::BinaryExpr {
+                       left: Box::new(Expression::Variable("i".to_owned())),
+                       op: BinaryOp::Add,
+                       right: Box::new(Expression::IntLiteral(1)),
+                       span: at(27, 28),
+                   }),
+               })));
+    assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new("a[1 2]");
+    assert_eq!(parser.next(),
+               Some(Err(ParseError::Unexpected(Token::Int(2)))));
+}
+
+#[test]
+fn test_map_literal() {
+    let mut parser = Parser::new(r#"{ a: 1, "b c": x, (k): {} }  { a }  {}"#);
+
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::MapLiteral(vec![
+        (Expression::StrLiteral("a".into()), Expression::IntLiteral(1)),
+        (Expression::StrLiteral("b c".into()), Expression::Variable("x".to_owned())),
+        (Expression::ParenExpr(Box::new(Expression::Variable("k".to_owned()))),
+         Expression::Block(vec![])),
+    ]))));
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::Block(vec![Expression::Variable("a".to_owned())]))));
+    assert_eq!(parser.next(), Some(Ok(Expression::Block(vec![]))));
+    assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new("{ a: 1 b: 2 }");
+    assert_eq!(parser.next(),
+               Some(Err(ParseError::Unexpected(Token::Identifier("b".to_owned())))));
+    let mut parser = Parser::new("{ a: 1, b }");
+    assert_eq!(parser.next(), Some(Err(ParseError::Unexpected(Token::CloseCurly))));
+    // A do block is never a map.
+    let mut parser = Parser::new("do { a: 1 }");
+    assert_eq!(parser.next(), Some(Err(ParseError::Unexpected(Token::Colon))));
+}
+
+#[test]
+fn test_do_block() {
+    let mut parser = Parser::new("do { 1 {} } do {}");
+
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::DoBlock(vec![
+        Expression::IntLiteral(1),
+        Expression::Block(vec![]),
+    ]))));
+    assert_eq!(parser.next(), Some(Ok(Expression::DoBlock(vec![]))));
+    assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new("do 1");
+    assert_eq!(parser.next(),
+               Some(Err(ParseError::Unexpected(Token::Int(1)))));
+}
+
+#[test]
+fn test_switch() {
+    let mut parser = Parser::new("switch x { case 1, y: {} case 3: 4 default: 5 }  switch x {}");
+    let x = || Box::new(Expression::Variable("x".to_owned()));
+
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::Switch {
+                   value: x(),
+                   cases: vec![
+        (vec![Expression::IntLiteral(1), Expression::Variable("y".to_owned())],
+         Expression::Block(vec![])),
+        (vec![Expression::IntLiteral(3)], Expression::IntLiteral(4)),
+    ],
+                   default: Some(Box::new(Expression::IntLiteral(5))),
+               })));
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::Switch {
+                   value: x(),
+                   cases: vec![],
+                   default: None,
+               })));
+    assert_eq!(parser.next(), None);
+
+    let errors = vec![
+        ("switch x { case: 1 }", ParseError::Unexpected(Token::Colon)),
+        ("switch x { default: 1 case 2: 3 }", ParseError::Unexpected(Token::Case)),
+        ("switch x { default: 1 default: 2 }", ParseError::Unexpected(Token::Default)),
+        ("switch x { 1 }", ParseError::Unexpected(Token::Int(1))),
+        ("switch x { case 1: 2", ParseError::UnexpectedEOF),
+    ];
+    for (s, err) in errors {
+        assert_eq!(Parser::new(s).next(), Some(Err(err)), "{:?}", s);
+    }
+}
+
+#[test]
+fn test_do_while() {
+    let mut parser = Parser::new("do { break } while x  do {} while x {}");
+
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::DoWhile {
+                   body: Box::new(Expression::DoBlock(vec![Expression::Break])),
+                   cond: Box::new(Expression::Variable("x".to_owned())),
+               })));
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::DoWhile {
+                   body: Box::new(Expression::DoBlock(vec![])),
+                   cond: Box::new(Expression::Variable("x".to_owned())),
+               })));
+    assert_eq!(parser.next(), Some(Ok(Expression::Block(vec![]))));
+    assert_eq!(parser.next(), None);
+
+    // A do block without a while isn't a loop, unless it's in one.
+    let errors = vec!["do { continue }",
+                      "do { while x {} else break }",
+                      "do { break } while do { break }"];
+    for s in errors {
+        assert_eq!(Parser::new(s).find(|res| res.is_err()).map(|res| res.is_err()),
+                   Some(true),
+                   "{:?}",
+                   s);
+    }
+    let ok = vec!["while x do { break }",
+                  "do { while x break  f = || { while x continue } } while x"];
+    for s in ok {
+        assert!(Parser::new(s).all(|res| res.is_ok()), "{:?}", s);
+    }
+    assert_eq!(Parser::new("do {} while").next(), Some(Err(ParseError::UnexpectedEOF)));
+}
+
+#[test]
+fn test_function_def() {
+    let mut parser = Parser::new("fn foo() {} fn bar(a, b) a");
+
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::FunctionDef {
+                   name: "foo".to_owned(),
+                   params: vec![],
+                   body: Box::new(Expression::Block(vec![])),
+               })));
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::FunctionDef {
+                   name: "bar".to_owned(),
+                   params: vec!["a".to_owned(), "b".to_owned()],
+                   body: Box::new(Expression::Variable("a".to_owned())),
+               })));
+    assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new("fn foo(a b) {}");
+    assert_eq!(parser.next(),
+               Some(Err(ParseError::Unexpected(Token::Identifier("b".to_owned())))));
+}
+
+#[test]
+fn test_let() {
+    let mut parser = Parser::new("let x = y = 1 + 2");
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::Declaration {
+                   left: "x".to_owned(),
+                   right: Box::new(Expression::Assignment {
+                       left: "y".to_owned(),
+                       right: Box::new(Expression::BinaryExpr {
+                           left: Box::new(Expression::IntLiteral(1)),
+                           op: BinaryOp::Add,
+                           right: Box::new(Expression::IntLiteral(2)),
+                           span: at(14, 15),
+                       }),
                    }),
+                   constant: false,
+               })));
+    assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new("const x = 1");
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::Declaration {
+                   left: "x".to_owned(),
+                   right: Box::new(Expression::IntLiteral(1)),
+                   constant: true,
+               })));
+
+    let mut parser = Parser::new("let x 1");
+    assert_eq!(parser.next(), Some(Err(ParseError::Unexpected(Token::Int(1)))));
+    let mut parser = Parser::new("let 1 = 1");
+    assert_eq!(parser.next(), Some(Err(ParseError::Unexpected(Token::Int(1)))));
+}
+
+#[test]
+fn test_conditional() {
+    let var = |name: &str| Box::new(Expression::Variable(name.to_owned()));
+    let cond = |cond, body, else_branch| {
+        Expression::IfExpr {
+            cond: cond,
+            body: body,
+            else_branch: Some(else_branch),
+        }
+    };
+
+    // The condition takes in every binary operator, and conditionals nest to
+    // the right.
+    let mut parser = Parser::new("x = a || b ? c : d ? 1 : 2");
+    let a_or_b = Box::new(Expression::BinaryExpr {
+        left: var("a"),
+        op: BinaryOp::Or,
+        right: var("b"),
+        span: at(6, 8),
+    });
+    let inner = Box::new(cond(var("d"),
+                              Box::new(Expression::IntLiteral(1)),
+                              Box::new(Expression::IntLiteral(2))));
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::Assignment {
+                   left: "x".to_owned(),
+                   right: Box::new(cond(a_or_b, var("c"), inner)),
+               })));
+    assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new("a ? b c");
+    assert_eq!(parser.next(),
+               Some(Err(ParseError::Unexpected(Token::Identifier("c".to_owned())))));
+    let mut parser = Parser::new("a ? b");
+    assert_eq!(parser.next(), Some(Err(ParseError::UnexpectedEOF)));
+}
+
+#[test]
+fn test_struct() {
+    let mut parser = Parser::new("struct Point { x, y }  p.x += 1");
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::StructDef {
+                   name: "Point".to_owned(),
+                   fields: vec!["x".to_owned(), "y".to_owned()],
+               })));
+
+    let x = Expression::FieldAccess {
+        object: Box::new(Expression::Variable("p".to_owned())),
+        field: "x".to_owned(),
+    };
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::CompoundAssignment {
+                   target: Box::new(x),
                    op: BinaryOp::Add,
-                   right: Box::new(Expression::NumberLiteral(3.0)),
+                   value: Box::new(Expression::IntLiteral(1)),
+                   span: at(27, 29),
+               })));
+    assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new("struct Point { x, 1 }");
+    assert_eq!(parser.next(), Some(Err(ParseError::Unexpected(Token::Int(1)))));
+}
+
+#[test]
+fn test_try() {
+    let mut parser = Parser::new("try { f() } catch e throw e");
+    let call = Expression::FunctionCall {
+        func: Box::new(Expression::Variable("f".to_owned())),
+        args: vec![],
+    };
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::TryCatch {
+                   body: Box::new(Expression::Block(vec![call])),
+                   var: "e".to_owned(),
+                   handler: Box::new(Expression::Throw(Box::new(Expression::Variable("e"
+                       .to_owned())))),
                })));
     assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new("try 1 e");
+    assert_eq!(parser.next(),
+               Some(Err(ParseError::Unexpected(Token::Identifier("e".to_owned())))));
+    let mut parser = Parser::new("try 1 catch 2 3");
+    assert_eq!(parser.next(), Some(Err(ParseError::Unexpected(Token::Int(2)))));
+}
+
+#[test]
+fn test_lambda() {
+    let mut parser = Parser::new("|a, b| a + b  fn(x) {}");
+
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::Lambda {
+                   params: vec!["a".to_owned(), "b".to_owned()],
+                   body: Box::new(Expression::BinaryExpr {
+                       left: Box::new(Expression::Variable("a".to_owned())),
+                       op: BinaryOp::Add,
+                       right: Box::new(Expression::Variable("b".to_owned())),
+                       span: at(9, 10),
+                   }),
+               })));
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::Lambda {
+                   params: vec!["x".to_owned()],
+                   body: Box::new(Expression::Block(vec![])),
+               })));
+    assert_eq!(parser.next(), None);
+
+    // The body extends as far as it can, so `|| 1 || 2` returns `1 || 2`.
+    let mut parser = Parser::new("|| 1");
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::Lambda {
+                   params: vec![],
+                   body: Box::new(Expression::IntLiteral(1)),
+               })));
+
+    let mut parser = Parser::new("|a b| a");
+    assert_eq!(parser.next(),
+               Some(Err(ParseError::Unexpected(Token::Identifier("b".to_owned())))));
+}
+
+#[test]
+fn test_field_access() {
+    let mut parser = Parser::new("math.sqrt(2) a.b.c");
+
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::FunctionCall {
+                   func: Box::new(Expression::FieldAccess {
+                       object: Box::new(Expression::Variable("math".to_owned())),
+                       field: "sqrt".to_owned(),
+                   }),
+                   args: vec![Expression::IntLiteral(2)],
+               })));
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::FieldAccess {
+                   object: Box::new(Expression::FieldAccess {
+                       object: Box::new(Expression::Variable("a".to_owned())),
+                       field: "b".to_owned(),
+                   }),
+                   field: "c".to_owned(),
+               })));
+    assert_eq!(parser.next(), None);
+
+    let mut parser = Parser::new("a.1");
+    assert_eq!(parser.next(),
+               Some(Err(ParseError::Unexpected(Token::Int(1)))));
+}
+
+#[test]
+fn test_incomplete() {
+    let cases = vec![
+        "1 +", "1 < 2 <=", "1 in", "x =", "f(", "f(1,", "a.", "(1", "{1", "if x",
+        "if x 1 else", "while x", "do", "fn", "fn f(a,", r#""abc"#,
+    ];
+
+    for s in cases {
+        let res = Parser::new(s).last().unwrap();
+        assert!(res.as_ref().err().map_or(false, |e| e.is_incomplete()),
+                "{:?} => {:?}",
+                s,
+                res);
+    }
+
+    for s in vec!["1 + )", "f(1 2)"] {
+        let res = Parser::new(s).find(|res| res.is_err()).unwrap();
+        assert!(!res.unwrap_err().is_incomplete(), "{:?}", s);
+    }
+}
+
+#[test]
+fn test_break_continue() {
+    let mut parser = Parser::new("while true { if x break else continue }");
+
+    assert_eq!(parser.next(),
+               Some(Ok(Expression::WhileLoop {
+                   cond: Box::new(Expression::BooleanLiteral(true)),
+                   body: Box::new(Expression::Block(vec![
+        Expression::IfExpr {
+            cond: Box::new(Expression::Variable("x".to_owned())),
+            body: Box::new(Expression::Break),
+            else_branch: Some(Box::new(Expression::Continue)),
+        },
+    ])),
+                   else_branch: None,
+               })));
+    assert_eq!(parser.next(), None);
+
+    for s in vec!["break", "while true {} else break", "while true { fn f() continue }"] {
+        let res = Parser::new(s).find(|res| res.is_err());
+        assert!(match res {
+                    Some(Err(ParseError::Unexpected(Token::Break))) |
+                    Some(Err(ParseError::Unexpected(Token::Continue))) => true,
+                    _ => false,
+                },
+                "{:?} => {:?}",
+                s,
+                res);
+    }
 }
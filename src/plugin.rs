@@ -0,0 +1,215 @@
+// The plugin module lets third parties ship packs of native builtins, such as
+// regex or database bindings, as dynamic libraries that are loaded at run
+// time.  A plugin is a cdylib crate depending on gate, which declares the
+// function that registers its builtins with gate_plugin!:
+//
+//     #[macro_use]
+//     extern crate gate;
+//
+//     fn register(registry: &mut gate::plugin::Registry) {
+//         registry.register("shout", |args: &[gate::Data]| ...);
+//     }
+//
+//     gate_plugin!(register);
+//
+// Rust has no stable ABI, so a plugin only works with a program built by the
+// same compiler, from the same version of gate with the same features.  load
+// checks the version and features, but can't check the compiler.
+//
+// Each builtin a plugin registers keeps the plugin's library loaded, so the
+// library is unloaded only once the engine has dropped all of them.  Because
+// plugins may be built without the plugins feature, the types they share
+// with gate mustn't depend on it.
+
+use std::any::Any;
+use std::rc::Rc;
+
+use builtins::NativeFn;
+use engine::Engine;
+
+#[cfg(feature = "net")]
+macro_rules! net_tag {
+    () => { "+net" };
+}
+
+#[cfg(not(feature = "net"))]
+macro_rules! net_tag {
+    () => { "" };
+}
+
+#[cfg(feature = "signals")]
+macro_rules! signals_tag {
+    () => { "+signals" };
+}
+
+#[cfg(not(feature = "signals"))]
+macro_rules! signals_tag {
+    () => { "" };
+}
+
+// ABI names the version of gate and the features that change the layout of
+// the values plugins are given, ending in a nul so plugins can return it to C.
+pub const ABI: &str = concat!(env!("CARGO_PKG_VERSION"), net_tag!(), signals_tag!(), "\0");
+
+// A Registry is what a plugin registers its builtins with.
+pub struct Registry<'a> {
+    engine: &'a mut Engine,
+    // The library the builtins are registered from, if any.
+    library: Option<Rc<dyn Any>>,
+}
+
+impl<'a> Registry<'a> {
+    pub fn new(engine: &'a mut Engine) -> Self {
+        Registry {
+            engine: engine,
+            library: None,
+        }
+    }
+
+    // register makes f callable from scripts under the given name, like
+    // Engine::register.
+    pub fn register<F>(&mut self, name: &str, f: F)
+        where F: NativeFn + 'static
+    {
+        self.engine.register(name, f);
+        self.keep_loaded(name);
+    }
+
+    // register_in makes f callable from scripts as `namespace.name`, like
+    // Engine::register_in.
+    pub fn register_in<F>(&mut self, namespace: &str, name: &str, f: F)
+        where F: NativeFn + 'static
+    {
+        self.engine.register_in(namespace, name, f);
+        self.keep_loaded(&format!("{}.{}", namespace, name));
+    }
+
+    fn keep_loaded(&mut self, name: &str) {
+        if let Some(ref library) = self.library {
+            self.engine.keep_loaded(name, library.clone());
+        }
+    }
+}
+
+// gate_plugin! exports a plugin's registration function under the name gate
+// looks for, along with the ABI the plugin was built for.
+#[macro_export]
+macro_rules! gate_plugin {
+    ($register:path) => {
+        #[no_mangle]
+        pub extern "C" fn gate_plugin_abi() -> *const ::std::os::raw::c_char {
+            $crate::plugin::ABI.as_ptr() as *const ::std::os::raw::c_char
+        }
+
+        #[no_mangle]
+        pub extern "C" fn gate_plugin_register(registry: &mut $crate::plugin::Registry) {
+            $register(registry)
+        }
+    };
+}
+
+#[cfg(feature = "plugins")]
+pub use self::loading::load;
+
+#[cfg(feature = "plugins")]
+mod loading {
+    use std::ffi::CStr;
+    use std::os::raw::c_char;
+    use std::path::Path;
+    use std::rc::Rc;
+
+    use libloading::{Library, Symbol};
+
+    use super::{ABI, Registry};
+    use engine::Engine;
+    use error::PluginError;
+
+    // load opens the plugin at path and registers its builtins on engine,
+    // each of which keeps the library loaded.
+    //
+    // Safety: opening the library runs its initializers, and its
+    // gate_plugin_abi and gate_plugin_register symbols are trusted to have the
+    // types gate_plugin! gives them.  The caller must make sure that the
+    // library is a gate plugin built by the same compiler as the program,
+    // which load can't check, and that nothing the plugin creates outlives
+    // its builtins, such as handles of its own types left in a Context after
+    // the engine is dropped.
+    pub unsafe fn load(engine: &mut Engine, path: &Path) -> Result<(), PluginError> {
+        let open_err = |e: ::libloading::Error| PluginError::Open(format!("{}", e));
+        let lib = Rc::new(Library::new(path).map_err(open_err)?);
+        let abi: Symbol<extern "C" fn() -> *const c_char> =
+            lib.get(b"gate_plugin_abi\0").map_err(open_err)?;
+        let abi = CStr::from_ptr(abi()).to_string_lossy().into_owned();
+        if abi != ABI.trim_end_matches('\0') {
+            return Err(PluginError::WrongAbi(abi));
+        }
+        let register: Symbol<extern "C" fn(&mut Registry)> =
+            lib.get(b"gate_plugin_register\0").map_err(open_err)?;
+        register(&mut Registry {
+            engine: engine,
+            library: Some(lib.clone()),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+
+    use data::Data;
+    use program::Program;
+
+    #[test]
+    fn test_registry() {
+        let mut p = Program::new();
+        {
+            let mut registry = Registry::new(&mut p.engine);
+            registry.register("one", |_: &[Data]| Ok(Data::Int(1)));
+            registry.register_in("pack", "two", |_: &[Data]| Ok(Data::Int(2)));
+        }
+        assert_eq!(p.run_source("one() + pack.two()"), Ok(Data::Int(3)));
+        assert!(ABI.starts_with(env!("CARGO_PKG_VERSION")) && ABI.ends_with('\0'));
+    }
+
+    #[test]
+    fn test_keep_loaded() {
+        // Library stands in for a plugin's library, noting when it's dropped.
+        struct Library(Rc<Cell<bool>>);
+
+        impl Drop for Library {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+
+        let unloaded = Rc::new(Cell::new(false));
+        let mut engine = Engine::new();
+        {
+            let mut registry = Registry {
+                engine: &mut engine,
+                library: Some(Rc::new(Library(unloaded.clone()))),
+            };
+            registry.register("one", |_: &[Data]| Ok(Data::Int(1)));
+            registry.register_in("pack", "two", |_: &[Data]| Ok(Data::Int(2)));
+        }
+        engine.register("one", |_: &[Data]| Ok(Data::Int(3)));
+        assert!(!unloaded.get());
+        drop(engine);
+        assert!(unloaded.get());
+    }
+
+    #[cfg(feature = "plugins")]
+    #[test]
+    fn test_load_missing() {
+        use error::PluginError;
+        use std::path::Path;
+
+        match unsafe { load(&mut Engine::new(), Path::new("no-such-plugin.so")) } {
+            Err(PluginError::Open(_)) => {}
+            _ => panic!("expected the plugin to fail to open"),
+        }
+    }
+}
@@ -1,33 +1,882 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+#[cfg(feature = "plugins")]
+use std::path::Path;
+use std::panic::{self, AssertUnwindSafe};
+use std::result;
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use audit::AuditEvent;
+use bound::BoundExpr;
+use builtins;
+use cancel::CancelToken;
+use compiled;
+use context::{Context, ErrorTrace};
+use convert;
 use data::Data;
+use engine::Engine;
+use error::{self, ConvertError, Error, ExecuteError, ParseError, Warning};
+#[cfg(feature = "plugins")]
+use error::PluginError;
 use expr::{Expression, Result};
-use scope::{Scope, ScopeTree};
+use parser::Parser;
+#[cfg(feature = "plugins")]
+use plugin;
+use rng::Rng;
+use scope::FrozenPolicy;
+use snapshot::Snapshot;
+use store::StoreKind;
 
+// A Program pairs an Engine with a single Context, which is all that's needed
+// to run one script at a time.
 pub struct Program {
-    pub scopes: ScopeTree,
+    pub engine: Engine,
+    pub context: Context,
 }
 
 impl Program {
     pub fn new() -> Self {
-        Program { scopes: ScopeTree::new() }
+        Program::with_store(StoreKind::Hash)
+    }
+
+    // with_store creates a program whose scopes keep their variables in the
+    // given kind of store.  The kinds behave the same and differ only in
+    // speed.
+    pub fn with_store(kind: StoreKind) -> Self {
+        Program {
+            engine: Engine::new(),
+            context: Context::with_store(kind),
+        }
     }
 
     pub fn eval(&mut self, e: &Expression) -> Result {
-        e.eval(self)
+        self.engine.eval(&mut self.context, e)
+    }
+
+    // bind captures the program's variables for e, so it can be evaluated
+    // later with eval_bound, on this program or another one, against the
+    // same variables.
+    pub fn bind(&self, e: Expression) -> BoundExpr {
+        self.context.bind(e)
+    }
+
+    pub fn eval_bound(&mut self, bound: &BoundExpr) -> Result {
+        bound.eval(&self.engine, &mut self.context)
+    }
+
+    // eval_transactional evaluates e, and if it fails, undoes every change it
+    // made to variables, including changes to lists, maps, sets and instances
+    // made in place, so the evaluation either succeeds entirely or has no
+    // effect.  Output it printed and warnings it raised are kept.
+    pub fn eval_transactional(&mut self, e: &Expression) -> Result {
+        let checkpoint = self.context.checkpoint();
+        let res = self.eval(e);
+        if res.is_err() {
+            checkpoint.restore();
+        }
+        res
+    }
+
+    // eval_isolated evaluates e without any lasting effect, for finding out
+    // what it gives.  Every change it made to variables is undone, even if it
+    // succeeds, and builtins that reach outside the program, like write_file,
+    // readline, random and exit, fail with NotAllowed.  Output it printed and
+    // warnings it raised are kept.
+    pub fn eval_isolated(&mut self, e: &Expression) -> Result {
+        let allowed = builtins::CORE
+            .iter()
+            .map(|&(name, _)| name)
+            .filter(|&name| name != "exit")
+            .chain(builtins::CONTAINED.iter().cloned())
+            .filter(|name| self.engine.is_allowed(name))
+            .map(|name| name.to_owned())
+            .collect();
+        let outer = self.engine.set_allowed(Some(allowed));
+        let checkpoint = self.context.checkpoint();
+        let res = self.eval(e);
+        checkpoint.restore();
+        self.engine.set_allowed(outer);
+        res
+    }
+
+    // run_source parses and evaluates source, stopping at the first error, and
+    // returns the value of its last expression.  A panic while running, from
+    // a bug in gate or in a native function, is returned as Error::Internal
+    // instead of unwinding into the caller.  Variables assigned before the
+    // panic keep their values.
+    pub fn run_source(&mut self, source: &str) -> result::Result<Data, Error> {
+        let mut last = Data::Nil;
+        for step in self.iter_results(source) {
+            last = step?.1?;
+        }
+        Ok(last)
+    }
+
+    // eval_str runs source on a new program and returns the value of its last
+    // expression, for hosts that only want a value.  Hosts that register
+    // functions or keep variables between scripts use run_source instead.
+    pub fn eval_str(source: &str) -> result::Result<Data, Error> {
+        Program::new().run_source(source)
+    }
+
+    // iter_results parses and evaluates source one top-level expression at a
+    // time, giving each expression along with its result as soon as it's run,
+    // so a host can show results as they come.  Evaluation carries on after an
+    // expression fails, but stops at the first one that can't be parsed or
+    // that calls exit.
+    // Panics are caught like in run_source.
+    pub fn iter_results<'a>(&'a mut self, source: &'a str) -> Results<'a> {
+        Results {
+            parser: self.engine.parser(source),
+            source: self.context.new_source(),
+            program: self,
+            done: false,
+        }
+    }
+
+    // eval_top evaluates a top-level expression parsed from the given source,
+    // so the spans of failed operations in it are kept.
+    fn eval_top(&mut self, source: u64, expr: &Expression) -> result::Result<Data, Error> {
+        let outer = self.context.set_source(Some(source));
+        let engine = &self.engine;
+        let ctx = &mut self.context;
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            engine.eval(ctx, expr).map_err(Error::from)
+        }));
+        self.context.set_source(outer);
+        self.recover(res)
+    }
+
+    // load_compiled runs a program produced by compiled::compile, the same way
+    // run_source runs source.  The program is checked first, so it fails with
+    // an error if it's corrupt or was compiled by another version of gate.
+    pub fn load_compiled(&mut self, bytes: &[u8]) -> result::Result<Data, Error> {
+        let exprs = compiled::decode(bytes)?;
+        self.run_exprs(&exprs)
+    }
+
+    // load_compiled_trusted is like load_compiled, but skips the checks.  Only
+    // use it on programs compiled by this version of gate and kept somewhere
+    // they can't be changed.
+    pub fn load_compiled_trusted(&mut self, bytes: &[u8]) -> result::Result<Data, Error> {
+        let exprs = compiled::decode_trusted(bytes)?;
+        self.run_exprs(&exprs)
+    }
+
+    // run_exprs evaluates parsed expressions the same way run_source does.
+    fn run_exprs(&mut self, exprs: &[Expression]) -> result::Result<Data, Error> {
+        let engine = &self.engine;
+        let ctx = &mut self.context;
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut last = Data::Nil;
+            for expr in exprs {
+                last = engine.eval(ctx, expr)?;
+            }
+            Ok(last)
+        }));
+        self.recover(res)
+    }
+
+    // recover turns a panic caught while running into an error, resetting the
+    // scopes the panic may have left behind.
+    fn recover(&mut self,
+               res: thread::Result<result::Result<Data, Error>>)
+               -> result::Result<Data, Error> {
+        match res {
+            Ok(res) => res,
+            Err(payload) => {
+                self.context.reset_scopes();
+                Err(Error::Internal(error::panic_message(&*payload)))
+            }
+        }
+    }
+
+    // register_fn makes f callable from scripts under the given name.  It's
+    // shorthand for registering f on the program's engine, for functions that
+    // only need their arguments.
+    pub fn register_fn<F>(&mut self, name: &str, f: F)
+        where F: FnMut(&[Data]) -> Result + 'static
+    {
+        self.engine.register(name, f)
+    }
+
+    // load_plugin loads the builtins of the plugin at path, a dynamic library
+    // declared with gate_plugin!.
+    //
+    // Safety: loading a plugin runs its code, so only load plugins from
+    // sources as trusted as the program itself.  The caller must also uphold
+    // the contract of plugin::load.
+    #[cfg(feature = "plugins")]
+    pub unsafe fn load_plugin<P: AsRef<Path>>(&mut self,
+                                              path: P)
+                                              -> result::Result<(), PluginError> {
+        plugin::load(&mut self.engine, path.as_ref())
     }
 
     pub fn var(&self, name: &str) -> Option<Data> {
-        self.scopes.var(name)
+        self.context.var(name)
+    }
+
+    // var_as converts the named variable into a Rust value, such as a struct
+    // filled in from a map.
+    pub fn var_as<T: DeserializeOwned>(&self, name: &str) -> result::Result<T, ConvertError> {
+        match self.var(name) {
+            Some(d) => T::deserialize(d),
+            None => Err(ConvertError::UndefinedVar(name.to_owned())),
+        }
+    }
+
+    pub fn freeze_globals(&mut self, vars: HashMap<String, Data>, policy: FrozenPolicy) {
+        self.context.freeze_globals(vars, policy)
     }
 
     pub fn set_var(&mut self, name: &str, val: Data) {
-        self.scopes.set_var(name, val)
+        self.context.set_var(name, val)
+    }
+
+    // set_var_from converts a Rust value into gate data and assigns it to the
+    // named variable.  Structs and maps become maps, and sequences become
+    // lists.
+    pub fn set_var_from<T: ?Sized + Serialize>(&mut self,
+                                               name: &str,
+                                               val: &T)
+                                               -> result::Result<(), ConvertError> {
+        let data = convert::to_data(val)?;
+        self.set_var(name, data);
+        Ok(())
+    }
+
+    // state_snapshot copies the program's global variables.  Comparing the
+    // snapshots taken before and after an evaluation with diff_snapshots
+    // shows which variables it created, changed or removed.
+    pub fn state_snapshot(&self) -> Snapshot {
+        Snapshot::new(self.context.globals())
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        self.context.take_warnings()
+    }
+
+    // capture_output collects the program's output in a buffer instead of
+    // writing it to stdout.  The buffer is read with take_output.
+    pub fn capture_output(&mut self) {
+        self.context.capture_output()
+    }
+
+    pub fn take_output(&mut self) -> String {
+        self.context.take_output()
+    }
+
+    // seed_rng makes the random numbers the program draws from now on the same
+    // every time it's given the same seed.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.context.seed_rng(seed)
+    }
+
+    // set_rng makes the program draw random numbers from rng.
+    pub fn set_rng<R: Rng + 'static>(&mut self, rng: R) {
+        self.context.set_rng(rng)
+    }
+
+    // cancel_token returns a token that stops the program from another thread.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.context.cancel_token()
+    }
+
+    // set_sleep replaces what the sleep builtin does, which is to block the
+    // thread by default, until the program is cancelled.
+    pub fn set_sleep<F: FnMut(Duration) + 'static>(&mut self, f: F) {
+        self.context.set_sleep(f)
     }
 
-    pub fn new_scope(&mut self) {
-        self.scopes.frames.push(Scope::new());
+    // allow_fs sets whether scripts may call read_file and write_file.  They
+    // can't by default, so a script can't touch the host's files unless the
+    // host decides it may.
+    pub fn allow_fs(&mut self, allow: bool) {
+        self.context.allow_fs(allow)
     }
 
-    pub fn pop_scope(&mut self) {
-        self.scopes.frames.pop();
+    // allow_net sets whether scripts may use the net builtins, which are built
+    // with the "net" feature.  Like files, they can't by default.
+    pub fn allow_net(&mut self, allow: bool) {
+        self.context.allow_net(allow)
+    }
+
+    // trace_errors sets whether runtime errors are traced.  After a script
+    // fails, take_error_trace returns the functions that were running and the
+    // variables in scope where the error happened.
+    pub fn trace_errors(&mut self, enable: bool) {
+        self.context.trace_errors(enable)
+    }
+
+    pub fn take_error_trace(&mut self) -> Option<ErrorTrace> {
+        self.context.take_error_trace()
+    }
+
+    // set_output makes println and print write to w instead of stdout, such as
+    // a log file or a host's own console.
+    pub fn set_output<W: Write + 'static>(&mut self, w: W) {
+        self.context.set_output(w)
+    }
+
+    // set_input makes readline read from r instead of stdin.
+    pub fn set_input<R: BufRead + 'static>(&mut self, r: R) {
+        self.context.set_input(r)
+    }
+
+    // enable_audit makes the program record every builtin call it makes in
+    // its audit log, leaving out the arguments of the builtins in redacted.
+    pub fn enable_audit(&mut self, redacted: &[&str]) {
+        self.context.enable_audit(redacted)
+    }
+
+    pub fn audit_log(&self) -> &[AuditEvent] {
+        self.context.audit_log()
+    }
+}
+
+// Results is an iterator over the top-level expressions of some source and
+// their results, returned by Program::iter_results.
+pub struct Results<'a> {
+    program: &'a mut Program,
+    parser: Parser<'a>,
+    // The id the context gave the source.
+    source: u64,
+    done: bool,
+}
+
+impl<'a> Iterator for Results<'a> {
+    type Item = result::Result<(Expression, result::Result<Data, Error>), ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let expr = match self.parser.next()? {
+            Ok(expr) => expr,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        let res = self.program.eval_top(self.source, &expr);
+        // Nothing runs after exit.
+        if let Err(Error::Execute(ExecuteError::Exit(_))) = res {
+            self.done = true;
+        }
+        Some(Ok((expr, res)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cst::{self, Span};
+    use data::Data::*;
+    use error::{Error, ExecuteError, LoadError, ParseError};
+    use scanner::Token;
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::{env, fs, process};
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    #[test]
+    fn test_run_source() {
+        let mut p = Program::new();
+        assert_eq!(p.run_source("x = 2  x * 3"), Ok(Int(6)));
+        assert_eq!(p.run_source(""), Ok(Nil));
+        assert_eq!(p.run_source("x )"),
+                   Err(Error::Parse(ParseError::Unexpected(Token::CloseParen))));
+        assert_eq!(p.run_source("y = 1  z"),
+                   Err(Error::Execute(ExecuteError::UndefinedVar("z".to_owned()))));
+        assert_eq!(p.var("y"), Some(Int(1)));
+
+        assert_eq!(Program::eval_str("x = 2  x * 3"), Ok(Int(6)));
+        assert_eq!(Program::eval_str("x"),
+                   Err(Error::Execute(ExecuteError::UndefinedVar("x".to_owned()))));
+    }
+
+    #[test]
+    fn test_error_trace() {
+        let mut p = Program::new();
+        let source = "limit = 2\n\
+                      fn check(n) {\n\
+                          doubled = n * 2\n\
+                          doubled + missing\n\
+                      }\n\
+                      fn outer(x) { check(x + 1) }\n\
+                      outer(2)";
+        assert!(p.run_source(source).is_err());
+        assert_eq!(p.take_error_trace(), None);
+
+        p.trace_errors(true);
+        assert!(p.run_source("outer(2)").is_err());
+        let trace = p.take_error_trace().unwrap();
+        assert_eq!(trace.stack, vec!["check", "outer"]);
+        let names: Vec<_> = trace.vars.iter().map(|&(ref name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["doubled", "n", "check", "limit", "outer"]);
+        assert_eq!(trace.vars[..2], [("doubled".to_owned(), Int(6)), ("n".to_owned(), Int(3))]);
+
+        assert!(p.run_source("try { outer(2) } catch e { 0 }  for i in list(1) { break }")
+            .is_ok());
+        assert_eq!(p.take_error_trace(), None);
+        assert!(p.run_source("f = || nope  f()").is_err());
+        assert_eq!(p.take_error_trace().unwrap().stack, vec!["<fn>"]);
+    }
+
+    #[test]
+    fn test_print_readline() {
+        let mut p = Program::new();
+        p.capture_output();
+        p.set_input(::std::io::Cursor::new("Ada\r\nBob"));
+        let source = r#"print("name? ")  a = readline()  println("hi ", a)  b = readline()"#;
+        assert_eq!(p.run_source(source), Ok(Str("Bob".into())));
+        assert_eq!(p.run_source("readline()"), Ok(Nil));
+        assert_eq!(p.take_output(), "name? hi Ada\n");
+        assert!(p.run_source("readline(1)").is_err());
+    }
+
+    #[test]
+    fn test_set_output() {
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+
+            fn flush(&mut self) -> ::std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let written = Rc::new(RefCell::new(vec![]));
+        let mut p = Program::new();
+        p.set_output(Shared(written.clone()));
+        assert_eq!(p.run_source("print(1, \"a\")  println()"), Ok(Nil));
+        assert_eq!(*written.borrow(), b"1a\n");
+
+        p.capture_output();
+        assert_eq!(p.run_source("println(2)"), Ok(Nil));
+        assert_eq!(p.context.end_capture(), "2\n");
+        assert_eq!(p.run_source("println(3)"), Ok(Nil));
+        assert_eq!(*written.borrow(), b"1a\n3\n");
+
+        p.set_output(::std::io::Cursor::new([0u8; 2]));
+        assert!(p.run_source("println(456)").is_err());
+    }
+
+    #[test]
+    fn test_exit() {
+        let mut p = Program::new();
+        p.capture_output();
+        let source = "try { println(1)  exit(3) } catch e { println(e) }  println(2)";
+        assert_eq!(p.run_source(source), Err(Error::Execute(ExecuteError::Exit(3))));
+        assert_eq!(p.take_output(), "1\n");
+        assert_eq!(p.run_source("exit()"), Err(Error::Execute(ExecuteError::Exit(0))));
+        assert!(p.run_source("exit(1 << 40)").is_err());
+
+        let results: Vec<_> = p.iter_results("x = 1  exit(1)  x = 2").collect();
+        assert_eq!(results.len(), 2);
+        assert_eq!(p.var("x"), Some(Int(1)));
+    }
+
+    #[test]
+    fn test_files() {
+        let path = env::temp_dir().join(format!("gate-test-files-{}", process::id()));
+        let path = path.to_str().unwrap();
+        let mut p = Program::new();
+        p.set_var("path", Str(path.into()));
+        assert_eq!(p.run_source("write_file(path, \"hi\")"),
+                   Err(Error::Execute(ExecuteError::NotAllowed("write_file".to_owned()))));
+        assert_eq!(p.run_source("has_feature(\"fs\")"), Ok(Boolean(false)));
+
+        p.allow_fs(true);
+        p.enable_audit(&[]);
+        assert_eq!(p.run_source("write_file(path, \"a\\nb\")  read_file(path)"),
+                   Ok(Str("a\nb".into())));
+        assert_eq!(p.audit_log()[1],
+                   AuditEvent::FileAccess {
+                       path: path.to_owned(),
+                       write: true,
+                   });
+        assert_eq!(p.run_source("has_feature(\"fs\")"), Ok(Boolean(true)));
+        fs::remove_file(path).unwrap();
+        match p.run_source("read_file(path)") {
+            Err(Error::Execute(ExecuteError::Io(_))) => {}
+            res => panic!("expected an i/o error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_store_kinds() {
+        let source = "fn count(n) { total = 0  for i in 0..n { const sq = i * i  total += sq }  \
+                      total }  \
+                      add = fn(a) fn(b) a + b  \
+                      x = count(4)  x += add(1)(2)  if x > 0 { y = 1  x + y }";
+        for &kind in &[StoreKind::Hash, StoreKind::Slots] {
+            let mut p = Program::with_store(kind);
+            assert_eq!(p.run_source(source), Ok(Int(18)));
+            assert_eq!(p.var("y"), None);
+            assert!(p.run_source("const c = 1  c = 2").is_err());
+        }
+    }
+
+    #[test]
+    fn test_random() {
+        let source = "[random(), random_int(1, 6), random_int(-3, -3)]";
+        let mut a = Program::new();
+        let mut b = Program::new();
+        a.seed_rng(7);
+        b.seed_rng(7);
+        let rolls = a.run_source(source).unwrap();
+        assert_eq!(b.run_source(source), Ok(rolls.clone()));
+        assert_eq!(rolls.iter().unwrap().nth(2), Some(Int(-3)));
+        assert!(a.run_source("random_int(2, 1)").is_err());
+        assert!(a.run_source("random_int(1.5, 2)").is_err());
+
+        // Hosts can supply their own generator.
+        struct Zero;
+        impl Rng for Zero {
+            fn next_u64(&mut self) -> u64 {
+                0
+            }
+        }
+        a.set_rng(Zero);
+        assert_eq!(a.run_source("[random(), random_int(10, 20)]"),
+                   Ok(Data::list(vec![Number(0.0), Int(10)])));
+    }
+
+    #[test]
+    fn test_time() {
+        let slept = Rc::new(RefCell::new(vec![]));
+        let mut p = Program::new();
+        let log = slept.clone();
+        p.set_sleep(move |d| log.borrow_mut().push(d));
+
+        assert_eq!(p.run_source("sleep(1500)  sleep(0.5)"), Ok(Nil));
+        assert_eq!(*slept.borrow(),
+                   vec![Duration::from_millis(1500), Duration::from_micros(500)]);
+        assert!(p.run_source("sleep(-1)").is_err());
+
+        let source = "start = clock()  elapsed = clock() - start  \
+                      list(now() > 1500000000, elapsed >= 0)";
+        assert_eq!(p.run_source(source),
+                   Ok(Data::list(vec![Boolean(true), Boolean(true)])));
+    }
+
+    #[test]
+    fn test_register_fn() {
+        let mut p = Program::new();
+        let mut calls = 0;
+        p.register_fn("count", move |args: &[Data]| {
+            calls += 1;
+            match args {
+                &[] => Ok(Int(calls)),
+                _ => Err(ExecuteError::WrongArgCount {
+                    expected: 0,
+                    found: args.len(),
+                }),
+            }
+        });
+        assert_eq!(p.run_source("count()  count()"), Ok(Int(2)));
+        assert_eq!(p.run_source("count(1)"),
+                   Err(Error::Execute(ExecuteError::WrongArgCount {
+                       expected: 0,
+                       found: 1,
+                   })));
+        assert_eq!(p.run_source("fn count() 0  count()"), Ok(Int(0)));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut p = Program::new();
+        let token = p.cancel_token();
+        let cancel_soon = || {
+            let token = token.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                token.cancel();
+            })
+        };
+
+        let canceller = cancel_soon();
+        let source = "i = 0  try { while true i += 1 } catch e { i = -1 }";
+        assert_eq!(p.run_source(source), Err(Error::Execute(ExecuteError::Cancelled)));
+        canceller.join().unwrap();
+        assert_eq!(p.run_source("i > 0"), Ok(Boolean(true)));
+        assert_eq!(p.run_source("i = 0  for x in list(1, 2) i += x  i"), Ok(Int(3)));
+
+        let canceller = cancel_soon();
+        let start = Instant::now();
+        assert_eq!(p.run_source("sleep(60000)"),
+                   Err(Error::Execute(ExecuteError::Cancelled)));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        canceller.join().unwrap();
+    }
+
+    #[test]
+    fn test_iter_results() {
+        let mut p = Program::new();
+        let steps: Vec<_> = p.iter_results("x = 1  y  x + 1  )  x = 5").collect();
+        assert_eq!(steps.len(), 4);
+
+        let (ref expr, ref res) = *steps[0].as_ref().unwrap();
+        assert_eq!(expr,
+                   &Expression::Assignment {
+                       left: "x".to_owned(),
+                       right: Box::new(Expression::IntLiteral(1)),
+                   });
+        assert_eq!(res, &Ok(Int(1)));
+
+        // An error doesn't stop the expressions after it, but a parse error
+        // does.
+        assert_eq!(steps[1].as_ref().unwrap().1,
+                   Err(Error::Execute(ExecuteError::UndefinedVar("y".to_owned()))));
+        assert_eq!(steps[2].as_ref().unwrap().1, Ok(Int(2)));
+        assert_eq!(steps[3], Err(ParseError::Unexpected(Token::CloseParen)));
+        assert_eq!(p.var("x"), Some(Int(1)));
+    }
+
+    #[test]
+    fn test_eval_transactional() {
+        let mut p = Program::new();
+        p.run_source("x = 1  l = [1]  shared = l  m = { a: [] }  \
+                      fn counter() { n = 0  fn() { n += 1  n } }  count = counter()  \
+                      struct Point { x, y }  pt = Point(1, 2)")
+            .unwrap();
+        let parse = |s| Parser::new(s).next().unwrap().unwrap();
+
+        let e = parse("do { x = 2  push(l, 2)  push(m[\"a\"], 1)  count()  y = 1  l[0] = 7  \
+                       pt.x = 3  m[\"b\"] = [1]  fn f(a) { a = 2  a }  f(1)  undefined }");
+        assert_eq!(p.eval_transactional(&e),
+                   Err(ExecuteError::UndefinedVar("undefined".to_owned())));
+        assert_eq!(p.run_source("[x, l, m, count(), pt.x]").unwrap().to_string(),
+                   "[1, [1], {a: []}, 1, 1]");
+        assert_eq!(p.var("f"), None);
+        assert_eq!(p.var("y"), None);
+
+        // Values shared between variables are still shared after a rollback.
+        p.run_source("push(l, 3)").unwrap();
+        assert_eq!(p.var("shared"), Some(Data::list(vec![Int(1), Int(3)])));
+
+        assert_eq!(p.eval_transactional(&parse("do { push(l, 4)  x = 5 }")), Ok(Int(5)));
+        assert_eq!(p.var("x"), Some(Int(5)));
+    }
+
+    #[test]
+    fn test_eval_isolated() {
+        let mut p = Program::new();
+        p.allow_fs(true);
+        p.run_source("x = 1  l = [1]").unwrap();
+        let parse = |s| Parser::new(s).next().unwrap().unwrap();
+
+        assert_eq!(p.eval_isolated(&parse("do { x = 5  push(l, 2)  y = len(l) }")), Ok(Int(2)));
+        assert_eq!(p.run_source("[x, l]").unwrap().to_string(), "[1, [1]]");
+        assert_eq!(p.var("y"), None);
+
+        let path = env::temp_dir().join(format!("gate-isolated-{}", process::id()));
+        p.set_var("path", Str(path.to_string_lossy().into_owned().into()));
+        for source in vec!["write_file(path, \"hi\")", "exit(1)", "readline()", "random()"] {
+            let name = source.split('(').next().unwrap().to_owned();
+            assert_eq!(p.eval_isolated(&parse(source)), Err(ExecuteError::NotAllowed(name)));
+        }
+        assert!(!path.exists());
+
+        // Builtins are allowed again afterwards, but not ones the host didn't
+        // allow in the first place.
+        assert_eq!(p.run_source("random_int(1, 1)"), Ok(Int(1)));
+        p.engine.allow_only(&["len"]);
+        assert_eq!(p.eval_isolated(&parse("str(1)")),
+                   Err(ExecuteError::NotAllowed("str".to_owned())));
+        assert_eq!(p.run_source("len(\"ab\")"), Ok(Int(2)));
+    }
+
+    #[test]
+    fn test_bind() {
+        let parse = |s| Parser::new(s).next().unwrap().unwrap();
+        let mut p = Program::new();
+        p.run_source("limit = 5").unwrap();
+        let bound = p.bind(parse("do { hits = limit * 2 }"));
+
+        // Another program evaluates it against the first one's variables,
+        // including changes made after binding.
+        let mut q = Program::new();
+        p.run_source("limit = 6").unwrap();
+        assert_eq!(q.eval_bound(&bound), Ok(Int(12)));
+        assert_eq!(p.var("hits"), Some(Int(12)));
+        assert_eq!(q.var("hits"), None);
+
+        // Locals are kept after the scope that held them ends.
+        p.context.new_scope();
+        p.context.declare_var("local", Int(1));
+        let bound = p.bind(parse("local + limit"));
+        p.context.pop_scope();
+        assert_eq!(p.eval_bound(&bound), Ok(Int(7)));
+        assert_eq!(p.run_source("local"),
+                   Err(Error::Execute(ExecuteError::UndefinedVar("local".to_owned()))));
+    }
+
+    #[test]
+    fn test_freeze_globals() {
+        let config = || {
+            let mut vars = HashMap::new();
+            vars.insert("limit".to_owned(), Int(10));
+            vars.insert("names".to_owned(), Data::list(vec![]));
+            vars
+        };
+        let read_only = |name: &str| Err(Error::Execute(ExecuteError::ReadOnly(name.to_owned())));
+
+        let mut p = Program::new();
+        p.freeze_globals(config(), FrozenPolicy::Error);
+        assert_eq!(p.run_source("limit * 2"), Ok(Int(20)));
+        assert_eq!(p.run_source("limit = 5"), read_only("limit"));
+        assert_eq!(p.run_source("do { limit += 1 }"), read_only("limit"));
+        assert_eq!(p.run_source("fn limit() 1"), read_only("limit"));
+        // Parameters can still have the same name, and lists can't be
+        // changed in place, even through other variables, but can be copied.
+        assert_eq!(p.run_source("fn f(limit) { limit = 1 }  f(2)"), Ok(Int(1)));
+        assert_eq!(p.run_source("push(names, 1)"), read_only("names"));
+        assert_eq!(p.run_source("all = [names]  all[0][0] = 1"), read_only("names"));
+        assert_eq!(p.run_source("mine = copy(names)  push(mine, 1)  list(names, mine)")
+                       .unwrap()
+                       .to_string(),
+                   "[[], [1]]");
+        assert_eq!(p.var("limit"), Some(Int(10)));
+
+        // Values that hold themselves can be frozen, and reading one doesn't
+        // copy it.
+        let cyclic = Data::list(vec![]);
+        if let List(ref l) = cyclic {
+            l.borrow_mut().push(cyclic.clone());
+        }
+        let mut vars = config();
+        vars.insert("cyclic".to_owned(), cyclic);
+        p.freeze_globals(vars, FrozenPolicy::Error);
+        assert_eq!(p.run_source("cyclic[0] == cyclic"), Ok(Boolean(true)));
+        assert_eq!(p.var("cyclic").unwrap().address(), p.var("cyclic").unwrap().address());
+        assert_eq!(p.run_source("push(cyclic[0], 1)"), read_only("cyclic"));
+        assert_eq!(p.run_source("push(names, 1)"), read_only("names"));
+
+        let mut p = Program::new();
+        p.freeze_globals(config(), FrozenPolicy::Shadow);
+        assert_eq!(p.run_source("{ limit = 5  limit }"), Ok(Int(5)));
+        assert_eq!(p.run_source("limit"), Ok(Int(10)));
+        assert_eq!(p.run_source("limit = 5  limit"), Ok(Int(5)));
+    }
+
+    #[test]
+    fn test_invalid_operation_span() {
+        let span = |source: &str| Program::new().run_source(source).unwrap_err().span();
+
+        let source = "total = 0\nfor x in [1, \"two\"] {\n    total += x\n}";
+        let err = Program::new().run_source(source).unwrap_err();
+        assert_eq!(err.to_string(),
+                   "error[E0018]: invalid operation (int + string): 1 + \"two\"");
+        assert_eq!(err.code(), "E0018");
+        assert_eq!(err.span(), Some(Span { start: 42, end: 44 }));
+
+        // The operation is inside a function defined by an earlier expression.
+        assert_eq!(span("fn f(a) a * 2\nx = 1\nf(nil)"), Some(Span { start: 10, end: 11 }));
+        // The span is the operator that failed, not another one like it.
+        assert_eq!(span("x = 1\ny = x - 1 - nil"), Some(Span { start: 16, end: 17 }));
+
+        // A span in one source means nothing next to another, so operations
+        // in functions run by an earlier call to run_source have none.
+        let mut p = Program::new();
+        assert!(p.run_source("fn f(a) a * nil  g = |a| a * nil").is_ok());
+        assert_eq!(p.run_source("x = 3 * 4  f(1)").unwrap_err().span(), None);
+        assert_eq!(p.run_source("map(list(1), g)").unwrap_err().span(), None);
+        assert_eq!(p.run_source("x * nil").unwrap_err().span(),
+                   Some(Span { start: 2, end: 3 }));
+        let e = Parser::new("x * nil").next().unwrap().unwrap();
+        match p.eval(&e) {
+            Err(ExecuteError::InvalidOperation { span, .. }) => assert_eq!(span, None),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_run_source_panic() {
+        let mut p = Program::new();
+        p.engine.register("boom", |_: &[Data]| -> Result { panic!("boom") });
+
+        assert_eq!(p.run_source("x = 1  do { fn f() { y = 2  boom() }  f() }"),
+                   Err(Error::Internal("boom".to_owned())));
+
+        // The program can still be used, and new variables are globals again.
+        assert_eq!(p.run_source("z = x + 1  z"), Ok(Int(2)));
+        assert_eq!(p.var("z"), Some(Int(2)));
+        assert_eq!(p.var("y"), None);
+
+        // Panics are caught by the engine too, for hosts that use it directly.
+        let e = Parser::new("boom()").next().unwrap().unwrap();
+        assert_eq!(p.engine.eval(&mut p.context, &e),
+                   Err(ExecuteError::Internal("boom".to_owned())));
+    }
+
+    #[test]
+    fn test_too_deep() {
+        let mut p = Program::new();
+        assert_eq!(p.run_source("fn f(n) f(n + 1)  f(0)"),
+                   Err(Error::Execute(ExecuteError::TooDeep)));
+        assert_eq!(p.run_source("fn g(n) { if n == 0 0 else g(n - 1) }  g(5)"), Ok(Int(0)));
+
+        let nested = format!("{}1{}", "(".repeat(100000), ")".repeat(100000));
+        assert_eq!(p.run_source(&nested), Err(Error::Parse(ParseError::TooDeep)));
+        let chain = format!("1{}", " + 1".repeat(100000));
+        assert_eq!(p.run_source(&chain), Err(Error::Parse(ParseError::TooDeep)));
+        assert_eq!(cst::parse(&nested).map(|_| ()), Err(ParseError::TooDeep));
+    }
+
+    #[test]
+    fn test_load_compiled() {
+        let bytes = compiled::compile("fn sq(x) x * x  n = sq(3)  n + 1").unwrap();
+        let mut p = Program::new();
+        assert_eq!(p.load_compiled(&bytes), Ok(Int(10)));
+        assert_eq!(p.var("n"), Some(Int(9)));
+        assert_eq!(p.load_compiled(b"n = 1"), Err(Error::Load(LoadError::NotCompiled)));
+
+        // Change the final 1 to a 2.
+        let mut damaged = bytes.clone();
+        let last = damaged.len() - 8;
+        damaged[last] = 2;
+        assert_eq!(p.load_compiled(&damaged), Err(Error::Load(LoadError::Corrupt)));
+        assert_eq!(p.load_compiled_trusted(&damaged), Ok(Int(11)));
+    }
+
+    // Runs random sequences of tokens and builtin names, none of which should
+    // make the interpreter panic.  Anything that could loop or recurse forever
+    // is left out.
+    #[test]
+    fn test_fuzz() {
+        let pieces = ["1", "2.5", "-1", "0x7f", "9223372036854775807", "99999999999999999999",
+                      "\"s\"", "\"{{x}}\"", "nil", "true", "x", "l", "m", "i", "=", "+=", "+",
+                      "-", "*", "/", "%", "<<", ">>", "&", "^", "~", "<", "==", "..", "in",
+                      "(", ")", "{", "}", "[", "]", ",", ".", ":", "if", "else", "do",
+                      "break", "l = [1, 2, 3]", "m = { a: 1 }", "for i in l", "l[", "m.a",
+                      "push", "pop", "insert", "remove", "slice", "get", "put", "keys",
+                      "render", "parse_int", "set", "union", "copy", "join", "map"];
+        let mut seed: u64 = 1;
+        for i in 0..2000 {
+            let mut source = String::new();
+            for _ in 0..(4 + i % 16) {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                source.push_str(pieces[(seed >> 33) as usize % pieces.len()]);
+                source.push(' ');
+            }
+
+            let mut p = Program::new();
+            p.capture_output();
+            if let Err(Error::Internal(msg)) = p.run_source(&source) {
+                panic!("{:?} panicked: {}", source, msg);
+            }
+        }
     }
 }
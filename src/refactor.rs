@@ -0,0 +1,399 @@
+// The refactor module rewrites source code while keeping its meaning, working
+// on the concrete syntax tree so that spacing and comments are left alone.
+
+use std::collections::{HashMap, HashSet};
+use std::result;
+
+use cst::{self, Element, Kind, Leaf, Node, Span};
+use error::RefactorError;
+use scanner::{Scanner, Token};
+
+pub type Result<T> = result::Result<T, RefactorError>;
+
+// rename renames the variable named at span, along with every other use of the
+// same variable, and returns the new source.  Variables that merely share the
+// name, such as a function parameter shadowing a global, are left alone.
+pub fn rename(source: &str, span: Span, new_name: &str) -> Result<String> {
+    let tokens: Vec<_> = Scanner::new(new_name).collect();
+    if tokens != vec![Ok(Token::Identifier(new_name.to_owned()))] {
+        return Err(RefactorError::InvalidName(new_name.to_owned()));
+    }
+
+    let root = cst::parse(source).map_err(RefactorError::Parse)?;
+    let uses = resolve(&root);
+    let target = match uses.iter().find(|u| u.span.contains(span.start)) {
+        Some(u) => u.clone(),
+        None => return Err(RefactorError::NotAVariable),
+    };
+    if target.name == new_name {
+        return Ok(source.to_owned());
+    }
+    if uses.iter().any(|u| u.name == new_name) {
+        return Err(RefactorError::NameInUse(new_name.to_owned()));
+    }
+
+    let mut renamed = String::new();
+    let mut last = 0;
+    for u in uses.iter().filter(|u| u.binding == target.binding) {
+        renamed.push_str(&source[last..u.span.start]);
+        renamed.push_str(new_name);
+        last = u.span.end;
+    }
+    renamed.push_str(&source[last..]);
+    Ok(renamed)
+}
+
+// find returns the span of the first place in source where name is used as a
+// variable.
+pub fn find(source: &str, name: &str) -> Result<Option<Span>> {
+    let root = cst::parse(source).map_err(RefactorError::Parse)?;
+    Ok(resolve(&root).into_iter().find(|u| u.name == name).map(|u| u.span))
+}
+
+// A Use is a place where a variable is named, whether it's read, assigned or
+// declared.  Uses of the same variable share a binding.
+#[derive(Clone,Debug)]
+struct Use {
+    span: Span,
+    name: String,
+    binding: usize,
+}
+
+// resolve returns every use of a variable in the tree, in source order.
+fn resolve(root: &Node) -> Vec<Use> {
+    let mut resolver = Resolver {
+        scopes: vec![],
+        global_names: HashSet::new(),
+        globals: HashMap::new(),
+        bindings: 0,
+        uses: vec![],
+    };
+    resolver.collect_globals(root);
+    resolver.walk(root);
+
+    let mut uses = resolver.uses;
+    uses.sort_by_key(|u| u.span.start);
+    uses
+}
+
+// Resolver follows the scoping rules of the interpreter.  Blocks, loop bodies
+// and functions get their own scopes, and a function body sees the scopes it
+// was defined in.  Since a function sees whichever globals exist when it's
+// called, globals are matched by name alone.
+struct Resolver {
+    // The local scopes, innermost last.
+    scopes: Vec<HashMap<String, usize>>,
+    // The names assigned anywhere at the top level of the program.
+    global_names: HashSet<String>,
+    globals: HashMap<String, usize>,
+    bindings: usize,
+    uses: Vec<Use>,
+}
+
+impl Resolver {
+    fn collect_globals(&mut self, node: &Node) {
+        match node.kind {
+            Kind::Block | Kind::For | Kind::Lambda => return,
+            Kind::Fn | Kind::Struct => {
+                self.global_names.insert(leaf(node, 1).lexeme.text.clone());
+                return;
+            }
+            Kind::Let => {
+                self.global_names.insert(leaf(node, 1).lexeme.text.clone());
+            }
+            // A do block's braces don't start a new scope.
+            Kind::DoBlock | Kind::DoWhile => {
+                let nodes = node.child_nodes();
+                for n in nodes[0].child_nodes() {
+                    self.collect_globals(n);
+                }
+                for n in &nodes[1..] {
+                    self.collect_globals(n);
+                }
+                return;
+            }
+            // The handler runs in its own scope, but the body doesn't.
+            Kind::Try => {
+                self.collect_globals(node.child_nodes()[0]);
+                return;
+            }
+            Kind::Assignment => {
+                let lhs = node.child_nodes()[0];
+                if lhs.kind == Kind::Variable {
+                    self.global_names.insert(leaf(lhs, 0).lexeme.text.clone());
+                }
+            }
+            _ => {}
+        }
+
+        for n in node.child_nodes() {
+            self.collect_globals(n);
+        }
+    }
+
+    fn walk(&mut self, node: &Node) {
+        let nodes = node.child_nodes();
+        match node.kind {
+            Kind::Variable => {
+                let name = leaf(node, 0);
+                let binding = self.lookup(&name.lexeme.text);
+                self.record(name, binding);
+            }
+            Kind::Assignment => {
+                // The value is evaluated before it's assigned.
+                self.walk(nodes[1]);
+                if nodes[0].kind == Kind::Variable {
+                    let name = leaf(nodes[0], 0);
+                    let binding = self.assign(&name.lexeme.text);
+                    self.record(name, binding);
+                } else {
+                    self.walk(nodes[0]);
+                }
+            }
+            Kind::Block => {
+                self.scopes.push(HashMap::new());
+                for n in nodes {
+                    self.walk(n);
+                }
+                self.scopes.pop();
+            }
+            Kind::Let => {
+                self.walk(nodes[0]);
+                let name = leaf(node, 1);
+                let binding = self.declare(&name.lexeme.text);
+                self.record(name, binding);
+            }
+            // A do block runs its expressions in the current scope.
+            Kind::DoBlock | Kind::DoWhile => {
+                for n in nodes[0].child_nodes() {
+                    self.walk(n);
+                }
+                for n in &nodes[1..] {
+                    self.walk(n);
+                }
+            }
+            // Field names aren't variables.
+            Kind::Field => self.walk(nodes[0]),
+            Kind::For => {
+                self.walk(nodes[0]);
+                self.scopes.push(HashMap::new());
+                let var = leaf(node, 1);
+                let binding = self.declare(&var.lexeme.text);
+                self.record(var, binding);
+                self.walk(nodes[1]);
+                self.scopes.pop();
+            }
+            // A struct's fields aren't variables.
+            Kind::Struct => {
+                let name = leaf(node, 1);
+                let binding = self.declare(&name.lexeme.text);
+                self.record(name, binding);
+            }
+            Kind::Try => {
+                self.walk(nodes[0]);
+                self.scopes.push(HashMap::new());
+                let var = leaf(node, 3);
+                let binding = self.declare(&var.lexeme.text);
+                self.record(var, binding);
+                self.walk(nodes[1]);
+                self.scopes.pop();
+            }
+            Kind::Fn | Kind::Lambda => {
+                if node.kind == Kind::Fn {
+                    let name = leaf(node, 1);
+                    let binding = self.declare(&name.lexeme.text);
+                    self.record(name, binding);
+                }
+
+                self.scopes.push(HashMap::new());
+                for param in nodes[0].child_nodes() {
+                    if param.kind == Kind::Variable {
+                        let name = leaf(param, 0);
+                        let binding = self.declare(&name.lexeme.text);
+                        self.record(name, binding);
+                    } else {
+                        self.walk(param);
+                    }
+                }
+                self.walk(nodes[1]);
+                self.scopes.pop();
+            }
+            _ => {
+                for n in nodes {
+                    self.walk(n);
+                }
+            }
+        }
+    }
+
+    fn record(&mut self, name: &Leaf, binding: usize) {
+        self.uses.push(Use {
+            span: name.span,
+            name: name.lexeme.text.clone(),
+            binding: binding,
+        });
+    }
+
+    fn new_binding(&mut self) -> usize {
+        self.bindings += 1;
+        self.bindings
+    }
+
+    fn local(&self, name: &str) -> Option<usize> {
+        self.scopes.iter().rev().filter_map(|s| s.get(name)).cloned().next()
+    }
+
+    fn global(&mut self, name: &str) -> usize {
+        if let Some(&binding) = self.globals.get(name) {
+            return binding;
+        }
+        let binding = self.new_binding();
+        self.globals.insert(name.to_owned(), binding);
+        binding
+    }
+
+    // lookup finds the variable that reading name refers to.  Names that
+    // aren't local are assumed to be globals or builtins.
+    fn lookup(&mut self, name: &str) -> usize {
+        match self.local(name) {
+            Some(binding) => binding,
+            None => self.global(name),
+        }
+    }
+
+    // assign finds the variable that assigning to name sets, which is a new
+    // variable in the innermost scope if name isn't defined yet.
+    fn assign(&mut self, name: &str) -> usize {
+        match self.local(name) {
+            Some(binding) => binding,
+            None if self.global_names.contains(name) => self.global(name),
+            None => self.declare(name),
+        }
+    }
+
+    // declare finds the variable named name in the innermost scope, creating
+    // it if needed.
+    fn declare(&mut self, name: &str) -> usize {
+        if self.scopes.is_empty() {
+            return self.global(name);
+        }
+        if let Some(&binding) = self.scopes.last().unwrap().get(name) {
+            return binding;
+        }
+        let binding = self.new_binding();
+        self.scopes.last_mut().unwrap().insert(name.to_owned(), binding);
+        binding
+    }
+}
+
+// leaf returns the ith child of node, which must be a leaf.
+fn leaf(node: &Node, i: usize) -> &Leaf {
+    match node.children[i] {
+        Element::Leaf(ref l) => l,
+        Element::Node(_) => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cst::Span;
+    use error::{ParseError, RefactorError};
+    use scanner::Token;
+    use super::*;
+
+    fn at(source: &str, needle: &str) -> Span {
+        let start = source.find(needle).unwrap();
+        Span {
+            start: start,
+            end: start + needle.len(),
+        }
+    }
+
+    #[test]
+    fn test_rename_global() {
+        let src = "x = 1\n\
+                   fn f(x) { x + 1 }\n\
+                   fn g() { x }\n\
+                   { x = x + 1 }\n\
+                   for x in l { x }  # x\n\
+                   y = m.x + x\n";
+        assert_eq!(rename(src, Span { start: 0, end: 1 }, "total"),
+                   Ok("total = 1\n\
+                       fn f(x) { x + 1 }\n\
+                       fn g() { total }\n\
+                       { total = total + 1 }\n\
+                       for x in l { x }  # x\n\
+                       y = m.x + total\n"
+                       .to_owned()));
+    }
+
+    #[test]
+    fn test_rename_local() {
+        let src = "a = 1  fn f(a) { b = a  { a } }  { b = 2  b }  b";
+        assert_eq!(rename(src, at(src, "a)"), "n"),
+                   Ok("a = 1  fn f(n) { b = n  { n } }  { b = 2  b }  b".to_owned()));
+        assert_eq!(rename(src, at(src, "b = 2"), "c"),
+                   Ok("a = 1  fn f(a) { b = a  { a } }  { c = 2  c }  b".to_owned()));
+
+        // A declaration always makes a new variable.
+        let src = "{ a = 1  { let a = a + 1  a }  a }";
+        assert_eq!(rename(src, at(src, "a = 1"), "n"),
+                   Ok("{ n = 1  { let a = n + 1  a }  n }".to_owned()));
+
+        // Functions see the locals around where they're defined.
+        let src = "{ a = 1  f = |b| a + b  g = fn(a) { a }  fn h() { a } }";
+        assert_eq!(rename(src, at(src, "a = 1"), "n"),
+                   Ok("{ n = 1  f = |b| n + b  g = fn(a) { a }  fn h() { n } }".to_owned()));
+
+        // Field names aren't variables, but the struct's name is.
+        let src = "x = 1  struct P { x }  p = P(x)  p.x";
+        assert_eq!(rename(src, at(src, "P {"), "Q"),
+                   Ok("x = 1  struct Q { x }  p = Q(x)  p.x".to_owned()));
+        assert_eq!(rename(src, at(src, "x = 1"), "n"),
+                   Ok("n = 1  struct P { x }  p = P(n)  p.x".to_owned()));
+
+        // The error variable is only seen by the handler.
+        let src = "e = 1  try f(e) catch e { e }  e";
+        assert_eq!(rename(src, at(src, "e }"), "err"),
+                   Ok("e = 1  try f(e) catch err { err }  e".to_owned()));
+
+        // A do-while loop's body runs in the surrounding scope.
+        let src = "{ do { a = f() } while a > 0  a }";
+        assert_eq!(rename(src, at(src, "a = f"), "n"),
+                   Ok("{ do { n = f() } while n > 0  n }".to_owned()));
+
+        // Map keys written as plain names aren't variables.
+        let src = "a = 1  m = { a: a }";
+        assert_eq!(rename(src, at(src, "a"), "n"), Ok("n = 1  m = { a: n }".to_owned()));
+
+        // Globals can be used in a function before they're assigned.
+        let src = "fn f() { n = n + 1 }  n = 0";
+        assert_eq!(rename(src, at(src, "n = 0"), "count"),
+                   Ok("fn f() { count = count + 1 }  count = 0".to_owned()));
+    }
+
+    #[test]
+    fn test_rename_errors() {
+        let src = "x = 1  y = m.z";
+        assert_eq!(rename(src, at(src, "1"), "a"), Err(RefactorError::NotAVariable));
+        assert_eq!(rename(src, at(src, "z"), "a"), Err(RefactorError::NotAVariable));
+        assert_eq!(rename(src, at(src, "x"), "1a"),
+                   Err(RefactorError::InvalidName("1a".to_owned())));
+        assert_eq!(rename(src, at(src, "x"), "if"),
+                   Err(RefactorError::InvalidName("if".to_owned())));
+        assert_eq!(rename(src, at(src, "x"), "m"),
+                   Err(RefactorError::NameInUse("m".to_owned())));
+        assert_eq!(rename(src, at(src, "x"), "x"), Ok(src.to_owned()));
+        assert_eq!(rename("(x", at(src, "x"), "a"),
+                   Err(RefactorError::Parse(ParseError::UnexpectedEOF)));
+        assert_eq!(rename("x ]", at(src, "x"), "a"),
+                   Err(RefactorError::Parse(ParseError::Unexpected(Token::CloseBracket))));
+    }
+
+    #[test]
+    fn test_find() {
+        let src = "y = m.x  fn f(x) { x }  x = 2";
+        assert_eq!(find(src, "x"), Ok(Some(Span { start: 14, end: 15 })));
+        assert_eq!(find(src, "z"), Ok(None));
+    }
+}
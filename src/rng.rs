@@ -0,0 +1,93 @@
+// The rng module holds the random number generators behind random and
+// random_int.  Each context has its own generator, which hosts can seed or
+// replace to make scripts that use random numbers behave the same every run.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// An Rng produces random numbers.  Hosts can implement it to supply their own
+// generator with Context::set_rng.
+pub trait Rng {
+    // next_u64 returns the next random number, which should be uniformly
+    // distributed over every u64.
+    fn next_u64(&mut self) -> u64;
+}
+
+// SplitMix64 is the generator contexts start with.  It's fast and small, and
+// the numbers it produces from a given seed will never change, so seeded
+// scripts give the same results on every version of gate.  It isn't suitable
+// for cryptography.
+#[derive(Clone,Debug)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+
+    // from_time seeds a generator from the current time, so each run gets
+    // different numbers.
+    pub fn from_time() -> Self {
+        let since = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        SplitMix64::new(since.as_secs() ^ ((since.subsec_nanos() as u64) << 32))
+    }
+}
+
+impl Rng for SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+// float returns a random number from 0 up to, but not including, 1.
+pub fn float(rng: &mut dyn Rng) -> f64 {
+    (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// int_between returns a random int from lo to hi, including both, with every
+// int equally likely.  lo must not be greater than hi.
+pub fn int_between(rng: &mut dyn Rng, lo: i64, hi: i64) -> i64 {
+    let span = (hi as i128 - lo as i128 + 1) as u128;
+    if span > u64::max_value() as u128 {
+        return rng.next_u64() as i64;
+    }
+
+    // Numbers past the last whole multiple of span are thrown away, so that
+    // taking the remainder doesn't favour small offsets.
+    let span = span as u64;
+    let limit = u64::max_value() - u64::max_value() % span;
+    loop {
+        let n = rng.next_u64();
+        if n < limit {
+            return (lo as i128 + (n % span) as i128) as i64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_mix() {
+        let mut a = SplitMix64::new(42);
+        let mut b = SplitMix64::new(42);
+        let first: Vec<u64> = (0..3).map(|_| a.next_u64()).collect();
+        assert_eq!(first, (0..3).map(|_| b.next_u64()).collect::<Vec<_>>());
+        assert_eq!(SplitMix64::new(0).next_u64(), 0xe220a8397b1dcdaf);
+
+        for _ in 0..1000 {
+            let f = float(&mut a);
+            assert!(0.0 <= f && f < 1.0);
+            let i = int_between(&mut a, -2, 2);
+            assert!(-2 <= i && i <= 2);
+        }
+        assert_eq!(int_between(&mut a, 7, 7), 7);
+        int_between(&mut a, i64::min_value(), i64::max_value());
+    }
+}
@@ -1,8 +1,8 @@
-use std::iter::{Iterator, Peekable};
+use std::iter::Iterator;
 use std::result;
-use std::str::Chars;
 
 use binary_op::BinaryOp;
+use cst::Span;
 use error::TokenError;
 
 #[derive(Clone,Debug,PartialEq)]
@@ -11,9 +11,15 @@ pub enum Token {
     CloseParen,
     OpenCurly,
     CloseCurly,
+    OpenBracket,
+    CloseBracket,
     Comma,
+    Colon,
+    Dot,
+    DotDot,
     Eq,
     DoubleEq,
+    BangEq,
     Lt,
     LtEq,
     Gt,
@@ -23,20 +29,56 @@ pub enum Token {
     Times,
     Divide,
     Percent,
+    PlusEq,
+    MinusEq,
+    TimesEq,
+    DivideEq,
+    PercentEq,
+    DoubleAmp,
+    DoublePipe,
+    Question,
+    DoubleQuestion,
+    Amp,
+    Pipe,
+    Caret,
+    LtLt,
+    GtGt,
+    Tilde,
+    TildeEq,
     Nil,
     If,
     Else,
     While,
+    For,
+    Break,
+    Continue,
+    Do,
+    Fn,
+    Let,
+    Const,
+    Throw,
+    Try,
+    Catch,
+    Struct,
+    Switch,
+    Case,
+    Default,
+    In,
     Boolean(bool),
     Identifier(String),
     Number(f64),
+    Int(i64),
     String(String),
+    // A string with a prefix registered by the host, as in `$"5.00"`.
+    CustomLiteral(char, String),
 }
 
 impl Token {
     pub fn to_binary_op(&self) -> Option<BinaryOp> {
         match self {
             &Token::DoubleEq => Some(BinaryOp::Eq),
+            &Token::BangEq => Some(BinaryOp::NotEq),
+            &Token::TildeEq => Some(BinaryOp::ApproxEq),
             &Token::Lt => Some(BinaryOp::Lt),
             &Token::LtEq => Some(BinaryOp::LtEq),
             &Token::Gt => Some(BinaryOp::Gt),
@@ -46,6 +88,29 @@ impl Token {
             &Token::Times => Some(BinaryOp::Mul),
             &Token::Divide => Some(BinaryOp::Div),
             &Token::Percent => Some(BinaryOp::Mod),
+            &Token::In => Some(BinaryOp::In),
+            &Token::DotDot => Some(BinaryOp::Range),
+            &Token::DoubleAmp => Some(BinaryOp::And),
+            &Token::DoublePipe => Some(BinaryOp::Or),
+            &Token::DoubleQuestion => Some(BinaryOp::Coalesce),
+            &Token::Amp => Some(BinaryOp::BitAnd),
+            &Token::Pipe => Some(BinaryOp::BitOr),
+            &Token::Caret => Some(BinaryOp::BitXor),
+            &Token::LtLt => Some(BinaryOp::Shl),
+            &Token::GtGt => Some(BinaryOp::Shr),
+            _ => None,
+        }
+    }
+
+    // compound_op returns the operator applied by a compound assignment token
+    // such as `+=`.
+    pub fn compound_op(&self) -> Option<BinaryOp> {
+        match self {
+            &Token::PlusEq => Some(BinaryOp::Add),
+            &Token::MinusEq => Some(BinaryOp::Sub),
+            &Token::TimesEq => Some(BinaryOp::Mul),
+            &Token::DivideEq => Some(BinaryOp::Div),
+            &Token::PercentEq => Some(BinaryOp::Mod),
             _ => None,
         }
     }
@@ -53,22 +118,158 @@ impl Token {
 
 pub type Result<T> = result::Result<T, TokenError>;
 
+// Trivia is the source text between tokens that doesn't affect the meaning of
+// a program.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Trivia {
+    Whitespace(String),
+    // A comment's text includes the leading '#' but not the newline ending it.
+    Comment(String),
+}
+
+// A Lexeme is a token along with the exact text it was scanned from and the
+// trivia before it, so that joining the trivia and text of every lexeme gives
+// back the original source.  The last lexeme has no token and holds the
+// trivia at the end of the input.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Lexeme {
+    pub leading: Vec<Trivia>,
+    pub token: Option<Token>,
+    pub text: String,
+}
+
+// Input is a peekable view of the source that keeps track of its position.
+#[derive(Clone)]
+struct Input<'a> {
+    rest: &'a str,
+    peeked: Option<char>,
+}
+
+impl<'a> Input<'a> {
+    fn new(s: &'a str) -> Self {
+        Input {
+            rest: s,
+            peeked: s.chars().next(),
+        }
+    }
+
+    fn peek(&self) -> Option<&char> {
+        self.peeked.as_ref()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peeked;
+        if let Some(c) = c {
+            self.rest = &self.rest[c.len_utf8()..];
+            self.peeked = self.rest.chars().next();
+        }
+        c
+    }
+}
+
+// LONG_QUOTE opens and closes strings that can span lines.
+const LONG_QUOTE: &'static str = "\"\"\"";
+
 pub struct Scanner<'a> {
-    input: Peekable<Chars<'a>>,
+    src: &'a str,
+    input: Input<'a>,
+    // Characters that turn a string right after them into a custom literal.
+    prefixes: Vec<char>,
+    // Where the token most recently read is.
+    span: Span,
 }
 
 impl<'a> Scanner<'a> {
     pub fn new(input: &'a str) -> Self {
-        Scanner { input: input.chars().peekable() }
+        Self::with_prefixes(input, vec![])
     }
 
-    fn read_rest_of_line(&mut self) {
+    pub fn with_prefixes(input: &'a str, prefixes: Vec<char>) -> Self {
+        Scanner {
+            src: input,
+            input: Input::new(input),
+            prefixes: prefixes,
+            span: Span { start: 0, end: 0 },
+        }
+    }
+
+    // span returns where in the source the token most recently read is.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    // lexemes turns the scanner into an iterator over lexemes rather than
+    // tokens, keeping the whitespace and comments that are otherwise skipped.
+    // Tools like formatters use it to reproduce the source exactly.
+    pub fn lexemes(self) -> Lexemes<'a> {
+        Lexemes {
+            scanner: self,
+            done: false,
+        }
+    }
+
+    // offset returns the byte offset of the next unread character.
+    fn offset(&self) -> usize {
+        self.src.len() - self.input.rest.len()
+    }
+
+    // read_trivia reads the whitespace and comments before the next token,
+    // keeping them only if keep is true.
+    fn read_trivia(&mut self, keep: bool) -> Vec<Trivia> {
+        let mut trivia = vec![];
         loop {
-            match self.input.next() {
-                Some('\n') => return,
-                _ => {}
+            if self.at_custom_literal() {
+                break;
+            }
+
+            let start = self.offset();
+            let is_comment = match self.input.peek() {
+                Some(&c) if Self::is_space(c) => {
+                    while let Some(&c) = self.input.peek() {
+                        if !Self::is_space(c) {
+                            break;
+                        }
+                        self.input.next();
+                    }
+                    false
+                }
+                Some(&'#') => {
+                    self.read_rest_of_line();
+                    true
+                }
+                _ => break,
+            };
+
+            if keep {
+                let text = self.src[start..self.offset()].to_owned();
+                trivia.push(if is_comment {
+                    Trivia::Comment(text)
+                } else {
+                    Trivia::Whitespace(text)
+                });
             }
         }
+        trivia
+    }
+
+    // at_custom_literal returns whether the input continues with a registered
+    // prefix followed immediately by a string.
+    fn at_custom_literal(&self) -> bool {
+        let mut ahead = self.input.clone();
+        match ahead.next() {
+            Some(c) if self.prefixes.contains(&c) => ahead.peek() == Some(&'"'),
+            _ => false,
+        }
+    }
+
+    // read_rest_of_line reads up to, but not including, the next newline.
+    fn read_rest_of_line(&mut self) {
+        while let Some(&c) = self.input.peek() {
+            if c == '\n' {
+                return;
+            }
+            self.input.next();
+        }
     }
 
     fn read_word(&mut self) -> Token {
@@ -87,41 +288,105 @@ impl<'a> Scanner<'a> {
             "if" => Token::If,
             "else" => Token::Else,
             "while" => Token::While,
+            "for" => Token::For,
+            "break" => Token::Break,
+            "continue" => Token::Continue,
+            "do" => Token::Do,
+            "fn" => Token::Fn,
+            "let" => Token::Let,
+            "const" => Token::Const,
+            "throw" => Token::Throw,
+            "try" => Token::Try,
+            "catch" => Token::Catch,
+            "struct" => Token::Struct,
+            "switch" => Token::Switch,
+            "case" => Token::Case,
+            "default" => Token::Default,
+            "in" => Token::In,
             "true" => Token::Boolean(true),
             "false" => Token::Boolean(false),
             _ => Token::Identifier(word),
         }
     }
 
-    fn read_number(&mut self) -> f64 {
+    // read_number reads an int, or a number if it has a decimal point or is
+    // too large for an int.  Ints can also be written in hex, octal or binary,
+    // as in `0xff`, and any number can have underscores between its digits.
+    fn read_number(&mut self, negative: bool) -> Result<Token> {
         let mut num = String::new();
-        while let Some(&c) = self.input.peek() {
-            if !Self::is_digit(c) {
-                break;
-            }
-
-            self.input.next();
-            num.push(c);
+        if negative {
+            num.push('-');
         }
 
-        if let Some(&'.') = self.input.peek() {
+        let radix = match self.input.rest.get(..2) {
+            Some("0x") => 16,
+            Some("0o") => 8,
+            Some("0b") => 2,
+            _ => 10,
+        };
+        if radix != 10 {
+            let start = self.input.rest;
             self.input.next();
-            num.push('.');
-
+            self.input.next();
+            num.push_str(&self.read_digits(radix));
+            // Digits that don't belong to the radix, as in `0b12`, make the
+            // whole literal invalid.
             while let Some(&c) = self.input.peek() {
-                if !Self::is_digit(c) {
+                if !Self::is_digit(c) && !Self::is_alpha(c) {
                     break;
                 }
-
                 self.input.next();
                 num.push(c);
             }
+
+            let text = &start[..start.len() - self.input.rest.len()];
+            return match i64::from_str_radix(&num, radix) {
+                Ok(i) => Ok(Token::Int(i)),
+                Err(_) => Err(TokenError::InvalidNumber(text.to_owned())),
+            };
+        }
+
+        num.push_str(&self.read_digits(10));
+
+        // A second dot means the number is the start of a range, as in `1..5`.
+        let mut after = self.input.clone();
+        after.next();
+        if self.input.peek() == Some(&'.') && after.peek() != Some(&'.') {
+            self.input.next();
+            num.push('.');
+            num.push_str(&self.read_digits(10));
         }
 
-        num.parse().unwrap()
+        match num.parse() {
+            Ok(i) => Ok(Token::Int(i)),
+            Err(_) => num.parse().map(Token::Number).map_err(|_| TokenError::InvalidNumber(num)),
+        }
+    }
+
+    // read_digits reads digits in the given radix, leaving out any underscores
+    // between them.
+    fn read_digits(&mut self, radix: u32) -> String {
+        let mut digits = String::new();
+        while let Some(&c) = self.input.peek() {
+            if c == '_' && !digits.is_empty() {
+                self.input.next();
+                continue;
+            }
+            if !c.is_digit(radix) {
+                break;
+            }
+
+            self.input.next();
+            digits.push(c);
+        }
+        digits
     }
 
     fn read_string(&mut self) -> Result<Token> {
+        if self.input.rest.starts_with(LONG_QUOTE) {
+            return self.read_long_string();
+        }
+
         // Skip the opening quote.
         self.input.next();
 
@@ -132,12 +397,18 @@ impl<'a> Scanner<'a> {
             match c {
                 '"' => return Ok(Token::String(buf)),
                 '\\' => {
-                    match self.input.peek() {
-                        Some(&c) if c == '"' || c == '\\' => {
-                            self.input.next();
-                            buf.push(c);
-                        }
-                        _ => return Err(TokenError::InvalidEscape),
+                    let c = match self.input.next() {
+                        Some(c) => c,
+                        None => break,
+                    };
+                    match c {
+                        '"' | '\\' => buf.push(c),
+                        'n' => buf.push('\n'),
+                        't' => buf.push('\t'),
+                        'r' => buf.push('\r'),
+                        '0' => buf.push('\0'),
+                        'u' => buf.push(self.read_unicode_escape()?),
+                        c => return Err(TokenError::InvalidEscape(format!("\\{}", c))),
                     }
                 }
                 _ => buf.push(c),
@@ -148,6 +419,90 @@ impl<'a> Scanner<'a> {
         Err(TokenError::IncompleteString)
     }
 
+    // read_unicode_escape reads the rest of an escape like `\u{1F600}` after
+    // the u, and returns the character with that code point in hex.
+    fn read_unicode_escape(&mut self) -> Result<char> {
+        let mut text = "\\u".to_owned();
+        if self.input.peek() == Some(&'{') {
+            // Stopping at a quote keeps an unclosed escape from swallowing the
+            // end of the string.
+            while let Some(&c) = self.input.peek() {
+                if c == '"' {
+                    break;
+                }
+                self.input.next();
+                text.push(c);
+                if c == '}' {
+                    break;
+                }
+            }
+        }
+
+        let digits = text.trim_start_matches("\\u{").trim_end_matches('}');
+        let valid = text.ends_with('}') && !digits.is_empty() && digits.len() <= 6 &&
+                    digits.chars().all(|c| c.is_ascii_hexdigit());
+        match u32::from_str_radix(digits, 16).ok().and_then(char::from_u32) {
+            Some(c) if valid => Ok(c),
+            _ => Err(TokenError::InvalidEscape(text)),
+        }
+    }
+
+    // read_long_string reads a string in triple quotes, which can span lines
+    // and hold quotes without escaping them.  If the text starts on the line
+    // after the opening quotes, the indentation shared by its lines is
+    // stripped, as is the line holding the closing quotes.
+    fn read_long_string(&mut self) -> Result<Token> {
+        for _ in 0..3 {
+            self.input.next();
+        }
+
+        let end = match self.input.rest.find(LONG_QUOTE) {
+            Some(end) => end,
+            None => {
+                while self.input.next().is_some() {}
+                return Err(TokenError::IncompleteString);
+            }
+        };
+        let text = self.input.rest[..end].to_owned();
+        for _ in text.chars().chain(LONG_QUOTE.chars()) {
+            self.input.next();
+        }
+
+        if !text.starts_with('\n') {
+            return Ok(Token::String(text));
+        }
+
+        let mut lines: Vec<&str> = text[1..].split('\n').collect();
+        if lines.last().map_or(false, |l| l.trim().is_empty()) {
+            lines.pop();
+            lines.push("");
+        }
+        // The indentation is the whitespace every line starts with, compared
+        // character by character, so lines indented with different kinds of
+        // whitespace only lose what they share.
+        let indent = lines.iter()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| &l[..l.len() - l.trim_start().len()])
+            .fold(None, |indent, ws| {
+                Some(indent.map_or(ws, |indent| Scanner::common_prefix(indent, ws)))
+            })
+            .unwrap_or("");
+        let lines: Vec<&str> = lines.iter()
+            .map(|l| if l.starts_with(indent) { &l[indent.len()..] } else { l.trim_start() })
+            .collect();
+        Ok(Token::String(lines.join("\n")))
+    }
+
+    // common_prefix returns the longest string both a and b start with.
+    fn common_prefix<'b>(a: &'b str, b: &str) -> &'b str {
+        let len = a.char_indices()
+            .zip(b.chars())
+            .take_while(|&((_, x), y)| x == y)
+            .last()
+            .map_or(0, |((i, c), _)| i + c.len_utf8());
+        &a[..len]
+    }
+
     fn is_space(c: char) -> bool {
         c == ' ' || c == '\t' || c == '\n' || c == '\r'
     }
@@ -165,24 +520,27 @@ impl<'a> Iterator for Scanner<'a> {
     type Item = Result<Token>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            match self.input.peek() {
-                Some(&c) if Self::is_space(c) => {
-                    self.input.next();
-                }
-                Some(&'#') => {
-                    self.read_rest_of_line();
-                }
-                _ => break,
-            }
-        }
+        self.read_trivia(false);
+        let start = self.offset();
+        let token = self.read_token();
+        self.span = Span {
+            start: start,
+            end: self.offset(),
+        };
+        token
+    }
+}
 
-        while let Some(&c) = self.input.peek() {
-            if Self::is_space(c) {
-                self.input.next();
-            } else {
-                break;
-            }
+impl<'a> Scanner<'a> {
+    // read_token reads the token at the current position, which must not be
+    // trivia.
+    fn read_token(&mut self) -> Option<Result<Token>> {
+        if self.at_custom_literal() {
+            let prefix = self.input.next().unwrap();
+            return Some(match self.read_string() {
+                Ok(Token::String(s)) => Ok(Token::CustomLiteral(prefix, s)),
+                res => res,
+            });
         }
 
         match self.input.peek() {
@@ -203,10 +561,31 @@ impl<'a> Iterator for Scanner<'a> {
                 self.input.next();
                 Some(Ok(Token::CloseCurly))
             }
+            Some(&'[') => {
+                self.input.next();
+                Some(Ok(Token::OpenBracket))
+            }
+            Some(&']') => {
+                self.input.next();
+                Some(Ok(Token::CloseBracket))
+            }
             Some(&',') => {
                 self.input.next();
                 Some(Ok(Token::Comma))
             }
+            Some(&':') => {
+                self.input.next();
+                Some(Ok(Token::Colon))
+            }
+            Some(&'.') => {
+                self.input.next();
+                if let Some(&'.') = self.input.peek() {
+                    self.input.next();
+                    Some(Ok(Token::DotDot))
+                } else {
+                    Some(Ok(Token::Dot))
+                }
+            }
             Some(&'=') => {
                 self.input.next();
                 if let Some(&'=') = self.input.peek() {
@@ -216,55 +595,140 @@ impl<'a> Iterator for Scanner<'a> {
                     Some(Ok(Token::Eq))
                 }
             }
+            Some(&'!') => {
+                self.input.next();
+                match self.input.peek() {
+                    Some(&'=') => {
+                        self.input.next();
+                        Some(Ok(Token::BangEq))
+                    }
+                    _ => Some(Err(TokenError::UnexpectedChar('!'))),
+                }
+            }
             Some(&'<') => {
                 self.input.next();
-                if let Some(&'=') = self.input.peek() {
-                    self.input.next();
-                    Some(Ok(Token::LtEq))
-                } else {
-                    Some(Ok(Token::Lt))
+                match self.input.peek() {
+                    Some(&'=') => {
+                        self.input.next();
+                        Some(Ok(Token::LtEq))
+                    }
+                    Some(&'<') => {
+                        self.input.next();
+                        Some(Ok(Token::LtLt))
+                    }
+                    _ => Some(Ok(Token::Lt)),
                 }
             }
             Some(&'>') => {
                 self.input.next();
-                if let Some(&'=') = self.input.peek() {
-                    self.input.next();
-                    Some(Ok(Token::GtEq))
-                } else {
-                    Some(Ok(Token::Gt))
+                match self.input.peek() {
+                    Some(&'=') => {
+                        self.input.next();
+                        Some(Ok(Token::GtEq))
+                    }
+                    Some(&'>') => {
+                        self.input.next();
+                        Some(Ok(Token::GtGt))
+                    }
+                    _ => Some(Ok(Token::Gt)),
                 }
             }
             Some(&'+') => {
                 self.input.next();
                 match self.input.peek() {
-                    Some(&c) if Self::is_digit(c) => Some(Ok(Token::Number(self.read_number()))),
+                    Some(&'=') => {
+                        self.input.next();
+                        Some(Ok(Token::PlusEq))
+                    }
+                    Some(&c) if Self::is_digit(c) => Some(self.read_number(false)),
                     _ => Some(Ok(Token::Plus)),
                 }
             }
             Some(&'-') => {
                 self.input.next();
                 match self.input.peek() {
-                    Some(&c) if Self::is_digit(c) => {
-                        Some(Ok(Token::Number(self.read_number() * -1.0)))
+                    Some(&'=') => {
+                        self.input.next();
+                        Some(Ok(Token::MinusEq))
                     }
+                    Some(&c) if Self::is_digit(c) => Some(self.read_number(true)),
                     _ => Some(Ok(Token::Minus)),
                 }
             }
             Some(&'*') => {
                 self.input.next();
-                Some(Ok(Token::Times))
+                if let Some(&'=') = self.input.peek() {
+                    self.input.next();
+                    Some(Ok(Token::TimesEq))
+                } else {
+                    Some(Ok(Token::Times))
+                }
             }
             Some(&'/') => {
                 self.input.next();
-                Some(Ok(Token::Divide))
+                if let Some(&'=') = self.input.peek() {
+                    self.input.next();
+                    Some(Ok(Token::DivideEq))
+                } else {
+                    Some(Ok(Token::Divide))
+                }
             }
             Some(&'%') => {
                 self.input.next();
-                Some(Ok(Token::Percent))
+                if let Some(&'=') = self.input.peek() {
+                    self.input.next();
+                    Some(Ok(Token::PercentEq))
+                } else {
+                    Some(Ok(Token::Percent))
+                }
+            }
+            Some(&'&') => {
+                self.input.next();
+                match self.input.peek() {
+                    Some(&'&') => {
+                        self.input.next();
+                        Some(Ok(Token::DoubleAmp))
+                    }
+                    _ => Some(Ok(Token::Amp)),
+                }
+            }
+            Some(&'|') => {
+                self.input.next();
+                match self.input.peek() {
+                    Some(&'|') => {
+                        self.input.next();
+                        Some(Ok(Token::DoublePipe))
+                    }
+                    _ => Some(Ok(Token::Pipe)),
+                }
+            }
+            Some(&'?') => {
+                self.input.next();
+                match self.input.peek() {
+                    Some(&'?') => {
+                        self.input.next();
+                        Some(Ok(Token::DoubleQuestion))
+                    }
+                    _ => Some(Ok(Token::Question)),
+                }
+            }
+            Some(&'^') => {
+                self.input.next();
+                Some(Ok(Token::Caret))
+            }
+            Some(&'~') => {
+                self.input.next();
+                match self.input.peek() {
+                    Some(&'=') => {
+                        self.input.next();
+                        Some(Ok(Token::TildeEq))
+                    }
+                    _ => Some(Ok(Token::Tilde)),
+                }
             }
             Some(&'"') => Some(self.read_string()),
             Some(&c) if Self::is_alpha(c) => Some(Ok(self.read_word())),
-            Some(&c) if Self::is_digit(c) => Some(Ok(Token::Number(self.read_number()))),
+            Some(&c) if Self::is_digit(c) => Some(self.read_number(false)),
             Some(&c) => {
                 self.input.next();
                 Some(Err(TokenError::UnexpectedChar(c)))
@@ -273,6 +737,38 @@ impl<'a> Iterator for Scanner<'a> {
     }
 }
 
+pub struct Lexemes<'a> {
+    scanner: Scanner<'a>,
+    done: bool,
+}
+
+impl<'a> Iterator for Lexemes<'a> {
+    type Item = Result<Lexeme>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let leading = self.scanner.read_trivia(true);
+        let start = self.scanner.offset();
+        let token = match self.scanner.read_token() {
+            Some(Ok(t)) => Some(t),
+            Some(Err(e)) => return Some(Err(e)),
+            None => {
+                self.done = true;
+                None
+            }
+        };
+
+        Some(Ok(Lexeme {
+            leading: leading,
+            token: token,
+            text: self.scanner.src[start..self.scanner.offset()].to_owned(),
+        }))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use error::TokenError;
@@ -282,12 +778,18 @@ mod tests {
 
     #[test]
     fn test_punctuation() {
-        let mut s = Scanner::new("(,) = == < <= > >= +-*/%");
+        let mut s = Scanner::new("(,:. ..)[] = == != < <= > >= +-*/% && || & | ^ << >> ~ ~= ?? ?");
         assert_eq!(s.next(), Some(Ok(OpenParen)));
         assert_eq!(s.next(), Some(Ok(Comma)));
+        assert_eq!(s.next(), Some(Ok(Colon)));
+        assert_eq!(s.next(), Some(Ok(Dot)));
+        assert_eq!(s.next(), Some(Ok(DotDot)));
         assert_eq!(s.next(), Some(Ok(CloseParen)));
+        assert_eq!(s.next(), Some(Ok(OpenBracket)));
+        assert_eq!(s.next(), Some(Ok(CloseBracket)));
         assert_eq!(s.next(), Some(Ok(Eq)));
         assert_eq!(s.next(), Some(Ok(DoubleEq)));
+        assert_eq!(s.next(), Some(Ok(BangEq)));
         assert_eq!(s.next(), Some(Ok(Lt)));
         assert_eq!(s.next(), Some(Ok(LtEq)));
         assert_eq!(s.next(), Some(Ok(Gt)));
@@ -297,6 +799,27 @@ mod tests {
         assert_eq!(s.next(), Some(Ok(Times)));
         assert_eq!(s.next(), Some(Ok(Divide)));
         assert_eq!(s.next(), Some(Ok(Percent)));
+        assert_eq!(s.next(), Some(Ok(DoubleAmp)));
+        assert_eq!(s.next(), Some(Ok(DoublePipe)));
+        assert_eq!(s.next(), Some(Ok(Amp)));
+        assert_eq!(s.next(), Some(Ok(Pipe)));
+        assert_eq!(s.next(), Some(Ok(Caret)));
+        assert_eq!(s.next(), Some(Ok(LtLt)));
+        assert_eq!(s.next(), Some(Ok(GtGt)));
+        assert_eq!(s.next(), Some(Ok(Tilde)));
+        assert_eq!(s.next(), Some(Ok(TildeEq)));
+        assert_eq!(s.next(), Some(Ok(DoubleQuestion)));
+        assert_eq!(s.next(), Some(Ok(Question)));
+        assert_eq!(s.next(), None);
+
+        let mut s = Scanner::new("+= -= *= /= %= +=1");
+        assert_eq!(s.next(), Some(Ok(PlusEq)));
+        assert_eq!(s.next(), Some(Ok(MinusEq)));
+        assert_eq!(s.next(), Some(Ok(TimesEq)));
+        assert_eq!(s.next(), Some(Ok(DivideEq)));
+        assert_eq!(s.next(), Some(Ok(PercentEq)));
+        assert_eq!(s.next(), Some(Ok(PlusEq)));
+        assert_eq!(s.next(), Some(Ok(Int(1))));
         assert_eq!(s.next(), None);
     }
 
@@ -305,11 +828,16 @@ mod tests {
         let mut s = Scanner::new("($)");
         assert_eq!(s.next(), Some(Ok(OpenParen)));
         assert_eq!(s.next(), Some(Err(TokenError::UnexpectedChar('$'))));
+
+        let mut s = Scanner::new("!");
+        assert_eq!(s.next(), Some(Err(TokenError::UnexpectedChar('!'))));
     }
 
     #[test]
     fn test_words() {
-        let mut s = Scanner::new("foo FOO _123_ Nil nil if else while false true");
+        let mut s = Scanner::new("foo FOO _123_ Nil nil if else while for break continue do fn \
+                                  let const throw try catch struct switch case default in \
+                                  false true");
         assert_eq!(s.next(), Some(Ok(Identifier("foo".to_owned()))));
         assert_eq!(s.next(), Some(Ok(Identifier("FOO".to_owned()))));
         assert_eq!(s.next(), Some(Ok(Identifier("_123_".to_owned()))));
@@ -318,6 +846,21 @@ mod tests {
         assert_eq!(s.next(), Some(Ok(If)));
         assert_eq!(s.next(), Some(Ok(Else)));
         assert_eq!(s.next(), Some(Ok(While)));
+        assert_eq!(s.next(), Some(Ok(For)));
+        assert_eq!(s.next(), Some(Ok(Break)));
+        assert_eq!(s.next(), Some(Ok(Continue)));
+        assert_eq!(s.next(), Some(Ok(Do)));
+        assert_eq!(s.next(), Some(Ok(Fn)));
+        assert_eq!(s.next(), Some(Ok(Let)));
+        assert_eq!(s.next(), Some(Ok(Const)));
+        assert_eq!(s.next(), Some(Ok(Throw)));
+        assert_eq!(s.next(), Some(Ok(Try)));
+        assert_eq!(s.next(), Some(Ok(Catch)));
+        assert_eq!(s.next(), Some(Ok(Struct)));
+        assert_eq!(s.next(), Some(Ok(Switch)));
+        assert_eq!(s.next(), Some(Ok(Case)));
+        assert_eq!(s.next(), Some(Ok(Default)));
+        assert_eq!(s.next(), Some(Ok(In)));
         assert_eq!(s.next(), Some(Ok(Boolean(false))));
         assert_eq!(s.next(), Some(Ok(Boolean(true))));
         assert_eq!(s.next(), None);
@@ -325,13 +868,41 @@ mod tests {
 
     #[test]
     fn test_number() {
-        let mut s = Scanner::new("0 -0 -1.2 +2.3 999 1.");
-        assert_eq!(s.next(), Some(Ok(Number(0.0))));
-        assert_eq!(s.next(), Some(Ok(Number(0.0))));
+        let mut s = Scanner::new("0 -0 -1.2 +2.3 999 1. 99999999999999999999");
+        assert_eq!(s.next(), Some(Ok(Int(0))));
+        assert_eq!(s.next(), Some(Ok(Int(0))));
         assert_eq!(s.next(), Some(Ok(Number(-1.2))));
         assert_eq!(s.next(), Some(Ok(Number(2.3))));
-        assert_eq!(s.next(), Some(Ok(Number(999.0))));
+        assert_eq!(s.next(), Some(Ok(Int(999))));
         assert_eq!(s.next(), Some(Ok(Number(1.0))));
+        assert_eq!(s.next(), Some(Ok(Number(1e20))));
+        assert_eq!(s.next(), None);
+
+        let mut s = Scanner::new("1..5 1.5..-2");
+        assert_eq!(s.next(), Some(Ok(Int(1))));
+        assert_eq!(s.next(), Some(Ok(DotDot)));
+        assert_eq!(s.next(), Some(Ok(Int(5))));
+        assert_eq!(s.next(), Some(Ok(Number(1.5))));
+        assert_eq!(s.next(), Some(Ok(DotDot)));
+        assert_eq!(s.next(), Some(Ok(Int(-2))));
+        assert_eq!(s.next(), None);
+
+        let mut s = Scanner::new("0xff -0x1F 0o17 0b1010 1_000_000 0b1111_0000 1_0.2_5");
+        assert_eq!(s.next(), Some(Ok(Int(255))));
+        assert_eq!(s.next(), Some(Ok(Int(-31))));
+        assert_eq!(s.next(), Some(Ok(Int(15))));
+        assert_eq!(s.next(), Some(Ok(Int(10))));
+        assert_eq!(s.next(), Some(Ok(Int(1000000))));
+        assert_eq!(s.next(), Some(Ok(Int(240))));
+        assert_eq!(s.next(), Some(Ok(Number(10.25))));
+        assert_eq!(s.next(), None);
+
+        let mut s = Scanner::new("0x 0b12 0x8000_0000_0000_0000 -0x8000000000000000");
+        assert_eq!(s.next(), Some(Err(TokenError::InvalidNumber("0x".to_owned()))));
+        assert_eq!(s.next(), Some(Err(TokenError::InvalidNumber("0b12".to_owned()))));
+        assert_eq!(s.next(),
+                   Some(Err(TokenError::InvalidNumber("0x8000_0000_0000_0000".to_owned()))));
+        assert_eq!(s.next(), Some(Ok(Int(i64::min_value()))));
         assert_eq!(s.next(), None);
     }
 
@@ -344,9 +915,123 @@ mod tests {
         assert_eq!(s.next(), None);
     }
 
+    #[test]
+    fn test_string_escapes() {
+        let mut s = Scanner::new(r#""a\tb\r\n\0" "\u{e9}\u{1F600}""#);
+        assert_eq!(s.next(), Some(Ok(String("a\tb\r\n\0".to_owned()))));
+        assert_eq!(s.next(), Some(Ok(String("\u{e9}\u{1F600}".to_owned()))));
+        assert_eq!(s.next(), None);
+
+        let cases = vec![(r#""\q""#, r"\q"),
+                         (r#""\u00e9""#, r"\u"),
+                         (r#""\u{}""#, r"\u{}"),
+                         (r#""\u{zz}""#, r"\u{zz}"),
+                         (r#""\u{+41}""#, r"\u{+41}"),
+                         (r#""\u{D800}""#, r"\u{D800}"),
+                         (r#""\u{1234567}""#, r"\u{1234567}"),
+                         (r#""\u{41""#, r"\u{41")];
+        for (src, escape) in cases {
+            assert_eq!(Scanner::new(src).next(),
+                       Some(Err(TokenError::InvalidEscape(escape.to_owned()))),
+                       "{:?}",
+                       src);
+        }
+
+        // A backslash at the end of the input might be followed by more.
+        assert_eq!(Scanner::new(r#""a\"#).next(), Some(Err(TokenError::IncompleteString)));
+    }
+
+    #[test]
+    fn test_long_string() {
+        let src = "\"\"\"say \"hi\"\n  \\o/\"\"\" \"\"\"\"\"\"";
+        let mut s = Scanner::new(src);
+        assert_eq!(s.next(), Some(Ok(String("say \"hi\"\n  \\o/".to_owned()))));
+        assert_eq!(s.next(), Some(Ok(String("".to_owned()))));
+        assert_eq!(s.next(), None);
+
+        // Indentation is stripped when the text starts on a new line.
+        let src = "q = \"\"\"\n    SELECT *\n\n      FROM t\n    \"\"\"";
+        let mut s = Scanner::new(src);
+        s.next();
+        s.next();
+        assert_eq!(s.next(), Some(Ok(String("SELECT *\n\n  FROM t\n".to_owned()))));
+        assert_eq!(s.next(), None);
+
+        // Only whitespace every line shares is stripped, even if it's more
+        // than one byte long.
+        let src = "\"\"\"\n  a\n\u{3000}b\n\u{3000} c\n\"\"\" \"\"\"\n\u{3000}a\n\u{3000} b\"\"\"";
+        let mut s = Scanner::new(src);
+        assert_eq!(s.next(), Some(Ok(String("  a\n\u{3000}b\n\u{3000} c\n".to_owned()))));
+        assert_eq!(s.next(), Some(Ok(String("a\n b".to_owned()))));
+        let src = "\"\"\"\n\t a\n\t\tb\n\"\"\"";
+        assert_eq!(Scanner::new(src).next(), Some(Ok(String(" a\n\tb\n".to_owned()))));
+
+        let mut s = Scanner::new("\"\"\"\n  a\n  \"");
+        assert_eq!(s.next(), Some(Err(TokenError::IncompleteString)));
+        assert_eq!(s.next(), None);
+    }
+
+    #[test]
+    fn test_custom_literal() {
+        let mut s = Scanner::with_prefixes(r#"$"5.00" #"2024-01-01" $ "x" # "y"
+                                           @"z""#,
+                                           vec!['$', '#']);
+        assert_eq!(s.next(), Some(Ok(CustomLiteral('$', "5.00".to_owned()))));
+        assert_eq!(s.next(), Some(Ok(CustomLiteral('#', "2024-01-01".to_owned()))));
+        assert_eq!(s.next(), Some(Err(TokenError::UnexpectedChar('$'))));
+        assert_eq!(s.next(), Some(Ok(String("x".to_owned()))));
+        // Without a string right after it, # still starts a comment.
+        assert_eq!(s.next(), Some(Err(TokenError::UnexpectedChar('@'))));
+        assert_eq!(s.next(), Some(Ok(String("z".to_owned()))));
+        assert_eq!(s.next(), None);
+    }
+
     #[test]
     fn test_comment() {
         let mut s = Scanner::new("#!/usr/bin/gate\n   # foo\n");
         assert_eq!(s.next(), None);
+
+        let mut s = Scanner::new("1 # no newline");
+        assert_eq!(s.next(), Some(Ok(Int(1))));
+        assert_eq!(s.next(), None);
+    }
+
+    #[test]
+    fn test_lexemes() {
+        let src = "# add\nx = 1.50 +\t\"\\\"\" # done\n";
+        let lexemes: Vec<Lexeme> = Scanner::new(src).lexemes().map(|l| l.unwrap()).collect();
+
+        assert_eq!(lexemes[0],
+                   Lexeme {
+                       leading: vec![Trivia::Comment("# add".to_owned()),
+                                     Trivia::Whitespace("\n".to_owned())],
+                       token: Some(Identifier("x".to_owned())),
+                       text: "x".to_owned(),
+                   });
+        assert_eq!(lexemes[2].token, Some(Number(1.5)));
+        assert_eq!(lexemes[2].text, "1.50");
+        assert_eq!(lexemes[4].leading, vec![Trivia::Whitespace("\t".to_owned())]);
+        assert_eq!(lexemes[4].text, r#""\"""#);
+        assert_eq!(lexemes[5],
+                   Lexeme {
+                       leading: vec![Trivia::Whitespace(" ".to_owned()),
+                                     Trivia::Comment("# done".to_owned()),
+                                     Trivia::Whitespace("\n".to_owned())],
+                       token: None,
+                       text: "".to_owned(),
+                   });
+        assert_eq!(lexemes.len(), 6);
+
+        // Joining everything back together gives the original source.
+        let mut joined = "".to_owned();
+        for l in lexemes {
+            for t in l.leading {
+                match t {
+                    Trivia::Whitespace(s) | Trivia::Comment(s) => joined.push_str(&s),
+                }
+            }
+            joined.push_str(&l.text);
+        }
+        assert_eq!(joined, src);
     }
 }
@@ -1,50 +1,301 @@
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::result;
 
+use checkpoint;
 use data::Data;
+use error::ExecuteError;
+use store::{StoreKind, VarStore};
 
-#[derive(Debug)]
+#[derive(Clone,Debug)]
 pub struct Scope {
-    vars: HashMap<String, Data>,
+    vars: Box<dyn VarStore>,
+    // The names of the variables declared with const.
+    consts: HashSet<String>,
+    // Scopes are numbered in the order they're created on their thread, so a
+    // checkpoint can tell which ones are newer than it.
+    id: u64,
+}
+
+thread_local! {
+    static CREATED: Cell<u64> = Cell::new(0);
+    // The address of every container inside a read-only global on this
+    // thread, with the name of the global.
+    static FROZEN: RefCell<HashMap<usize, String>> = RefCell::new(HashMap::new());
+}
+
+// created returns how many scopes have been created on this thread, which is
+// the id the next one will get.
+pub fn created() -> u64 {
+    CREATED.with(Cell::get)
 }
 
 impl Scope {
-    pub fn new() -> Self {
-        Scope { vars: HashMap::new() }
+    pub fn new(kind: StoreKind) -> Self {
+        Scope {
+            vars: kind.new_store(),
+            consts: HashSet::new(),
+            id: CREATED.with(|n| n.replace(n.get() + 1)),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn values(&self) -> Vec<&Data> {
+        self.vars.entries().into_iter().map(|(_, val)| val).collect()
     }
 }
 
+// An Env is a scope that can be shared.  A function keeps the scopes it was
+// defined in, so they live on after the block that created them ends.
+pub type Env = Rc<RefCell<Scope>>;
+
+// FrozenPolicy decides what happens when a script assigns to a read-only
+// global.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum FrozenPolicy {
+    // The assignment fails with ExecuteError::ReadOnly.
+    Error,
+    // The assignment creates a variable in the current scope, which hides the
+    // read-only global from then on.
+    Shadow,
+}
+
 #[derive(Debug)]
 pub struct ScopeTree {
-    pub frames: Vec<Scope>,
+    pub frames: Vec<Env>,
+    // The kind of store every new scope uses.
+    kind: StoreKind,
+    // The read-only globals, which sit beneath every scope.
+    frozen: HashMap<String, Data>,
+    policy: FrozenPolicy,
 }
 
 impl ScopeTree {
-    pub fn new() -> Self {
-        ScopeTree { frames: vec![Scope::new()] }
+    pub fn new(kind: StoreKind) -> Self {
+        ScopeTree {
+            frames: vec![new_env(kind)],
+            kind: kind,
+            frozen: HashMap::new(),
+            policy: FrozenPolicy::Error,
+        }
+    }
+
+    // freeze replaces the read-only globals.  The values are copied once, so
+    // the host's own lists and maps aren't shared with scripts, and then every
+    // read shares the copy.  Its containers can't be changed in place.
+    pub fn freeze(&mut self, vars: HashMap<String, Data>, policy: FrozenPolicy) {
+        self.thaw();
+        self.frozen = vars.into_iter().map(|(name, val)| (name, val.deep_copy())).collect();
+        self.policy = policy;
+        FROZEN.with(|frozen| {
+            let mut frozen = frozen.borrow_mut();
+            for (name, val) in &self.frozen {
+                mark_frozen(val, name, &mut frozen);
+            }
+        });
+    }
+
+    // thaw forgets that the containers of the read-only globals can't be
+    // changed, before the globals are replaced or dropped.
+    fn thaw(&mut self) {
+        FROZEN.with(|frozen| {
+            let mut frozen = frozen.borrow_mut();
+            for val in self.frozen.values() {
+                unmark_frozen(val, &mut frozen);
+            }
+        });
     }
 
     pub fn var(&self, name: &str) -> Option<Data> {
         for frame in self.frames.iter().rev() {
-            let var = frame.vars.get(name);
+            let var = frame.borrow().vars.get(name).cloned();
             if var.is_some() {
-                return var.cloned();
+                return var;
             }
         }
 
-        None
+        self.frozen.get(name).cloned()
+    }
+
+    // check_frozen fails if name is a read-only global that can't be hidden.
+    fn check_frozen(&self, name: &str) -> result::Result<(), ExecuteError> {
+        if self.policy == FrozenPolicy::Error && self.frozen.contains_key(name) {
+            return Err(ExecuteError::ReadOnly(name.to_owned()));
+        }
+        Ok(())
     }
 
     pub fn set_var(&mut self, name: &str, val: Data) {
-        for frame in self.frames.iter_mut().rev() {
-            match frame.vars.get_mut(name) {
-                Some(v) => {
-                    *v = val;
-                    return;
+        for frame in self.frames.iter().rev() {
+            if frame.borrow().vars.get(name).is_some() {
+                checkpoint::save(frame);
+                frame.borrow_mut().vars.insert(name, val);
+                return;
+            }
+        }
+
+        self.declare_var(name, val);
+    }
+
+    // declare_var sets the variable in the innermost scope, shadowing any
+    // variable of the same name in outer scopes.
+    pub fn declare_var(&mut self, name: &str, val: Data) {
+        if let Some(frame) = self.frames.last() {
+            checkpoint::save(frame);
+            let mut frame = frame.borrow_mut();
+            frame.consts.remove(name);
+            frame.vars.insert(name, val);
+        }
+    }
+
+    // assign_var is like set_var, but fails if the variable is a constant, or
+    // a read-only global that the policy doesn't allow hiding.
+    pub fn assign_var(&mut self, name: &str, val: Data) -> result::Result<(), ExecuteError> {
+        for frame in self.frames.iter().rev() {
+            if frame.borrow().consts.contains(name) {
+                return Err(ExecuteError::AssignToConst(name.to_owned()));
+            }
+            if frame.borrow().vars.get(name).is_some() {
+                checkpoint::save(frame);
+                frame.borrow_mut().vars.insert(name, val);
+                return Ok(());
+            }
+        }
+
+        self.check_frozen(name)?;
+        self.declare_var(name, val);
+        Ok(())
+    }
+
+    // define_var is like declare_var, but fails if the innermost scope already
+    // has a constant with the same name, or if it would hide a read-only
+    // global against the policy.  If constant is true, the variable can't be
+    // assigned to afterwards.
+    pub fn define_var(&mut self,
+                      name: &str,
+                      val: Data,
+                      constant: bool)
+                      -> result::Result<(), ExecuteError> {
+        self.check_frozen(name)?;
+        if let Some(frame) = self.frames.last() {
+            checkpoint::save(frame);
+            let mut frame = frame.borrow_mut();
+            if frame.consts.contains(name) {
+                return Err(ExecuteError::AssignToConst(name.to_owned()));
+            }
+            if constant {
+                frame.consts.insert(name.to_owned());
+            }
+            frame.vars.insert(name, val);
+        }
+        Ok(())
+    }
+
+    // globals returns the variables in the global scope.
+    pub fn globals(&self) -> Vec<(String, Data)> {
+        let global = self.frames[0].borrow();
+        global.vars
+            .entries()
+            .into_iter()
+            .map(|(name, val)| (name.to_owned(), val.clone()))
+            .collect()
+    }
+
+    // visible returns every variable that can be seen from the innermost
+    // scope, innermost first and sorted by name within each scope.  Read-only
+    // globals are left out, since they come from the host.
+    pub fn visible(&self) -> Vec<(String, Data)> {
+        let mut vars: Vec<(String, Data)> = vec![];
+        for frame in self.frames.iter().rev() {
+            let frame = frame.borrow();
+            let mut entries = frame.vars.entries();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            for (name, val) in entries {
+                if !vars.iter().any(|&(ref seen, _)| seen == name) {
+                    vars.push((name.to_owned(), val.clone()));
                 }
-                None => {}
             }
         }
+        vars
+    }
+
+    // new_env creates a scope using the tree's kind of store.
+    pub fn new_env(&self) -> Env {
+        new_env(self.kind)
+    }
+
+    // locals returns every scope outside the global one, outermost first.
+    pub fn locals(&self) -> Vec<Env> {
+        self.frames.iter().skip(1).cloned().collect()
+    }
+}
+
+impl Drop for ScopeTree {
+    fn drop(&mut self) {
+        self.thaw();
+    }
+}
+
+pub fn new_env(kind: StoreKind) -> Env {
+    Rc::new(RefCell::new(Scope::new(kind)))
+}
+
+// check_writable fails with ReadOnly if the container at address is inside a
+// read-only global.
+pub fn check_writable(address: usize) -> result::Result<(), ExecuteError> {
+    FROZEN.with(|frozen| {
+        let frozen = frozen.borrow();
+        if frozen.is_empty() {
+            return Ok(());
+        }
+        match frozen.get(&address) {
+            Some(name) => Err(ExecuteError::ReadOnly(name.clone())),
+            None => Ok(()),
+        }
+    })
+}
+
+// mark_frozen records every container inside d as belonging to the read-only
+// global name.  Containers already recorded aren't visited again, so values
+// that hold themselves are handled.
+fn mark_frozen(d: &Data, name: &str, frozen: &mut HashMap<usize, String>) {
+    let address = match d.address() {
+        Some(address) => address,
+        None => return,
+    };
+    if frozen.contains_key(&address) {
+        return;
+    }
+    frozen.insert(address, name.to_owned());
+    for child in children(d) {
+        mark_frozen(&child, name, frozen);
+    }
+}
+
+fn unmark_frozen(d: &Data, frozen: &mut HashMap<usize, String>) {
+    let address = match d.address() {
+        Some(address) => address,
+        None => return,
+    };
+    if frozen.remove(&address).is_none() {
+        return;
+    }
+    for child in children(d) {
+        unmark_frozen(&child, frozen);
+    }
+}
 
-        self.frames.last_mut().unwrap().vars.insert(String::from(name), val);
+// children returns the values directly inside a container.
+fn children(d: &Data) -> Vec<Data> {
+    match d {
+        &Data::List(ref l) => l.borrow().clone(),
+        &Data::Map(ref m) => m.borrow().values().cloned().collect(),
+        &Data::Set(ref s) => s.borrow().to_vec(),
+        &Data::Struct(ref s) => s.borrow().fields.values().cloned().collect(),
+        _ => vec![],
     }
 }
@@ -0,0 +1,68 @@
+// The seq module holds lazy sequences, which produce their values one at a
+// time as they're needed.  Builtins like take and zip return them, so
+// `take(0..1000000000, 3)` only ever produces three numbers.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::iter;
+
+use data::Data;
+
+// A Sequence can only be used once: looping over it uses up the values it
+// produces, through every variable holding it.
+pub struct Sequence {
+    iter: RefCell<Box<dyn Iterator<Item = Data>>>,
+}
+
+impl Sequence {
+    pub fn new<I>(iter: I) -> Self
+        where I: Iterator<Item = Data> + 'static
+    {
+        Sequence { iter: RefCell::new(Box::new(iter)) }
+    }
+
+    // next produces the sequence's next value, or None once it's used up.
+    pub fn next(&self) -> Option<Data> {
+        self.iter.borrow_mut().next()
+    }
+}
+
+// A sequence is only equal to itself, since comparing the values of two
+// sequences would use them up.
+impl PartialEq for Sequence {
+    fn eq(&self, other: &Sequence) -> bool {
+        self as *const Sequence == other as *const Sequence
+    }
+}
+
+impl fmt::Debug for Sequence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sequence")
+    }
+}
+
+// values returns the values of d one at a time, or None if d can't be looped
+// over.  Unlike Data::iter, lists and arrays are read as the values are
+// needed rather than copied up front, so taking a few values from a large list
+// only does as much work as it has to.
+pub fn values(d: &Data) -> Option<Box<dyn Iterator<Item = Data>>> {
+    match d {
+        &Data::List(ref l) => {
+            let l = l.clone();
+            let mut i = 0;
+            Some(Box::new(iter::from_fn(move || {
+                i += 1;
+                l.borrow().get(i - 1).cloned()
+            })))
+        }
+        &Data::NumArray(ref a) => {
+            let a = a.clone();
+            let mut i = 0;
+            Some(Box::new(iter::from_fn(move || {
+                i += 1;
+                a.borrow().get(i - 1).cloned().map(Data::Number)
+            })))
+        }
+        d => d.iter(),
+    }
+}
@@ -0,0 +1,154 @@
+// The set module holds the values of sets.  Sets keep their values in the
+// order they were added, like lists, but each value only once, and finding
+// out whether a set holds a value doesn't mean looking through all of them.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::iter;
+use std::rc::Rc;
+use std::slice;
+
+use data::Data;
+
+// A ValueSet holds a set's values in the order they were added.  Values that
+// can be hashed, which are nil, booleans, numbers and strings, are indexed, so
+// looking one up takes constant time.  Other values, like lists, are compared
+// one at a time.
+#[derive(Clone,Default)]
+pub struct ValueSet {
+    items: Vec<Data>,
+    index: HashSet<Key>,
+}
+
+// A Key is a hashable value.  Whole numbers in range of an int are hashed as
+// that int, since they're equal to it, which also makes 0 and -0 the same.
+// Other numbers are hashed by their bits.
+#[derive(Clone,Eq,Hash,PartialEq)]
+enum Key {
+    Nil,
+    Boolean(bool),
+    Int(i64),
+    Number(u64),
+    Str(Rc<str>),
+}
+
+impl Key {
+    fn new(d: &Data) -> Option<Key> {
+        match d {
+            &Data::Nil => Some(Key::Nil),
+            &Data::Boolean(b) => Some(Key::Boolean(b)),
+            &Data::Int(i) => Some(Key::Int(i)),
+            // NaN isn't equal to anything, so it's never looked up.
+            &Data::Number(n) if n.is_nan() => None,
+            &Data::Number(n) if n.fract() == 0.0 && n >= i64::MIN as f64 &&
+                                n < -(i64::MIN as f64) => Some(Key::Int(n as i64)),
+            &Data::Number(n) => Some(Key::Number(n.to_bits())),
+            &Data::Str(ref s) => Some(Key::Str(s.clone())),
+            _ => None,
+        }
+    }
+}
+
+impl ValueSet {
+    pub fn new() -> Self {
+        ValueSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn contains(&self, d: &Data) -> bool {
+        match Key::new(d) {
+            Some(key) => self.index.contains(&key),
+            None => self.items.iter().any(|item| item == d),
+        }
+    }
+
+    // insert adds d to the end of the set, unless the set already holds it.
+    // It returns whether d was added.
+    pub fn insert(&mut self, d: Data) -> bool {
+        let added = match Key::new(&d) {
+            Some(key) => self.index.insert(key),
+            None => !self.items.contains(&d),
+        };
+        if added {
+            self.items.push(d);
+        }
+        added
+    }
+
+    pub fn iter<'a>(&'a self) -> slice::Iter<'a, Data> {
+        self.items.iter()
+    }
+
+    pub fn to_vec(&self) -> Vec<Data> {
+        self.items.clone()
+    }
+}
+
+impl Extend<Data> for ValueSet {
+    fn extend<I: IntoIterator<Item = Data>>(&mut self, items: I) {
+        for item in items {
+            self.insert(item);
+        }
+    }
+}
+
+impl iter::FromIterator<Data> for ValueSet {
+    fn from_iter<I: IntoIterator<Item = Data>>(items: I) -> Self {
+        let mut set = ValueSet::new();
+        set.extend(items);
+        set
+    }
+}
+
+// Sets are equal if they hold the same values, in any order.
+impl PartialEq for ValueSet {
+    fn eq(&self, other: &ValueSet) -> bool {
+        self.len() == other.len() && self.iter().all(|item| other.contains(item))
+    }
+}
+
+impl fmt::Debug for ValueSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(&self.items).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use data::Data::*;
+
+    #[test]
+    fn test_value_set() {
+        let mut s: ValueSet = vec![Int(1), Str("a".into()), Number(-0.0), Int(1)]
+            .into_iter()
+            .collect();
+        assert_eq!(s.to_vec(), vec![Int(1), Str("a".into()), Number(-0.0)]);
+        assert!(s.contains(&Str("a".into())) && s.contains(&Number(0.0)));
+        assert!(!s.contains(&Number(1.5)) && !s.contains(&Nil));
+
+        // Ints and whole numbers are the same value.
+        assert!(s.contains(&Number(1.0)) && s.contains(&Int(0)));
+        assert!(!s.insert(Number(1.0)) && !s.insert(Int(0)));
+        assert!(s.insert(Number(1e300)) && s.contains(&Number(1e300)));
+
+        let l = Data::list(vec![Int(2)]);
+        assert!(s.insert(l.clone()));
+        assert!(!s.insert(Data::list(vec![Int(2)])));
+        assert!(s.contains(&l));
+        assert!(s.insert(Number(f64::NAN)) && !s.contains(&Number(f64::NAN)));
+
+        let a: ValueSet = vec![Int(1), Int(2)].into_iter().collect();
+        let b: ValueSet = vec![Int(2), Int(1)].into_iter().collect();
+        assert_eq!(a, b);
+        assert!(a != vec![Int(1)].into_iter().collect());
+    }
+}
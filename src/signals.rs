@@ -0,0 +1,180 @@
+// The signals module lets scripts handle signals like SIGINT with on_signal,
+// so long-running scripts such as watchers and bots can clean up when they're
+// stopped.  It's only built with the "signals" feature.
+//
+// A signal can arrive while the program is in the middle of anything, so it
+// doesn't run gate code itself.  It cancels the program instead, and once the
+// interrupted evaluation has unwound, the handlers of the signals that arrived
+// run in its place.
+
+use std::os::raw::c_int;
+use std::result;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use signal_hook::{consts, flag, low_level, SigId};
+
+use builtins::{expect_arg_count, expect_str, NativeFn};
+use cancel::{self, CancelToken};
+use context::Context;
+use data::Data;
+use engine::Engine;
+use error::ExecuteError;
+use error::ExecuteError::*;
+use expr::Result;
+
+struct Handler {
+    name: String,
+    func: Data,
+    // Set when the signal arrives, and cleared when its handler runs.
+    pending: Arc<AtomicBool>,
+    ids: Vec<SigId>,
+}
+
+// Signals holds a program's signal handlers.  They're unregistered when it's
+// dropped.
+#[derive(Default)]
+pub struct Signals {
+    handlers: Vec<Handler>,
+}
+
+impl Signals {
+    // handle makes func the handler for the named signal, replacing any
+    // handler it had.  The signal cancels the program through cancel.
+    pub fn handle(&mut self,
+                  name: &str,
+                  func: Data,
+                  cancel: &CancelToken)
+                  -> result::Result<(), ExecuteError> {
+        let name = name.trim_start_matches("SIG").to_owned();
+        if let Some(handler) = self.handlers.iter_mut().find(|h| h.name == name) {
+            handler.func = func;
+            return Ok(());
+        }
+
+        let signal = signal_number(&name)?;
+        let pending = Arc::new(AtomicBool::new(false));
+        let mut ids = vec![];
+        for f in vec![pending.clone(), cancel::flag(cancel)] {
+            ids.push(flag::register(signal, f).map_err(|e| Io(e.to_string()))?);
+        }
+        self.handlers.push(Handler {
+            name: name,
+            func: func,
+            pending: pending,
+            ids: ids,
+        });
+        Ok(())
+    }
+
+    // take_pending returns the name and handler of each signal that has
+    // arrived since it was last called.
+    pub fn take_pending(&mut self) -> Vec<(String, Data)> {
+        self.handlers
+            .iter()
+            .filter(|h| h.pending.swap(false, Ordering::SeqCst))
+            .map(|h| (h.name.clone(), h.func.clone()))
+            .collect()
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        for handler in &self.handlers {
+            for &id in &handler.ids {
+                low_level::unregister(id);
+            }
+        }
+    }
+}
+
+fn signal_number(name: &str) -> result::Result<c_int, ExecuteError> {
+    match name {
+        "INT" => Ok(consts::SIGINT),
+        "TERM" => Ok(consts::SIGTERM),
+        "HUP" => Ok(consts::SIGHUP),
+        "QUIT" => Ok(consts::SIGQUIT),
+        "USR1" => Ok(consts::SIGUSR1),
+        "USR2" => Ok(consts::SIGUSR2),
+        _ => {
+            Err(InvalidArgument {
+                expected: "INT, TERM, HUP, QUIT, USR1 or USR2".to_owned(),
+                found: name.to_owned(),
+            })
+        }
+    }
+}
+
+// on_signal(name, f) makes the named signal, such as "INT" or "TERM", stop
+// the program and then call f with the signal's name.
+pub struct OnSignal;
+
+impl NativeFn for OnSignal {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_count(v, 2)?;
+        let name = expect_str(&v[0])?;
+        if v[1].type_name() != "function" {
+            return Err(InvalidArgument {
+                expected: "function".to_owned(),
+                found: v[1].type_name(),
+            });
+        }
+        ctx.handle_signal(&name, v[1].clone())?;
+        Ok(Data::Nil)
+    }
+}
+
+// run_handlers calls the handlers of the signals that have arrived, stopping
+// at the first one that fails.
+pub fn run_handlers(engine: &Engine, ctx: &mut Context) -> result::Result<(), ExecuteError> {
+    for (name, func) in ctx.take_signals() {
+        engine.call(ctx, &func, vec![Data::Str(name.into())])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use error::Error;
+    use program::Program;
+
+    #[test]
+    fn test_on_signal() {
+        let mut p = Program::new();
+        p.engine.register("raise", |_: &[Data]| {
+            low_level::raise(consts::SIGUSR1).unwrap();
+            Ok(Data::Nil)
+        });
+        let source = "stopped = nil  i = 0\n\
+                      on_signal(\"SIGUSR1\", fn(name) { stopped = name })\n\
+                      while true { i += 1  if i == 3 raise() }";
+        assert_eq!(p.run_source(source), Err(Error::Execute(Cancelled)));
+        assert_eq!(p.var("stopped"), Some(Data::Str("USR1".into())));
+        assert_eq!(p.var("i"), Some(Data::Int(3)));
+        assert_eq!(p.run_source("i"), Ok(Data::Int(3)));
+
+        assert!(p.run_source("on_signal(\"NOPE\", fn(name) 1)").is_err());
+        assert!(p.run_source("on_signal(\"INT\", 1)").is_err());
+    }
+
+    #[test]
+    fn test_on_signal_nested() {
+        let mut p = Program::new();
+        p.engine.register("raise", |_: &[Data]| {
+            low_level::raise(consts::SIGUSR2).unwrap();
+            Ok(Data::Nil)
+        });
+        // The signal arrives in a template, but its handler runs once the
+        // whole program has stopped, so the try around the template can't
+        // catch what the handler throws.
+        let source = "caught = nil\n\
+                      on_signal(\"SIGUSR2\", fn(name) { throw name })\n\
+                      m = { raise: raise, f: || 0 }  t = \"{{ raise() + f() }}\"\n\
+                      for i in 0..3 { try render(t, m, true) catch e { caught = e } }";
+        assert_eq!(p.run_source(source),
+                   Err(Error::Execute(Thrown(Data::Str("USR2".into())))));
+        assert_eq!(p.var("caught"), Some(Data::Nil));
+    }
+}
@@ -0,0 +1,120 @@
+// The snapshot module records the global variables of a program at a point in
+// time, so front ends can show what an evaluation changed and hosts can check
+// that a script only touched the variables it was allowed to.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use data::Data;
+
+// A Snapshot holds a copy of every global variable.  Lists, maps, sets and
+// instances are copied all the way down, so changing them in place afterwards
+// doesn't change the snapshot.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Snapshot {
+    vars: BTreeMap<String, Data>,
+}
+
+impl Snapshot {
+    pub fn new<I: IntoIterator<Item = (String, Data)>>(vars: I) -> Self {
+        Snapshot { vars: vars.into_iter().map(|(name, val)| (name, val.deep_copy())).collect() }
+    }
+
+    pub fn var(&self, name: &str) -> Option<&Data> {
+        self.vars.get(name)
+    }
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Change {
+    Created { name: String, value: Data },
+    Changed { name: String, old: Data, new: Data },
+    Removed { name: String, old: Data },
+}
+
+impl Change {
+    pub fn name(&self) -> &str {
+        match self {
+            &Change::Created { ref name, .. } |
+            &Change::Changed { ref name, .. } |
+            &Change::Removed { ref name, .. } => name,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Change::Created { ref name, ref value } => write!(f, "+ {} = {:?}", name, value),
+            &Change::Changed { ref name, ref old, ref new } => {
+                write!(f, "~ {} = {:?} (was {:?})", name, new, old)
+            }
+            &Change::Removed { ref name, ref old } => write!(f, "- {} (was {:?})", name, old),
+        }
+    }
+}
+
+// diff_snapshots returns how the variables changed between snapshots a and b,
+// ordered by name.
+pub fn diff_snapshots(a: &Snapshot, b: &Snapshot) -> Vec<Change> {
+    let mut changes = vec![];
+    for (name, new) in &b.vars {
+        match a.vars.get(name) {
+            None => {
+                changes.push(Change::Created {
+                    name: name.clone(),
+                    value: new.clone(),
+                })
+            }
+            Some(old) if old != new => {
+                changes.push(Change::Changed {
+                    name: name.clone(),
+                    old: old.clone(),
+                    new: new.clone(),
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, old) in &a.vars {
+        if !b.vars.contains_key(name) {
+            changes.push(Change::Removed {
+                name: name.clone(),
+                old: old.clone(),
+            });
+        }
+    }
+    changes.sort_by(|x, y| x.name().cmp(y.name()));
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use program::Program;
+
+    #[test]
+    fn test_diff_snapshots() {
+        let mut p = Program::new();
+        p.run_source("a = 1  b = [1]  c = \"same\"").unwrap();
+        let before = p.state_snapshot();
+
+        p.run_source("a = 2  push(b, 2)  c = \"same\"  d = nil").unwrap();
+        let after = p.state_snapshot();
+        assert_eq!(before.var("b"), Some(&Data::list(vec![Data::Int(1)])));
+
+        let changes = diff_snapshots(&before, &after);
+        let shown: Vec<_> = changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(shown,
+                   vec!["~ a = Int(2) (was Int(1))",
+                        "~ b = List([Int(1), Int(2)]) (was List([Int(1)]))",
+                        "+ d = Nil"]);
+
+        assert_eq!(diff_snapshots(&after, &before)[2],
+                   Change::Removed {
+                       name: "d".to_owned(),
+                       old: Data::Nil,
+                   });
+        assert!(diff_snapshots(&after, &after).is_empty());
+    }
+}
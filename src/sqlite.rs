@@ -0,0 +1,204 @@
+// The sqlite module lets scripts query SQLite databases, which makes gate
+// handy for small scripts that inspect data.  It's only built with the
+// "sqlite" feature.  Connections are handles, which scripts close with close.
+//
+// Databases are files, so opening one needs the same permission as read_file
+// and write_file, except for ":memory:", which is held in memory.  SQL can
+// also reach files, through ATTACH and VACUUM INTO, so while the program
+// isn't allowed to use files, connections can't attach databases.
+
+use std::i32;
+use std::result;
+
+use indexmap::IndexMap;
+use rusqlite::{Connection, OpenFlags};
+use rusqlite::limits::Limit;
+use rusqlite::types::{Value, ValueRef};
+
+use builtins::{expect_arg_count, expect_arg_range, expect_file_access, expect_str, NativeFn};
+use context::Context;
+use data::Data;
+use data::Data::*;
+use engine::Engine;
+use error::ExecuteError;
+use error::ExecuteError::*;
+use expr::Result;
+
+// sqlite_open(path) opens the SQLite database at path, creating it if it
+// doesn't exist, and returns the connection.  ":memory:" opens a new, empty
+// database held in memory.
+pub struct SqliteOpen;
+
+impl NativeFn for SqliteOpen {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_count(v, 1)?;
+        let path = match expect_str(&v[0])?.as_str() {
+            ":memory:" => ":memory:".to_owned(),
+            _ => expect_file_access(ctx, "sqlite_open", &v[0], true)?,
+        };
+        // Without SQLITE_OPEN_URI, paths like "file:/x?vfs=..." are plain file
+        // names rather than instructions to SQLite.
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE |
+                    OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let conn = Connection::open_with_flags(&path, flags)
+            .map_err(|e| Database(e.to_string()))?;
+        Ok(ctx.handles().add(conn))
+    }
+}
+
+// query(db, sql[, params]) runs a query and returns its rows as a list of
+// maps from column names to values.  Each ? in sql is replaced by the next
+// value in the list params.
+pub struct Query;
+
+impl NativeFn for Query {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_range(v, 2, 3)?;
+        let sql = expect_str(&v[1])?;
+        let params = expect_params(v.get(2))?;
+        let conn = expect_connection(ctx, &v[0])?;
+
+        let mut stmt = conn.prepare(&sql).map_err(|e| Database(e.to_string()))?;
+        let columns: Vec<String> = stmt.column_names().iter().map(|&c| c.to_owned()).collect();
+        let mut rows = stmt.query(rusqlite::params_from_iter(params))
+            .map_err(|e| Database(e.to_string()))?;
+        let mut results = vec![];
+        while let Some(row) = rows.next().map_err(|e| Database(e.to_string()))? {
+            let mut fields = IndexMap::new();
+            for (i, column) in columns.iter().enumerate() {
+                let value = row.get_ref(i).map_err(|e| Database(e.to_string()))?;
+                fields.insert(column.clone(), from_sql(value));
+            }
+            results.push(Data::map(fields));
+        }
+        Ok(Data::list(results))
+    }
+}
+
+// execute(db, sql[, params]) runs a statement that doesn't return rows, such as
+// an INSERT or CREATE TABLE, and returns the number of rows it changed.
+pub struct Execute;
+
+impl NativeFn for Execute {
+    fn call(&mut self, _: &Engine, ctx: &mut Context, v: &[Data]) -> Result {
+        expect_arg_range(v, 2, 3)?;
+        let sql = expect_str(&v[1])?;
+        let params = expect_params(v.get(2))?;
+        let conn = expect_connection(ctx, &v[0])?;
+        let changed = conn.execute(&sql, rusqlite::params_from_iter(params))
+            .map_err(|e| Database(e.to_string()))?;
+        Ok(Int(changed as i64))
+    }
+}
+
+// expect_connection returns the connection d refers to, letting it attach
+// databases only if the program may use files.  VACUUM INTO attaches the
+// database it writes, so it's limited too.
+fn expect_connection<'a>(ctx: &'a mut Context,
+                         d: &Data)
+                         -> result::Result<&'a mut Connection, ExecuteError> {
+    // SQLite lowers limits above its own maximum to that maximum.
+    let attached = if ctx.fs_allowed() { i32::MAX } else { 0 };
+    let conn: &mut Connection = ctx.handles().get(d).ok_or_else(|| {
+        InvalidArgument {
+            expected: "open database".to_owned(),
+            found: d.to_string(),
+        }
+    })?;
+    conn.set_limit(Limit::SQLITE_LIMIT_ATTACHED, attached);
+    Ok(conn)
+}
+
+fn expect_params(d: Option<&Data>) -> result::Result<Vec<Value>, ExecuteError> {
+    let l = match d {
+        None => return Ok(vec![]),
+        Some(&List(ref l)) => l,
+        Some(d) => {
+            return Err(InvalidArgument {
+                expected: "list".to_owned(),
+                found: d.type_name(),
+            })
+        }
+    };
+    let values = l.borrow();
+    values.iter().map(to_sql).collect()
+}
+
+// to_sql converts d to a SQLite value.  SQLite has no booleans, so they're
+// stored as 0 and 1.
+fn to_sql(d: &Data) -> result::Result<Value, ExecuteError> {
+    match d {
+        &Nil => Ok(Value::Null),
+        &Boolean(b) => Ok(Value::Integer(b as i64)),
+        &Int(i) => Ok(Value::Integer(i)),
+        &Number(n) => Ok(Value::Real(n)),
+        &Str(ref s) => Ok(Value::Text(s.to_string())),
+        d => {
+            Err(InvalidArgument {
+                expected: "nil, boolean, number or string".to_owned(),
+                found: d.type_name(),
+            })
+        }
+    }
+}
+
+// from_sql converts a SQLite value to data.  Blobs become lists of bytes.
+fn from_sql(v: ValueRef) -> Data {
+    match v {
+        ValueRef::Null => Nil,
+        ValueRef::Integer(i) => Int(i),
+        ValueRef::Real(n) => Number(n),
+        ValueRef::Text(s) => Str(String::from_utf8_lossy(s).into()),
+        ValueRef::Blob(b) => Data::list(b.iter().map(|&b| Int(b as i64)).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use error::Error;
+    use program::Program;
+
+    #[test]
+    fn test_sqlite() {
+        let mut p = Program::new();
+        let source = "db = sqlite_open(\":memory:\")\n\
+                      execute(db, \"CREATE TABLE pets (name TEXT, age INTEGER, weight REAL)\")\n\
+                      execute(db, \"INSERT INTO pets VALUES (?, ?, ?), (?, ?, ?)\",\n\
+                              list(\"Rex\", 3, 12.5, \"Tom\", nil, true))";
+        assert_eq!(p.run_source(source), Ok(Int(2)));
+
+        let mut rex = IndexMap::new();
+        rex.insert("name".to_owned(), Str("Rex".into()));
+        rex.insert("age".to_owned(), Int(3));
+        assert_eq!(p.run_source("query(db, \"SELECT name, age FROM pets WHERE age > ?\", \
+                                 list(1))"),
+                   Ok(Data::list(vec![Data::map(rex)])));
+        assert_eq!(p.run_source("query(db, \"SELECT age, weight FROM pets WHERE name = 'Tom'\")"),
+                   Ok(Data::list(vec![Data::map(vec![("age".to_owned(), Nil),
+                                                     ("weight".to_owned(), Number(1.0))]
+                                                    .into_iter()
+                                                    .collect())])));
+
+        assert_eq!(p.run_source("query(db, \"SELECT * FROM nope\")"),
+                   Err(Error::Execute(Database("no such table: nope".to_owned()))));
+        assert!(p.run_source("execute(db, \"INSERT INTO pets VALUES (?, 1, 1)\", \
+                              list(list()))")
+            .is_err());
+        assert!(p.run_source("sqlite_open(\"pets.db\")").is_err());
+
+        // Without access to files, SQL can't create them either.
+        let attach = "execute(db, \"ATTACH DATABASE 'gate-sqlite-test.db' AS x\")";
+        assert_eq!(p.run_source(attach),
+                   Err(Error::Execute(Database("too many attached databases - max 0"
+                       .to_owned()))));
+        assert!(p.run_source("execute(db, \"VACUUM INTO 'gate-sqlite-test.db'\")").is_err());
+        assert!(!Path::new("gate-sqlite-test.db").exists());
+
+        assert_eq!(p.run_source("close(db)"), Ok(Nil));
+        assert!(p.run_source("query(db, \"SELECT 1\")").is_err());
+    }
+}
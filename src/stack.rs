@@ -0,0 +1,105 @@
+// The stack module keeps gate from recursing so deeply that it overflows the
+// thread's stack, which aborts the whole process instead of panicking.
+// Parsing, loading and evaluation check how much stack they've used since the
+// outermost of them started, and fail with an error once it's more than the
+// limit.  Counting stack rather than levels of recursion keeps the limit
+// meaningful in debug builds, which use many times more stack per level.
+
+use std::cell::Cell;
+
+// DEFAULT_LIMIT leaves room to spare on a thread with Rust's default 2MiB
+// stack.
+pub const DEFAULT_LIMIT: usize = 1 << 20;
+
+thread_local! {
+    // Where the stack was when the outermost parse or evaluation on this
+    // thread started, or 0 if none is running.
+    static BASE: Cell<usize> = Cell::new(0);
+    static LIMIT: Cell<usize> = Cell::new(DEFAULT_LIMIT);
+}
+
+// set_limit sets how many bytes of stack gate may use on the current thread,
+// counted from where the host calls into it.  Hosts that run gate on a thread
+// with a bigger stack can raise it to allow deeper recursion, and ones that
+// call into gate with much of the stack already used should lower it.
+pub fn set_limit(bytes: usize) {
+    LIMIT.with(|limit| limit.set(bytes));
+}
+
+pub fn limit() -> usize {
+    LIMIT.with(Cell::get)
+}
+
+// A Guard marks a parse or evaluation as running.  Only the outermost guard
+// on a thread records where the stack starts, and it forgets it when it's
+// dropped, including while unwinding from a panic.
+pub struct Guard {
+    outermost: bool,
+}
+
+impl Guard {
+    pub fn is_outermost(&self) -> bool {
+        self.outermost
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if self.outermost {
+            BASE.with(|base| base.set(0));
+        }
+    }
+}
+
+pub fn enter() -> Guard {
+    let here = position();
+    BASE.with(|base| {
+        let outermost = base.get() == 0;
+        if outermost {
+            base.set(here);
+        }
+        Guard { outermost: outermost }
+    })
+}
+
+// exceeded returns whether the running parse or evaluation has used more of
+// the stack than the limit allows.
+pub fn exceeded() -> bool {
+    let base = BASE.with(Cell::get);
+    if base == 0 {
+        return false;
+    }
+    let here = position();
+    let used = if base > here { base - here } else { here - base };
+    used > limit()
+}
+
+// position returns the address of a local in a frame of its own, which is
+// about where the caller's frame ends.
+#[inline(never)]
+fn position() -> usize {
+    let marker = 0u8;
+    &marker as *const u8 as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // recurse returns how many times it recursed before running out of stack.
+    fn recurse() -> usize {
+        if exceeded() { 0 } else { 1 + recurse() }
+    }
+
+    #[test]
+    fn test_exceeded() {
+        assert!(!exceeded());
+        let guard = enter();
+        assert!(guard.is_outermost() && !enter().is_outermost());
+        set_limit(64 << 10);
+        assert!(recurse() > 0);
+        set_limit(DEFAULT_LIMIT);
+        drop(guard);
+        assert!(!exceeded());
+    }
+}
@@ -0,0 +1,142 @@
+// The store module holds the ways a scope can keep its variables.  Every scope
+// of a context uses the same kind of store, chosen when the context is
+// created, so different designs can be compared on real scripts without
+// changing anything else.  benches/scopes.rs times them.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use data::Data;
+
+// A VarStore holds the variables of one scope.
+pub trait VarStore: Debug {
+    fn get(&self, name: &str) -> Option<&Data>;
+    fn get_mut(&mut self, name: &str) -> Option<&mut Data>;
+    // insert sets the variable, adding it if the store doesn't have it yet.
+    fn insert(&mut self, name: &str, val: Data);
+    fn entries(&self) -> Vec<(&str, &Data)>;
+    fn box_clone(&self) -> Box<dyn VarStore>;
+}
+
+impl Clone for Box<dyn VarStore> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+// StoreKind chooses the VarStore used by every scope of a context.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum StoreKind {
+    // Each scope hashes its variables' names.  This is the default.
+    Hash,
+    // Each scope keeps its variables in a flat vector of slots, found by
+    // comparing names, which avoids hashing for the few variables most scopes
+    // hold.
+    Slots,
+}
+
+impl StoreKind {
+    pub fn new_store(self) -> Box<dyn VarStore> {
+        match self {
+            StoreKind::Hash => Box::new(HashStore::default()),
+            StoreKind::Slots => Box::new(SlotStore::default()),
+        }
+    }
+}
+
+#[derive(Clone,Debug,Default)]
+pub struct HashStore {
+    vars: HashMap<String, Data>,
+}
+
+impl VarStore for HashStore {
+    fn get(&self, name: &str) -> Option<&Data> {
+        self.vars.get(name)
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut Data> {
+        self.vars.get_mut(name)
+    }
+
+    fn insert(&mut self, name: &str, val: Data) {
+        self.vars.insert(name.to_owned(), val);
+    }
+
+    fn entries(&self) -> Vec<(&str, &Data)> {
+        self.vars.iter().map(|(name, val)| (name.as_str(), val)).collect()
+    }
+
+    fn box_clone(&self) -> Box<dyn VarStore> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone,Debug,Default)]
+pub struct SlotStore {
+    // A variable's slot is its index in both vectors.  Variables are never
+    // removed, so slots don't move.
+    names: Vec<String>,
+    slots: Vec<Data>,
+}
+
+impl SlotStore {
+    fn slot(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n == name)
+    }
+}
+
+impl VarStore for SlotStore {
+    fn get(&self, name: &str) -> Option<&Data> {
+        self.slot(name).map(|i| &self.slots[i])
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut Data> {
+        match self.slot(name) {
+            Some(i) => Some(&mut self.slots[i]),
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, name: &str, val: Data) {
+        match self.slot(name) {
+            Some(i) => self.slots[i] = val,
+            None => {
+                self.names.push(name.to_owned());
+                self.slots.push(val);
+            }
+        }
+    }
+
+    fn entries(&self) -> Vec<(&str, &Data)> {
+        self.names.iter().map(|n| n.as_str()).zip(&self.slots).collect()
+    }
+
+    fn box_clone(&self) -> Box<dyn VarStore> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data::Data::*;
+    use super::*;
+
+    #[test]
+    fn test_stores() {
+        for &kind in &[StoreKind::Hash, StoreKind::Slots] {
+            let mut store = kind.new_store();
+            assert_eq!(store.get("x"), None);
+            store.insert("x", Int(1));
+            store.insert("y", Int(2));
+            store.insert("x", Int(3));
+            *store.get_mut("y").unwrap() = Int(4);
+            assert_eq!(store.get_mut("z"), None);
+
+            let copy = store.clone();
+            store.insert("x", Nil);
+            let mut entries = copy.entries();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            assert_eq!(entries, vec![("x", &Int(3)), ("y", &Int(4))]);
+        }
+    }
+}
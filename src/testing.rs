@@ -0,0 +1,157 @@
+// The testing module runs tests written in gate.  A test is a function at the
+// top level of a script whose name starts with `test_`, and it fails if
+// calling it fails, usually because an assert or assert_eq didn't hold.
+//
+// Each test runs in a program of its own, which runs the whole script first,
+// so tests can share helper functions and setup but can't affect each other.
+// Programs run with their output captured, no input and random numbers
+// seeded, so a test does the same thing every time.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use corpus;
+use error::Error;
+use expr::Expression;
+use parser::Parser;
+use program::Program;
+
+// TOP_LEVEL names the result of running a script's top level, which is only
+// reported when it fails, since none of the script's tests can run then.
+pub const TOP_LEVEL: &str = "(top level)";
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    // Why the test failed, or None if it passed.
+    pub error: Option<Error>,
+}
+
+// A Failure is a test that failed, along with the script it's in.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Failure {
+    pub path: PathBuf,
+    pub test: String,
+    pub error: Error,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {}: {}", self.path.display(), self.test, self.error)
+    }
+}
+
+// A Report is the outcome of running the tests in one or more scripts.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Report {
+    pub total: usize,
+    pub failures: Vec<Failure>,
+}
+
+// test_names returns the names of the tests in source, in order.
+pub fn test_names(source: &str) -> Result<Vec<String>, Error> {
+    let mut names = vec![];
+    for res in Parser::new(source) {
+        if let Expression::FunctionDef { ref name, .. } = res? {
+            if name.starts_with("test_") {
+                names.push(name.clone());
+            }
+        }
+    }
+    Ok(names)
+}
+
+// run runs each test in source and returns their results.  If the script
+// can't be parsed or its top level fails, that's the only result.
+pub fn run(source: &str) -> Vec<TestResult> {
+    let top_level_failed = |e| {
+        vec![TestResult {
+                 name: TOP_LEVEL.to_owned(),
+                 error: Some(e),
+             }]
+    };
+    let names = match test_names(source) {
+        Ok(names) => names,
+        Err(e) => return top_level_failed(e),
+    };
+    if let Err(e) = new_program().run_source(source) {
+        return top_level_failed(e);
+    }
+
+    names.into_iter()
+        .map(|name| {
+            let mut program = new_program();
+            let error = program.run_source(source)
+                .and_then(|_| program.run_source(&format!("{}()", name)))
+                .err();
+            TestResult {
+                name: name,
+                error: error,
+            }
+        })
+        .collect()
+}
+
+// run_path runs the tests in the script at path, or in every script in the
+// directory at path and the directories inside it.
+pub fn run_path(path: &Path) -> io::Result<Report> {
+    let paths = if path.is_dir() {
+        corpus::cases(path)?
+    } else {
+        vec![path.to_owned()]
+    };
+
+    let mut report = Report::default();
+    for path in paths {
+        let source = fs::read_to_string(&path)?;
+        for result in run(&source) {
+            report.total += 1;
+            if let Some(error) = result.error {
+                report.failures.push(Failure {
+                    path: path.clone(),
+                    test: result.name,
+                    error: error,
+                });
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn new_program() -> Program {
+    let mut program = Program::new();
+    program.capture_output();
+    program.set_input(io::empty());
+    program.seed_rng(0);
+    program
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use error::ExecuteError;
+
+    #[test]
+    fn test_run() {
+        let source = "fn double(n) n * 2\n\
+                      count = 0\n\
+                      fn test_double() assert_eq(double(2), 4)\n\
+                      fn test_isolated() { count += 1  assert_eq(count, 1) }\n\
+                      fn test_again() { count += 1  assert_eq(count, 1) }\n\
+                      fn test_wrong() assert(double(1) == 3, \"1 doubled isn't 3\")\n";
+        let results = run(source);
+        let names: Vec<_> = results.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["test_double", "test_isolated", "test_again", "test_wrong"]);
+        assert!(results[..3].iter().all(|r| r.error.is_none()));
+        assert_eq!(results[3].error,
+                   Some(Error::Execute(ExecuteError::AssertionFailed("1 doubled isn't 3"
+                       .to_owned()))));
+
+        let results = run("fn test_unreached() 1\nnope");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].name, TOP_LEVEL);
+    }
+}
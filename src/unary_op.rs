@@ -0,0 +1,63 @@
+use std::fmt;
+
+use data::Data;
+use data::Data::*;
+use error::ExecuteError;
+use expr::Result;
+
+use UnaryOp::*;
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    BitNot,
+}
+
+impl UnaryOp {
+    pub fn eval(&self, operand: &Data) -> Result {
+        match (self, operand) {
+            (&Neg, &Number(n)) => Ok(Number(-n)),
+            (&Neg, &Int(i)) => Ok(i.checked_neg().map_or(Number(-(i as f64)), Int)),
+            (&BitNot, &Int(i)) => Ok(Int(!i)),
+            (o, d) => {
+                Err(ExecuteError::InvalidUnaryOperation {
+                    op: o.clone(),
+                    operand: d.type_name(),
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Neg => write!(f, "-"),
+            &BitNot => write!(f, "~"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data::Data::*;
+    use error::ExecuteError::*;
+    use super::UnaryOp::*;
+
+    #[test]
+    fn test_unary_expr() {
+        assert_eq!(Neg.eval(&Number(2.5)), Ok(Number(-2.5)));
+        assert_eq!(Neg.eval(&Number(-1.0)), Ok(Number(1.0)));
+        assert_eq!(Neg.eval(&Int(3)), Ok(Int(-3)));
+        assert_eq!(Neg.eval(&Int(i64::MIN)), Ok(Number(-(i64::MIN as f64))));
+        assert_eq!(BitNot.eval(&Int(0)), Ok(Int(-1)));
+        assert_eq!(BitNot.eval(&Int(0b101)), Ok(Int(-6)));
+
+        // Invalid operation
+        assert_eq!(Neg.eval(&Str("foo".into())),
+                   Err(InvalidUnaryOperation {
+                       op: Neg,
+                       operand: "string".to_owned(),
+                   }));
+    }
+}
@@ -0,0 +1,15 @@
+extern crate gate;
+
+use std::path::Path;
+
+#[test]
+fn test_corpus() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    assert!(!gate::corpus::cases(&dir).unwrap().is_empty());
+
+    let mismatches = gate::corpus::verify(&dir).unwrap();
+    let report: String = mismatches.iter().map(|m| m.to_string()).collect();
+    assert!(mismatches.is_empty(),
+            "run `gate parse --update` to accept intended changes\n{}",
+            report);
+}
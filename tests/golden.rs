@@ -0,0 +1,85 @@
+// Golden tests run each script in tests/golden and compare what it printed,
+// followed by its final value or error, with the .out file beside it.  Set
+// GATE_UPDATE_GOLDEN=1 to write the .out files instead, which records new
+// scripts and accepts intended changes.
+
+extern crate gate;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+// run runs source the way the golden files expect: with its output captured,
+// no input and random numbers seeded, so the result is the same every time.
+// Scripts get a stack as large as a main thread's, since the threads tests run
+// on have small ones, and may use most of it.
+fn run(source: String) -> String {
+    thread::Builder::new()
+        .stack_size(8 << 20)
+        .spawn(move || {
+            gate::stack::set_limit(6 << 20);
+            run_on_this_thread(&source)
+        })
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
+fn run_on_this_thread(source: &str) -> String {
+    let mut program = gate::Program::new();
+    program.capture_output();
+    program.set_input(io::empty());
+    program.seed_rng(0);
+    let res = program.run_source(source);
+    let mut out = program.take_output();
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out.push_str(&format!("=> {}\n", gate::doctest::render(&res)));
+    out
+}
+
+fn scripts(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "gate"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[test]
+fn test_golden() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let update = env::var("GATE_UPDATE_GOLDEN").map_or(false, |v| v == "1");
+    let paths = scripts(&dir);
+    assert!(!paths.is_empty());
+
+    let mut failures = String::new();
+    for path in paths {
+        let actual = run(fs::read_to_string(&path).unwrap());
+        let golden = path.with_extension("out");
+        let expected = fs::read_to_string(&golden).unwrap_or_default();
+        if actual == expected {
+            continue;
+        }
+
+        if update {
+            fs::write(&golden, &actual).unwrap();
+            continue;
+        }
+        failures.push_str(&format!("{}:\n", path.display()));
+        for line in expected.lines() {
+            failures.push_str(&format!("- {}\n", line));
+        }
+        for line in actual.lines() {
+            failures.push_str(&format!("+ {}\n", line));
+        }
+    }
+    assert!(failures.is_empty(),
+            "set GATE_UPDATE_GOLDEN=1 to accept intended changes\n{}",
+            failures);
+}
@@ -0,0 +1,27 @@
+// The scripts in tests/lang test the language itself, in gate, using the same
+// runner as `gate test`.
+
+extern crate gate;
+
+use std::path::Path;
+use std::thread;
+
+#[test]
+fn test_lang() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lang");
+    // Scripts get a stack as large as a main thread's, since the threads tests
+    // run on have small ones, and may use most of it.
+    let (total, failures) = thread::Builder::new()
+        .stack_size(8 << 20)
+        .spawn(move || {
+            gate::stack::set_limit(6 << 20);
+            let report = gate::testing::run_path(&dir).unwrap();
+            let failures: String = report.failures.iter().map(|f| f.to_string()).collect();
+            (report.total, failures)
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    assert!(total > 0);
+    assert!(failures.is_empty(), "\n{}", failures);
+}